@@ -0,0 +1,158 @@
+//! A compact binary encoding of `RadarAddress`, for storing cached addresses
+//! in less space than their JSON and reading them without a JSON parser.
+//!
+//! An encoded address is `MAGIC`, the format `VERSION`, a little-endian
+//! `u16` with a bit set for each of the optional fields present (in the
+//! order they are declared), those fields, and then the sources. Strings are
+//! their length as a LEB128 varint followed by their UTF-8; coordinates are
+//! little-endian `f64`s; the sources are a varint count of strings. Field
+//! names aren't stored, so a new field means a new version.
+
+use crate::RadarAddress;
+
+/// The first byte of an encoded address. JSON never starts with it.
+pub const MAGIC: u8 = 1;
+
+pub const VERSION: u8 = 1;
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+enum Field<'a> {
+    Text(Option<&'a str>),
+    Number(Option<f64>),
+}
+
+fn text(value: &Option<String>) -> Field<'_> {
+    Field::Text(value.as_deref())
+}
+
+/// The optional fields of `address`, in declaration order.
+fn fields(address: &RadarAddress) -> [Field<'_>; 15] {
+    [
+        text(&address.address_label),
+        text(&address.city),
+        text(&address.confidence),
+        text(&address.country),
+        text(&address.country_code),
+        text(&address.county),
+        text(&address.formatted_address),
+        Field::Number(address.latitude),
+        text(&address.layer),
+        Field::Number(address.longitude),
+        text(&address.number),
+        text(&address.postal_code),
+        text(&address.state),
+        text(&address.state_code),
+        text(&address.street),
+    ]
+}
+
+pub fn encode(address: &RadarAddress) -> Vec<u8> {
+    let fields = fields(address);
+    let present = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| !matches!(field, Field::Text(None) | Field::Number(None)))
+        .fold(0u16, |bits, (i, _)| bits | 1 << i);
+    let mut out = vec![MAGIC, VERSION];
+    out.extend_from_slice(&present.to_le_bytes());
+    for field in fields {
+        match field {
+            Field::Text(Some(text)) => write_string(&mut out, text),
+            Field::Number(Some(number)) => out.extend_from_slice(&number.to_le_bytes()),
+            Field::Text(None) | Field::Number(None) => {}
+        }
+    }
+    write_varint(&mut out, address.sources.len() as u64);
+    for source in &address.sources {
+        write_string(&mut out, source);
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.bytes.len() < n {
+            return Err(String::from("truncated binary address"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("varint too long in binary address"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = usize::try_from(self.varint()?).map_err(|e| e.to_string())?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let bytes = self.take(8)?.try_into().expect("took 8 bytes");
+        Ok(f64::from_le_bytes(bytes))
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<RadarAddress, String> {
+    let mut reader = Reader { bytes };
+    match reader.take(2)? {
+        [MAGIC, VERSION] => {}
+        [MAGIC, version] => return Err(format!("unknown binary address version {}", version)),
+        _ => return Err(String::from("not a binary address")),
+    }
+    let present = u16::from_le_bytes(reader.take(2)?.try_into().expect("took 2 bytes"));
+    let has = |i: u16| present & (1 << i) != 0;
+    let text = |i: u16, reader: &mut Reader| has(i).then(|| reader.string()).transpose();
+    let mut address = RadarAddress {
+        address_label: text(0, &mut reader)?,
+        city: text(1, &mut reader)?,
+        confidence: text(2, &mut reader)?,
+        country: text(3, &mut reader)?,
+        country_code: text(4, &mut reader)?,
+        county: text(5, &mut reader)?,
+        formatted_address: text(6, &mut reader)?,
+        ..Default::default()
+    };
+    address.latitude = has(7).then(|| reader.number()).transpose()?;
+    address.layer = text(8, &mut reader)?;
+    address.longitude = has(9).then(|| reader.number()).transpose()?;
+    address.number = text(10, &mut reader)?;
+    address.postal_code = text(11, &mut reader)?;
+    address.state = text(12, &mut reader)?;
+    address.state_code = text(13, &mut reader)?;
+    address.street = text(14, &mut reader)?;
+    let sources = reader.varint()?;
+    address.sources = (0..sources)
+        .map(|_| reader.string())
+        .collect::<Result<_, _>>()?;
+    Ok(address)
+}
@@ -0,0 +1,72 @@
+//! A 0-1 confidence for each result, so consumers can accept good matches
+//! automatically and send the rest for review.
+//!
+//! It averages how close the address is to the cell (1 at the cell, 0.5 at
+//! 25m, falling off from there), how specific its layer is (an address beats
+//! a street beats a locality) and, when the provider rated its own match
+//! (`exact`, `interpolated`, `fallback`), that rating. Forward geocodes have
+//! no cell to be close to, so theirs leaves closeness out.
+
+use crate::{GeocodeResponse, RadarAddress};
+
+/// Meters at which closeness alone scores 0.5.
+const HALF_SCORE_METERS: f64 = 25.0;
+
+fn closeness(distance: f64) -> f64 {
+    1.0 / (1.0 + distance.max(0.0) / HALF_SCORE_METERS)
+}
+
+fn specificity(layer: Option<&str>) -> f64 {
+    match layer {
+        Some("address") => 1.0,
+        Some("intersection") => 0.9,
+        Some("street") => 0.8,
+        Some("neighborhood") => 0.6,
+        Some("postalCode") => 0.5,
+        Some("locality") => 0.4,
+        Some("county") => 0.2,
+        Some("state") | Some("country") => 0.1,
+        _ => 0.3,
+    }
+}
+
+fn provider_rating(confidence: Option<&str>) -> Option<f64> {
+    match confidence? {
+        "exact" => Some(1.0),
+        "interpolated" => Some(0.8),
+        "fallback" => Some(0.5),
+        _ => None,
+    }
+}
+
+/// The mean of `parts`, to three decimal places.
+fn mean(parts: &[f64]) -> f64 {
+    let mean = parts.iter().sum::<f64>() / parts.len() as f64;
+    (mean * 1000.0).round() / 1000.0
+}
+
+/// The confidence of `geocode`, to three decimal places.
+pub fn score(geocode: &GeocodeResponse) -> f64 {
+    let mut parts = vec![
+        closeness(geocode.distance),
+        specificity(geocode.address.layer.as_deref()),
+    ];
+    parts.extend(provider_rating(geocode.address.confidence.as_deref()));
+    mean(&parts)
+}
+
+/// The confidence of `address` as the answer to a forward geocode, to three
+/// decimal places.
+pub fn forward_score(address: &RadarAddress) -> f64 {
+    let mut parts = vec![specificity(address.layer.as_deref())];
+    parts.extend(provider_rating(address.confidence.as_deref()));
+    mean(&parts)
+}
+
+/// Score `geocodes` and drop those below `min`.
+pub fn apply(geocodes: &mut Vec<GeocodeResponse>, min: f64) {
+    for geocode in geocodes.iter_mut() {
+        geocode.confidence = score(geocode);
+    }
+    geocodes.retain(|g| g.confidence >= min);
+}
@@ -0,0 +1,202 @@
+//! Geometry helpers.
+//!
+//! Distances are `Vincenty`, accurate on the ellipsoid to well under a
+//! metre, or `Haversine`, a sphere that is cheaper but up to about 0.5% off,
+//! shortest towards the poles. Which one matters near the edge of the match
+//! radius.
+
+use geoutils::Location;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    Vincenty,
+    Haversine,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Vincenty => "vincenty",
+            Algorithm::Haversine => "haversine",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "vincenty" => Some(Algorithm::Vincenty),
+            "haversine" => Some(Algorithm::Haversine),
+            _ => None,
+        }
+    }
+}
+
+/// Meters per degree of latitude (and of longitude at the equator).
+pub const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// `lon` brought into -180 to 180, for longitudes that went round the
+/// antimeridian.
+pub fn wrap_lon(lon: f64) -> f64 {
+    match (-180.0..=180.0).contains(&lon) {
+        true => lon,
+        false => (lon + 180.0).rem_euclid(360.0) - 180.0,
+    }
+}
+
+/// A box on the globe: a range of latitudes, and the one or two ranges of
+/// longitudes it takes in, split where the box crosses the antimeridian
+/// (the same range twice when it doesn't).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub lat: (f64, f64),
+    pub lon: [(f64, f64); 2],
+}
+
+/// The box `lat_span` degrees of latitude and `lon_span` of longitude either
+/// side of `lat`/`lon`. A box reaching a pole takes in every longitude, as
+/// they all meet there.
+pub fn bounds(lat: f64, lon: f64, lat_span: f64, lon_span: f64) -> Bounds {
+    let (south, north) = (lat - lat_span, lat + lat_span);
+    let lats = (south.max(-90.0), north.min(90.0));
+    let (west, east) = (lon - lon_span, lon + lon_span);
+    let lon = if south <= -90.0 || north >= 90.0 || lon_span >= 180.0 || lon_span.is_nan() {
+        [(-180.0, 180.0); 2]
+    } else if west < -180.0 {
+        [(west + 360.0, 180.0), (-180.0, east)]
+    } else if east > 180.0 {
+        [(west, 180.0), (-180.0, east - 360.0)]
+    } else {
+        [(west, east); 2]
+    };
+    Bounds { lat: lats, lon }
+}
+
+/// The box around `lat`/`lon` holding everything within `meters` of it, a
+/// little over for the distance algorithms whose degrees are shorter than
+/// `METERS_PER_DEGREE`.
+pub fn around(lat: f64, lon: f64, meters: f64) -> Bounds {
+    let lat_span = meters * 1.01 / METERS_PER_DEGREE;
+    // infinite at a pole, which `bounds` takes as every longitude
+    let lon_span = lat_span / lat.to_radians().cos().abs();
+    bounds(lat, lon, lat_span, lon_span)
+}
+
+/// Meters between two points by `algorithm`. Vincenty falls back to
+/// haversine for the near-antipodal pairs where it fails to converge.
+pub fn distance_meters(algorithm: Algorithm, lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let a = Location::new(lat1, lon1);
+    let b = Location::new(lat2, lon2);
+    match algorithm {
+        Algorithm::Vincenty => a
+            .distance_to(&b)
+            .unwrap_or_else(|_| a.haversine_distance_to(&b)),
+        Algorithm::Haversine => a.haversine_distance_to(&b),
+    }
+    .meters()
+}
+
+/// Even-odd ray casting test for a closed ring of (x, y) points.
+pub fn ring_contains(ring: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (xi, yi) = ring[i];
+        let (xj, yj) = ring[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVERYWHERE: [(f64, f64); 2] = [(-180.0, 180.0); 2];
+
+    #[test]
+    fn wrap_lon_leaves_longitudes_in_range() {
+        assert_eq!(wrap_lon(10.0), 10.0);
+        assert_eq!(wrap_lon(-180.0), -180.0);
+        assert_eq!(wrap_lon(180.0), 180.0);
+    }
+
+    #[test]
+    fn wrap_lon_brings_longitudes_back_round() {
+        assert_eq!(wrap_lon(190.0), -170.0);
+        assert_eq!(wrap_lon(-190.0), 170.0);
+        assert_eq!(wrap_lon(540.0), -180.0);
+        assert_eq!(wrap_lon(-370.0), -10.0);
+    }
+
+    #[test]
+    fn bounds_away_from_the_antimeridian_is_one_range() {
+        let bounds = bounds(10.0, 20.0, 1.0, 2.0);
+        assert_eq!(bounds.lat, (9.0, 11.0));
+        assert_eq!(bounds.lon, [(18.0, 22.0); 2]);
+    }
+
+    #[test]
+    fn bounds_splits_east_of_the_antimeridian() {
+        let bounds = bounds(10.0, 179.5, 1.0, 1.0);
+        assert_eq!(bounds.lon, [(178.5, 180.0), (-180.0, -179.5)]);
+    }
+
+    #[test]
+    fn bounds_splits_west_of_the_antimeridian() {
+        let bounds = bounds(10.0, -179.5, 1.0, 1.0);
+        assert_eq!(bounds.lon, [(179.5, 180.0), (-180.0, -178.5)]);
+    }
+
+    #[test]
+    fn bounds_reaching_a_pole_takes_every_longitude() {
+        let north = bounds(89.5, 30.0, 1.0, 1.0);
+        assert_eq!(north.lat, (88.5, 90.0));
+        assert_eq!(north.lon, EVERYWHERE);
+
+        let south = bounds(-89.5, 30.0, 1.0, 1.0);
+        assert_eq!(south.lat, (-90.0, -88.5));
+        assert_eq!(south.lon, EVERYWHERE);
+    }
+
+    #[test]
+    fn bounds_wider_than_the_globe_takes_every_longitude() {
+        assert_eq!(bounds(10.0, 0.0, 1.0, 180.0).lon, EVERYWHERE);
+        assert_eq!(bounds(10.0, 0.0, 1.0, f64::INFINITY).lon, EVERYWHERE);
+        assert_eq!(bounds(10.0, 0.0, 1.0, f64::NAN).lon, EVERYWHERE);
+    }
+
+    #[test]
+    fn around_the_antimeridian_reaches_the_other_side() {
+        let bounds = around(10.0, 179.99999, 10.0);
+        let (west, east) = bounds.lon[1];
+        assert_eq!(west, -180.0);
+        assert!((west..=east).contains(&-179.99999));
+    }
+
+    #[test]
+    fn around_a_pole_takes_every_longitude() {
+        assert_eq!(around(90.0, 0.0, 10.0).lon, EVERYWHERE);
+        assert_eq!(around(-90.0, 0.0, 10.0).lon, EVERYWHERE);
+    }
+
+    #[test]
+    fn distances_cross_the_antimeridian_the_short_way() {
+        for algorithm in [Algorithm::Vincenty, Algorithm::Haversine] {
+            let meters = distance_meters(algorithm, 10.0, 179.99999, 10.0, -179.99999);
+            assert!(meters < 3.0, "{:?}: {}", algorithm, meters);
+        }
+    }
+
+    #[test]
+    fn distances_at_a_pole_ignore_longitude() {
+        for algorithm in [Algorithm::Vincenty, Algorithm::Haversine] {
+            let meters = distance_meters(algorithm, 90.0, 0.0, 90.0, 120.0);
+            assert!(meters < 1.0, "{:?}: {}", algorithm, meters);
+            let meters = distance_meters(algorithm, 89.99999, 0.0, 89.99999, 180.0);
+            assert!(meters < 3.0, "{:?}: {}", algorithm, meters);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Grid references: UTM and MGRS coordinates read into WGS84 latitude and
+//! longitude, for callers whose tooling speaks those rather than degrees.
+//!
+//! UTM is written `18T 285000 4780000`: the zone, its latitude band, then
+//! the easting and northing in metres. MGRS is the same grid with the
+//! hundred-kilometre square named by two letters and the metres within it
+//! by an even run of digits, `18TWN8500080000` (spaces allowed), from ten
+//! kilometres down to one metre; a reference names the centre of its
+//! square. Both cover 80°S to 84°N; the polar UPS zones aren't read.
+//!
+//! The inverse projection is the Krüger series on WGS84, well under a
+//! millimetre off inside a zone.
+
+/// Latitude band letters from 80°S, 8° each (X is 12°).
+const BANDS: &[u8] = b"CDEFGHJKLMNPQRSTUVWX";
+
+/// MGRS column letters, eight to each zone in turn.
+const COLUMNS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// MGRS row letters, repeating every 2,000 km of northing.
+const ROWS: &[u8] = b"ABCDEFGHJKLMNPQRSTUV";
+
+/// The smallest northing, in hundreds of kilometres, of a square in each
+/// band, which places a row letter's 2,000 km cycle.
+const BAND_MIN_NORTHING: [u32; 20] = [
+    11, 20, 28, 37, 46, 55, 64, 73, 82, 91, 0, 8, 17, 26, 35, 44, 53, 62, 70, 79,
+];
+
+const SCALE: f64 = 0.9996;
+const FALSE_EASTING: f64 = 500_000.0;
+const FALSE_NORTHING_SOUTH: f64 = 10_000_000.0;
+
+/// The latitude and longitude of `easting`/`northing` in UTM `zone`, north
+/// or south of the equator.
+pub fn utm_to_wgs84(zone: u8, north: bool, easting: f64, northing: f64) -> (f64, f64) {
+    let f = 1.0 / 298.257_223_563;
+    let n = f / (2.0 - f);
+    let (n2, n3) = (n * n, n * n * n);
+    let a = 6_378_137.0 / (1.0 + n) * (1.0 + n2 / 4.0 + n2 * n2 / 64.0);
+    let beta = [
+        n / 2.0 - 2.0 * n2 / 3.0 + 37.0 * n3 / 96.0,
+        n2 / 48.0 + n3 / 15.0,
+        17.0 * n3 / 480.0,
+    ];
+    let delta = [
+        2.0 * n - 2.0 * n2 / 3.0 - 2.0 * n3,
+        7.0 * n2 / 3.0 - 8.0 * n3 / 5.0,
+        56.0 * n3 / 15.0,
+    ];
+
+    let northing = if north {
+        northing
+    } else {
+        northing - FALSE_NORTHING_SOUTH
+    };
+    let xi = northing / (SCALE * a);
+    let eta = (easting - FALSE_EASTING) / (SCALE * a);
+    let (mut xi1, mut eta1) = (xi, eta);
+    for (j, beta) in (1..).zip(beta) {
+        let j = 2.0 * j as f64;
+        xi1 -= beta * (j * xi).sin() * (j * eta).cosh();
+        eta1 -= beta * (j * xi).cos() * (j * eta).sinh();
+    }
+    let chi = (xi1.sin() / eta1.cosh()).asin();
+    let mut lat = chi;
+    for (j, delta) in (1..).zip(delta) {
+        lat += delta * (2.0 * j as f64 * chi).sin();
+    }
+    let central = f64::from(zone) * 6.0 - 183.0;
+    let lon = central + eta1.sinh().atan2(xi1.cos()).to_degrees();
+    (lat.to_degrees(), crate::geo::wrap_lon(lon))
+}
+
+/// The zone and band at the start of a reference, like `18T`, and the rest.
+fn zone_band(text: &str) -> Option<(u8, u8, &str)> {
+    let digits = text.bytes().take_while(u8::is_ascii_digit).count();
+    let zone = text.get(..digits)?.parse::<u8>().ok()?;
+    let rest = text[digits..].trim_start();
+    let band = rest.bytes().next()?.to_ascii_uppercase();
+    match (1..=60).contains(&zone) && BANDS.contains(&band) {
+        true => Some((zone, band, &rest[1..])),
+        false => None,
+    }
+}
+
+/// Latitude and longitude of a UTM reference like `18T 285000 4780000`.
+pub fn parse_utm(text: &str) -> Option<(f64, f64)> {
+    let (zone, band, rest) = zone_band(text.trim())?;
+    let mut metres = rest
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<f64>().ok().filter(|v| v.is_finite()));
+    let (easting, northing) = (metres.next()??, metres.next()??);
+    if metres.next().is_some()
+        || !(100_000.0..1_000_000.0).contains(&easting)
+        || !(0.0..=10_000_000.0).contains(&northing)
+    {
+        return None;
+    }
+    Some(utm_to_wgs84(zone, band >= b'N', easting, northing))
+}
+
+/// Latitude and longitude of the centre of the square an MGRS reference like
+/// `18TWN8500080000` names.
+pub fn parse_mgrs(text: &str) -> Option<(f64, f64)> {
+    let text = text.split_whitespace().collect::<String>();
+    let (zone, band, rest) = zone_band(&text)?;
+    let rest = rest.as_bytes();
+    let (column, row, digits) = match rest {
+        [column, row, digits @ ..] => (
+            column.to_ascii_uppercase(),
+            row.to_ascii_uppercase(),
+            digits,
+        ),
+        _ => return None,
+    };
+    if digits.len() % 2 != 0 || digits.len() > 10 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let first_column = (usize::from(zone) - 1) % 3 * 8;
+    let column = COLUMNS.iter().position(|&c| c == column)?;
+    let east = column.checked_sub(first_column).filter(|&east| east < 8)? as u32 + 1;
+    let row = ROWS.iter().position(|&r| r == row)?;
+    let offset = if zone % 2 == 0 { 5 } else { 0 };
+    let mut north = ((row + 20 - offset) % 20) as u32;
+    let band = BANDS.iter().position(|&b| b == band)?;
+    while north < BAND_MIN_NORTHING[band] {
+        north += 20;
+    }
+
+    let half = digits.len() / 2;
+    let (within_east, within_north) = digits.split_at(half);
+    let unit = 10f64.powi(5 - half as i32);
+    let metres = |digits: &[u8]| {
+        // no digits for the square itself
+        let value = std::str::from_utf8(digits)
+            .ok()?
+            .parse::<f64>()
+            .unwrap_or(0.0);
+        Some(value * unit + unit / 2.0)
+    };
+    let easting = f64::from(east) * 100_000.0 + metres(within_east)?;
+    let northing = f64::from(north) * 100_000.0 + metres(within_north)?;
+    Some(utm_to_wgs84(zone, band >= 10, easting, northing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `point` is within about a metre of `lat`/`lon`.
+    fn near(point: Option<(f64, f64)>, lat: f64, lon: f64) -> bool {
+        let (got_lat, got_lon) = point.expect("a point");
+        (got_lat - lat).abs() < 1e-5 && (got_lon - lon).abs() < 1e-5
+    }
+
+    /// How far up the central meridian 45° is, in UTM metres.
+    const NORTHING_45: f64 = 4_982_950.4;
+
+    #[test]
+    fn utm_on_the_central_meridian() {
+        assert!(near(parse_utm("31N 500000 0"), 0.0, 3.0));
+        assert!(near(
+            parse_utm(&format!("18T 500000 {}", NORTHING_45)),
+            45.0,
+            -75.0
+        ));
+    }
+
+    #[test]
+    fn utm_south_of_the_equator_mirrors_the_north() {
+        let south = 10_000_000.0 - NORTHING_45;
+        assert!(near(
+            parse_utm(&format!("18G 500000 {}", south)),
+            -45.0,
+            -75.0
+        ));
+        let (north_lat, north_lon) = parse_utm("56T 334873 5252266").unwrap();
+        let (south_lat, south_lon) = parse_utm("56G 334873 4747734").unwrap();
+        assert!((north_lat + south_lat).abs() < 1e-9);
+        assert!((north_lon - south_lon).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mgrs_to_the_metre() {
+        // the Washington Monument and null island
+        assert!(near(parse_mgrs("18S UJ 23487 06483"), 38.8895, -77.0352));
+        assert!(near(parse_mgrs("31NAA6602100000"), 0.0, 0.0));
+    }
+
+    #[test]
+    fn mgrs_square_without_digits_is_its_centre() {
+        let (lat, lon) = utm_to_wgs84(18, true, 350_000.0, 4_350_000.0);
+        assert!(near(parse_mgrs("18SUJ"), lat, lon));
+    }
+
+    #[test]
+    fn mgrs_in_the_south() {
+        // near Cape Town: the second column of zone 34's set and, as the zone
+        // is even, rows lettered from F
+        let point = parse_mgrs("34HBH6150066700");
+        assert_eq!(point, parse_utm("34H 261500.5 6266700.5"));
+        let (lat, lon) = point.unwrap();
+        assert!((-34.0..-33.5).contains(&lat) && (18.0..19.0).contains(&lon));
+    }
+
+    #[test]
+    fn bad_zones_and_letters_are_refused() {
+        for reference in [
+            "0SUJ2348706483",
+            "61SUJ2348706483",
+            // no such band
+            "18IUJ2348706483",
+            "18OUJ2348706483",
+            // column A belongs to zones 1, 4, 7...
+            "18SAJ2348706483",
+            // no row I or W
+            "18SUI2348706483",
+            "18SUW2348706483",
+            // digits must pair up, at most five each
+            "18SUJ234870648",
+            "18SUJ234870648300",
+            "18SUJ2348706X83",
+        ] {
+            assert_eq!(parse_mgrs(reference), None, "{}", reference);
+        }
+        for reference in [
+            "18I 285000 4780000",
+            "0T 285000 4780000",
+            "18T 285000",
+            "18T 50 4780000",
+        ] {
+            assert_eq!(parse_utm(reference), None, "{}", reference);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! The geocoding cache's logic, apart from the server around it: the address
+//! types, distances, which cached addresses answer a lookup, how results are
+//! ranked and scored, and the lookup itself over pluggable storage and
+//! provider traits (see `lookup`).
+//!
+//! Nothing here does IO, reads the environment or needs an async runtime;
+//! every setting is passed in. The `gaia` server reads its settings from the
+//! environment and hands them to these functions.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub mod binary;
+pub mod confidence;
+pub mod geo;
+pub mod grid;
+pub mod lookup;
+pub mod matching;
+pub mod ranking;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeocodeResponse {
+    pub lat: String,
+    pub lon: String,
+    pub distance: f64,
+    /// See `confidence`; computed per response, never stored.
+    #[serde(default)]
+    pub confidence: f64,
+    pub address: RadarAddress,
+    /// When the address was fetched from the provider, if known; for
+    /// freshness and caching headers, never sent.
+    #[serde(skip)]
+    pub fetched_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RadarReverseGeocodeResponse {
+    pub meta: Value,
+    /// Absent from answers that aren't addresses, like place searches.
+    #[serde(default)]
+    pub addresses: Vec<RadarAddress>,
+    /// The response body exactly as the provider sent it.
+    #[serde(skip)]
+    pub raw: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RadarAddress {
+    pub address_label: Option<String>,
+    pub city: Option<String>,
+    /// The provider's own rating of the match, when it gives one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub county: Option<String>,
+    pub formatted_address: Option<String>,
+    pub latitude: Option<f64>,
+    pub layer: Option<String>,
+    pub longitude: Option<f64>,
+    pub number: Option<String>,
+    pub postal_code: Option<String>,
+    pub state: Option<String>,
+    pub state_code: Option<String>,
+    pub street: Option<String>,
+    /// Which providers gave this address, when results were merged from
+    /// several.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+}
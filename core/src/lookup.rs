@@ -0,0 +1,368 @@
+//! A reverse geocode through the cache: answer from `Storage` when a fresh
+//! cached address is within the match radius of the cell, otherwise ask the
+//! `Provider` and store what it says.
+//!
+//! `Geocoder::reverse` is the whole lookup. Callers with more to do between
+//! the two halves (waiting on another fetch of the cell, say) call `cached`
+//! and `fetch` themselves, as the server does.
+//!
+//! Both traits are async without naming a runtime, so implementations can
+//! sit on a database pool, an HTTP client, a `HashMap` in a test, or a
+//! worker's fetch API. `MemoryStorage` is the `HashMap` one.
+
+use std::{collections::HashMap, fmt, future::Future, sync::Mutex};
+
+use crate::{
+    confidence, geo::Algorithm, matching, ranking::Ranking, GeocodeResponse, RadarAddress,
+    RadarReverseGeocodeResponse,
+};
+
+/// A cached address, the cell it was cached for, and when it was fetched, if
+/// known.
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub cell: (String, String),
+    pub address: RadarAddress,
+    pub fetched_at: Option<i64>,
+}
+
+/// Where cached addresses live. The cache is split into namespaces; the
+/// empty one is shared.
+pub trait Storage {
+    type Error;
+
+    /// Cached addresses that may answer the cell `lat`/`lon`. Returning more
+    /// than that is fine, as those out of the match radius are dropped.
+    fn candidates(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+    ) -> impl Future<Output = Result<Vec<Cached>, Self::Error>>;
+
+    /// Cache the addresses of the provider's `response` for the cell,
+    /// fetched at `now`. With `replace` they take the place of those the
+    /// cell was answered with before.
+    fn store(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+        response: &RadarReverseGeocodeResponse,
+        replace: bool,
+        now: i64,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// Where addresses come from on a miss.
+pub trait Provider {
+    type Error;
+
+    fn reverse(
+        &self,
+        lat: &str,
+        lon: &str,
+    ) -> impl Future<Output = Result<RadarReverseGeocodeResponse, Self::Error>>;
+}
+
+#[derive(Debug)]
+pub enum LookupError<S, P> {
+    Storage(S),
+    Provider(P),
+}
+
+impl<S: fmt::Display, P: fmt::Display> fmt::Display for LookupError<S, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::Storage(e) => write!(f, "storage: {}", e),
+            LookupError::Provider(e) => write!(f, "provider: {}", e),
+        }
+    }
+}
+
+/// The settings a lookup runs with; the defaults are the server's.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Decimal places cells are rounded to.
+    pub precision: usize,
+    /// Seconds a cached address stays fresh; zero is forever.
+    pub ttl: i64,
+    /// `(layer, ttl)` for layers that stay fresh longer or shorter than
+    /// `ttl`.
+    pub layer_ttls: Vec<(String, i64)>,
+    /// Drop results with a lower confidence.
+    pub min_confidence: f64,
+    pub ranking: Ranking,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            precision: 5,
+            ttl: 0,
+            layer_ttls: vec![],
+            min_confidence: 0.0,
+            ranking: Ranking::default(),
+        }
+    }
+}
+
+impl Policy {
+    fn algorithm(&self) -> Algorithm {
+        self.ranking.algorithm
+    }
+
+    /// Seconds a cached address of `layer` stays fresh; zero is forever.
+    pub fn ttl_of(&self, layer: Option<&str>) -> i64 {
+        layer
+            .and_then(|layer| self.layer_ttls.iter().find(|(l, _)| l == layer))
+            .map_or(self.ttl, |&(_, ttl)| ttl)
+    }
+
+    /// Score, filter, sort and dedup answers for the cell.
+    pub fn finish(&self, geocodes: &mut Vec<GeocodeResponse>) {
+        confidence::apply(geocodes, self.min_confidence);
+        self.ranking.sort(geocodes);
+        self.ranking.dedup(geocodes);
+    }
+}
+
+#[derive(Debug)]
+pub struct Geocoder<'p, S, P> {
+    pub storage: S,
+    pub provider: P,
+    pub policy: &'p Policy,
+}
+
+impl<'p, S: Storage, P: Provider> Geocoder<'p, S, P> {
+    pub fn new(storage: S, provider: P, policy: &'p Policy) -> Self {
+        Geocoder {
+            storage,
+            provider,
+            policy,
+        }
+    }
+
+    /// Answers for `lat`/`lon` in `namespace` at time `now`, in ranking order.
+    pub async fn reverse(
+        &self,
+        namespace: &str,
+        lat: f64,
+        lon: f64,
+        now: i64,
+    ) -> Result<Vec<GeocodeResponse>, LookupError<S::Error, P::Error>> {
+        let lat = matching::round(lat, self.policy.precision);
+        let lon = matching::round_lon(lon, self.policy.precision);
+        let (mut geocodes, _) = self
+            .cached(namespace, &lat, &lon, now)
+            .await
+            .map_err(LookupError::Storage)?;
+        if geocodes.is_empty() {
+            geocodes = self.fetch(namespace, &lat, &lon, false, now).await?;
+        }
+        self.policy.finish(&mut geocodes);
+        Ok(geocodes)
+    }
+
+    /// Fresh cached answers for the cell `lat`/`lon`, unranked, and the
+    /// cells they were cached for.
+    pub async fn cached(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+        now: i64,
+    ) -> Result<(Vec<GeocodeResponse>, Vec<(String, String)>), S::Error> {
+        let policy = self.policy;
+        let mut cells = vec![];
+        let geocodes = self
+            .storage
+            .candidates(namespace, lat, lon)
+            .await?
+            .into_iter()
+            .filter(|c| {
+                let ttl = policy.ttl_of(c.address.layer.as_deref());
+                matching::fresh(c.fetched_at, ttl, now)
+            })
+            .filter_map(|c| {
+                let geocode =
+                    matching::response(policy.algorithm(), lat, lon, c.address, c.fetched_at)?;
+                Some((c.cell, geocode))
+            })
+            .filter(|(_, geocode)| matching::matches(geocode))
+            .map(|(cell, geocode)| {
+                cells.push(cell);
+                geocode
+            })
+            .collect();
+        Ok((geocodes, cells))
+    }
+
+    /// Ask the provider about the cell `lat`/`lon` and cache what it says,
+    /// replacing what the cell was answered with before if `replace`. Its
+    /// answers, unranked.
+    pub async fn fetch(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+        replace: bool,
+        now: i64,
+    ) -> Result<Vec<GeocodeResponse>, LookupError<S::Error, P::Error>> {
+        let response = self
+            .provider
+            .reverse(lat, lon)
+            .await
+            .map_err(LookupError::Provider)?;
+        self.storage
+            .store(namespace, lat, lon, &response, replace, now)
+            .await
+            .map_err(LookupError::Storage)?;
+        let algorithm = self.policy.algorithm();
+        Ok(response
+            .addresses
+            .into_iter()
+            .filter_map(|a| matching::response(algorithm, lat, lon, a, Some(now)))
+            .collect())
+    }
+}
+
+/// An in-process cache keyed by namespace and cell. Every address in the
+/// namespace is a candidate, which is fine for tests and small tools, and
+/// storing a cell replaces what it held either way.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    cells: Mutex<HashMap<(String, String, String), Vec<Cached>>>,
+}
+
+impl Storage for MemoryStorage {
+    type Error = std::convert::Infallible;
+
+    async fn candidates(
+        &self,
+        namespace: &str,
+        _lat: &str,
+        _lon: &str,
+    ) -> Result<Vec<Cached>, Self::Error> {
+        Ok(self
+            .cells
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((ns, _, _), _)| ns == namespace)
+            .flat_map(|(_, cached)| cached.iter().cloned())
+            .collect())
+    }
+
+    async fn store(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+        response: &RadarReverseGeocodeResponse,
+        _replace: bool,
+        now: i64,
+    ) -> Result<(), Self::Error> {
+        let cached = response.addresses.iter().map(|address| Cached {
+            cell: (lat.to_string(), lon.to_string()),
+            address: address.clone(),
+            fetched_at: Some(now),
+        });
+        self.cells.lock().unwrap().insert(
+            (namespace.to_string(), lat.to_string(), lon.to_string()),
+            cached.collect(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    /// Run `future`, which must not wait on anything.
+    fn ready<T>(future: impl Future<Output = T>) -> T {
+        let mut context = Context::from_waker(Waker::noop());
+        match pin!(future).poll(&mut context) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("the future waited"),
+        }
+    }
+
+    /// Knows one address, and counts how often it is asked.
+    #[derive(Default)]
+    struct OneAddress {
+        lat: f64,
+        lon: f64,
+        calls: Cell<usize>,
+    }
+
+    impl Provider for OneAddress {
+        type Error = std::convert::Infallible;
+
+        async fn reverse(
+            &self,
+            _lat: &str,
+            _lon: &str,
+        ) -> Result<RadarReverseGeocodeResponse, Self::Error> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(RadarReverseGeocodeResponse {
+                addresses: vec![RadarAddress {
+                    latitude: Some(self.lat),
+                    longitude: Some(self.lon),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        }
+    }
+
+    fn geocoder(lat: f64, lon: f64, policy: &Policy) -> Geocoder<'_, MemoryStorage, OneAddress> {
+        let provider = OneAddress {
+            lat,
+            lon,
+            ..Default::default()
+        };
+        Geocoder::new(MemoryStorage::default(), provider, policy)
+    }
+
+    #[test]
+    fn reverse_answers_from_across_the_antimeridian() {
+        let policy = Policy::default();
+        let geocoder = geocoder(10.0, -179.99999, &policy);
+        ready(geocoder.reverse("", 10.0, -179.99999, 0)).unwrap();
+        for lon in [179.99999, 180.0, -180.0] {
+            let geocodes = ready(geocoder.reverse("", 10.0, lon, 0)).unwrap();
+            assert_eq!(geocodes.len(), 1, "at {}", lon);
+            assert!(geocodes[0].distance < 3.0, "at {}", lon);
+        }
+        assert_eq!(geocoder.provider.calls.get(), 1);
+    }
+
+    #[test]
+    fn reverse_keys_180_as_minus_180() {
+        let policy = Policy::default();
+        let geocoder = geocoder(10.0, 180.0, &policy);
+        let geocodes = ready(geocoder.reverse("", 10.0, 180.0, 0)).unwrap();
+        assert_eq!(geocodes[0].lon, "-180.00000");
+    }
+
+    #[test]
+    fn reverse_answers_from_anywhere_around_a_pole() {
+        let policy = Policy::default();
+        let geocoder = geocoder(89.99999, 0.0, &policy);
+        ready(geocoder.reverse("", 89.99999, 0.0, 0)).unwrap();
+        for lon in [90.0, 180.0, -120.0] {
+            let geocodes = ready(geocoder.reverse("", 89.99999, lon, 0)).unwrap();
+            assert_eq!(geocodes.len(), 1, "at {}", lon);
+        }
+        assert_eq!(geocoder.provider.calls.get(), 1);
+    }
+}
@@ -0,0 +1,150 @@
+//! Which cached addresses answer a lookup.
+//!
+//! A lookup's coordinates are rounded to a number of decimal places; that
+//! rounded pair is the cell. It is what gets cached, and distances are
+//! measured from it rather than from the exact input. Any cached address
+//! within `MATCH_RADIUS_METERS` of the cell answers the lookup.
+
+use std::collections::HashSet;
+
+use crate::{
+    geo::{self, Algorithm},
+    GeocodeResponse, RadarAddress,
+};
+
+/// How far a cached address may be from the cell and still be served.
+pub const MATCH_RADIUS_METERS: f64 = 40.0;
+
+/// `value` rounded to `precision` decimal places, as cells are keyed.
+pub fn round(value: f64, precision: usize) -> String {
+    format!("{:.*}", precision, value)
+}
+
+/// The longitude `value` rounded like `round`, wrapped into -180 to 180 and
+/// with 180 keyed as -180, so the two sides of the antimeridian share their
+/// cells.
+pub fn round_lon(value: f64, precision: usize) -> String {
+    let rounded = round(geo::wrap_lon(value), precision);
+    match rounded.parse::<f64>() {
+        Ok(lon) if lon >= 180.0 => round(lon - 360.0, precision),
+        _ => rounded,
+    }
+}
+
+/// `address` as an answer for the cell `lat`/`lon`, or `None` if either the
+/// cell or the address has no usable coordinates.
+pub fn response(
+    algorithm: Algorithm,
+    lat: &str,
+    lon: &str,
+    address: RadarAddress,
+    fetched_at: Option<i64>,
+) -> Option<GeocodeResponse> {
+    let (cell_lat, cell_lon) = (lat.parse::<f64>().ok()?, lon.parse::<f64>().ok()?);
+    let distance = geo::distance_meters(
+        algorithm,
+        address.latitude?,
+        address.longitude?,
+        cell_lat,
+        cell_lon,
+    );
+    Some(GeocodeResponse {
+        lat: lat.to_string(),
+        lon: lon.to_string(),
+        distance,
+        confidence: 0.0,
+        address,
+        fetched_at,
+    })
+}
+
+/// Whether a cached answer is close enough to its cell to be served.
+pub fn matches(geocode: &GeocodeResponse) -> bool {
+    geocode.distance < MATCH_RADIUS_METERS
+}
+
+/// Whether an address fetched at `fetched_at` is still fresh at `now`, with
+/// entries living `ttl` seconds; zero keeps them forever, and an address of
+/// unknown age is taken as fresh.
+pub fn fresh(fetched_at: Option<i64>, ttl: i64, now: i64) -> bool {
+    ttl <= 0 || fetched_at.is_none_or(|fetched| now - fetched < ttl)
+}
+
+/// The lowercased words of `text`, letters and digits only.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// The trigrams of `text`'s words, each word padded as pg_trgm pads them, so
+/// `jay` gives `  j`, ` ja`, `jay` and `ay `.
+pub fn trigrams(text: &str) -> HashSet<String> {
+    words(text)
+        .flat_map(|word| {
+            let padded = format!("  {} ", word).chars().collect::<Vec<_>>();
+            padded
+                .windows(3)
+                .map(|w| w.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// How much of `query` `candidate` has, from 0 (nothing) to 1 (every trigram
+/// of it), so an address spelled out in full still matches the few words of
+/// it someone typed, typos and all.
+pub fn similarity(query: &str, candidate: &str) -> f64 {
+    let query = trigrams(query);
+    if query.is_empty() {
+        return 0.0;
+    }
+    let candidate = trigrams(candidate);
+    query.intersection(&candidate).count() as f64 / query.len() as f64
+}
+
+/// How many letters must be inserted, removed, replaced or swapped with
+/// their neighbour to turn `a` into `b`, so `raod` is one from `road`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    // rows i-2, i-1 and i of the distances between prefixes
+    let mut before = vec![0; b.len() + 1];
+    let mut last = (0..=b.len()).collect::<Vec<_>>();
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (last[j] + 1).min(row[j - 1] + 1).min(last[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut last, row);
+    }
+    last[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_lon_keys_both_sides_of_the_antimeridian_as_one() {
+        assert_eq!(round_lon(180.0, 5), "-180.00000");
+        assert_eq!(round_lon(-180.0, 5), "-180.00000");
+        assert_eq!(round_lon(179.999999, 5), "-180.00000");
+        assert_eq!(round_lon(-179.999999, 5), "-180.00000");
+    }
+
+    #[test]
+    fn round_lon_wraps_longitudes_past_the_antimeridian() {
+        assert_eq!(round_lon(190.0, 2), "-170.00");
+        assert_eq!(round_lon(-190.0, 2), "170.00");
+    }
+
+    #[test]
+    fn round_lon_rounds_like_round_elsewhere() {
+        assert_eq!(round_lon(-77.123456, 5), round(-77.123456, 5));
+        assert_eq!(round_lon(179.99, 2), "179.99");
+    }
+}
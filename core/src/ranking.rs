@@ -0,0 +1,178 @@
+//! The order results come back in: by layer, most specific first, then by
+//! distance from the cell, or the other way round.
+//!
+//! Copies of the same address a cache picked up over time (the same
+//! formatted address within `dedup_meters`) are collapsed into the best
+//! ranked one.
+//!
+//! Forward geocodes are ordered by `ForwardRanking` instead: their confidence
+//! plus whatever a deployment weighs on top of it.
+
+use crate::{
+    geo::{self, Algorithm},
+    GeocodeResponse, RadarAddress,
+};
+
+/// The layer priority the server uses by default.
+pub const DEFAULT_LAYERS: &str =
+    "address,intersection,street,neighborhood,postalCode,locality,county,state,country";
+
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    /// Layers best first; unlisted layers come after all listed ones.
+    pub layers: Vec<String>,
+    /// Make distance the first key and layer the tie-breaker.
+    pub distance_first: bool,
+    /// How close two copies of an address must be to count as one; zero
+    /// keeps every copy.
+    pub dedup_meters: f64,
+    pub algorithm: Algorithm,
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Ranking {
+            layers: DEFAULT_LAYERS.split(',').map(String::from).collect(),
+            distance_first: false,
+            dedup_meters: 10.0,
+            algorithm: Algorithm::default(),
+        }
+    }
+}
+
+/// `formatted_address` compared loosely: case and spacing don't count.
+fn normalized(address: &RadarAddress) -> Option<String> {
+    Some(
+        address
+            .formatted_address
+            .as_deref()?
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase(),
+    )
+}
+
+impl Ranking {
+    /// Where `layer` sits in the priority list.
+    fn layer_rank(&self, layer: Option<&str>) -> usize {
+        layer
+            .and_then(|layer| self.layers.iter().position(|l| l == layer))
+            .unwrap_or(self.layers.len())
+    }
+
+    /// Put `geocodes` in ranking order. Equal results keep their order.
+    pub fn sort(&self, geocodes: &mut [GeocodeResponse]) {
+        geocodes.sort_by(|a, b| {
+            let layer = self
+                .layer_rank(a.address.layer.as_deref())
+                .cmp(&self.layer_rank(b.address.layer.as_deref()));
+            let distance = a.distance.total_cmp(&b.distance);
+            if self.distance_first {
+                distance.then(layer)
+            } else {
+                layer.then(distance)
+            }
+        });
+    }
+
+    /// Whether `a` and `b` are the same address: the same formatted address
+    /// within `dedup_meters` of each other.
+    pub fn same_address(&self, a: &RadarAddress, b: &RadarAddress) -> bool {
+        match (
+            normalized(a),
+            normalized(b),
+            (a.latitude, a.longitude),
+            (b.latitude, b.longitude),
+        ) {
+            (Some(x), Some(y), (Some(alat), Some(alon)), (Some(blat), Some(blon))) => {
+                self.dedup_meters > 0.0
+                    && x == y
+                    && geo::distance_meters(self.algorithm, alat, alon, blat, blon)
+                        <= self.dedup_meters
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop results that repeat an earlier one's address, keeping the earlier
+    /// one.
+    pub fn dedup(&self, geocodes: &mut Vec<GeocodeResponse>) {
+        let mut kept: Vec<RadarAddress> = vec![];
+        geocodes.retain(|g| {
+            let repeat = kept.iter().any(|k| self.same_address(k, &g.address));
+            if !repeat {
+                kept.push(g.address.clone());
+            }
+            !repeat
+        });
+    }
+}
+
+/// Weights ordering forward geocode results, added to each result's
+/// confidence. All zero, results come in confidence order.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardRanking {
+    /// Added in full at the bias point, half at `proximity_scale_meters`
+    /// from it, and less further out.
+    pub proximity_weight: f64,
+    pub proximity_scale_meters: f64,
+    /// `(layer, weight)` added to addresses of that layer.
+    pub layers: Vec<(String, f64)>,
+    /// `(country code, weight)` added to addresses in that country.
+    pub countries: Vec<(String, f64)>,
+    /// Taken off addresses that are post office boxes.
+    pub po_box_penalty: f64,
+    pub algorithm: Algorithm,
+}
+
+/// Whether `address` is a post office box rather than somewhere to go.
+pub fn is_po_box(address: &RadarAddress) -> bool {
+    [
+        &address.address_label,
+        &address.street,
+        &address.formatted_address,
+    ]
+    .into_iter()
+    .flatten()
+    .any(|text| {
+        let letters = text
+            .to_lowercase()
+            .chars()
+            .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+            .collect::<String>();
+        let words = letters.split_whitespace().collect::<Vec<_>>();
+        words.windows(2).any(|w| matches!(w, ["po" | "p", "box"]))
+            || words
+                .windows(3)
+                .any(|w| matches!(w, ["post", "office", "box"]))
+    })
+}
+
+impl ForwardRanking {
+    /// Where a result with `confidence` and `address` ranks, higher first,
+    /// given the point to favour results near, if any.
+    pub fn score(&self, confidence: f64, address: &RadarAddress, bias: Option<(f64, f64)>) -> f64 {
+        let mut score = confidence;
+        if let (Some((lat, lon)), Some(alat), Some(alon)) =
+            (bias, address.latitude, address.longitude)
+        {
+            if self.proximity_weight != 0.0 {
+                let meters = geo::distance_meters(self.algorithm, lat, lon, alat, alon);
+                score +=
+                    self.proximity_weight / (1.0 + meters / self.proximity_scale_meters.max(1.0));
+            }
+        }
+        let weight = |weights: &[(String, f64)], value: Option<&str>| {
+            value
+                .and_then(|value| weights.iter().find(|(v, _)| v.eq_ignore_ascii_case(value)))
+                .map_or(0.0, |(_, weight)| *weight)
+        };
+        score += weight(&self.layers, address.layer.as_deref());
+        score += weight(&self.countries, address.country_code.as_deref());
+        if self.po_box_penalty != 0.0 && is_po_box(address) {
+            score -= self.po_box_penalty;
+        }
+        score
+    }
+}
@@ -1,5 +1,5 @@
-CREATE TABLE geocode (
+CREATE TABLE IF NOT EXISTS geocode (
     lat TEXT,
     lon TEXT,
-    address TEXT,
+    address TEXT
 );
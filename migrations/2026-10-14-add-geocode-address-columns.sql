@@ -0,0 +1,18 @@
+ALTER TABLE geocode ADD COLUMN country_code TEXT;
+ALTER TABLE geocode ADD COLUMN state_code TEXT;
+ALTER TABLE geocode ADD COLUMN postal_code TEXT;
+ALTER TABLE geocode ADD COLUMN city TEXT;
+ALTER TABLE geocode ADD COLUMN layer TEXT;
+
+UPDATE geocode SET
+    country_code = json_extract(address, '$.countryCode'),
+    state_code = json_extract(address, '$.stateCode'),
+    postal_code = json_extract(address, '$.postalCode'),
+    city = json_extract(address, '$.city'),
+    layer = json_extract(address, '$.layer')
+WHERE json_valid(address);
+
+CREATE INDEX IF NOT EXISTS geocode_country_state ON geocode(country_code, state_code);
+CREATE INDEX IF NOT EXISTS geocode_postal_code ON geocode(postal_code);
+CREATE INDEX IF NOT EXISTS geocode_city ON geocode(city);
+CREATE INDEX IF NOT EXISTS geocode_layer ON geocode(layer);
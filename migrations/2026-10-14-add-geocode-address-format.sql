@@ -0,0 +1,3 @@
+-- How `address` is encoded: 0 for JSON, 1 for gaia_core::binary; either
+-- may also be compressed (see compression.rs).
+ALTER TABLE geocode ADD COLUMN address_format INTEGER NOT NULL DEFAULT 0;
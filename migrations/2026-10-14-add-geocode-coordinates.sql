@@ -0,0 +1,7 @@
+ALTER TABLE geocode ADD COLUMN latitude REAL;
+ALTER TABLE geocode ADD COLUMN longitude REAL;
+UPDATE geocode SET
+    latitude = json_extract(address, '$.latitude'),
+    longitude = json_extract(address, '$.longitude')
+WHERE json_valid(address);
+CREATE INDEX IF NOT EXISTS geocode_latitude_longitude ON geocode(latitude, longitude);
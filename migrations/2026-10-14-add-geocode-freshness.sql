@@ -0,0 +1,4 @@
+ALTER TABLE geocode ADD COLUMN created_at INTEGER;
+ALTER TABLE geocode ADD COLUMN hits INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE geocode ADD COLUMN last_hit_at INTEGER;
+CREATE INDEX IF NOT EXISTS geocode_lat_lon ON geocode(lat, lon);
@@ -0,0 +1,3 @@
+-- Which version of the address schema (see schema.rs) `address` was written
+-- in. Rows from before versions were kept are the first.
+ALTER TABLE geocode ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+-- The tenant that owns each geofence; NULL for the anonymous tenant, which
+-- is who every geofence from before tenants were kept belongs to.
+ALTER TABLE geofences ADD COLUMN tenant TEXT;
+CREATE INDEX IF NOT EXISTS geofences_tenant ON geofences(tenant);
@@ -0,0 +1,5 @@
+ALTER TABLE jobs ADD COLUMN idempotency_key TEXT;
+ALTER TABLE jobs ADD COLUMN fingerprint TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS jobs_idempotency_key
+    ON jobs(COALESCE(tenant, ''), idempotency_key) WHERE idempotency_key IS NOT NULL;
@@ -0,0 +1,3 @@
+-- The cluster node (`CLUSTER_NODE_ID`) running each job, so a restart only
+-- fails its own unfinished jobs; NULL outside cluster mode.
+ALTER TABLE jobs ADD COLUMN node TEXT;
@@ -0,0 +1,9 @@
+ALTER TABLE geocode ADD COLUMN namespace TEXT NOT NULL DEFAULT '';
+
+CREATE TABLE IF NOT EXISTS tenant_usage (
+    tenant TEXT NOT NULL,
+    day INTEGER NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    upstream_calls INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (tenant, day)
+);
@@ -0,0 +1 @@
+ALTER TABLE upstream_calls ADD COLUMN results INTEGER;
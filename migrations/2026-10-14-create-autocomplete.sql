@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS autocomplete (
+    namespace TEXT NOT NULL DEFAULT '',
+    prefix TEXT NOT NULL,
+    bucket TEXT NOT NULL DEFAULT '',
+    addresses TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (namespace, prefix, bucket)
+);
+CREATE INDEX IF NOT EXISTS autocomplete_created_at ON autocomplete(created_at);
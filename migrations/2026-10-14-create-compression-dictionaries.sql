@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS compression_dictionaries (
+    id INTEGER PRIMARY KEY,
+    dictionary BLOB NOT NULL,
+    samples INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
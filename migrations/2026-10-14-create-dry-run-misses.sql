@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS dry_run_misses (
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    tenant TEXT NOT NULL DEFAULT '',
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (lat, lon, tenant)
+);
+CREATE INDEX IF NOT EXISTS dry_run_misses_first_seen ON dry_run_misses(first_seen);
+CREATE INDEX IF NOT EXISTS dry_run_misses_last_seen ON dry_run_misses(last_seen);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS fetch_leases (
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    holder TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (lat, lon)
+);
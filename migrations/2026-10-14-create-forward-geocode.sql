@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS forward_geocode (
+    namespace TEXT NOT NULL DEFAULT '',
+    query TEXT NOT NULL,
+    addresses TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (namespace, query)
+);
+CREATE INDEX IF NOT EXISTS forward_geocode_created_at ON forward_geocode(created_at);
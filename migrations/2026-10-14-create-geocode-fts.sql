@@ -0,0 +1,25 @@
+ALTER TABLE geocode ADD COLUMN formatted_address TEXT;
+UPDATE geocode SET formatted_address = json_extract(address, '$.formattedAddress')
+WHERE json_valid(address);
+
+-- geocode has no INTEGER PRIMARY KEY, so VACUUM may renumber its rowids;
+-- follow any VACUUM with the 'rebuild' below.
+CREATE VIRTUAL TABLE IF NOT EXISTS geocode_fts USING fts5(
+    formatted_address,
+    content = 'geocode',
+    content_rowid = 'rowid'
+);
+INSERT INTO geocode_fts(geocode_fts) VALUES ('rebuild');
+
+CREATE TRIGGER IF NOT EXISTS geocode_fts_insert AFTER INSERT ON geocode BEGIN
+    INSERT INTO geocode_fts(rowid, formatted_address) VALUES (new.rowid, new.formatted_address);
+END;
+CREATE TRIGGER IF NOT EXISTS geocode_fts_delete AFTER DELETE ON geocode BEGIN
+    INSERT INTO geocode_fts(geocode_fts, rowid, formatted_address)
+    VALUES ('delete', old.rowid, old.formatted_address);
+END;
+CREATE TRIGGER IF NOT EXISTS geocode_fts_update AFTER UPDATE OF formatted_address ON geocode BEGIN
+    INSERT INTO geocode_fts(geocode_fts, rowid, formatted_address)
+    VALUES ('delete', old.rowid, old.formatted_address);
+    INSERT INTO geocode_fts(rowid, formatted_address) VALUES (new.rowid, new.formatted_address);
+END;
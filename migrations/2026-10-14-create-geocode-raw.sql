@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS geocode_raw (
+    id INTEGER PRIMARY KEY,
+    response TEXT NOT NULL,
+    fetched_at INTEGER NOT NULL
+);
+ALTER TABLE geocode ADD COLUMN raw_id INTEGER REFERENCES geocode_raw(id);
+CREATE INDEX IF NOT EXISTS geocode_raw_id ON geocode(raw_id);
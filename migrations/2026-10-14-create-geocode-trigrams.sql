@@ -0,0 +1,22 @@
+-- Like geocode_fts, but by trigram, for forward lookups nobody could answer
+-- exactly; also needs a 'rebuild' after any VACUUM.
+CREATE VIRTUAL TABLE IF NOT EXISTS geocode_trigrams USING fts5(
+    formatted_address,
+    content = 'geocode',
+    content_rowid = 'rowid',
+    tokenize = 'trigram'
+);
+INSERT INTO geocode_trigrams(geocode_trigrams) VALUES ('rebuild');
+
+CREATE TRIGGER IF NOT EXISTS geocode_trigrams_insert AFTER INSERT ON geocode BEGIN
+    INSERT INTO geocode_trigrams(rowid, formatted_address) VALUES (new.rowid, new.formatted_address);
+END;
+CREATE TRIGGER IF NOT EXISTS geocode_trigrams_delete AFTER DELETE ON geocode BEGIN
+    INSERT INTO geocode_trigrams(geocode_trigrams, rowid, formatted_address)
+    VALUES ('delete', old.rowid, old.formatted_address);
+END;
+CREATE TRIGGER IF NOT EXISTS geocode_trigrams_update AFTER UPDATE OF formatted_address ON geocode BEGIN
+    INSERT INTO geocode_trigrams(geocode_trigrams, rowid, formatted_address)
+    VALUES ('delete', old.rowid, old.formatted_address);
+    INSERT INTO geocode_trigrams(rowid, formatted_address) VALUES (new.rowid, new.formatted_address);
+END;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    tenant TEXT,
+    status TEXT NOT NULL,
+    items INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    expires_at INTEGER,
+    error TEXT,
+    result BLOB
+);
+CREATE INDEX IF NOT EXISTS jobs_created_at ON jobs(created_at);
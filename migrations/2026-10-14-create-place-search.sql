@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS place_search (
+    namespace TEXT NOT NULL DEFAULT '',
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    categories TEXT NOT NULL,
+    radius INTEGER NOT NULL,
+    places TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (namespace, lat, lon, categories, radius)
+);
+CREATE INDEX IF NOT EXISTS place_search_created_at ON place_search(created_at);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS point_context (
+    namespace TEXT NOT NULL DEFAULT '',
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    context TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (namespace, lat, lon)
+);
+CREATE INDEX IF NOT EXISTS point_context_created_at ON point_context(created_at);
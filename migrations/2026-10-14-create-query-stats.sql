@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS query_stats (
+    cell_lat TEXT NOT NULL,
+    cell_lon TEXT NOT NULL,
+    hour INTEGER NOT NULL,
+    queries INTEGER NOT NULL DEFAULT 0,
+    hits INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (cell_lat, cell_lon, hour)
+);
+CREATE INDEX IF NOT EXISTS query_stats_hour ON query_stats(hour);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS upstream_calls (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    called_at INTEGER NOT NULL,
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    provider_key TEXT NOT NULL,
+    tenant TEXT,
+    latency_ms INTEGER NOT NULL,
+    status INTEGER,
+    outcome TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS upstream_calls_called_at ON upstream_calls(called_at);
+CREATE INDEX IF NOT EXISTS upstream_calls_tenant ON upstream_calls(tenant, called_at);
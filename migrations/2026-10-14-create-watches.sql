@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS watches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    lat TEXT NOT NULL,
+    lon TEXT NOT NULL,
+    schedule TEXT NOT NULL,
+    webhook_url TEXT,
+    last_result TEXT,
+    last_checked_at INTEGER,
+    next_run_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS watches_next_run_at ON watches(next_run_at);
+
+CREATE TABLE IF NOT EXISTS watch_changes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    watch_id INTEGER NOT NULL REFERENCES watches(id) ON DELETE CASCADE,
+    detected_at INTEGER NOT NULL,
+    previous TEXT,
+    current TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS watch_changes_watch_id ON watch_changes(watch_id);
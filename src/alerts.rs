@@ -0,0 +1,313 @@
+//! Notifying operators when something needs attention, instead of waiting
+//! for someone to look at `/metrics`.
+//!
+//! Enabled by setting `ALERT_WEBHOOK_URL` (or `ALERT_WEBHOOK_URL_FILE`, as
+//! Slack URLs carry a token). Every `ALERT_CHECK_SECS` (default 60) these
+//! conditions are checked, and one notification is posted when each starts
+//! and another when it clears:
+//!
+//! - `upstream_error_rate`: over `ALERT_UPSTREAM_ERROR_RATE` (default 0.5)
+//!   of the audited provider calls in the last `ALERT_WINDOW_SECS` (default
+//!   300) failed, counting only windows with `ALERT_UPSTREAM_MIN_CALLS`
+//!   (default 10) calls. Needs the audit log.
+//! - `circuit_open`: the upstream circuit breaker is open, unless
+//!   `ALERT_CIRCUIT_OPEN=false`.
+//! - `quota:<tenant>`: a tenant has used `ALERT_QUOTA_PERCENT` (default 90)
+//!   of its daily quota.
+//! - `database_size`: the database file has reached `ALERT_DATABASE_PERCENT`
+//!   (default 90) of `ALERT_DATABASE_MAX_MB`, the space set aside for it.
+//!   Not checked unless that is set.
+//!
+//! A threshold of 0 turns its condition off. `ALERT_WEBHOOK_FORMAT` shapes
+//! the post: `generic` (the default) sends
+//! `{"alert", "status", "summary", "at"}` JSON, `slack` a Slack-compatible
+//! `{"text"}` message and `ntfy` a plain text body with ntfy's title, tag
+//! and priority headers. Each instance checks and notifies on its own.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{audit, breaker::CircuitState, config, db, metrics, tenants, upstream::Upstream};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Generic,
+    Slack,
+    Ntfy,
+}
+
+#[derive(Debug)]
+struct Settings {
+    url: String,
+    format: Format,
+    interval: Duration,
+    window: i64,
+    error_rate: f64,
+    min_calls: i64,
+    circuit_open: bool,
+    quota_percent: f64,
+    database_max_bytes: Option<i64>,
+    database_percent: f64,
+}
+
+impl Settings {
+    fn from_env() -> Option<Self> {
+        let url = config::secret("ALERT_WEBHOOK_URL").filter(|url| !url.is_empty())?;
+        let format = match config::var("ALERT_WEBHOOK_FORMAT", String::from("generic")).as_str() {
+            "generic" => Format::Generic,
+            "slack" => Format::Slack,
+            "ntfy" => Format::Ntfy,
+            other => panic!("Invalid ALERT_WEBHOOK_FORMAT: {}", other),
+        };
+        Some(Settings {
+            url,
+            format,
+            interval: Duration::from_secs(config::var("ALERT_CHECK_SECS", 60).max(1)),
+            window: config::var("ALERT_WINDOW_SECS", 300i64).max(1),
+            error_rate: config::var("ALERT_UPSTREAM_ERROR_RATE", 0.5),
+            min_calls: config::var("ALERT_UPSTREAM_MIN_CALLS", 10i64).max(1),
+            circuit_open: config::var("ALERT_CIRCUIT_OPEN", true),
+            quota_percent: config::var("ALERT_QUOTA_PERCENT", 90.0),
+            database_max_bytes: std::env::var("ALERT_DATABASE_MAX_MB")
+                .ok()
+                .map(|_| config::var("ALERT_DATABASE_MAX_MB", 0i64) * 1024 * 1024),
+            database_percent: config::var("ALERT_DATABASE_PERCENT", 90.0),
+        })
+    }
+}
+
+/// Read the `ALERT_*` settings, if alerts are configured.
+pub fn check() {
+    Settings::from_env();
+}
+
+/// What is wrong right now: alert name to summary. A condition that couldn't
+/// be checked keeps whatever it last reported, so a database hiccup doesn't
+/// resolve and re-fire everything.
+async fn firing(
+    settings: &Settings,
+    pool: &Pool<Sqlite>,
+    upstream: &Upstream,
+    previous: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut firing = BTreeMap::new();
+    let keep = |firing: &mut BTreeMap<String, String>, prefix: &str, e: sqlx::Error| {
+        tracing::warn!("failed to check the {} alert: {}", prefix, e);
+        for (name, summary) in previous.range(prefix.to_string()..) {
+            if !name.starts_with(prefix) {
+                break;
+            }
+            firing.insert(name.clone(), summary.clone());
+        }
+    };
+
+    if settings.error_rate > 0.0 && audit::enabled() {
+        match sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COUNT(*), COALESCE(SUM(outcome != 'ok'), 0) FROM upstream_calls \
+             WHERE called_at >= ?",
+        )
+        .bind(db::now() - settings.window)
+        .fetch_one(pool)
+        .await
+        {
+            Ok((calls, failed)) => {
+                let rate = failed as f64 / calls.max(1) as f64;
+                if calls >= settings.min_calls && rate > settings.error_rate {
+                    firing.insert(
+                        String::from("upstream_error_rate"),
+                        format!(
+                            "{} of {} provider calls failed in the last {}s ({:.0}%)",
+                            failed,
+                            calls,
+                            settings.window,
+                            rate * 100.0
+                        ),
+                    );
+                }
+            }
+            Err(e) => keep(&mut firing, "upstream_error_rate", e),
+        }
+    }
+
+    if settings.circuit_open && upstream.breaker.state() != CircuitState::Closed {
+        firing.insert(
+            String::from("circuit_open"),
+            format!(
+                "the upstream circuit is {}; cache misses are failing",
+                upstream.breaker.state()
+            ),
+        );
+    }
+
+    if settings.quota_percent > 0.0 {
+        match tenants::quota_usage(pool).await {
+            Ok(usage) => {
+                for (tenant, used, quota) in usage {
+                    if used as f64 >= quota as f64 * settings.quota_percent / 100.0 {
+                        firing.insert(
+                            format!("quota:{}", tenant),
+                            format!(
+                                "tenant {} has used {} of its {} daily requests",
+                                tenant, used, quota
+                            ),
+                        );
+                    }
+                }
+            }
+            Err(e) => keep(&mut firing, "quota:", e),
+        }
+    }
+
+    if let Some(max) = settings
+        .database_max_bytes
+        .filter(|_| settings.database_percent > 0.0)
+    {
+        match sqlx::query_as::<_, (i64,)>(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(pool)
+        .await
+        {
+            Ok((size,)) if size as f64 >= max as f64 * settings.database_percent / 100.0 => {
+                firing.insert(
+                    String::from("database_size"),
+                    format!(
+                        "the database is {:.1} MB of the {} MB set aside for it",
+                        size as f64 / 1024.0 / 1024.0,
+                        max / 1024 / 1024
+                    ),
+                );
+            }
+            Ok(_) => {}
+            Err(e) => keep(&mut firing, "database_size", e),
+        }
+    }
+    firing
+}
+
+/// Post one notification; `resolved` is false when the alert starts. Errors
+/// leave out the URL, which may hold a token.
+fn send(
+    agent: &ureq::Agent,
+    settings: &Settings,
+    name: &str,
+    summary: &str,
+    resolved: bool,
+) -> Result<(), String> {
+    let status = if resolved { "resolved" } else { "firing" };
+    let request = agent.post(&settings.url);
+    match settings.format {
+        Format::Generic => request.send_json(json!({
+            "alert": name,
+            "status": status,
+            "summary": summary,
+            "at": db::now(),
+        })),
+        Format::Slack => request.send_json(json!({
+            "text": if resolved {
+                format!(":white_check_mark: gaia: {} resolved (was: {})", name, summary)
+            } else {
+                format!(":rotating_light: gaia: {}: {}", name, summary)
+            },
+        })),
+        Format::Ntfy => request
+            .set("Title", &format!("gaia {} {}", name, status))
+            .set(
+                "Tags",
+                if resolved {
+                    "white_check_mark"
+                } else {
+                    "rotating_light"
+                },
+            )
+            .set("Priority", if resolved { "default" } else { "high" })
+            .send_string(&if resolved {
+                format!("resolved (was: {})", summary)
+            } else {
+                summary.to_string()
+            }),
+    }
+    .map(drop)
+    .map_err(|e| match e {
+        ureq::Error::Status(status, _) => format!("status code {}", status),
+        ureq::Error::Transport(t) => t.kind().to_string(),
+    })
+}
+
+async fn notify(
+    agent: &ureq::Agent,
+    settings: &Arc<Settings>,
+    name: &str,
+    summary: &str,
+    resolved: bool,
+) {
+    if resolved {
+        tracing::info!("alert {} resolved: {}", name, summary);
+    } else {
+        tracing::warn!("alert {} firing: {}", name, summary);
+    }
+    metrics::set_gauge(
+        "gaia_alert_firing",
+        &[("alert", name)],
+        if resolved { 0.0 } else { 1.0 },
+    );
+    let (agent, settings, owned) = (agent.clone(), settings.clone(), name.to_string());
+    let summary = summary.to_string();
+    let sent =
+        tokio::task::spawn_blocking(move || send(&agent, &settings, &owned, &summary, resolved))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|sent| sent);
+    let result = match sent {
+        Ok(()) => "ok",
+        Err(e) => {
+            tracing::warn!("alert {} notification failed: {}", name, e);
+            "error"
+        }
+    };
+    metrics::increment(
+        "gaia_alert_notifications_total",
+        &[("alert", name), ("result", result)],
+    );
+}
+
+pub fn spawn(pool: Arc<Pool<Sqlite>>, upstream: Arc<Upstream>) {
+    let Some(settings) = Settings::from_env() else {
+        return;
+    };
+    tracing::info!(
+        "alerting to a {:?} webhook every {:?}",
+        settings.format,
+        settings.interval
+    );
+    let settings = Arc::new(settings);
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build();
+    tokio::spawn(async move {
+        let mut previous = BTreeMap::new();
+        loop {
+            tokio::time::sleep(settings.interval).await;
+            let current = firing(&settings, &pool, &upstream, &previous).await;
+            let names = previous
+                .keys()
+                .chain(current.keys())
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            for name in names {
+                match (previous.get(&name), current.get(&name)) {
+                    (None, Some(summary)) => notify(&agent, &settings, &name, summary, false).await,
+                    (Some(summary), None) => notify(&agent, &settings, &name, summary, true).await,
+                    _ => {}
+                }
+            }
+            previous = current;
+        }
+    });
+}
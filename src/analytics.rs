@@ -0,0 +1,172 @@
+//! Where people are asking about: hourly query and cache-hit counts per
+//! rounded cell, for deciding where to pre-warm the cache and which provider
+//! coverage matters most.
+//!
+//! Off unless `ANALYTICS_ENABLED=true`. Coordinates are rounded to
+//! `ANALYTICS_CELL_PRECISION` decimal places (default 2, roughly 1km) before
+//! they are stored, or coarser if `PRIVACY_PRECISION` asks for it. Under
+//! `PRIVACY_MODE=hash` a cell is stored and reported as the hash of its
+//! coordinates, with an empty `lon`.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{auth::Admin, config, db, params, privacy};
+
+#[derive(Debug)]
+struct Settings {
+    enabled: bool,
+    precision: usize,
+}
+
+/// Read `ANALYTICS_ENABLED` and `ANALYTICS_CELL_PRECISION`.
+pub fn check() {
+    settings();
+}
+
+/// Whether `ANALYTICS_ENABLED` is on.
+pub fn enabled() -> bool {
+    settings().enabled
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        enabled: config::var("ANALYTICS_ENABLED", false),
+        precision: config::var("ANALYTICS_CELL_PRECISION", 2usize).min(5),
+    })
+}
+
+/// Count one lookup of `lat`/`lon` in the current hour. Done off the request
+/// path, like cache hit tracking.
+pub fn record(pool: Arc<Pool<Sqlite>>, lat: &str, lon: &str, hit: bool) {
+    let settings = settings();
+    if !settings.enabled {
+        return;
+    }
+    if lat.parse::<f64>().is_err() || lon.parse::<f64>().is_err() {
+        return;
+    }
+    let (cell_lat, cell_lon) = privacy::stored(lat, lon, Some(settings.precision));
+    tokio::spawn(async move {
+        if let Err(e) = sqlx::query(
+            "INSERT INTO query_stats(cell_lat, cell_lon, hour, queries, hits) VALUES (?, ?, ?, 1, ?) \
+             ON CONFLICT DO UPDATE SET queries = queries + 1, hits = hits + excluded.hits",
+        )
+        .bind(cell_lat)
+        .bind(cell_lon)
+        .bind(db::now() / 3600 * 3600)
+        .bind(i64::from(hit))
+        .execute(&*pool)
+        .await
+        {
+            tracing::warn!("failed to record query analytics: {}", e);
+        }
+    });
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HotCell {
+    pub lat: String,
+    pub lon: String,
+    pub queries: i64,
+    pub hits: i64,
+    pub hit_rate: f64,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Volume {
+    /// Start of the bucket, unix seconds.
+    pub start: i64,
+    pub queries: i64,
+    pub hits: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyticsReport {
+    pub since: i64,
+    pub hot_cells: Vec<HotCell>,
+    pub volume: Vec<Volume>,
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("analytics query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+/// `GET /api/v0/admin/analytics?since=&limit=&interval=hour|day`
+pub async fn get_analytics(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let since = match params::optional::<i64>(&params, "since", db::now() - 7 * 86400) {
+        Ok(since) => since,
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<i64>(&params, "limit", 20) {
+        Ok(limit) if (1..=1000).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 1000").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let bucket = match params.get("interval").map(String::as_str) {
+        None | Some("hour") => 3600,
+        Some("day") => 86400,
+        Some(_) => return params::bad_request("invalid interval").into_response(),
+    };
+
+    let hot_cells = match sqlx::query_as::<_, HotCell>(
+        "SELECT cell_lat AS lat, cell_lon AS lon, SUM(queries) AS queries, SUM(hits) AS hits, \
+         CAST(SUM(hits) AS REAL) / SUM(queries) AS hit_rate \
+         FROM query_stats WHERE hour >= ? GROUP BY cell_lat, cell_lon \
+         ORDER BY queries DESC LIMIT ?",
+    )
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(cells) => cells,
+        Err(e) => return database_error(e),
+    };
+
+    let volume = match sqlx::query_as::<_, Volume>(
+        "SELECT hour / ? * ? AS start, SUM(queries) AS queries, SUM(hits) AS hits \
+         FROM query_stats WHERE hour >= ? GROUP BY start ORDER BY start",
+    )
+    .bind(bucket)
+    .bind(bucket)
+    .bind(since)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(volume) => volume,
+        Err(e) => return database_error(e),
+    };
+
+    (
+        StatusCode::OK,
+        Json(AnalyticsReport {
+            since,
+            hot_cells,
+            volume,
+        }),
+    )
+        .into_response()
+}
@@ -0,0 +1,325 @@
+//! A record of every call made to the geocoding provider, retries included,
+//! for reconciling provider invoices against what gaia actually sent.
+//!
+//! On by default; `UPSTREAM_AUDIT_ENABLED=false` turns it off. Coordinates
+//! are stored as `PRIVACY_MODE` allows.
+//!
+//! Every call is counted in the per-provider metrics as well, audited or
+//! not: `gaia_upstream_calls_total{provider,outcome}`,
+//! `gaia_upstream_call_results_total{provider}` and the
+//! `gaia_upstream_call_latency_seconds{provider}` summary.
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc, sync::OnceLock, time::Duration};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{
+    auth::Admin, cache::Page, config, db, metrics, params, privacy, upstream::UpstreamError,
+    RadarAddress, RadarReverseGeocodeResponse,
+};
+
+/// Read `UPSTREAM_AUDIT_ENABLED`.
+pub fn check() {
+    enabled();
+}
+
+/// Whether `UPSTREAM_AUDIT_ENABLED` is on.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| config::var("UPSTREAM_AUDIT_ENABLED", true))
+}
+
+/// One provider call, as recorded.
+#[derive(Debug)]
+pub struct Call<'a> {
+    pub lat: &'a str,
+    pub lon: &'a str,
+    pub provider: &'a str,
+    /// The provider key's label, never the key itself.
+    pub provider_key: String,
+    pub tenant: Option<&'a str>,
+    /// The client the request came from, when it came from one.
+    pub client: Option<IpAddr>,
+    pub latency: Duration,
+}
+
+/// What a provider call answered with, to count its results.
+pub trait Answer {
+    fn results(&self) -> usize;
+}
+
+impl Answer for RadarReverseGeocodeResponse {
+    fn results(&self) -> usize {
+        // place searches answer with places instead
+        match self.raw.get("places").and_then(|p| p.as_array()) {
+            Some(places) => places.len(),
+            None => self.addresses.len(),
+        }
+    }
+}
+
+impl Answer for Vec<RadarAddress> {
+    fn results(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Log `call` with how it turned out. Done off the request path.
+pub fn record<T: Answer>(pool: &Arc<Pool<Sqlite>>, call: Call, result: &Result<T, UpstreamError>) {
+    let (status, outcome) = match result {
+        Ok(_) => (Some(200), "ok"),
+        Err(UpstreamError::RateLimited(_)) => (Some(429), "rate_limited"),
+        Err(UpstreamError::Status(status)) => (Some(i64::from(*status)), "http_error"),
+        Err(UpstreamError::Decode(_)) => (Some(200), "decode_error"),
+        Err(_) => (None, "transport_error"),
+    };
+    let results = result.as_ref().ok().map(Answer::results);
+    let provider = [("provider", call.provider)];
+    metrics::increment(
+        "gaia_upstream_calls_total",
+        &[("provider", call.provider), ("outcome", outcome)],
+    );
+    metrics::increment_by(
+        "gaia_upstream_call_results_total",
+        &provider,
+        results.unwrap_or(0) as u64,
+    );
+    metrics::observe(
+        "gaia_upstream_call_latency_seconds",
+        &provider,
+        call.latency.as_secs_f64(),
+    );
+    if !enabled() {
+        return;
+    }
+    let (lat, lon) = privacy::stored(call.lat, call.lon, None);
+    let (pool, provider, provider_key, tenant, client) = (
+        pool.clone(),
+        call.provider.to_string(),
+        call.provider_key,
+        call.tenant.map(String::from),
+        call.client.map(|ip| ip.to_string()),
+    );
+    let latency_ms = call.latency.as_millis() as i64;
+    tokio::spawn(async move {
+        if let Err(e) = sqlx::query(
+            "INSERT INTO upstream_calls(called_at, lat, lon, provider, provider_key, tenant, \
+             client, latency_ms, status, outcome, results) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(db::now())
+        .bind(lat)
+        .bind(lon)
+        .bind(provider)
+        .bind(provider_key)
+        .bind(tenant)
+        .bind(client)
+        .bind(latency_ms)
+        .bind(status)
+        .bind(outcome)
+        .bind(results.map(|n| n as i64))
+        .execute(&*pool)
+        .await
+        {
+            tracing::warn!("failed to record upstream call: {}", e);
+        }
+    });
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub called_at: i64,
+    pub lat: String,
+    pub lon: String,
+    pub provider: String,
+    pub provider_key: String,
+    pub tenant: Option<String>,
+    pub client: Option<String>,
+    pub latency_ms: i64,
+    pub status: Option<i64>,
+    pub outcome: String,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditSummary {
+    pub day: i64,
+    pub provider: String,
+    pub provider_key: String,
+    pub tenant: Option<String>,
+    pub calls: i64,
+    pub ok: i64,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderStats {
+    pub provider: String,
+    pub calls: i64,
+    pub ok: i64,
+    pub error_rate: f64,
+    pub p50_latency_ms: i64,
+    pub p99_latency_ms: i64,
+    /// Addresses per successful call, from the calls that recorded it.
+    pub results_per_call: Option<f64>,
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("audit query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+/// The `since`/`until` window and optional `tenant` filter shared by the
+/// endpoints.
+fn window(
+    params: &HashMap<String, String>,
+) -> Result<(i64, i64, Option<String>), params::ParamError> {
+    let since = params::optional::<i64>(params, "since", db::now() - 30 * 86400)?;
+    let until = params::optional::<i64>(params, "until", i64::MAX)?;
+    Ok((since, until, params.get("tenant").cloned()))
+}
+
+/// `GET /api/v0/admin/audit?since=&until=&tenant=&limit=&cursor=`: provider
+/// calls, oldest first.
+pub async fn get_audit(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (since, until, tenant) = match window(&params) {
+        Ok(window) => window,
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<i64>(&params, "limit", 100) {
+        Ok(limit) if (1..=1000).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 1000").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let (cursor,) = match params::cursor::<(i64,)>(&params) {
+        Ok(cursor) => cursor.unwrap_or_default(),
+        Err(e) => return e.into_response(),
+    };
+
+    match sqlx::query_as::<_, AuditEntry>(
+        "SELECT id, called_at, lat, lon, provider, provider_key, tenant, client, latency_ms, \
+         status, outcome FROM upstream_calls \
+         WHERE called_at >= ? AND called_at < ? AND (? IS NULL OR tenant = ?) AND id > ? \
+         ORDER BY id LIMIT ?",
+    )
+    .bind(since)
+    .bind(until)
+    .bind(&tenant)
+    .bind(&tenant)
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(results) => {
+            let next_cursor = match results.last() {
+                Some(last) if results.len() as i64 == limit => {
+                    Some(params::encode_cursor(&(last.id,)))
+                }
+                _ => None,
+            };
+            (
+                StatusCode::OK,
+                Json(Page {
+                    results,
+                    next_cursor,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => database_error(e),
+    }
+}
+
+/// `GET /api/v0/admin/audit/summary?since=&until=&tenant=`: calls per day,
+/// provider key and tenant, the shape invoices come in.
+pub async fn get_audit_summary(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (since, until, tenant) = match window(&params) {
+        Ok(window) => window,
+        Err(e) => return e.into_response(),
+    };
+
+    match sqlx::query_as::<_, AuditSummary>(
+        "SELECT called_at / 86400 * 86400 AS day, provider, provider_key, tenant, \
+         COUNT(*) AS calls, SUM(outcome = 'ok') AS ok FROM upstream_calls \
+         WHERE called_at >= ? AND called_at < ? AND (? IS NULL OR tenant = ?) \
+         GROUP BY day, provider, provider_key, tenant ORDER BY day, provider, provider_key",
+    )
+    .bind(since)
+    .bind(until)
+    .bind(&tenant)
+    .bind(&tenant)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+/// Calls, error rate, latency and results per call for each provider in the
+/// window. Latencies are nearest-rank percentiles.
+pub async fn provider_stats(
+    pool: &Pool<Sqlite>,
+    since: i64,
+    until: i64,
+    tenant: Option<&str>,
+) -> Result<Vec<ProviderStats>, sqlx::Error> {
+    sqlx::query_as::<_, ProviderStats>(
+        "WITH calls AS (SELECT provider, outcome, latency_ms, results, \
+         ROW_NUMBER() OVER (PARTITION BY provider ORDER BY latency_ms) AS n, \
+         COUNT(*) OVER (PARTITION BY provider) AS total FROM upstream_calls \
+         WHERE called_at >= ? AND called_at < ? AND (? IS NULL OR tenant = ?)) \
+         SELECT provider, COUNT(*) AS calls, SUM(outcome = 'ok') AS ok, \
+         1.0 * SUM(outcome != 'ok') / COUNT(*) AS error_rate, \
+         MIN(CASE WHEN n >= 0.5 * total THEN latency_ms END) AS p50_latency_ms, \
+         MIN(CASE WHEN n >= 0.99 * total THEN latency_ms END) AS p99_latency_ms, \
+         AVG(CASE WHEN outcome = 'ok' THEN results END) AS results_per_call \
+         FROM calls GROUP BY provider ORDER BY provider",
+    )
+    .bind(since)
+    .bind(until)
+    .bind(tenant)
+    .bind(tenant)
+    .fetch_all(pool)
+    .await
+}
+
+/// `GET /api/v0/admin/audit/providers?since=&until=&tenant=`: each
+/// provider's `provider_stats`, to see which one is slow or flaky.
+pub async fn get_audit_providers(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (since, until, tenant) = match window(&params) {
+        Ok(window) => window,
+        Err(e) => return e.into_response(),
+    };
+    match provider_stats(&pool, since, until, tenant.as_deref()).await {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        Err(e) => database_error(e),
+    }
+}
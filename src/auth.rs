@@ -0,0 +1,60 @@
+use std::sync::OnceLock;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    Json,
+};
+use serde_json::{json, Value};
+
+use crate::config;
+
+/// `ADMIN_TOKEN` (or `ADMIN_TOKEN_FILE`). Admin features are disabled when unset.
+fn admin_token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+    TOKEN
+        .get_or_init(|| config::secret("ADMIN_TOKEN").filter(|t| !t.is_empty()))
+        .as_deref()
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Compare without bailing at the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether the request carries `Authorization: Bearer <ADMIN_TOKEN>`.
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    match (admin_token(), bearer(headers)) {
+        (Some(token), Some(given)) => constant_time_eq(token.as_bytes(), given.as_bytes()),
+        _ => false,
+    }
+}
+
+pub fn forbidden() -> (StatusCode, Json<Value>) {
+    (StatusCode::FORBIDDEN, Json(json!("admin token required")))
+}
+
+/// Extractor guarding admin-only routes.
+pub struct Admin;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if is_admin(&parts.headers) {
+            Ok(Admin)
+        } else {
+            Err(forbidden())
+        }
+    }
+}
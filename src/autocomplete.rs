@@ -0,0 +1,197 @@
+//! `GET /api/v0/geocode/autocomplete?query=&near=lat,lon`: addresses that
+//! complete what someone has typed so far, for address forms.
+//!
+//! A form asks again on every keystroke, and everyone typing the same street
+//! types the same prefixes, so answers are cached per prefix: the query with
+//! spaces collapsed and lowercased, as for forward lookups, together with
+//! the proximity bucket it was asked from, `near` rounded to
+//! `AUTOCOMPLETE_BUCKET_PRECISION` decimal places (default 1, about 10
+//! kilometres), and the tenant namespace. The provider is asked about the
+//! bucket rather than the exact point. The second person to type `123 ma`
+//! in a town is answered from the cache at every keystroke.
+//!
+//! Queries shorter than `AUTOCOMPLETE_MIN_CHARS` (default 3) answer an empty
+//! list without a lookup. `limit` (default 5, at most 10) is how many
+//! completions to answer with; the cache keeps the provider's ten. Answers
+//! stay fresh like forward lookups' and are removed after
+//! `RETENTION_AUTOCOMPLETE_DAYS`. `cacheOnly`, `refresh` and `lang` work as
+//! they do for lookups.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config, db, dry_run, formatting, forward, localize, metrics, params, precision,
+    tenants::{self, Tenant},
+    upstream::{Caller, Upstream, UpstreamError},
+    LookupOptions, RadarAddress,
+};
+
+const MAX_LIMIT: usize = 10;
+
+#[derive(Debug)]
+struct Settings {
+    bucket_precision: usize,
+    min_chars: usize,
+}
+
+/// Read `AUTOCOMPLETE_BUCKET_PRECISION` and `AUTOCOMPLETE_MIN_CHARS`.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        bucket_precision: config::var("AUTOCOMPLETE_BUCKET_PRECISION", 1usize),
+        min_chars: config::var("AUTOCOMPLETE_MIN_CHARS", 3usize),
+    })
+}
+
+fn database_error(e: sqlx::Error) -> UpstreamError {
+    tracing::error!("autocomplete cache query failed: {}", e);
+    UpstreamError::Transport(String::from("database error"))
+}
+
+/// The fresh cached completions of `prefix` from `bucket`, if there are any.
+async fn cached(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    prefix: &str,
+    bucket: &str,
+) -> Result<Option<Vec<RadarAddress>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (sqlx::types::Json<Vec<RadarAddress>>, i64)>(
+        "SELECT addresses, created_at FROM autocomplete \
+         WHERE namespace = ? AND prefix = ? AND bucket = ?",
+    )
+    .bind(namespace)
+    .bind(prefix)
+    .bind(bucket)
+    .fetch_optional(db::reads(pool))
+    .await?;
+    Ok(row
+        .filter(|(addresses, created_at)| {
+            gaia_core::matching::fresh(Some(*created_at), forward::ttl(addresses), db::now())
+        })
+        .map(|(addresses, _)| addresses.0))
+}
+
+/// Completions of `query` near the bucket `near`, from the cache or else the
+/// provider.
+async fn complete(
+    query: &str,
+    near: Option<(String, String)>,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    options: &LookupOptions,
+) -> Result<Option<Vec<RadarAddress>>, UpstreamError> {
+    let prefix = forward::normalized(query);
+    let bucket = near
+        .as_ref()
+        .map(|(lat, lon)| format!("{},{}", lat, lon))
+        .unwrap_or_default();
+    let cached = match options.refresh {
+        true => None,
+        false => cached(pool, &options.namespace, &prefix, &bucket)
+            .await
+            .map_err(database_error)?,
+    };
+    metrics::increment(
+        "gaia_autocomplete_lookups_total",
+        &[("result", if cached.is_some() { "hit" } else { "miss" })],
+    );
+    if cached.is_some() || options.cache_only || dry_run::enabled() {
+        return Ok(cached);
+    }
+    let caller = Caller {
+        tenant: options.tenant.as_deref(),
+        client: options.client,
+    };
+    let near = near.as_ref().map(|(lat, lon)| (lat.as_str(), lon.as_str()));
+    let addresses = upstream.autocomplete(pool, &prefix, near, caller).await?;
+    tenants::record_upstream_call(pool.clone(), options.tenant.clone());
+    sqlx::query(
+        "INSERT OR REPLACE INTO autocomplete(namespace, prefix, bucket, addresses, created_at) \
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&options.namespace)
+    .bind(&prefix)
+    .bind(&bucket)
+    .bind(json!(addresses))
+    .bind(db::now())
+    .execute(&**pool)
+    .await
+    .map_err(database_error)?;
+    Ok(Some(addresses))
+}
+
+/// `GET /api/v0/geocode/autocomplete`.
+pub async fn get_geocode_autocomplete(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    let settings = settings();
+    let query = match params::required::<String>(&params, "query") {
+        Ok(query) => query,
+        Err(e) => return e.into_response(),
+    };
+    let near = match params.get("near") {
+        None => None,
+        Some(near) => match near.split_once(',').and_then(|(lat, lon)| {
+            Some((
+                lat.trim().parse::<f64>().ok()?,
+                lon.trim().parse::<f64>().ok()?,
+            ))
+        }) {
+            Some((lat, lon)) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => Some((
+                precision::round(lat, settings.bucket_precision),
+                precision::round_lon(lon, settings.bucket_precision),
+            )),
+            _ => return params::bad_request("near must be lat,lon").into_response(),
+        },
+    };
+    let limit = match params::optional::<usize>(&params, "limit", 5) {
+        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 10").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    if forward::normalized(&query).chars().count() < settings.min_chars {
+        return Json(Vec::<RadarAddress>::new()).into_response();
+    }
+
+    let addresses = match complete(&query, near, &pool, &upstream, &options).await {
+        Ok(Some(addresses)) => addresses,
+        Ok(None) if options.cache_only => {
+            return (StatusCode::NOT_FOUND, Json(json!("not in cache"))).into_response()
+        }
+        Ok(None) => vec![],
+        Err(e) => return e.into_response(),
+    };
+    let addresses = addresses
+        .into_iter()
+        .take(limit)
+        .map(|mut address| {
+            if let Some(lang) = &options.lang {
+                localize::apply(&mut address, lang);
+            }
+            formatting::apply(&mut address);
+            address
+        })
+        .collect::<Vec<_>>();
+    Json(addresses).into_response()
+}
@@ -0,0 +1,269 @@
+//! Scheduled snapshots of the database uploaded to S3-compatible storage,
+//! keeping the newest `BACKUP_RETENTION`, and the `gaia restore` command to
+//! bring one back. Losing the cache means paying for every lookup again.
+//!
+//! Operators can also pull a snapshot on demand from
+//! `GET /api/v0/admin/backup`, which works with or without S3 configured.
+//!
+//! Enabled by setting `BACKUP_S3_BUCKET`; see `s3::Bucket` for the rest of
+//! the connection settings. Snapshots are taken on `BACKUP_SCHEDULE` (cron,
+//! UTC, default `@daily`) under `BACKUP_S3_PREFIX` (default `gaia/`).
+
+use std::{io::Write, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use futures_util::stream;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use tokio::io::AsyncReadExt;
+
+use crate::{auth::Admin, config, cron::Schedule, db, metrics, s3::Bucket};
+
+#[derive(Debug, Clone)]
+struct Settings {
+    bucket: Bucket,
+    prefix: String,
+    schedule: Schedule,
+    retention: usize,
+}
+
+impl Settings {
+    fn from_env() -> Option<Self> {
+        let schedule = config::var("BACKUP_SCHEDULE", String::from("@daily"));
+        Some(Settings {
+            bucket: Bucket::from_env()?,
+            prefix: config::var("BACKUP_S3_PREFIX", String::from("gaia/")),
+            schedule: Schedule::parse(&schedule)
+                .unwrap_or_else(|e| panic!("Invalid BACKUP_SCHEDULE: {}", e)),
+            retention: config::var("BACKUP_RETENTION", 7usize).max(1),
+        })
+    }
+}
+
+/// Parse `BACKUP_SCHEDULE` and the bucket settings, if backups are configured.
+pub fn check() {
+    Settings::from_env();
+}
+
+/// `gaia-20261014T030000Z.db`: sorts chronologically.
+fn object_key(prefix: &str, now: i64) -> String {
+    let (year, month, day) = crate::cron::civil_from_days(now.div_euclid(86400));
+    let secs = now.rem_euclid(86400);
+    format!(
+        "{}gaia-{:04}{:02}{:02}T{:02}{:02}{:02}Z.db",
+        prefix,
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+pub fn spawn(pool: Arc<Pool<Sqlite>>) {
+    let Some(settings) = Settings::from_env() else {
+        return;
+    };
+    tracing::info!(
+        "backups to s3://{}/{} on {:?}, keeping {}",
+        settings.bucket.name(),
+        settings.prefix,
+        settings.schedule.to_string(),
+        settings.retention
+    );
+    tokio::spawn(async move {
+        loop {
+            let now = db::now();
+            let Some(next) = settings.schedule.next_after(now) else {
+                tracing::warn!("backup schedule never fires again");
+                return;
+            };
+            tokio::time::sleep(Duration::from_secs((next - now) as u64)).await;
+            match run(&settings, &pool).await {
+                Ok(key) => {
+                    tracing::info!("backed up database to {}", key);
+                    metrics::increment("gaia_backup_total", &[("result", "ok")]);
+                    metrics::set_gauge("gaia_backup_last_success_timestamp", &[], db::now() as f64);
+                }
+                Err(e) => {
+                    tracing::error!("backup failed: {}", e);
+                    metrics::increment("gaia_backup_total", &[("result", "error")]);
+                }
+            }
+        }
+    });
+}
+
+/// Snapshot into a fresh temporary file.
+async fn snapshot_to_temp(pool: &Pool<Sqlite>, now: i64) -> Result<PathBuf, String> {
+    let path =
+        std::env::temp_dir().join(format!("gaia-backup-{}-{}.db", now, rand::random::<u32>()));
+    if let Err(e) = db::snapshot(pool, &path).await {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+async fn run(settings: &Settings, pool: &Pool<Sqlite>) -> Result<String, String> {
+    let now = db::now();
+    let path = snapshot_to_temp(pool, now).await?;
+
+    let key = object_key(&settings.prefix, now);
+    let uploaded = {
+        let (bucket, key, path) = (settings.bucket.clone(), key.clone(), path.clone());
+        tokio::task::spawn_blocking(move || bucket.put_file(&key, &path))
+            .await
+            .map_err(|e| e.to_string())?
+    };
+    let _ = std::fs::remove_file(&path);
+    uploaded?;
+
+    let bucket = settings.bucket.clone();
+    let (prefix, retention) = (settings.prefix.clone(), settings.retention);
+    tokio::task::spawn_blocking(move || -> Result<(), String> {
+        let backups = bucket
+            .list(&prefix)?
+            .into_iter()
+            .filter(|k| k.ends_with(".db"))
+            .collect::<Vec<_>>();
+        for old in &backups[..backups.len().saturating_sub(retention)] {
+            tracing::info!("removing old backup {}", old);
+            bucket.delete(old)?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    Ok(key)
+}
+
+/// `gaia restore [--key <object key>] [--list]`: replace the database file
+/// behind `DATABASE_URL` with a backup (the newest by default). Stop the
+/// server first.
+pub async fn restore(args: &[String]) -> Result<(), String> {
+    let settings = Settings::from_env().ok_or("BACKUP_S3_BUCKET is not set")?;
+    let mut key = None;
+    let mut list = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = Some(args.next().ok_or("--key needs a value")?.clone()),
+            "--list" => list = true,
+            other => return Err(format!("unexpected argument {:?}", other)),
+        }
+    }
+    let url = config::secret("DATABASE_URL").ok_or("Missing DATABASE_URL")?;
+    let target = db::database_path(&url)
+        .ok_or_else(|| format!("cannot restore into {:?}", url))?
+        .to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let backups = settings.bucket.list(&settings.prefix)?;
+        if list {
+            for backup in backups {
+                println!("{}", backup);
+            }
+            return Ok(());
+        }
+        let key = match key {
+            Some(key) => key,
+            None => backups
+                .into_iter()
+                .rfind(|k| k.ends_with(".db"))
+                .ok_or("no backups found")?,
+        };
+        download(&settings.bucket, &key, &target)?;
+        tracing::info!("restored {} from {}", target.display(), key);
+        Ok(())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn download(bucket: &Bucket, key: &str, target: &std::path::Path) -> Result<(), String> {
+    let partial = PathBuf::from(format!("{}.restore", target.display()));
+    let mut file = std::fs::File::create(&partial).map_err(|e| e.to_string())?;
+    std::io::copy(&mut bucket.get(key)?, &mut file).map_err(|e| e.to_string())?;
+    file.flush().map_err(|e| e.to_string())?;
+
+    // an encrypted database has no recognisable header
+    let mut header = [0u8; 16];
+    let valid = db::database_key().is_some()
+        || std::fs::File::open(&partial)
+            .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut header))
+            .is_ok()
+            && &header == b"SQLite format 3\0";
+    if !valid {
+        let _ = std::fs::remove_file(&partial);
+        return Err(format!("{} is not a SQLite database", key));
+    }
+
+    // stale WAL files from the old database would be replayed over the new one
+    for suffix in ["-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", target.display(), suffix));
+    }
+    std::fs::rename(&partial, target).map_err(|e| e.to_string())
+}
+
+/// `GET /api/v0/admin/backup`: a consistent snapshot of the database,
+/// streamed as a download.
+pub async fn get_backup(
+    _: Admin,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let now = db::now();
+    let file = match snapshot_to_temp(&pool, now).await {
+        Ok(path) => {
+            let file = tokio::fs::File::open(&path).await;
+            // the open handle keeps the data around until the stream is done
+            let _ = std::fs::remove_file(&path);
+            file.map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::error!("backup snapshot failed: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("backup failed")),
+            )
+                .into_response();
+        }
+    };
+    let length = file.metadata().await.map(|m| m.len()).unwrap_or_default();
+    metrics::increment("gaia_backup_downloads_total", &[]);
+
+    let body = stream::unfold(file, |mut file| async move {
+        let mut buf = vec![0; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok::<_, std::io::Error>(Bytes::from(buf)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", object_key("", now)),
+            ),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
@@ -0,0 +1,271 @@
+//! `gaia bench`: steady reverse-geocode load against a running instance, to
+//! size a deployment before it takes real traffic.
+//!
+//! ```text
+//! gaia bench --target http://host:8000 [--rps 100] [--duration 30]
+//!     [--coords file.csv] [--hit-ratio 0.8] [--concurrency 256] [--key KEY]
+//! ```
+//!
+//! Requests go out at `--rps` for `--duration` seconds whether or not earlier
+//! ones have been answered, as real clients would send them. A `--hit-ratio`
+//! share of them repeat a point already sent, which the cache should answer;
+//! the rest are new points, read in order from `--coords` (a CSV whose first
+//! two columns are latitude and longitude; a header is skipped) or, without
+//! one, scattered over the contiguous United States. Once the file runs out,
+//! its points are reused a few metres off so they still miss. No more than
+//! `--concurrency` requests are in flight; those that would pass it are
+//! skipped and counted. `--key` is sent as `X-API-Key` for tenanted targets.
+//!
+//! Latency percentiles, the rate achieved and a count per status are printed
+//! at the end. Keep in mind that the provider is called for every miss, so
+//! bench against the mock provider or with a key that can take it.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Semaphore;
+
+#[derive(Debug)]
+struct Options {
+    target: String,
+    rps: f64,
+    duration: Duration,
+    coords: Option<String>,
+    hit_ratio: f64,
+    concurrency: usize,
+    key: Option<String>,
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    value
+        .ok_or_else(|| format!("{} needs a value", flag))?
+        .parse::<T>()
+        .map_err(|_| format!("invalid {}", flag))
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            target: String::new(),
+            rps: 100.0,
+            duration: Duration::from_secs(30),
+            coords: None,
+            hit_ratio: 0.8,
+            concurrency: 256,
+            key: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--target" => options.target = parse(arg, args.next())?,
+                "--rps" => options.rps = parse(arg, args.next())?,
+                "--duration" => options.duration = Duration::from_secs(parse(arg, args.next())?),
+                "--coords" => options.coords = Some(parse(arg, args.next())?),
+                "--hit-ratio" => options.hit_ratio = parse(arg, args.next())?,
+                "--concurrency" => options.concurrency = parse(arg, args.next())?,
+                "--key" => options.key = Some(parse(arg, args.next())?),
+                other => return Err(format!("unexpected argument {:?}", other)),
+            }
+        }
+        if options.target.is_empty() {
+            return Err(String::from("--target is required"));
+        }
+        options.target = options.target.trim_end_matches('/').to_string();
+        if !(options.rps > 0.0 && options.rps <= 100_000.0) {
+            return Err(String::from("--rps must be between 0 and 100000"));
+        }
+        if !(0.0..=1.0).contains(&options.hit_ratio) {
+            return Err(String::from("--hit-ratio must be between 0 and 1"));
+        }
+        if options.duration.is_zero() || options.concurrency == 0 {
+            return Err(String::from(
+                "--duration and --concurrency must be positive",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The `lat,lon` pairs at the start of each line of `path`.
+fn read_coords(path: &str) -> Result<Vec<(f64, f64)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut coords = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let mut fields = line.split(',').map(|f| f.trim().trim_matches('"'));
+        match (
+            fields.next().and_then(|f| f.parse::<f64>().ok()),
+            fields.next().and_then(|f| f.parse::<f64>().ok()),
+        ) {
+            (Some(lat), Some(lon)) => coords.push((lat, lon)),
+            _ if i == 0 || line.trim().is_empty() => {}
+            _ => return Err(format!("{}:{}: expected lat,lon", path, i + 1)),
+        }
+    }
+    if coords.is_empty() {
+        return Err(format!("{}: no coordinates", path));
+    }
+    Ok(coords)
+}
+
+/// The points requests go to: new ones, and those already sent.
+#[derive(Debug)]
+struct Points {
+    file: Vec<(f64, f64)>,
+    next: usize,
+    seen: Vec<(f64, f64)>,
+}
+
+impl Points {
+    fn fresh(&mut self) -> (f64, f64) {
+        let point = match self.file.get(self.next % self.file.len().max(1)) {
+            // a lap past the end: nudge by up to ~50 metres to land in new cells
+            Some(&(lat, lon)) if self.next >= self.file.len() => (
+                lat + (rand::random::<f64>() - 0.5) * 0.001,
+                lon + (rand::random::<f64>() - 0.5) * 0.001,
+            ),
+            Some(&point) => point,
+            None => (
+                24.5 + rand::random::<f64>() * 24.5,
+                -124.8 + rand::random::<f64>() * 57.9,
+            ),
+        };
+        self.next += 1;
+        self.seen.push(point);
+        point
+    }
+
+    fn next(&mut self, hit_ratio: f64) -> (f64, f64) {
+        if !self.seen.is_empty() && rand::random::<f64>() < hit_ratio {
+            return self.seen[rand::random::<usize>() % self.seen.len()];
+        }
+        self.fresh()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    /// Per HTTP status, or 0 for requests that got no response.
+    statuses: BTreeMap<u16, u64>,
+    skipped: u64,
+}
+
+/// One request, blocking, returning its status.
+fn request(agent: &ureq::Agent, url: &str, key: Option<&str>) -> u16 {
+    let mut request = agent.get(url);
+    if let Some(key) = key {
+        request = request.set("X-API-Key", key);
+    }
+    match request.call() {
+        Ok(response) => {
+            let status = response.status();
+            // read the body, as a real client would
+            let _ = response.into_string();
+            status
+        }
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(_) => 0,
+    }
+}
+
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    let mut points = Points {
+        file: match &options.coords {
+            Some(path) => read_coords(path)?,
+            None => vec![],
+        },
+        next: 0,
+        seen: vec![],
+    };
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(30))
+        .max_idle_connections_per_host(options.concurrency)
+        .build();
+    let key = options.key.map(Arc::<str>::from);
+    let results = Arc::new(Mutex::new(Results::default()));
+    let slots = Arc::new(Semaphore::new(options.concurrency));
+
+    tracing::info!(
+        "sending {} requests a second to {} for {:?}",
+        options.rps,
+        options.target,
+        options.duration
+    );
+    let started = Instant::now();
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rps));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Burst);
+    let mut tasks = vec![];
+    while started.elapsed() < options.duration {
+        ticks.tick().await;
+        let (lat, lon) = points.next(options.hit_ratio);
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            results.lock().unwrap().skipped += 1;
+            continue;
+        };
+        let url = format!(
+            "{}/api/v0/geocode/reverse?lat={:.6}&lon={:.6}",
+            options.target, lat, lon
+        );
+        let (agent, key, results) = (agent.clone(), key.clone(), results.clone());
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let sent = Instant::now();
+            let status = request(&agent, &url, key.as_deref());
+            let mut results = results.lock().unwrap();
+            results.latencies.push(sent.elapsed());
+            *results.statuses.entry(status).or_default() += 1;
+            drop(slot);
+        }));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    let elapsed = started.elapsed();
+
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.latencies.sort();
+    let sent = results.latencies.len() as u64;
+    let ok = results.statuses.get(&200).copied().unwrap_or(0);
+    println!(
+        "requests  {} in {:.1}s ({:.1}/s), {} ok, {} failed, {} skipped",
+        sent,
+        elapsed.as_secs_f64(),
+        sent as f64 / elapsed.as_secs_f64(),
+        ok,
+        sent - ok,
+        results.skipped
+    );
+    if let Some(max) = results.latencies.last() {
+        let sorted = &results.latencies;
+        println!(
+            "latency   p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+            millis(percentile(sorted, 0.5)),
+            millis(percentile(sorted, 0.9)),
+            millis(percentile(sorted, 0.99)),
+            millis(percentile(sorted, 0.999)),
+            millis(*max)
+        );
+    }
+    let statuses = results
+        .statuses
+        .iter()
+        .map(|(status, count)| match status {
+            0 => format!("no response: {}", count),
+            status => format!("{}: {}", status, count),
+        })
+        .collect::<Vec<_>>();
+    println!("statuses  {}", statuses.join(", "));
+    Ok(())
+}
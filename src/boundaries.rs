@@ -0,0 +1,480 @@
+//! Offline admin boundaries: which country, state, county and city a point
+//! lies in, from polygons loaded at startup out of `BOUNDARIES_DIR`.
+//!
+//! Each level is read from `<level>.geojson`, a FeatureCollection of Polygon
+//! and MultiPolygon features, or else from the shapefile `<level>.shp` with
+//! its `<level>.dbf` (see `shapefile`). A shapefile's outer rings run
+//! clockwise and its holes anticlockwise; each hole belongs to the outer
+//! ring around it. Both must be in WGS84 longitude and latitude, so a
+//! shapefile whose `.prj` names a projected system is refused rather than
+//! matched in the wrong units; reproject it first (`ogr2ogr -t_srs
+//! EPSG:4326`). A boundary's name and code come from the first of the
+//! properties or fields below that it has.
+
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{geo::ring_contains, params, shapefile, tenants::Tenant};
+
+/// Admin levels gaia knows how to answer for, in order from largest to smallest.
+const LEVELS: [&str; 4] = ["country", "state", "county", "city"];
+
+/// Property names checked (in order) for a boundary's display name.
+const NAME_PROPERTIES: [&str; 4] = ["name", "NAME", "Name", "name_en"];
+
+/// Property names checked (in order) for a boundary's short code.
+const CODE_PROPERTIES: [&str; 6] = ["code", "CODE", "iso_a2", "ISO_A2", "postal", "STUSPS"];
+
+#[derive(Debug, Default)]
+pub struct Boundaries {
+    levels: HashMap<&'static str, Vec<Boundary>>,
+}
+
+#[derive(Debug)]
+struct Boundary {
+    name: String,
+    code: Option<String>,
+    /// min_lon, min_lat, max_lon, max_lat
+    bbox: [f64; 4],
+    polygons: Vec<Polygon>,
+}
+
+/// A polygon ring list as found in GeoJSON: the first ring is the exterior,
+/// any following rings are holes. Points are (lon, lat).
+#[derive(Debug)]
+struct Polygon {
+    rings: Vec<Vec<(f64, f64)>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundaryMatch {
+    pub name: String,
+    pub code: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundaryResponse {
+    pub country: Option<BoundaryMatch>,
+    pub state: Option<BoundaryMatch>,
+    pub county: Option<BoundaryMatch>,
+    pub city: Option<BoundaryMatch>,
+}
+
+impl Boundaries {
+    /// The boundaries in `BOUNDARIES_DIR`, or none when it isn't set.
+    pub fn from_env() -> Self {
+        match std::env::var("BOUNDARIES_DIR") {
+            Ok(dir) => Boundaries::load(Path::new(&dir)).expect("Failed to load boundaries"),
+            Err(_) => Boundaries::default(),
+        }
+    }
+
+    /// Load every `<level>.geojson` or `<level>.shp` present in `dir`.
+    /// Missing levels are skipped so a deployment can ship only the data it has.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let mut levels = HashMap::new();
+        for level in LEVELS {
+            let (geojson, shp) = (
+                dir.join(format!("{}.geojson", level)),
+                dir.join(format!("{}.shp", level)),
+            );
+            let (path, boundaries) = if geojson.exists() {
+                (geojson.clone(), load_geojson(&geojson)?)
+            } else if shp.exists() {
+                (shp.clone(), load_shapefile(&shp)?)
+            } else {
+                continue;
+            };
+            tracing::info!(
+                "loaded {} {} boundaries from {}",
+                boundaries.len(),
+                level,
+                path.display()
+            );
+            levels.insert(level, boundaries);
+        }
+        Ok(Boundaries { levels })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.values().all(|b| b.is_empty())
+    }
+
+    /// Whether any boundaries were loaded for `level`.
+    pub fn has_level(&self, level: &str) -> bool {
+        self.levels.get(level).is_some_and(|b| !b.is_empty())
+    }
+
+    /// Find the first boundary at `level` containing the point.
+    pub fn lookup_level(&self, level: &str, lat: f64, lon: f64) -> Option<BoundaryMatch> {
+        self.levels
+            .get(level)?
+            .iter()
+            .find(|b| b.contains(lon, lat))
+            .map(|b| BoundaryMatch {
+                name: b.name.clone(),
+                code: b.code.clone(),
+            })
+    }
+
+    pub fn lookup(&self, lat: f64, lon: f64) -> BoundaryResponse {
+        BoundaryResponse {
+            country: self.lookup_level("country", lat, lon),
+            state: self.lookup_level("state", lat, lon),
+            county: self.lookup_level("county", lat, lon),
+            city: self.lookup_level("city", lat, lon),
+        }
+    }
+}
+
+impl Boundary {
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        if lon < self.bbox[0] || lat < self.bbox[1] || lon > self.bbox[2] || lat > self.bbox[3] {
+            return false;
+        }
+        self.polygons.iter().any(|p| p.contains(lon, lat))
+    }
+}
+
+impl Polygon {
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        let mut rings = self.rings.iter();
+        match rings.next() {
+            Some(exterior) if ring_contains(exterior, lon, lat) => {
+                !rings.any(|hole| ring_contains(hole, lon, lat))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn load_geojson(path: &Path) -> Result<Vec<Boundary>, String> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let collection: Value = serde_json::from_str(&raw)
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    parse_feature_collection(&collection)
+        .map_err(|e| format!("invalid geojson in {}: {}", path.display(), e))
+}
+
+fn load_shapefile(shp: &Path) -> Result<Vec<Boundary>, String> {
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+    };
+    let prj = shp.with_extension("prj");
+    if prj.exists()
+        && String::from_utf8_lossy(&read(&prj)?)
+            .trim_start()
+            .starts_with("PROJCS")
+    {
+        return Err(format!(
+            "{} is projected; boundaries must be in WGS84 longitude and latitude",
+            shp.display()
+        ));
+    }
+    let records = shapefile::read(&read(shp)?, &read(&shp.with_extension("dbf"))?)
+        .map_err(|e| format!("invalid shapefile {}: {}", shp.display(), e))?;
+
+    let mut boundaries = vec![];
+    for record in records {
+        let properties = Value::Object(
+            record
+                .attributes
+                .into_iter()
+                .map(|(field, value)| (field, Value::String(value)))
+                .collect(),
+        );
+        let Some(name) = first_string(&properties, &NAME_PROPERTIES) else {
+            continue;
+        };
+        let polygons = group_rings(record.rings);
+        if polygons.is_empty() {
+            continue;
+        }
+        boundaries.push(Boundary {
+            bbox: bounding_box(&polygons),
+            name,
+            code: first_string(&properties, &CODE_PROPERTIES),
+            polygons,
+        });
+    }
+    Ok(boundaries)
+}
+
+/// Twice the signed area of `ring`: negative when it runs clockwise.
+fn signed_area(ring: &[(f64, f64)]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|((x1, y1), (x2, y2))| x1 * y2 - x2 * y1)
+        .sum()
+}
+
+/// A shapefile polygon's rings as polygons: one per clockwise outer ring,
+/// with each anticlockwise hole under the outer ring containing it (or the
+/// one before it, if none does). A file with no clockwise rings at all has
+/// them the wrong way round, so each is taken as an outer ring.
+fn group_rings(rings: Vec<Vec<(f64, f64)>>) -> Vec<Polygon> {
+    let rings = rings
+        .into_iter()
+        .filter(|ring| ring.len() >= 3)
+        .collect::<Vec<_>>();
+    if !rings.iter().any(|ring| signed_area(ring) < 0.0) {
+        return rings
+            .into_iter()
+            .map(|ring| Polygon { rings: vec![ring] })
+            .collect();
+    }
+    let mut polygons: Vec<Polygon> = vec![];
+    let mut holes = vec![];
+    for ring in rings {
+        match signed_area(&ring) < 0.0 {
+            true => polygons.push(Polygon { rings: vec![ring] }),
+            false => holes.push((polygons.len().saturating_sub(1), ring)),
+        }
+    }
+    for (before, hole) in holes {
+        let (x, y) = hole[0];
+        let outer = polygons
+            .iter()
+            .position(|polygon| ring_contains(&polygon.rings[0], x, y))
+            .unwrap_or(before);
+        polygons[outer].rings.push(hole);
+    }
+    polygons
+}
+
+fn parse_feature_collection(collection: &Value) -> Result<Vec<Boundary>, String> {
+    let features = collection
+        .get("features")
+        .and_then(Value::as_array)
+        .ok_or("expected a FeatureCollection")?;
+
+    let mut boundaries = vec![];
+    for feature in features {
+        let properties = feature.get("properties").unwrap_or(&Value::Null);
+        let name = match first_string(properties, &NAME_PROPERTIES) {
+            Some(name) => name,
+            None => continue,
+        };
+        let geometry = match feature.get("geometry") {
+            Some(geometry) if !geometry.is_null() => geometry,
+            _ => continue,
+        };
+        let polygons = parse_geometry(geometry)?;
+        if polygons.is_empty() {
+            continue;
+        }
+        boundaries.push(Boundary {
+            bbox: bounding_box(&polygons),
+            name,
+            code: first_string(properties, &CODE_PROPERTIES),
+            polygons,
+        });
+    }
+    Ok(boundaries)
+}
+
+fn first_string(properties: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .filter_map(|k| properties.get(*k).and_then(Value::as_str))
+        .find(|s| !s.is_empty())
+        .map(String::from)
+}
+
+fn parse_geometry(geometry: &Value) -> Result<Vec<Polygon>, String> {
+    let coordinates = geometry
+        .get("coordinates")
+        .ok_or("geometry is missing coordinates")?;
+    match geometry.get("type").and_then(Value::as_str) {
+        Some("Polygon") => Ok(vec![parse_polygon(coordinates)?]),
+        Some("MultiPolygon") => coordinates
+            .as_array()
+            .ok_or("MultiPolygon coordinates must be an array")?
+            .iter()
+            .map(parse_polygon)
+            .collect(),
+        // points and lines can't contain anything
+        _ => Ok(vec![]),
+    }
+}
+
+fn parse_polygon(coordinates: &Value) -> Result<Polygon, String> {
+    let rings = coordinates
+        .as_array()
+        .ok_or("Polygon coordinates must be an array")?
+        .iter()
+        .map(|ring| {
+            ring.as_array()
+                .ok_or("ring must be an array")?
+                .iter()
+                .map(|point| match point.as_array().map(Vec::as_slice) {
+                    Some([lon, lat, ..]) => match (lon.as_f64(), lat.as_f64()) {
+                        (Some(lon), Some(lat)) => Ok((lon, lat)),
+                        _ => Err("position must be numeric"),
+                    },
+                    _ => Err("position must have at least two elements"),
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Polygon { rings })
+}
+
+fn bounding_box(polygons: &[Polygon]) -> [f64; 4] {
+    let mut bbox = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+    for (lon, lat) in polygons.iter().filter_map(|p| p.rings.first()).flatten() {
+        bbox[0] = bbox[0].min(*lon);
+        bbox[1] = bbox[1].min(*lat);
+        bbox[2] = bbox[2].max(*lon);
+        bbox[3] = bbox[3].max(*lat);
+    }
+    bbox
+}
+
+pub async fn get_boundaries(
+    Query(params): Query<HashMap<String, String>>,
+    // for the key check and usage count
+    _tenant: Tenant,
+    Extension(boundaries): Extension<Arc<Boundaries>>,
+) -> impl IntoResponse {
+    if boundaries.is_empty() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!("no boundaries loaded")),
+        )
+            .into_response();
+    }
+    let (lat, lon) = match params::point(&params) {
+        Ok(point) => point,
+        Err(e) => return e.into_response(),
+    };
+
+    (StatusCode::OK, Json(boundaries.lookup(lat, lon))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square with its lower left corner at `lon`/`lat`, clockwise.
+    fn square(lon: f64, lat: f64, size: f64) -> Vec<(f64, f64)> {
+        vec![
+            (lon, lat),
+            (lon, lat + size),
+            (lon + size, lat + size),
+            (lon + size, lat),
+            (lon, lat),
+        ]
+    }
+
+    fn coordinates(ring: &[(f64, f64)]) -> Value {
+        json!(ring.iter().map(|(lon, lat)| [lon, lat]).collect::<Vec<_>>())
+    }
+
+    /// Boundaries at one level from GeoJSON `features`, as (name, geometry).
+    fn boundaries(features: &[(&str, Value)]) -> Boundaries {
+        let collection = json!({
+            "type": "FeatureCollection",
+            "features": features
+                .iter()
+                .map(|(name, geometry)| json!({
+                    "type": "Feature",
+                    "properties": {"name": name},
+                    "geometry": geometry,
+                }))
+                .collect::<Vec<_>>(),
+        });
+        Boundaries {
+            levels: HashMap::from([("state", parse_feature_collection(&collection).unwrap())]),
+        }
+    }
+
+    fn state(boundaries: &Boundaries, lat: f64, lon: f64) -> Option<String> {
+        boundaries
+            .lookup_level("state", lat, lon)
+            .map(|found| found.name)
+    }
+
+    #[test]
+    fn holes_are_outside() {
+        let mut hole = square(4.0, 4.0, 2.0);
+        hole.reverse();
+        let boundaries = boundaries(&[(
+            "Ring",
+            json!({
+                "type": "Polygon",
+                "coordinates": [coordinates(&square(0.0, 0.0, 10.0)), coordinates(&hole)],
+            }),
+        )]);
+        assert_eq!(state(&boundaries, 1.0, 1.0).as_deref(), Some("Ring"));
+        assert_eq!(state(&boundaries, 5.0, 5.0), None);
+        assert_eq!(state(&boundaries, 11.0, 5.0), None);
+    }
+
+    #[test]
+    fn every_part_of_a_multipolygon_counts() {
+        let boundaries = boundaries(&[(
+            "Islands",
+            json!({
+                "type": "MultiPolygon",
+                "coordinates": [
+                    [coordinates(&square(0.0, 0.0, 1.0))],
+                    [coordinates(&square(20.0, 20.0, 1.0))],
+                ],
+            }),
+        )]);
+        assert_eq!(state(&boundaries, 0.5, 0.5).as_deref(), Some("Islands"));
+        assert_eq!(state(&boundaries, 20.5, 20.5).as_deref(), Some("Islands"));
+        // inside the bounding box, between the islands
+        assert_eq!(state(&boundaries, 10.0, 10.0), None);
+    }
+
+    #[test]
+    fn a_point_on_a_shared_edge_is_in_one_boundary() {
+        let polygon =
+            |ring: Vec<(f64, f64)>| json!({"type": "Polygon", "coordinates": [coordinates(&ring)]});
+        let boundaries = boundaries(&[
+            ("West", polygon(square(0.0, 0.0, 1.0))),
+            ("East", polygon(square(1.0, 0.0, 1.0))),
+            ("North", polygon(square(0.0, 1.0, 1.0))),
+        ]);
+        for (lat, lon, name) in [(0.5, 1.0, "East"), (1.0, 0.5, "North")] {
+            let matches = boundaries.levels["state"]
+                .iter()
+                .filter(|b| b.contains(lon, lat))
+                .collect::<Vec<_>>();
+            assert_eq!(matches.len(), 1);
+            assert_eq!(matches[0].name, name);
+        }
+    }
+
+    #[test]
+    fn shapefile_holes_go_with_the_ring_around_them() {
+        let mut hole = square(21.0, 21.0, 1.0);
+        hole.reverse();
+        let polygons = group_rings(vec![
+            square(0.0, 0.0, 10.0),
+            square(20.0, 20.0, 5.0),
+            hole.clone(),
+        ]);
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].rings.len(), 1);
+        assert_eq!(polygons[1].rings, vec![square(20.0, 20.0, 5.0), hole]);
+        assert!(!polygons[1].contains(21.5, 21.5));
+        assert!(polygons[1].contains(24.0, 24.0));
+    }
+
+    #[test]
+    fn shapefile_rings_the_wrong_way_round_are_outer_rings() {
+        let mut ring = square(0.0, 0.0, 1.0);
+        ring.reverse();
+        let polygons = group_rings(vec![ring.clone(), vec![(0.0, 0.0), (1.0, 1.0)]]);
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].rings, vec![ring]);
+    }
+}
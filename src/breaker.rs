@@ -0,0 +1,140 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config, metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls flow normally.
+    Closed,
+    /// Too many consecutive failures; calls are rejected until the cooldown ends.
+    Open,
+    /// Cooldown ended; a single trial call is in flight to probe the provider.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Trips after `failure_threshold` consecutive upstream failures. While open,
+/// callers are expected to answer from cache only. After `open_duration` one
+/// trial call is let through; its outcome closes or re-opens the circuit.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn from_env() -> Self {
+        let breaker = CircuitBreaker {
+            failure_threshold: config::var("CIRCUIT_BREAKER_THRESHOLD", 5u32).max(1),
+            open_duration: Duration::from_secs(config::var("CIRCUIT_BREAKER_OPEN_SECS", 30)),
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+            }),
+        };
+        breaker.publish(CircuitState::Closed);
+        breaker
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a call may be made right now. Moves an expired open circuit to
+    /// half-open and admits exactly one trial call.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            // a trial that never reported back (e.g. its request was cancelled)
+            // shouldn't wedge the circuit, so re-probe after another cooldown
+            CircuitState::HalfOpen => {
+                if inner
+                    .opened_at
+                    .is_some_and(|t| t.elapsed() >= self.open_duration)
+                {
+                    inner.opened_at = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+            CircuitState::Open => {
+                if inner
+                    .opened_at
+                    .is_some_and(|t| t.elapsed() >= self.open_duration)
+                {
+                    inner.state = CircuitState::HalfOpen;
+                    inner.opened_at = Some(Instant::now());
+                    self.publish(inner.state);
+                    tracing::info!("upstream circuit half-open, sending trial request");
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        if inner.state != CircuitState::Closed {
+            tracing::info!("upstream circuit closed");
+            inner.state = CircuitState::Closed;
+            inner.opened_at = None;
+            self.publish(inner.state);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        if inner.state == CircuitState::HalfOpen
+            || (inner.state == CircuitState::Closed
+                && inner.consecutive_failures >= self.failure_threshold)
+        {
+            tracing::warn!(
+                "upstream circuit opened after {} consecutive failures",
+                inner.consecutive_failures
+            );
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+            self.publish(inner.state);
+        }
+    }
+
+    fn publish(&self, state: CircuitState) {
+        let value = match state {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen => 2.0,
+        };
+        metrics::set_gauge("gaia_upstream_circuit_state", &[], value);
+        if state == CircuitState::Open {
+            metrics::increment("gaia_upstream_circuit_opened_total", &[]);
+        }
+    }
+}
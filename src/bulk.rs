@@ -0,0 +1,340 @@
+//! `POST /api/v0/geocode/reverse/bulk`: many reverse geocodes in one request.
+//!
+//! The body is a JSON array of `{"lat", "lon"}` objects (numbers or numeric
+//! strings), or of `{"utm"}` or `{"mgrs"}` grid references (see
+//! `gaia_core::grid`). The response has one entry per input, in input order, each with
+//! the `input` it answers, an HTTP-style `status`, and either `results` or an
+//! `error`, so a bad item fails alone instead of sinking the whole batch.
+//!
+//! Each item also reports the `cell` it was looked up as, its coordinates
+//! rounded to the request's `precision` (see `precision`). Items asking about
+//! the same cell are resolved once and share the answer.
+//!
+//! A request body may be at most `BULK_MAX_BODY_BYTES` (default 2 MiB) and
+//! hold at most `BULK_MAX_ITEMS` coordinates (default 10000); bigger ones get
+//! a 413 before any work is done. The body may be gzipped (see `decompress`);
+//! the limit is on its decompressed size.
+//!
+//! Clients that can't send a body can ask for a small batch with
+//! `GET /api/v0/geocode/reverse?coords=43.1,-77.6;43.2,-77.5` instead, up to
+//! `BULK_MAX_GET_ITEMS` pairs (default 100). The response is the same.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use gaia_core::grid;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config,
+    flags::{self, Flag},
+    geo, geo_reverse, metrics, params, precision,
+    tenants::Tenant,
+    units::Unit,
+    upstream::Upstream,
+    write_behind, GeocodeResponse, LookupOptions,
+};
+
+#[derive(Debug)]
+struct Settings {
+    max_body_bytes: usize,
+    max_items: usize,
+    max_get_items: usize,
+}
+
+/// Read the bulk size limits.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        max_body_bytes: config::var("BULK_MAX_BODY_BYTES", 2 * 1024 * 1024usize),
+        max_items: config::var("BULK_MAX_ITEMS", 10_000usize),
+        max_get_items: config::var("BULK_MAX_GET_ITEMS", 100usize),
+    })
+}
+
+/// The body limit for the bulk route, replacing axum's default.
+pub fn body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(settings().max_body_bytes)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkItem {
+    pub input: Value,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cell: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<GeocodeResponse>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BulkItem {
+    fn error(input: Value, status: StatusCode, error: impl Into<String>) -> Self {
+        BulkItem {
+            input,
+            status: status.as_u16(),
+            cell: None,
+            results: None,
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// The `lat`/`lon` cell an input item asks about.
+fn cell(item: &Value, precision: usize) -> Result<(String, String), String> {
+    if !item.is_object() {
+        return Err(String::from("item must be an object"));
+    }
+    let (lat, lon) = match (&item["utm"], &item["mgrs"]) {
+        (Value::Null, Value::Null) => (
+            coordinate(item, "lat", 90.0)?,
+            coordinate(item, "lon", 180.0)?,
+        ),
+        (Value::String(utm), Value::Null) if item["lat"].is_null() && item["lon"].is_null() => {
+            grid::parse_utm(utm).ok_or("invalid utm")?
+        }
+        (Value::Null, Value::String(mgrs)) if item["lat"].is_null() && item["lon"].is_null() => {
+            grid::parse_mgrs(mgrs).ok_or("invalid mgrs")?
+        }
+        _ => return Err(String::from("give one of lat and lon, utm or mgrs")),
+    };
+    Ok((
+        precision::round(lat, precision),
+        precision::round_lon(lon, precision),
+    ))
+}
+
+/// `item[name]` as a coordinate within `range`, to be rounded like the single
+/// lookup endpoint rounds it.
+fn coordinate(item: &Value, name: &str, range: f64) -> Result<f64, String> {
+    let value = match &item[name] {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        Value::Null => return Err(format!("missing {}", name)),
+        _ => None,
+    };
+    match value {
+        Some(v) if v.is_finite() && v.abs() <= range => Ok(v),
+        Some(_) => Err(format!("{} out of range", name)),
+        None => Err(format!("invalid {}", name)),
+    }
+}
+
+/// The items of a bulk body, or why it was rejected.
+pub fn items(
+    body: Result<Json<Vec<Value>>, JsonRejection>,
+    max_body_bytes: usize,
+    max_items: usize,
+) -> Result<Vec<Value>, (StatusCode, Json<Value>)> {
+    let data = match body {
+        Ok(Json(data)) => data,
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!(format!(
+                    "request body exceeds {} bytes",
+                    max_body_bytes
+                ))),
+            ))
+        }
+        Err(e) => return Err((e.status(), Json(json!(e.body_text())))),
+    };
+    if data.len() > max_items {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!(format!(
+                "batch has {} items, at most {} are allowed",
+                data.len(),
+                max_items
+            ))),
+        ));
+    }
+    Ok(data)
+}
+
+/// The items of a `coords` parameter: `lat,lon` pairs separated by `;`, each
+/// becoming an object like those of a bulk body.
+fn coords_items(coords: &str, max_items: usize) -> Result<Vec<Value>, (StatusCode, Json<Value>)> {
+    let data = coords
+        .split(';')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once(',') {
+            Some((lat, lon)) => json!({"lat": lat.trim(), "lon": lon.trim()}),
+            None => json!({"lat": pair.trim()}),
+        })
+        .collect::<Vec<_>>();
+    if data.is_empty() {
+        return Err(params::bad_request(
+            "coords must hold at least one lat,lon pair",
+        ));
+    }
+    if data.len() > max_items {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!(format!(
+                "coords has {} pairs, at most {} are allowed; POST larger batches to /bulk",
+                data.len(),
+                max_items
+            ))),
+        ));
+    }
+    Ok(data)
+}
+
+/// Look up every item of `data` at `precision`, one answer per item in input
+/// order with distances in `units`.
+pub async fn resolve(
+    data: Vec<Value>,
+    precision: usize,
+    units: Unit,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Arc<Upstream>,
+    options: &LookupOptions,
+) -> Vec<BulkItem> {
+    let options = &LookupOptions {
+        write_behind: write_behind::enabled(),
+        ..options.clone()
+    };
+    let cells = data
+        .iter()
+        .map(|item| cell(item, precision))
+        .collect::<Vec<_>>();
+
+    // telemetry batches repeat coordinates a lot; look each one up only once
+    let mut resolved = HashMap::new();
+    for (lat, lon) in cells.iter().flatten() {
+        if resolved.contains_key(&(lat, lon)) {
+            metrics::increment("gaia_bulk_deduplicated_total", &[]);
+            continue;
+        }
+        let result = match geo_reverse(
+            lat.clone(),
+            lon.clone(),
+            pool.clone(),
+            upstream.clone(),
+            options,
+        )
+        .await
+        {
+            Ok(geocodes) if geocodes.is_empty() && options.cache_only => {
+                Err((StatusCode::NOT_FOUND, String::from("not in cache")))
+            }
+            Ok(mut geocodes) => {
+                units.convert(&mut geocodes);
+                Ok(geocodes)
+            }
+            Err(e) => Err((e.status(), e.to_string())),
+        };
+        resolved.insert((lat, lon), result);
+    }
+
+    data.into_iter()
+        .zip(&cells)
+        .map(|(input, cell)| match cell {
+            Err(e) => BulkItem::error(input, StatusCode::BAD_REQUEST, e.clone()),
+            Ok((lat, lon)) => match &resolved[&(lat, lon)] {
+                Ok(geocodes) => BulkItem {
+                    input,
+                    status: StatusCode::OK.as_u16(),
+                    cell: Some(format!("{},{}", lat, lon)),
+                    results: Some(geocodes.clone()),
+                    error: None,
+                },
+                Err((status, e)) => BulkItem {
+                    cell: Some(format!("{},{}", lat, lon)),
+                    ..BulkItem::error(input, *status, e.clone())
+                },
+            },
+        })
+        .collect()
+}
+
+pub async fn post_geo_reverse_bulk(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+    body: Result<Json<Vec<Value>>, JsonRejection>,
+) -> impl IntoResponse {
+    if !flags::enabled(Flag::Bulk) {
+        return flags::disabled(Flag::Bulk).into_response();
+    }
+    let settings = settings();
+    let data = match items(body, settings.max_body_bytes, settings.max_items) {
+        Ok(data) => data,
+        Err(e) => return e.into_response(),
+    };
+
+    respond(data, &params, &headers, &tenant, &pool, &upstream).await
+}
+
+/// `GET /api/v0/geocode/reverse?coords=`, answered like the bulk route.
+pub async fn get_geo_reverse_coords(
+    coords: &str,
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    tenant: &Tenant,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Arc<Upstream>,
+) -> Response {
+    if !flags::enabled(Flag::Bulk) {
+        return flags::disabled(Flag::Bulk).into_response();
+    }
+    if ["lat", "lon", "utm", "mgrs"]
+        .iter()
+        .any(|name| params.contains_key(*name))
+    {
+        return params::bad_request("coords can't be combined with lat and lon, utm or mgrs")
+            .into_response();
+    }
+    let data = match coords_items(coords, settings().max_get_items) {
+        Ok(data) => data,
+        Err(e) => return e.into_response(),
+    };
+    respond(data, params, headers, tenant, pool, upstream).await
+}
+
+/// Resolve `data` with the request's options into the bulk response.
+async fn respond(
+    data: Vec<Value>,
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    tenant: &Tenant,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Arc<Upstream>,
+) -> Response {
+    let options = match LookupOptions::from_params(params, headers, upstream, tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let precision = match precision::from_params(params) {
+        Ok(precision) => precision,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+
+    let response = resolve(data, precision, units, pool, upstream, &options).await;
+    (
+        StatusCode::OK,
+        [("x-gaia-distance-algorithm", geo::algorithm().name())],
+        Json(response),
+    )
+        .into_response()
+}
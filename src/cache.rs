@@ -0,0 +1,346 @@
+//! Read-only queries over what's already in the geocode cache.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{
+    compression, confidence, formatting,
+    geo::{self, distance_meters},
+    localize, params, schema,
+    tenants::Tenant,
+    units::Unit,
+    GeocodeResponse, RadarAddress,
+};
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub lat: String,
+    pub lon: String,
+    pub address: compression::Stored<RadarAddress>,
+    /// The sort key, for the cursor.
+    #[serde(skip)]
+    pub rank: f64,
+    #[serde(skip)]
+    pub formatted_address: String,
+    #[serde(skip)]
+    pub schema_version: i64,
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("cache query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+/// Turn free text into an FTS5 query: every word must appear, the last one
+/// may be a prefix (so `main st roch` finds Rochester). Quoting each token
+/// keeps user input from being read as FTS5 syntax.
+fn fts_query(q: &str) -> Option<String> {
+    let tokens = q
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("\"{}\"", t))
+        .collect::<Vec<_>>();
+    if tokens.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", tokens.join(" ")))
+}
+
+/// `GET /api/v0/cache/search?q=&limit=&cursor=`: cached addresses matching
+/// `q`, best first. The next page's cursor is in `X-Gaia-Next-Cursor`.
+pub async fn get_cache_search(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let q = match params::required::<String>(&params, "q") {
+        Ok(q) => q,
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<i64>(&params, "limit", 10) {
+        Ok(limit) if (1..=100).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 100").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let cursor = match params::cursor::<(f64, String)>(&params) {
+        Ok(cursor) => cursor,
+        Err(e) => return e.into_response(),
+    };
+    let Some(query) = fts_query(&q) else {
+        return (StatusCode::OK, Json(Vec::<SearchResult>::new())).into_response();
+    };
+
+    // the same address is usually cached for several nearby cells
+    let (after_rank, after_address) = cursor.unzip();
+    match sqlx::query_as::<_, SearchResult>(
+        "SELECT g.lat, g.lon, g.address, MIN(geocode_fts.rank) AS rank, \
+         COALESCE(g.formatted_address, '') AS formatted_address, g.schema_version \
+         FROM geocode_fts \
+         JOIN geocode g ON g.rowid = geocode_fts.rowid \
+         WHERE geocode_fts MATCH ?1 AND g.namespace = ?2 \
+         GROUP BY g.formatted_address \
+         HAVING ?3 IS NULL OR (MIN(geocode_fts.rank), COALESCE(g.formatted_address, '')) > (?3, ?4) \
+         ORDER BY rank, formatted_address LIMIT ?5",
+    )
+    .bind(query)
+    .bind(tenant.namespace())
+    .bind(after_rank)
+    .bind(after_address)
+    .bind(limit)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(mut results) => {
+            for result in &mut results {
+                schema::upgrade(&mut result.address.0, result.schema_version);
+            }
+            let mut response = (StatusCode::OK, Json(&results)).into_response();
+            params::next_cursor(&mut response, &results, limit, |r| {
+                (r.rank, r.formatted_address.clone())
+            });
+            response
+        }
+        Err(e) => database_error(e),
+    }
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedAddress {
+    #[serde(skip)]
+    pub rowid: i64,
+    pub lat: String,
+    pub lon: String,
+    pub address: compression::Stored<RadarAddress>,
+    #[serde(skip)]
+    pub schema_version: i64,
+}
+
+/// `rows` with their addresses upgraded to the current schema.
+fn upgraded(mut rows: Vec<CachedAddress>) -> Vec<CachedAddress> {
+    for row in &mut rows {
+        schema::upgrade(&mut row.address.0, row.schema_version);
+    }
+    rows
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub results: Vec<T>,
+    /// Pass back as `cursor` for the next page; absent on the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+fn bbox(params: &HashMap<String, String>) -> Result<(f64, f64, f64, f64), params::ParamError> {
+    let min_lat = params::required::<f64>(params, "minLat")?;
+    let min_lon = params::required::<f64>(params, "minLon")?;
+    let max_lat = params::required::<f64>(params, "maxLat")?;
+    let max_lon = params::required::<f64>(params, "maxLon")?;
+    if min_lat > max_lat || min_lon > max_lon {
+        return Err(params::bad_request("minimums must not exceed maximums"));
+    }
+    Ok((min_lat, min_lon, max_lat, max_lon))
+}
+
+pub async fn get_cache_bbox(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (min_lat, min_lon, max_lat, max_lon) = match bbox(&params) {
+        Ok(bbox) => bbox,
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<i64>(&params, "limit", 100) {
+        Ok(limit) if (1..=1000).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 1000").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let (cursor,) = match params::cursor::<(i64,)>(&params) {
+        Ok(cursor) => cursor.unwrap_or_default(),
+        Err(e) => return e.into_response(),
+    };
+
+    match sqlx::query_as::<_, CachedAddress>(
+        "SELECT rowid, lat, lon, address, schema_version FROM geocode \
+         WHERE latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ? AND namespace = ? \
+         AND rowid > ? ORDER BY rowid LIMIT ?",
+    )
+    .bind(min_lat)
+    .bind(max_lat)
+    .bind(min_lon)
+    .bind(max_lon)
+    .bind(tenant.namespace())
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(&*pool)
+    .await
+    .map(upgraded)
+    {
+        Ok(results) => {
+            let next_cursor = match results.last() {
+                Some(last) if results.len() as i64 == limit => {
+                    Some(params::encode_cursor(&(last.rowid,)))
+                }
+                _ => None,
+            };
+            (
+                StatusCode::OK,
+                Json(Page {
+                    results,
+                    next_cursor,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => database_error(e),
+    }
+}
+
+pub use gaia_core::geo::METERS_PER_DEGREE;
+
+async fn within_box(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    lat: f64,
+    lon: f64,
+    lat_span: f64,
+    lon_span: f64,
+) -> Result<Vec<CachedAddress>, sqlx::Error> {
+    let bounds = gaia_core::geo::bounds(lat, lon, lat_span, lon_span);
+    sqlx::query_as::<_, CachedAddress>(
+        "SELECT rowid, lat, lon, address, schema_version FROM geocode \
+         WHERE latitude BETWEEN ? AND ? \
+         AND (longitude BETWEEN ? AND ? OR longitude BETWEEN ? AND ?) AND namespace = ?",
+    )
+    .bind(bounds.lat.0)
+    .bind(bounds.lat.1)
+    .bind(bounds.lon[0].0)
+    .bind(bounds.lon[0].1)
+    .bind(bounds.lon[1].0)
+    .bind(bounds.lon[1].1)
+    .bind(namespace)
+    .fetch_all(pool)
+    .await
+    .map(upgraded)
+}
+
+/// The `k` closest distinct cached addresses, with no distance cutoff.
+///
+/// Grows a box around the point until it holds `k` candidates, then re-queries
+/// a box just big enough to contain the circle through the k-th candidate so
+/// nothing closer hiding in a corner outside the first box is missed.
+async fn nearest(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    lat: f64,
+    lon: f64,
+    k: usize,
+    lang: Option<&str>,
+) -> Result<Vec<GeocodeResponse>, sqlx::Error> {
+    let lon_scale = lat.to_radians().cos().max(0.01);
+    let mut span = 0.01;
+    let candidates = loop {
+        let candidates = within_box(pool, namespace, lat, lon, span, span).await?;
+        if candidates.len() >= k || span >= 180.0 {
+            break candidates;
+        }
+        span *= 4.0;
+    };
+
+    let by_distance = |candidates: Vec<CachedAddress>| {
+        let mut results = candidates
+            .into_iter()
+            .filter_map(|c| {
+                let mut address = c.address.0;
+                if let Some(lang) = lang {
+                    localize::apply(&mut address, lang);
+                }
+                formatting::apply(&mut address);
+                let distance = distance_meters(lat, lon, address.latitude?, address.longitude?);
+                let mut result = GeocodeResponse {
+                    lat: c.lat,
+                    lon: c.lon,
+                    distance,
+                    confidence: 0.0,
+                    address,
+                    fetched_at: None,
+                };
+                result.confidence = confidence::score(&result);
+                Some(result)
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let mut seen = std::collections::HashSet::new();
+        results.retain(|r| seen.insert(r.address.formatted_address.clone()));
+        results.truncate(k);
+        results
+    };
+
+    let results = by_distance(candidates);
+    let Some(radius) = results.last().map(|r| r.distance) else {
+        return Ok(results);
+    };
+    let lat_span = (radius / METERS_PER_DEGREE).min(180.0);
+    let lon_span = (lat_span / lon_scale).min(180.0);
+    if lat_span <= span && lon_span <= span {
+        return Ok(results);
+    }
+    Ok(by_distance(
+        within_box(pool, namespace, lat, lon, lat_span, lon_span).await?,
+    ))
+}
+
+pub async fn get_geocode_nearest(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (lat, lon) = match params::point(&params) {
+        Ok(point) => point,
+        Err(e) => return e.into_response(),
+    };
+    let k = match params::optional::<usize>(&params, "k", 5) {
+        Ok(k) if (1..=50).contains(&k) => k,
+        Ok(_) => return params::bad_request("k must be between 1 and 50").into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    let lang = match localize::from_params(&params) {
+        Ok(lang) => lang,
+        Err(e) => return e.into_response(),
+    };
+
+    match nearest(&pool, &tenant.namespace(), lat, lon, k, lang.as_deref()).await {
+        Ok(mut results) => {
+            units.convert(&mut results);
+            (
+                StatusCode::OK,
+                [("x-gaia-distance-algorithm", geo::algorithm().name())],
+                Json(results),
+            )
+                .into_response()
+        }
+        Err(e) => database_error(e),
+    }
+}
@@ -0,0 +1,186 @@
+//! Fetch coordination, so a miss storm on one cell costs one provider call.
+//!
+//! Within an instance, concurrent misses for a cell queue behind the first.
+//! With `CLUSTER_MODE=true`, the first additionally takes a lease row in the
+//! shared database, and other instances wait for it to be released (or to
+//! expire after `CLUSTER_LEASE_SECS`) before fetching themselves. Either way
+//! the caller re-checks the cache once it holds a claim.
+//!
+//! The leases live in the `DATABASE_URL` SQLite file, so only instances that
+//! open that same file coordinate: processes on one host, or on hosts that
+//! share it over a filesystem with working locks. Instances with files of
+//! their own each hold leases nobody else sees. There is no Postgres or
+//! libsql backend to coordinate through.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::{Pool, Sqlite};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{config, db, metrics, privacy};
+
+type Cell = (String, String);
+
+#[derive(Debug)]
+pub struct Cluster {
+    enabled: bool,
+    /// `CLUSTER_NODE_ID`: identifies our leases and jobs; random unless set.
+    node_id: String,
+    lease: Duration,
+    /// Longest we'll wait on another instance's lease before fetching anyway.
+    max_wait: Duration,
+    local: Arc<Mutex<HashMap<Cell, Arc<tokio::sync::Mutex<()>>>>>,
+    claims: AtomicU64,
+}
+
+impl Cluster {
+    pub fn from_env() -> Self {
+        Cluster {
+            enabled: config::var("CLUSTER_MODE", false),
+            node_id: config::var(
+                "CLUSTER_NODE_ID",
+                format!("gaia-{:08x}", rand::random::<u32>()),
+            ),
+            lease: Duration::from_secs(config::var("CLUSTER_LEASE_SECS", 30).max(1)),
+            max_wait: Duration::from_millis(config::var("CLUSTER_LEASE_WAIT_MS", 10000)),
+            local: Default::default(),
+            claims: AtomicU64::new(0),
+        }
+    }
+
+    /// Our `CLUSTER_NODE_ID` in cluster mode.
+    pub fn node(&self) -> Option<&str> {
+        self.enabled.then_some(self.node_id.as_str())
+    }
+
+    /// Wait until nobody else is fetching `lat`/`lon`, then hold that right
+    /// until the returned claim is dropped.
+    pub async fn claim(&self, pool: &Arc<Pool<Sqlite>>, lat: &str, lon: &str) -> Claim {
+        let cell = (lat.to_string(), lon.to_string());
+        let lock = self
+            .local
+            .lock()
+            .unwrap()
+            .entry(cell.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+        let mut claim = Claim {
+            cell,
+            local: self.local.clone(),
+            guard: Some(guard),
+            lease: None,
+        };
+        if !self.enabled {
+            return claim;
+        }
+
+        // unique per claim, so releasing an old lease can't drop a newer one
+        let holder = format!(
+            "{}/{}",
+            self.node_id,
+            self.claims.fetch_add(1, Ordering::Relaxed)
+        );
+        let deadline = Instant::now() + self.max_wait;
+        let mut waited = false;
+        loop {
+            match self.try_lease(pool, lat, lon, &holder).await {
+                Ok(true) => {
+                    claim.lease = Some((pool.clone(), holder));
+                    break;
+                }
+                Ok(false) if Instant::now() < deadline => {
+                    waited = true;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Ok(false) => {
+                    tracing::warn!(
+                        "gave up waiting on fetch lease for {}",
+                        privacy::coordinates(lat, lon)
+                    );
+                    metrics::increment("gaia_cluster_lease_timeouts_total", &[]);
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("failed to take fetch lease: {}", e);
+                    break;
+                }
+            }
+        }
+        if waited {
+            metrics::increment("gaia_cluster_lease_waits_total", &[]);
+        }
+        claim
+    }
+
+    async fn try_lease(
+        &self,
+        pool: &Pool<Sqlite>,
+        lat: &str,
+        lon: &str,
+        holder: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let now = db::now();
+        let result = sqlx::query(
+            "INSERT INTO fetch_leases(lat, lon, holder, expires_at) VALUES (?, ?, ?, ?) \
+             ON CONFLICT(lat, lon) DO UPDATE SET \
+             holder = excluded.holder, expires_at = excluded.expires_at \
+             WHERE fetch_leases.expires_at <= ? OR fetch_leases.holder LIKE ?",
+        )
+        .bind(lat)
+        .bind(lon)
+        .bind(holder)
+        .bind(now + self.lease.as_secs() as i64)
+        .bind(now)
+        // our own leases are only left over from claims already dropped
+        .bind(format!("{}/%", self.node_id))
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// The right to fetch a cell, released on drop.
+pub struct Claim {
+    cell: Cell,
+    local: Arc<Mutex<HashMap<Cell, Arc<tokio::sync::Mutex<()>>>>>,
+    guard: Option<OwnedMutexGuard<()>>,
+    lease: Option<(Arc<Pool<Sqlite>>, String)>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some((pool, holder)) = self.lease.take() {
+            let (lat, lon) = self.cell.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    sqlx::query("DELETE FROM fetch_leases WHERE lat = ? AND lon = ? AND holder = ?")
+                        .bind(lat)
+                        .bind(lon)
+                        .bind(holder)
+                        .execute(&*pool)
+                        .await
+                {
+                    tracing::warn!("failed to release fetch lease: {}", e);
+                }
+            });
+        }
+
+        drop(self.guard.take());
+        // forget the cell once nobody else is queued on it
+        let mut local = self.local.lock().unwrap();
+        if local
+            .get(&self.cell)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            local.remove(&self.cell);
+        }
+    }
+}
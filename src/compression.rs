@@ -0,0 +1,703 @@
+//! Compressing the addresses cached in `geocode`, for deployments on small
+//! disks. Address JSON is mostly the same keys and the same few values over
+//! and over, which deflate with a preset dictionary of them squeezes to a
+//! third or less.
+//!
+//! `CACHE_COMPRESSION=deflate` (default `none`) stores new addresses
+//! compressed, at `CACHE_COMPRESSION_LEVEL` (0 to 9, default 6), with the
+//! dictionary `CACHE_COMPRESSION_DICTIONARY` (default 0, the built-in one).
+//! Addresses are read back the same whether they are compressed or not, so
+//! compression can be turned on or off, or the dictionary changed, at any
+//! time; rows keep the form they were written in until they are rewritten.
+//! An address that wouldn't come out smaller is stored as it is.
+//!
+//! The built-in dictionary is written for US addresses from Radar. A better
+//! one for what a deployment actually caches is trained from its own rows
+//! with `POST /api/v0/admin/compression/dictionaries?samples=&bytes=`, which
+//! keeps it in `compression_dictionaries` and answers its id and how well it
+//! does on the samples against the built-in one. It is only used once
+//! `CACHE_COMPRESSION_DICTIONARY` names it: dictionaries are loaded at
+//! startup, and every instance on the database must have one loaded before
+//! any writes with it. Dictionaries are never deleted, as rows may still
+//! need them.
+//!
+//! `POST /api/v0/admin/compression/rewrite` rewrites the rows not in the
+//! configured form in batches of `batch` (default 500), compressing them or,
+//! with compression off, decompressing them. SQLite reuses the pages that
+//! frees for new rows; only a `VACUUM` gives them back to the disk.
+//! `GET /api/v0/admin/compression` has the settings, the dictionaries and
+//! how many rows, and bytes, are stored each way.
+//!
+//! Separately, `CACHE_STORAGE_FORMAT=binary` (default `json`) stores new
+//! addresses in `gaia_core::binary`'s encoding rather than as JSON: about
+//! half the size before any compression, and read without parsing JSON. The
+//! format of each row is in `geocode.address_format` (0 for JSON, 1 for
+//! binary; compressed or not), and rewriting converts between formats as it
+//! does between compressions. Only plain JSON rows are text SQLite's JSON
+//! functions can read.
+//!
+//! A compressed address is a blob of a zero byte, the dictionary id (two
+//! bytes, big-endian) and the raw deflate stream of its JSON or binary. Deflate itself has no
+//! preset dictionaries, so the stream is the continuation of one that began
+//! with the dictionary: the compressor is fed the dictionary and flushed,
+//! that output dropped, and on reading a stored copy of it goes in front.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Write,
+    ops::Deref,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use flate2::{
+    write::{DeflateDecoder, DeflateEncoder},
+    Compression,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef},
+    Decode, Encode, Pool, Sqlite, Type,
+};
+
+use gaia_core::binary;
+
+use crate::{auth::Admin, config, db, params, RadarAddress};
+
+/// The first byte of a compressed address; JSON never starts with it.
+const MAGIC: u8 = 0;
+
+const BUILT_IN: u16 = 0;
+
+/// The most deflate can look back, less room for the address itself.
+const MAX_DICTIONARY_BYTES: usize = 30 * 1024;
+
+/// What US addresses from Radar have in common, the commonest last, where
+/// deflate reaches it cheapest.
+const BUILT_IN_DICTIONARY: &str = concat!(
+    r#""sources":["radar","nominatim"],"layer":"locality","layer":"postalCode","#,
+    r#""layer":"county","layer":"state","layer":"street","confidence":"fallback","#,
+    r#""confidence":"interpolated"," Boulevard"," Parkway"," Court"," Place"," Circle"," Way","#,
+    r#"" Lane"," Drive"," Road"," Avenue"," Street"," Blvd"," Ct"," Pl"," Cir"," Ln"," Dr"," Rd","#,
+    r#"" Ave"," St"," County","North ","South ","East ","West ","#,
+    r#"{"addressLabel":"","city":"","confidence":"exact","country":"United States","#,
+    r#""countryCode":"US","county":" County","formattedAddress":", United States","#,
+    r#""latitude":,"layer":"address","longitude":-,"number":"","postalCode":"","#,
+    r#""state":"","stateCode":"","street":""}"#,
+);
+
+/// How an address is encoded, before any compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Binary,
+}
+
+impl Format {
+    /// As kept in `geocode.address_format`.
+    pub fn id(self) -> i64 {
+        match self {
+            Format::Json => 0,
+            Format::Binary => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Settings {
+    enabled: bool,
+    dictionary: u16,
+    level: u32,
+    format: Format,
+}
+
+/// Read `CACHE_COMPRESSION`, `CACHE_COMPRESSION_LEVEL`,
+/// `CACHE_COMPRESSION_DICTIONARY` and `CACHE_STORAGE_FORMAT`.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let compression = std::env::var("CACHE_COMPRESSION").unwrap_or_default();
+        let format = std::env::var("CACHE_STORAGE_FORMAT").unwrap_or_default();
+        Settings {
+            enabled: match compression.trim() {
+                "" | "none" => false,
+                "deflate" => true,
+                other => panic!("Invalid CACHE_COMPRESSION: {}", other),
+            },
+            dictionary: config::var("CACHE_COMPRESSION_DICTIONARY", BUILT_IN),
+            level: config::var("CACHE_COMPRESSION_LEVEL", 6u32),
+            format: match format.trim() {
+                "" | "json" => Format::Json,
+                "binary" => Format::Binary,
+                other => panic!("Invalid CACHE_STORAGE_FORMAT: {}", other),
+            },
+        }
+    })
+}
+
+#[derive(Debug)]
+struct Dictionary {
+    bytes: Vec<u8>,
+    /// The dictionary as a deflate stream ending on a byte boundary,
+    /// ahead of which a compressed address decompresses.
+    prefix: Vec<u8>,
+}
+
+impl Dictionary {
+    fn new(bytes: Vec<u8>) -> Self {
+        let mut prefix = DeflateEncoder::new(vec![], Compression::none());
+        prefix
+            .write_all(&bytes)
+            .expect("writes to a Vec don't fail");
+        prefix.flush().expect("writes to a Vec don't fail");
+        Dictionary {
+            prefix: prefix.get_ref().clone(),
+            bytes,
+        }
+    }
+
+    fn compress(&self, data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::new(level));
+        encoder
+            .write_all(&self.bytes)
+            .and_then(|_| encoder.flush())
+            .expect("writes to a Vec don't fail");
+        let skip = encoder.get_ref().len();
+        encoder.write_all(data).expect("writes to a Vec don't fail");
+        let mut out = encoder.finish().expect("writes to a Vec don't fail");
+        out.drain(..skip);
+        out
+    }
+
+    fn decompress(&self, stream: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoder = DeflateDecoder::new(vec![]);
+        decoder.write_all(&self.prefix)?;
+        decoder.write_all(stream)?;
+        let mut out = decoder.finish()?;
+        out.drain(..self.bytes.len().min(out.len()));
+        Ok(out)
+    }
+}
+
+fn dictionaries() -> &'static RwLock<HashMap<u16, Arc<Dictionary>>> {
+    static DICTIONARIES: OnceLock<RwLock<HashMap<u16, Arc<Dictionary>>>> = OnceLock::new();
+    DICTIONARIES.get_or_init(|| {
+        let built_in = Dictionary::new(BUILT_IN_DICTIONARY.as_bytes().to_vec());
+        RwLock::new(HashMap::from([(BUILT_IN, Arc::new(built_in))]))
+    })
+}
+
+fn dictionary(id: u16) -> Option<Arc<Dictionary>> {
+    dictionaries().read().unwrap().get(&id).cloned()
+}
+
+/// Load the trained dictionaries, so rows compressed with them can be read.
+/// Fails if the configured one isn't among them.
+pub async fn load(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows =
+        sqlx::query_as::<_, (i64, Vec<u8>)>("SELECT id, dictionary FROM compression_dictionaries")
+            .fetch_all(pool)
+            .await?;
+    let mut dictionaries = dictionaries().write().unwrap();
+    for (id, bytes) in rows {
+        if let Ok(id) = u16::try_from(id) {
+            dictionaries.insert(id, Arc::new(Dictionary::new(bytes)));
+        }
+    }
+    let configured = settings().dictionary;
+    if !dictionaries.contains_key(&configured) {
+        panic!(
+            "Invalid CACHE_COMPRESSION_DICTIONARY: no dictionary {}",
+            configured
+        );
+    }
+    Ok(())
+}
+
+/// The dictionary id of `stored` if it's compressed.
+fn compressed_with(stored: &[u8]) -> Option<u16> {
+    match stored {
+        [MAGIC, high, low, ..] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+/// A stored address, decompressed if it was compressed: its JSON or binary.
+pub fn decompress(stored: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    let Some(id) = compressed_with(stored) else {
+        return Ok(Cow::Borrowed(stored));
+    };
+    let dictionary =
+        dictionary(id).ok_or_else(|| format!("no compression dictionary {} loaded", id))?;
+    dictionary
+        .decompress(&stored[3..])
+        .map(Cow::Owned)
+        .map_err(|e| format!("corrupt compressed address: {}", e))
+}
+
+/// A stored address, whatever its format and compression.
+pub fn read(stored: &[u8]) -> Result<RadarAddress, String> {
+    let encoded = decompress(stored)?;
+    match encoded.first() {
+        Some(&binary::MAGIC) => binary::decode(&encoded),
+        _ => serde_json::from_slice(&encoded).map_err(|e| e.to_string()),
+    }
+}
+
+/// An address as it goes into `geocode`, bound for `address`, and its
+/// `format` for `address_format`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    data: Data,
+    pub format: Format,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Data {
+    /// Plain JSON, kept as text for SQLite's JSON functions.
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Payload {
+    fn bytes(&self) -> &[u8] {
+        match &self.data {
+            Data::Text(text) => text.as_bytes(),
+            Data::Blob(bytes) => bytes,
+        }
+    }
+}
+
+/// `encoded`, in `format`, as it is stored with the configured compression.
+fn compress(encoded: Vec<u8>, format: Format) -> Payload {
+    let settings = settings();
+    let uncompressed = |encoded: Vec<u8>| match (format, String::from_utf8(encoded)) {
+        (Format::Json, Ok(text)) => Data::Text(text),
+        (_, Ok(text)) => Data::Blob(text.into_bytes()),
+        (_, Err(e)) => Data::Blob(e.into_bytes()),
+    };
+    let dictionary = dictionary(settings.dictionary).filter(|_| settings.enabled);
+    let Some(dictionary) = dictionary else {
+        return Payload {
+            data: uncompressed(encoded),
+            format,
+        };
+    };
+    let mut compressed = vec![MAGIC];
+    compressed.extend(settings.dictionary.to_be_bytes());
+    compressed.extend(dictionary.compress(&encoded, settings.level));
+    let data = match compressed.len() < encoded.len() {
+        true => Data::Blob(compressed),
+        false => uncompressed(encoded),
+    };
+    Payload { data, format }
+}
+
+/// `address` as it is stored with the configured format and compression.
+pub fn encode(address: &RadarAddress) -> Payload {
+    let format = settings().format;
+    let encoded = match format {
+        Format::Json => serde_json::to_vec(address).expect("addresses serialize"),
+        Format::Binary => binary::encode(address),
+    };
+    compress(encoded, format)
+}
+
+impl Type<Sqlite> for Payload {
+    fn type_info() -> SqliteTypeInfo {
+        <String as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&[u8] as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for Payload {
+    fn encode_by_ref(&self, args: &mut Vec<SqliteArgumentValue<'q>>) -> IsNull {
+        match &self.data {
+            Data::Text(text) => <String as Encode<Sqlite>>::encode_by_ref(text, args),
+            Data::Blob(bytes) => <Vec<u8> as Encode<Sqlite>>::encode_by_ref(bytes, args),
+        }
+    }
+}
+
+/// What a stored address can be read as.
+pub trait Storable: DeserializeOwned {
+    fn from_address(address: RadarAddress) -> Result<Self, BoxDynError>;
+}
+
+impl Storable for RadarAddress {
+    fn from_address(address: RadarAddress) -> Result<Self, BoxDynError> {
+        Ok(address)
+    }
+}
+
+impl Storable for Value {
+    fn from_address(address: RadarAddress) -> Result<Self, BoxDynError> {
+        Ok(serde_json::to_value(address)?)
+    }
+}
+
+/// A value read from a column that may hold compressed or binary addresses,
+/// as `sqlx::types::Json` reads plain JSON.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct Stored<T>(pub T);
+
+impl<T> Deref for Stored<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Type<Sqlite> for Stored<T> {
+    fn type_info() -> SqliteTypeInfo {
+        <&[u8] as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&[u8] as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, T: Storable> Decode<'r, Sqlite> for Stored<T> {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        let stored = <&[u8] as Decode<Sqlite>>::decode(value)?;
+        let encoded = decompress(stored)?;
+        match encoded.first() {
+            Some(&binary::MAGIC) => Ok(Stored(T::from_address(binary::decode(&encoded)?)?)),
+            _ => Ok(Stored(serde_json::from_slice(&encoded)?)),
+        }
+    }
+}
+
+/// A dictionary of what `samples` have in common: their commonest key-value
+/// pairs and keys, up to `max_bytes`, the most bytes saved last.
+fn train(samples: &[Value], max_bytes: usize) -> Vec<u8> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for sample in samples {
+        for (key, value) in sample.as_object().into_iter().flatten() {
+            *counts.entry(format!("\"{}\":{}", key, value)).or_default() += 1;
+            *counts.entry(format!("\"{}\":", key)).or_default() += 1;
+            if let Some(text) = value.as_str() {
+                // the words of values repeat across keys: a city's name is
+                // in the formatted address too
+                for word in text.split([' ', ',']).filter(|w| w.len() > 3) {
+                    *counts.entry(word.to_string()).or_default() += 1;
+                }
+            }
+        }
+    }
+    let mut fragments = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(fragment, count)| ((count - 1) * fragment.len(), fragment))
+        .collect::<Vec<_>>();
+    fragments.sort_by(|a, b| b.cmp(a));
+    let mut chosen = vec![];
+    let mut total = 0;
+    for (_, fragment) in fragments {
+        if total + fragment.len() > max_bytes {
+            continue;
+        }
+        total += fragment.len();
+        chosen.push(fragment);
+    }
+    chosen.reverse();
+    chosen.concat().into_bytes()
+}
+
+/// How big `samples` come out with `dictionary`, as a share of their JSON.
+fn ratio(dictionary: &Dictionary, samples: &[String]) -> f64 {
+    let level = settings().level;
+    let (before, after) = samples.iter().fold((0, 0), |(before, after), sample| {
+        let compressed = dictionary.compress(sample.as_bytes(), level).len() + 3;
+        (before + sample.len(), after + compressed.min(sample.len()))
+    });
+    match before {
+        0 => 1.0,
+        _ => after as f64 / before as f64,
+    }
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("compression query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DictionaryInfo {
+    pub id: i64,
+    pub bytes: i64,
+    pub samples: i64,
+    pub created_at: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    /// 0 for JSON, 1 for binary, as in `geocode.address_format`.
+    pub format: i64,
+    pub compressed: bool,
+    pub rows: i64,
+    pub bytes: i64,
+}
+
+/// `GET /api/v0/admin/compression`.
+pub async fn get_compression(_: Admin, Extension(pool): Extension<Arc<Pool<Sqlite>>>) -> Response {
+    let settings = settings();
+    let dictionaries = match sqlx::query_as::<_, (i64, i64, i64, i64)>(
+        "SELECT id, length(dictionary), samples, created_at FROM compression_dictionaries \
+         ORDER BY id",
+    )
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    let storage = match sqlx::query_as::<_, (i64, bool, i64, i64)>(
+        "SELECT address_format, typeof(address) = 'blob' AND substr(address, 1, 1) = x'00', \
+         count(*), COALESCE(sum(length(address)), 0) FROM geocode GROUP BY 1, 2 ORDER BY 1, 2",
+    )
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    let built_in = DictionaryInfo {
+        id: i64::from(BUILT_IN),
+        bytes: BUILT_IN_DICTIONARY.len() as i64,
+        samples: 0,
+        created_at: None,
+    };
+    Json(json!({
+        "enabled": settings.enabled,
+        "format": settings.format.id(),
+        "dictionary": settings.dictionary,
+        "level": settings.level,
+        "dictionaries": std::iter::once(built_in)
+            .chain(dictionaries.into_iter().map(|(id, bytes, samples, created_at)| {
+                DictionaryInfo { id, bytes, samples, created_at: Some(created_at) }
+            }))
+            .collect::<Vec<_>>(),
+        "storage": storage
+            .into_iter()
+            .map(|(format, compressed, rows, bytes)| Storage { format, compressed, rows, bytes })
+            .collect::<Vec<_>>(),
+    }))
+    .into_response()
+}
+
+/// `POST /api/v0/admin/compression/dictionaries`: train a dictionary on the
+/// newest `samples` cached addresses (default 1000).
+pub async fn post_compression_dictionary(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> Response {
+    let samples = match params::optional::<i64>(&params, "samples", 1000) {
+        Ok(samples) if (1..=100_000).contains(&samples) => samples,
+        Ok(_) => {
+            return params::bad_request("samples must be between 1 and 100000").into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    let max_bytes = match params::optional::<usize>(&params, "bytes", 16 * 1024) {
+        Ok(bytes) if (256..=MAX_DICTIONARY_BYTES).contains(&bytes) => bytes,
+        Ok(_) => {
+            return params::bad_request(&format!(
+                "bytes must be between 256 and {}",
+                MAX_DICTIONARY_BYTES
+            ))
+            .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    let rows = match sqlx::query_as::<_, (Stored<Value>,)>(
+        "SELECT address FROM geocode WHERE address IS NOT NULL ORDER BY rowid DESC LIMIT ?",
+    )
+    .bind(samples)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    if rows.is_empty() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!("there are no cached addresses to train on")),
+        )
+            .into_response();
+    }
+    let samples = rows.into_iter().map(|(row,)| row.0).collect::<Vec<_>>();
+    let trained = Dictionary::new(train(&samples, max_bytes));
+    let json = samples.iter().map(Value::to_string).collect::<Vec<_>>();
+    let built_in_ratio = dictionary(BUILT_IN).map_or(1.0, |d| ratio(&d, &json));
+    let trained_ratio = ratio(&trained, &json);
+
+    let id = match sqlx::query(
+        "INSERT INTO compression_dictionaries(dictionary, samples, created_at) VALUES (?, ?, ?)",
+    )
+    .bind(&trained.bytes)
+    .bind(samples.len() as i64)
+    .bind(db::now())
+    .execute(&*pool)
+    .await
+    {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => return database_error(e),
+    };
+    let Ok(short_id) = u16::try_from(id) else {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!("no dictionary ids are left")),
+        )
+            .into_response();
+    };
+    let bytes = trained.bytes.len();
+    dictionaries()
+        .write()
+        .unwrap()
+        .insert(short_id, Arc::new(trained));
+    tracing::info!(
+        "trained compression dictionary {} ({} bytes) on {} addresses",
+        id,
+        bytes,
+        samples.len()
+    );
+    (
+        StatusCode::CREATED,
+        Json(json!({
+            "id": id,
+            "bytes": bytes,
+            "samples": samples.len(),
+            "ratio": trained_ratio,
+            "builtInRatio": built_in_ratio,
+        })),
+    )
+        .into_response()
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Rewrite {
+    pub rows: u64,
+    pub rewritten: u64,
+    /// Rows that couldn't be read, left for the janitor.
+    pub unreadable: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Whether `stored`, in `format`, is already as the settings would write it.
+fn settled(stored: &[u8], format: i64) -> bool {
+    let settings = settings();
+    if format != settings.format.id() {
+        return false;
+    }
+    match compressed_with(stored) {
+        Some(id) => settings.enabled && id == settings.dictionary,
+        None => !settings.enabled,
+    }
+}
+
+/// `POST /api/v0/admin/compression/rewrite`.
+pub async fn post_compression_rewrite(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> Response {
+    let batch = match params::optional::<i64>(&params, "batch", 500) {
+        Ok(batch) if (1..=10_000).contains(&batch) => batch,
+        Ok(_) => return params::bad_request("batch must be between 1 and 10000").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let mut report = Rewrite::default();
+    let mut after = 0;
+    loop {
+        let rows = match sqlx::query_as::<_, (i64, Vec<u8>, i64)>(
+            "SELECT rowid, address, address_format FROM geocode \
+             WHERE rowid > ? AND address IS NOT NULL ORDER BY rowid LIMIT ?",
+        )
+        .bind(after)
+        .bind(batch)
+        .fetch_all(&*pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => return database_error(e),
+        };
+        let Some(&(last, _, _)) = rows.last() else {
+            break;
+        };
+        after = last;
+        report.rows += rows.len() as u64;
+
+        let mut updates = vec![];
+        for (rowid, stored, format) in rows {
+            report.bytes_before += stored.len() as u64;
+            if settled(&stored, format) {
+                report.bytes_after += stored.len() as u64;
+                continue;
+            }
+            let Ok(address) = read(&stored) else {
+                report.unreadable += 1;
+                report.bytes_after += stored.len() as u64;
+                continue;
+            };
+            let payload = encode(&address);
+            report.bytes_after += payload.bytes().len() as u64;
+            // compressing wouldn't have made it smaller
+            if payload.bytes() == stored && payload.format.id() == format {
+                continue;
+            }
+            updates.push((rowid, payload));
+        }
+        if updates.is_empty() {
+            continue;
+        }
+        let result = async {
+            let mut tx = pool.begin().await?;
+            for (rowid, payload) in &updates {
+                sqlx::query("UPDATE geocode SET address = ?, address_format = ? WHERE rowid = ?")
+                    .bind(payload)
+                    .bind(payload.format.id())
+                    .bind(rowid)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await
+        }
+        .await;
+        if let Err(e) = result {
+            return database_error(e);
+        }
+        report.rewritten += updates.len() as u64;
+    }
+    tracing::info!("compression rewrite: {:?}", report);
+    Json(report).into_response()
+}
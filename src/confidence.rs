@@ -0,0 +1,16 @@
+//! `minConfidence=`, which drops results scoring below it on
+//! `gaia_core::confidence`'s 0-1 scale.
+
+use std::collections::HashMap;
+
+pub use gaia_core::confidence::{apply, forward_score, score};
+
+use crate::params;
+
+/// The request's `minConfidence`, 0 when absent.
+pub fn from_params(params: &HashMap<String, String>) -> Result<f64, params::ParamError> {
+    match params::optional(params, "minConfidence", 0.0)? {
+        min if (0.0..=1.0).contains(&min) => Ok(min),
+        _ => Err(params::bad_request("minConfidence must be between 0 and 1")),
+    }
+}
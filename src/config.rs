@@ -0,0 +1,37 @@
+use std::{env, fmt::Debug, fs, path::PathBuf, str::FromStr};
+
+/// Read and parse an optional environment variable, falling back to `default`
+/// when it is unset. A value that is set but malformed is a startup error.
+pub fn var<T: FromStr>(name: &str, default: T) -> T
+where
+    T::Err: Debug,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid {}: {:?}", name, e)),
+        Err(_) => default,
+    }
+}
+
+/// The path in `<NAME>_FILE`, if set. Secrets can be mounted as files (Docker
+/// and Kubernetes secrets) instead of being put in the environment.
+pub fn secret_file(name: &str) -> Option<PathBuf> {
+    env::var_os(format!("{}_FILE", name)).map(PathBuf::from)
+}
+
+/// Read a secret from the file named by `<NAME>_FILE`, falling back to the
+/// `<NAME>` environment variable. Surrounding whitespace is trimmed.
+pub fn secret(name: &str) -> Option<String> {
+    match secret_file(name) {
+        Some(path) => Some(
+            fs::read_to_string(&path)
+                .unwrap_or_else(|e| {
+                    panic!("Failed to read {}_FILE {}: {}", name, path.display(), e)
+                })
+                .trim()
+                .to_string(),
+        ),
+        None => env::var(name).ok(),
+    }
+}
@@ -0,0 +1,150 @@
+//! `GET /api/v0/context?lat=&lon=`: Radar's context for a point (the
+//! country, state, DMA, postal code and time zone it is in, and any Radar
+//! geofences around it), cached like lookups are.
+//!
+//! The answer is the `context` object of Radar's `/v1/context` as Radar
+//! sends it. Points are cached per tenant namespace at `CONTEXT_PRECISION`
+//! decimal places (default 3, about 100 metres), asking Radar about the
+//! rounded point, as the regions a context names are far bigger than that;
+//! the cell used is in `X-Gaia-Cell`. Answers stay fresh for the
+//! `CACHE_TTL_LAYER_DAYS` of the `context` layer, or `CACHE_TTL_DAYS`, and
+//! are removed after `RETENTION_POINT_CONTEXT_DAYS`. `cacheOnly` and
+//! `refresh` work as they do for lookups. The mock provider makes contexts
+//! up; Nominatim and the boundaries have none to give, so with them this is
+//! a 501.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config, db, dry_run, janitor, metrics, params, precision,
+    tenants::{self, Tenant},
+    upstream::{Caller, Upstream, UpstreamError},
+    LookupOptions,
+};
+
+/// Read `CONTEXT_PRECISION`.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> usize {
+    static PRECISION: OnceLock<usize> = OnceLock::new();
+    *PRECISION.get_or_init(|| config::var("CONTEXT_PRECISION", 3usize))
+}
+
+fn database_error(e: sqlx::Error) -> UpstreamError {
+    tracing::error!("context cache query failed: {}", e);
+    UpstreamError::Transport(String::from("database error"))
+}
+
+/// The fresh cached context for the cell, if there is one.
+async fn cached(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    (lat, lon): (&str, &str),
+) -> Result<Option<Value>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (sqlx::types::Json<Value>, i64)>(
+        "SELECT context, created_at FROM point_context \
+         WHERE namespace = ? AND lat = ? AND lon = ?",
+    )
+    .bind(namespace)
+    .bind(lat)
+    .bind(lon)
+    .fetch_optional(db::reads(pool))
+    .await?;
+    let ttl = janitor::layer_ttl(Some("context"));
+    Ok(row
+        .filter(|(_, created_at)| gaia_core::matching::fresh(Some(*created_at), ttl, db::now()))
+        .map(|(context, _)| context.0))
+}
+
+/// The context of the cell `lat`/`lon`, from the cache or else the provider;
+/// `Ok(None)` when neither has one.
+async fn context(
+    (lat, lon): (&str, &str),
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    options: &LookupOptions,
+) -> Result<Option<Value>, UpstreamError> {
+    let cached = match options.refresh {
+        true => None,
+        false => cached(pool, &options.namespace, (lat, lon))
+            .await
+            .map_err(database_error)?,
+    };
+    metrics::increment(
+        "gaia_context_lookups_total",
+        &[("result", if cached.is_some() { "hit" } else { "miss" })],
+    );
+    if cached.is_some() || options.cache_only || dry_run::enabled() {
+        return Ok(cached);
+    }
+    let caller = Caller {
+        tenant: options.tenant.as_deref(),
+        client: options.client,
+    };
+    let Some(context) = upstream.context(pool, (lat, lon), caller).await? else {
+        return Ok(None);
+    };
+    tenants::record_upstream_call(pool.clone(), options.tenant.clone());
+    sqlx::query(
+        "INSERT OR REPLACE INTO point_context(namespace, lat, lon, context, created_at) \
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&options.namespace)
+    .bind(lat)
+    .bind(lon)
+    .bind(&context)
+    .bind(db::now())
+    .execute(&**pool)
+    .await
+    .map_err(database_error)?;
+    Ok(Some(context))
+}
+
+/// `GET /api/v0/context`.
+pub async fn get_context(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    let (lat, lon) = match params::point(&params) {
+        Ok((lat, lon)) => (
+            precision::round(lat, settings()),
+            precision::round_lon(lon, settings()),
+        ),
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+
+    let cell = [("x-gaia-cell", format!("{},{}", lat, lon))];
+    match context((&lat, &lon), &pool, &upstream, &options).await {
+        Ok(Some(context)) => (StatusCode::OK, cell, Json(context)).into_response(),
+        Ok(None) if options.cache_only || dry_run::enabled() => {
+            (StatusCode::NOT_FOUND, cell, Json(json!("not in cache"))).into_response()
+        }
+        Ok(None) => (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!(format!(
+                "the {} provider has no context",
+                upstream.provider.name()
+            ))),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
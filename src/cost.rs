@@ -0,0 +1,165 @@
+//! What the provider costs, and would cost with more or less caching.
+//!
+//! `UPSTREAM_PRICE_PER_1000` is the provider's price per thousand calls
+//! (default 0, so estimates are call counts until it is set), in
+//! `UPSTREAM_PRICE_CURRENCY` (default `USD`), with the first
+//! `UPSTREAM_FREE_CALLS_PER_MONTH` each month free. Only Radar calls are
+//! priced, and rate limited or failed-to-connect ones aren't counted.
+//!
+//! `GET /api/v0/admin/cost-estimate?days=7&hitRate=` averages the last
+//! `days` of the audit log and query analytics into a 30-day projection
+//! for the cache as it is, for no cache at all, and for `hitRate` if given.
+//! Lookup counts need `ANALYTICS_ENABLED`; without them only the current
+//! spend can be projected.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{analytics, audit, auth::Admin, config, db, params};
+
+#[derive(Debug)]
+struct Pricing {
+    per_1000: f64,
+    currency: String,
+    free_per_month: f64,
+}
+
+impl Pricing {
+    fn from_env() -> Self {
+        Pricing {
+            per_1000: config::var("UPSTREAM_PRICE_PER_1000", 0.0),
+            currency: config::var("UPSTREAM_PRICE_CURRENCY", String::from("USD")),
+            free_per_month: config::var("UPSTREAM_FREE_CALLS_PER_MONTH", 0.0),
+        }
+    }
+
+    /// The monthly bill for `calls` in a month.
+    fn monthly(&self, calls: f64) -> f64 {
+        (calls - self.free_per_month).max(0.0) / 1000.0 * self.per_1000
+    }
+}
+
+/// Read the `UPSTREAM_PRICE_*` settings.
+pub fn check() {
+    Pricing::from_env();
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Projection {
+    /// Provider calls in 30 days at this rate.
+    pub calls: f64,
+    pub cost: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CostEstimate {
+    pub days: i64,
+    pub price_per_1000: f64,
+    pub currency: String,
+    pub free_calls_per_month: f64,
+    /// Lookups in the window, when analytics are on.
+    pub lookups: Option<i64>,
+    pub hits: Option<i64>,
+    pub hit_rate: Option<f64>,
+    /// Priced provider calls in the window, when auditing is on.
+    pub upstream_calls: Option<i64>,
+    pub current: Option<Projection>,
+    pub without_cache: Option<Projection>,
+    /// At the requested `hitRate`.
+    pub at_hit_rate: Option<Projection>,
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("cost estimate query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+pub async fn get_cost_estimate(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> Response {
+    let days = match params::optional::<i64>(&params, "days", 7) {
+        Ok(days) if (1..=90).contains(&days) => days,
+        Ok(_) => return params::bad_request("days must be between 1 and 90").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let hit_rate = match params.get("hitRate").map(|r| r.parse::<f64>()) {
+        None => None,
+        Some(Ok(rate)) if (0.0..=1.0).contains(&rate) => Some(rate),
+        Some(_) => return params::bad_request("hitRate must be between 0 and 1").into_response(),
+    };
+    let since = db::now() - days * 86400;
+
+    let lookups = if analytics::enabled() {
+        match sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COALESCE(SUM(queries), 0), COALESCE(SUM(hits), 0) FROM query_stats \
+             WHERE hour >= ?",
+        )
+        .bind(since / 3600 * 3600)
+        .fetch_one(&*pool)
+        .await
+        {
+            Ok(lookups) => Some(lookups),
+            Err(e) => return database_error(e),
+        }
+    } else {
+        None
+    };
+    let upstream_calls = if audit::enabled() {
+        match sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM upstream_calls WHERE called_at >= ? AND provider = 'radar' \
+             AND outcome NOT IN ('rate_limited', 'transport_error')",
+        )
+        .bind(since)
+        .fetch_one(&*pool)
+        .await
+        {
+            Ok((calls,)) => Some(calls),
+            Err(e) => return database_error(e),
+        }
+    } else {
+        None
+    };
+
+    let pricing = Pricing::from_env();
+    let month = |calls: f64| {
+        let calls = calls / days as f64 * 30.0;
+        Projection {
+            calls,
+            cost: pricing.monthly(calls),
+        }
+    };
+    let misses_at = |rate: f64| lookups.map(|(queries, _)| month(queries as f64 * (1.0 - rate)));
+    Json(CostEstimate {
+        days,
+        price_per_1000: pricing.per_1000,
+        currency: pricing.currency.clone(),
+        free_calls_per_month: pricing.free_per_month,
+        lookups: lookups.map(|(queries, _)| queries),
+        hits: lookups.map(|(_, hits)| hits),
+        hit_rate: lookups
+            .filter(|&(queries, _)| queries > 0)
+            .map(|(queries, hits)| hits as f64 / queries as f64),
+        upstream_calls,
+        current: upstream_calls.map(|calls| month(calls as f64)),
+        without_cache: misses_at(0.0),
+        at_hit_rate: hit_rate.and_then(misses_at),
+    })
+    .into_response()
+}
@@ -0,0 +1,166 @@
+//! Checking the provider accepts its keys before a cache miss finds out.
+//!
+//! `VERIFY_PROVIDER_KEYS=warn` makes one reverse geocode with each Radar key
+//! at startup, and with each new key a rotated key file brings in, logging
+//! any the provider rejects; `fail` refuses to start with a rejected key and
+//! keeps using the previous keys when a rotation brings one in. A key that
+//! can't be checked because the provider is unreachable is only ever
+//! warned about. Off by default, as each check is a billed call; they are
+//! audited like any other.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config, dry_run,
+    fixtures::Mode,
+    keys::ApiKey,
+    metrics,
+    upstream::{Provider, Upstream, UpstreamError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verify {
+    Off,
+    Warn,
+    Fail,
+}
+
+fn mode() -> Verify {
+    static MODE: OnceLock<Verify> = OnceLock::new();
+    *MODE.get_or_init(
+        || match config::var("VERIFY_PROVIDER_KEYS", String::from("off")).as_str() {
+            "off" => Verify::Off,
+            "warn" => Verify::Warn,
+            "fail" => Verify::Fail,
+            other => panic!("Invalid VERIFY_PROVIDER_KEYS: {}", other),
+        },
+    )
+}
+
+/// Read `VERIFY_PROVIDER_KEYS`.
+pub fn check() {
+    mode();
+}
+
+/// Whether keys are checked at all: only Radar takes them, and only when it
+/// would actually be called.
+fn enabled(upstream: &Upstream) -> bool {
+    let radar = upstream.provider == Provider::Radar
+        || upstream.merge == Some(Provider::Radar)
+        || upstream.routes.providers().any(|p| p == Provider::Radar);
+    let replaying = upstream
+        .fixtures
+        .as_ref()
+        .is_some_and(|f| f.mode == Mode::Replay);
+    mode() != Verify::Off && radar && !upstream.offline && !replaying && !dry_run::enabled()
+}
+
+/// Check `key`; false only when the provider rejected it.
+async fn accepted(pool: &Arc<Pool<Sqlite>>, upstream: &Upstream, key: &ApiKey) -> bool {
+    let (accepted, result) = match upstream.verify_key(pool, key).await {
+        // a rate limited key still authenticated
+        Ok(()) | Err(UpstreamError::RateLimited(_)) => (true, "ok"),
+        Err(UpstreamError::Status(401 | 403)) => {
+            tracing::error!("the provider rejected API key {}", key.label());
+            (false, "rejected")
+        }
+        Err(e) => {
+            tracing::warn!("could not verify API key {}: {}", key.label(), e);
+            (true, "unknown")
+        }
+    };
+    metrics::increment(
+        "gaia_upstream_key_verifications_total",
+        &[("key", &key.label()), ("result", result)],
+    );
+    accepted
+}
+
+/// Check every key at startup, exiting in `fail` mode if one is rejected.
+pub async fn verify(pool: &Arc<Pool<Sqlite>>, upstream: &Upstream) {
+    if !enabled(upstream) {
+        return;
+    }
+    let mut rejected = 0;
+    for key in upstream.keys.all() {
+        if !accepted(pool, upstream, &key).await {
+            rejected += 1;
+        }
+    }
+    if rejected == 0 {
+        tracing::info!("verified the provider API keys");
+    } else if mode() == Verify::Fail {
+        eprintln!(
+            "gaia: the provider rejected {} API key(s); fix them or set VERIFY_PROVIDER_KEYS=warn",
+            rejected
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Switch to the keys in `path` if they changed there, checking the new
+/// ones first. `refused` is the last set turned down, so the same file isn't
+/// checked (and billed) again until it changes.
+async fn reload(
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    path: &PathBuf,
+    refused: &mut Option<Vec<String>>,
+) {
+    let Some(wanted) = upstream.keys.changed(path) else {
+        return;
+    };
+    if refused.as_ref() == Some(&wanted) {
+        return;
+    }
+    if !enabled(upstream) {
+        upstream.keys.replace(&wanted);
+        tracing::info!(
+            "reloaded {} API key(s) from {}",
+            wanted.len(),
+            path.display()
+        );
+        return;
+    }
+    let mut rejected = false;
+    for value in wanted.iter().filter(|v| !upstream.keys.contains(v)) {
+        rejected |= !accepted(pool, upstream, &ApiKey::new(value)).await;
+    }
+    if rejected && mode() == Verify::Fail {
+        tracing::error!(
+            "not switching to the keys in {}: the provider rejected a new one",
+            path.display()
+        );
+        *refused = Some(wanted);
+        return;
+    }
+    *refused = None;
+    upstream.keys.replace(&wanted);
+    tracing::info!(
+        "reloaded {} API key(s) from {}",
+        wanted.len(),
+        path.display()
+    );
+}
+
+/// If the keys came from a file, poll it every `SECRETS_RELOAD_SECS` so a
+/// rotated secret mount is picked up without a restart.
+pub fn watch(pool: Arc<Pool<Sqlite>>, upstream: Arc<Upstream>) {
+    let Some(path) = upstream.keys.source().cloned() else {
+        return;
+    };
+    let interval = Duration::from_secs(config::var("SECRETS_RELOAD_SECS", 30).max(1));
+    tokio::spawn(async move {
+        let mut refused = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            reload(&pool, &upstream, &path, &mut refused).await;
+        }
+    });
+}
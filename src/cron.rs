@@ -0,0 +1,160 @@
+//! Minimal five-field cron expressions (`minute hour day-of-month month
+//! day-of-week`, always UTC) plus the usual `@hourly`-style shorthands.
+//!
+//! Each field accepts `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps
+//! (`*/15`, `0-30/10`). Day-of-week is 0-7 with both 0 and 7 = Sunday.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    source: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // cron's quirk: if both day fields are restricted, either may match
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; (max + 1) as usize];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid step in {:?}", part))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start
+                    .parse()
+                    .map_err(|_| format!("invalid range {:?}", part))?,
+                end.parse()
+                    .map_err(|_| format!("invalid range {:?}", part))?,
+            )
+        } else {
+            let value = range
+                .parse()
+                .map_err(|_| format!("invalid value {:?}", part))?;
+            // `5/10` means "from 5 every 10"
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{:?} out of range {}-{}", part, min, max));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            allowed[v as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields in cron expression {:?}",
+                expression
+            ));
+        };
+        Ok(Schedule {
+            source: expression.trim().to_string(),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays: {
+                // both 0 and 7 mean Sunday
+                let mut weekdays = parse_field(weekday, 0, 7)?;
+                weekdays[0] |= weekdays.pop().unwrap_or(false);
+                weekdays
+            },
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+        })
+    }
+
+    /// The first matching minute strictly after `after` (unix seconds).
+    /// Looks at most four years ahead, enough for any satisfiable schedule.
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let mut minute = after.div_euclid(60) + 1;
+        let limit = minute + 4 * 366 * 24 * 60;
+        while minute < limit {
+            let days = minute.div_euclid(24 * 60);
+            let (_, month, day) = civil_from_days(days);
+            // 1970-01-01 was a Thursday
+            let weekday = (days + 4).rem_euclid(7) as usize;
+            let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+                (true, true) => self.days[day as usize] || self.weekdays[weekday],
+                _ => self.days[day as usize] && self.weekdays[weekday],
+            };
+            if !self.months[month as usize] || !day_matches {
+                // skip to the next day
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            let hour = minute.rem_euclid(24 * 60) / 60;
+            if !self.hours[hour as usize] {
+                minute = days * 24 * 60 + (hour + 1) * 60;
+                continue;
+            }
+            if self.minutes[minute.rem_euclid(60) as usize] {
+                return Some(minute * 60);
+            }
+            minute += 1;
+        }
+        None
+    }
+}
+
+/// (year, month, day) for a count of days since 1970-01-01, per Howard
+/// Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since 1970-01-01 for a (year, month, day), the inverse of
+/// `civil_from_days`.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,173 @@
+//! `GET /api/v0/admin/dashboard`: the numbers a status page shows, in one
+//! document so it needs one request.
+//!
+//! "Today" is the UTC day so far, as for tenant quotas. The hit rate needs
+//! `ANALYTICS_ENABLED` and the provider calls, their error rate and the
+//! per-provider breakdown need the audit log; without them those fields are
+//! null. The request counts under `errors` are since this instance started,
+//! like `/metrics`.
+
+use std::sync::Arc;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{analytics, audit, auth::Admin, db, metrics, tenants, upstream::Upstream};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    pub tenant: String,
+    pub used: i64,
+    pub quota: i64,
+    pub remaining: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheSize {
+    pub addresses: i64,
+    pub forward_queries: i64,
+    pub database_bytes: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobQueue {
+    pub queued: i64,
+    pub running: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Errors {
+    /// Failed provider calls today over all of them.
+    pub upstream_error_rate: Option<f64>,
+    pub panics: u64,
+    pub timed_out: u64,
+    pub shed: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Dashboard {
+    pub at: i64,
+    pub lookups_today: Option<i64>,
+    pub hit_rate: Option<f64>,
+    pub upstream_calls_today: Option<i64>,
+    /// Today's calls for each provider.
+    pub providers: Option<Vec<audit::ProviderStats>>,
+    pub circuit: String,
+    pub quotas: Vec<Quota>,
+    pub cache: CacheSize,
+    pub jobs: JobQueue,
+    pub errors: Errors,
+}
+
+async fn dashboard(pool: &Pool<Sqlite>, upstream: &Upstream) -> Result<Dashboard, sqlx::Error> {
+    let now = db::now();
+    let today = now / 86400 * 86400;
+
+    let lookups = if analytics::enabled() {
+        Some(
+            sqlx::query_as::<_, (i64, i64)>(
+                "SELECT COALESCE(SUM(queries), 0), COALESCE(SUM(hits), 0) FROM query_stats \
+                 WHERE hour >= ?",
+            )
+            .bind(today)
+            .fetch_one(pool)
+            .await?,
+        )
+    } else {
+        None
+    };
+    let providers = if audit::enabled() {
+        Some(audit::provider_stats(pool, today, i64::MAX, None).await?)
+    } else {
+        None
+    };
+    let calls = providers.as_ref().map(|providers| {
+        providers.iter().fold((0, 0), |(calls, failed), p| {
+            (calls + p.calls, failed + p.calls - p.ok)
+        })
+    });
+
+    let quotas = tenants::quota_usage(pool)
+        .await?
+        .into_iter()
+        .map(|(tenant, used, quota)| Quota {
+            tenant,
+            used,
+            quota,
+            remaining: (quota - used).max(0),
+        })
+        .collect();
+
+    let (addresses,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM geocode")
+        .fetch_one(pool)
+        .await?;
+    let (forward_queries,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM forward_geocode")
+        .fetch_one(pool)
+        .await?;
+    let (database_bytes,) = sqlx::query_as::<_, (i64,)>(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+    )
+    .fetch_one(pool)
+    .await?;
+    let (queued, running) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COALESCE(SUM(status = 'queued'), 0), COALESCE(SUM(status = 'running'), 0) \
+         FROM jobs WHERE status IN ('queued', 'running')",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Dashboard {
+        at: now,
+        lookups_today: lookups.map(|(queries, _)| queries),
+        hit_rate: lookups
+            .filter(|&(queries, _)| queries > 0)
+            .map(|(queries, hits)| hits as f64 / queries as f64),
+        upstream_calls_today: calls.map(|(calls, _)| calls),
+        providers,
+        circuit: upstream.breaker.state().to_string(),
+        quotas,
+        cache: CacheSize {
+            addresses,
+            forward_queries,
+            database_bytes,
+        },
+        jobs: JobQueue { queued, running },
+        errors: Errors {
+            upstream_error_rate: calls
+                .filter(|&(calls, _)| calls > 0)
+                .map(|(calls, failed)| failed as f64 / calls as f64),
+            panics: metrics::total("gaia_panics_total"),
+            timed_out: metrics::total("gaia_requests_timed_out_total"),
+            shed: metrics::total("gaia_requests_shed_total"),
+        },
+    })
+}
+
+pub async fn get_dashboard(
+    _: Admin,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    match dashboard(&pool, &upstream).await {
+        Ok(dashboard) => Json(dashboard).into_response(),
+        Err(e) => {
+            tracing::error!("dashboard query failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("database error")),
+            )
+                .into_response()
+        }
+    }
+}
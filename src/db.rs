@@ -0,0 +1,486 @@
+use std::{
+    ffi::CString,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use libsqlite3_sys as ffi;
+use log::LevelFilter;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    ConnectOptions, Pool, Sqlite,
+};
+
+use crate::{compression, config, metrics};
+
+/// How often `spawn_metrics` samples the pool.
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Schema migrations, applied in order at startup. Each one is recorded in
+/// `schema_migrations` by name so it only ever runs once per database.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "2024-06-25-create-db",
+        include_str!("../migrations/2024-06-25-create-db.sql"),
+    ),
+    (
+        "2026-10-14-create-geofences",
+        include_str!("../migrations/2026-10-14-create-geofences.sql"),
+    ),
+    (
+        "2026-10-14-add-geocode-freshness",
+        include_str!("../migrations/2026-10-14-add-geocode-freshness.sql"),
+    ),
+    (
+        "2026-10-14-create-watches",
+        include_str!("../migrations/2026-10-14-create-watches.sql"),
+    ),
+    (
+        "2026-10-14-create-geocode-raw",
+        include_str!("../migrations/2026-10-14-create-geocode-raw.sql"),
+    ),
+    (
+        "2026-10-14-add-geocode-address-columns",
+        include_str!("../migrations/2026-10-14-add-geocode-address-columns.sql"),
+    ),
+    (
+        "2026-10-14-create-geocode-fts",
+        include_str!("../migrations/2026-10-14-create-geocode-fts.sql"),
+    ),
+    (
+        "2026-10-14-add-geocode-coordinates",
+        include_str!("../migrations/2026-10-14-add-geocode-coordinates.sql"),
+    ),
+    (
+        "2026-10-14-create-query-stats",
+        include_str!("../migrations/2026-10-14-create-query-stats.sql"),
+    ),
+    (
+        "2026-10-14-create-fetch-leases",
+        include_str!("../migrations/2026-10-14-create-fetch-leases.sql"),
+    ),
+    (
+        "2026-10-14-add-tenants",
+        include_str!("../migrations/2026-10-14-add-tenants.sql"),
+    ),
+    (
+        "2026-10-14-create-upstream-calls",
+        include_str!("../migrations/2026-10-14-create-upstream-calls.sql"),
+    ),
+    (
+        "2026-10-14-create-jobs",
+        include_str!("../migrations/2026-10-14-create-jobs.sql"),
+    ),
+    (
+        "2026-10-14-add-job-idempotency",
+        include_str!("../migrations/2026-10-14-add-job-idempotency.sql"),
+    ),
+    (
+        "2026-10-14-add-upstream-call-client",
+        include_str!("../migrations/2026-10-14-add-upstream-call-client.sql"),
+    ),
+    (
+        "2026-10-14-create-dry-run-misses",
+        include_str!("../migrations/2026-10-14-create-dry-run-misses.sql"),
+    ),
+    (
+        "2026-10-14-create-forward-geocode",
+        include_str!("../migrations/2026-10-14-create-forward-geocode.sql"),
+    ),
+    (
+        "2026-10-14-add-upstream-call-results",
+        include_str!("../migrations/2026-10-14-add-upstream-call-results.sql"),
+    ),
+    (
+        "2026-10-14-create-place-search",
+        include_str!("../migrations/2026-10-14-create-place-search.sql"),
+    ),
+    (
+        "2026-10-14-create-point-context",
+        include_str!("../migrations/2026-10-14-create-point-context.sql"),
+    ),
+    (
+        "2026-10-14-create-autocomplete",
+        include_str!("../migrations/2026-10-14-create-autocomplete.sql"),
+    ),
+    (
+        "2026-10-14-create-geocode-trigrams",
+        include_str!("../migrations/2026-10-14-create-geocode-trigrams.sql"),
+    ),
+    (
+        "2026-10-14-create-compression-dictionaries",
+        include_str!("../migrations/2026-10-14-create-compression-dictionaries.sql"),
+    ),
+    (
+        "2026-10-14-add-geocode-address-format",
+        include_str!("../migrations/2026-10-14-add-geocode-address-format.sql"),
+    ),
+    (
+        "2026-10-14-add-geocode-schema-version",
+        include_str!("../migrations/2026-10-14-add-geocode-schema-version.sql"),
+    ),
+    (
+        "2026-10-14-add-geofence-tenant",
+        include_str!("../migrations/2026-10-14-add-geofence-tenant.sql"),
+    ),
+    (
+        "2026-10-14-add-job-node",
+        include_str!("../migrations/2026-10-14-add-job-node.sql"),
+    ),
+];
+
+/// `DATABASE_KEY` (or `DATABASE_KEY_FILE`): the passphrase the database is
+/// encrypted with. Needs a build with the `sqlcipher` feature.
+pub fn database_key() -> Option<String> {
+    config::secret("DATABASE_KEY").filter(|key| !key.is_empty())
+}
+
+/// `key` as a SQL string literal for `PRAGMA key`.
+fn key_literal(key: &str) -> String {
+    format!("'{}'", key.replace('\'', "''"))
+}
+
+/// How the pool `connect` opens is sized and how long it waits, in place of
+/// sqlx's defaults.
+#[derive(Debug)]
+struct PoolSettings {
+    /// `DATABASE_MAX_CONNECTIONS` (default 10).
+    max_connections: u32,
+    /// `DATABASE_MIN_CONNECTIONS` (default 0): kept open even when idle.
+    min_connections: u32,
+    /// `DATABASE_ACQUIRE_TIMEOUT_SECS` (default 30): how long a query waits
+    /// for a connection before failing.
+    acquire_timeout: Duration,
+    /// `DATABASE_IDLE_TIMEOUT_SECS` (default 600): how long a connection over
+    /// the minimum stays open unused. Zero keeps them open.
+    idle_timeout: Option<Duration>,
+    /// `DATABASE_STATEMENT_CACHE_CAPACITY` (default 100): prepared statements
+    /// kept per connection.
+    statement_cache_capacity: usize,
+}
+
+/// Read the pool settings.
+pub fn check() {
+    pool_settings();
+}
+
+fn pool_settings() -> &'static PoolSettings {
+    static SETTINGS: OnceLock<PoolSettings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let settings = PoolSettings {
+            max_connections: config::var("DATABASE_MAX_CONNECTIONS", 10u32),
+            min_connections: config::var("DATABASE_MIN_CONNECTIONS", 0u32),
+            acquire_timeout: Duration::from_secs(config::var(
+                "DATABASE_ACQUIRE_TIMEOUT_SECS",
+                30u64,
+            )),
+            idle_timeout: Some(config::var("DATABASE_IDLE_TIMEOUT_SECS", 600u64))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            statement_cache_capacity: config::var("DATABASE_STATEMENT_CACHE_CAPACITY", 100usize),
+        };
+        if settings.min_connections > settings.max_connections {
+            panic!(
+                "Invalid DATABASE_MIN_CONNECTIONS: {} is more than DATABASE_MAX_CONNECTIONS ({})",
+                settings.min_connections, settings.max_connections
+            );
+        }
+        settings
+    })
+}
+
+/// `DATABASE_SLOW_QUERY_MS` (default 1000): statements that take longer,
+/// waiting on a locked database included, are logged as warnings with their
+/// SQL. Zero logs none.
+fn slow_query_threshold() -> Option<Duration> {
+    let millis = config::var("DATABASE_SLOW_QUERY_MS", 1000u64);
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+/// `DATABASE_URL`, parsed. Only SQLite is supported: the schema and queries
+/// lean on FTS5, SQLite's JSON functions and its online backup API. Any other
+/// scheme is refused here, as sqlx would otherwise take a `mysql://` URL for
+/// the name of a SQLite file.
+pub fn connect_options() -> SqliteConnectOptions {
+    sqlite_options(
+        "DATABASE_URL",
+        &config::secret("DATABASE_URL").expect("Missing DATABASE_URL"),
+    )
+}
+
+/// `DATABASE_READ_URL`, parsed: a read-only copy of the database kept up to
+/// date by something else, like a LiteFS or Litestream replica, which cache
+/// lookups read instead of `DATABASE_URL`. Unset reads the primary.
+pub fn read_options() -> Option<SqliteConnectOptions> {
+    let url = config::secret("DATABASE_READ_URL").filter(|url| !url.is_empty())?;
+    Some(sqlite_options("DATABASE_READ_URL", &url).read_only(true))
+}
+
+/// `url`, the value of `var`, as SQLite connect options.
+fn sqlite_options(var: &str, url: &str) -> SqliteConnectOptions {
+    match url.split_once("://") {
+        // libsql speaks SQLite's dialect, but over its own protocol, while
+        // gaia opens the file through the SQLite library
+        Some(("libsql", _)) => panic!(
+            "Invalid {}: libsql (Turso, sqld) servers aren't supported, only a local \
+             sqlite:<path>",
+            var
+        ),
+        Some((scheme, _)) if scheme != "sqlite" => panic!(
+            "Invalid {}: {} databases aren't supported, only sqlite:<path>",
+            var, scheme
+        ),
+        _ => {}
+    }
+    let options =
+        SqliteConnectOptions::from_str(url).unwrap_or_else(|e| panic!("Invalid {}: {}", var, e));
+    match slow_query_threshold() {
+        Some(threshold) => options.log_slow_statements(LevelFilter::Warn, threshold),
+        None => options.log_slow_statements(LevelFilter::Off, Duration::MAX),
+    }
+}
+
+/// The `DATABASE_READ_URL` pool, once `connect` has opened it.
+static REPLICA: OnceLock<Pool<Sqlite>> = OnceLock::new();
+
+/// The pool cache lookups read from: the replica if there is one, else
+/// `primary`. Rows written moments ago may not have reached the replica yet.
+pub fn reads(primary: &Pool<Sqlite>) -> &Pool<Sqlite> {
+    REPLICA.get().unwrap_or(primary)
+}
+
+/// A pool on `options`, sized by the pool settings and unlocked with
+/// `DATABASE_KEY`.
+async fn open(options: SqliteConnectOptions) -> Pool<Sqlite> {
+    let settings = pool_settings();
+    let mut options = options.statement_cache_capacity(settings.statement_cache_capacity);
+    let key = database_key();
+    if let Some(key) = &key {
+        // sqlx issues `key` before any other pragma, as SQLCipher requires
+        options = options.pragma("key", key_literal(key));
+    }
+    let pool = SqlitePoolOptions::new()
+        .max_connections(settings.max_connections)
+        .min_connections(settings.min_connections)
+        .acquire_timeout(settings.acquire_timeout)
+        .idle_timeout(settings.idle_timeout)
+        .connect_with(options)
+        .await
+        .unwrap();
+
+    if key.is_some() {
+        // plain SQLite accepts `PRAGMA key` and silently ignores it
+        let cipher = sqlx::query_as::<_, (String,)>("PRAGMA cipher_version")
+            .fetch_optional(&pool)
+            .await
+            .unwrap();
+        match cipher {
+            Some((version,)) => tracing::info!("database encrypted with SQLCipher {}", version),
+            None => panic!("DATABASE_KEY is set but gaia was built without the sqlcipher feature"),
+        }
+    }
+    pool
+}
+
+/// Connect to `DATABASE_URL` and bring the schema up to date, then to
+/// `DATABASE_READ_URL` if it is set.
+pub async fn connect() -> Pool<Sqlite> {
+    let pool = open(connect_options()).await;
+    migrate(&pool).await.expect("Failed to apply migrations");
+    compression::load(&pool)
+        .await
+        .expect("Failed to load compression dictionaries");
+
+    if let Some(options) = read_options() {
+        let replica = open(options).await;
+        // a replica still behind on migrations can't answer today's queries
+        let (applied,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM schema_migrations")
+            .fetch_one(&replica)
+            .await
+            .expect("Failed to read DATABASE_READ_URL");
+        if applied < MIGRATIONS.len() as i64 {
+            panic!(
+                "Invalid DATABASE_READ_URL: it has {} of {} migrations applied",
+                applied,
+                MIGRATIONS.len()
+            );
+        }
+        tracing::info!("cache lookups read from DATABASE_READ_URL");
+        REPLICA.set(replica).ok();
+    }
+    pool
+}
+
+/// Sample the pool every `METRICS_INTERVAL`: connections in use and idle as
+/// `gaia_db_connections`, the most it opens as `gaia_db_connections_max`,
+/// and how long a query would wait for a connection as
+/// `gaia_db_acquire_seconds`, timed by taking one.
+pub fn spawn_metrics(pool: Arc<Pool<Sqlite>>) {
+    metrics::set_gauge(
+        "gaia_db_connections_max",
+        &[],
+        f64::from(pool.options().get_max_connections()),
+    );
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METRICS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let idle = pool.num_idle() as u32;
+            let active = pool.size().saturating_sub(idle);
+            metrics::set_gauge(
+                "gaia_db_connections",
+                &[("state", "active")],
+                f64::from(active),
+            );
+            metrics::set_gauge("gaia_db_connections", &[("state", "idle")], f64::from(idle));
+
+            let started = Instant::now();
+            match pool.acquire().await {
+                Ok(_) => metrics::observe(
+                    "gaia_db_acquire_seconds",
+                    &[],
+                    started.elapsed().as_secs_f64(),
+                ),
+                Err(e) => {
+                    tracing::warn!("no database connection to be had: {}", e);
+                    metrics::increment("gaia_db_acquire_errors_total", &[]);
+                }
+            }
+        }
+    });
+}
+
+/// A private in-memory database with the schema applied. It is held on one
+/// connection, as every connection to `:memory:` opens a database of its own.
+pub async fn memory() -> Result<Pool<Sqlite>, sqlx::Error> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(SqliteConnectOptions::from_str("sqlite::memory:")?)
+        .await?;
+    migrate(&pool).await?;
+    compression::load(&pool).await?;
+    Ok(pool)
+}
+
+pub async fn migrate(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_migrations (name TEXT PRIMARY KEY)")
+        .execute(pool)
+        .await?;
+
+    for (name, sql) in MIGRATIONS {
+        let applied: Option<(String,)> =
+            sqlx::query_as("SELECT name FROM schema_migrations WHERE name = ?")
+                .bind(name)
+                .fetch_optional(pool)
+                .await?;
+        if applied.is_some() {
+            continue;
+        }
+
+        tracing::info!("applying migration {}", name);
+        let mut tx = pool.begin().await?;
+        sqlx::raw_sql(sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_migrations(name) VALUES (?)")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// Seconds since the unix epoch, as stored in timestamp columns.
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Copy the database to `dest` with SQLite's online backup API, giving a
+/// consistent snapshot without stopping writers. An encrypted database's
+/// snapshot is encrypted with the same key.
+pub async fn snapshot(pool: &Pool<Sqlite>, dest: &Path) -> Result<(), String> {
+    let dest = dest
+        .to_str()
+        .and_then(|d| CString::new(d).ok())
+        .ok_or_else(|| format!("invalid snapshot path {:?}", dest))?;
+    let keying = database_key()
+        .map(|key| CString::new(format!("PRAGMA key = {}", key_literal(&key))))
+        .transpose()
+        .map_err(|_| "invalid DATABASE_KEY".to_string())?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    // on a blocking thread that owns the connection, so the copy neither
+    // stalls the runtime nor outlives the handle if the caller goes away
+    tokio::task::spawn_blocking(move || {
+        let runtime = tokio::runtime::Handle::current();
+        let mut handle = runtime
+            .block_on(conn.lock_handle())
+            .map_err(|e| e.to_string())?;
+        let source = handle.as_raw_handle().as_ptr();
+        // a single step copies every page while holding the read lock
+        unsafe { backup(source, &dest, keying.as_ref()) }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Copy the `main` database of `source` into a new database at `dest`,
+/// keyed with `keying` first if set.
+unsafe fn backup(
+    source: *mut ffi::sqlite3,
+    dest: &CString,
+    keying: Option<&CString>,
+) -> Result<(), String> {
+    let mut target = std::ptr::null_mut();
+    let keyed = |target| match keying {
+        Some(sql) => {
+            let (arg, err) = (std::ptr::null_mut(), std::ptr::null_mut());
+            ffi::sqlite3_exec(target, sql.as_ptr(), None, arg, err) == ffi::SQLITE_OK
+        }
+        None => true,
+    };
+    let result = if ffi::sqlite3_open(dest.as_ptr(), &mut target) != ffi::SQLITE_OK
+        || !keyed(target)
+    {
+        Err(errmsg(target))
+    } else {
+        let backup = ffi::sqlite3_backup_init(target, c"main".as_ptr(), source, c"main".as_ptr());
+        if backup.is_null() {
+            Err(errmsg(target))
+        } else {
+            let step = ffi::sqlite3_backup_step(backup, -1);
+            ffi::sqlite3_backup_finish(backup);
+            match step {
+                ffi::SQLITE_DONE => Ok(()),
+                _ => Err(errmsg(target)),
+            }
+        }
+    };
+    ffi::sqlite3_close(target);
+    result
+}
+
+unsafe fn errmsg(db: *mut ffi::sqlite3) -> String {
+    if db.is_null() {
+        return "out of memory".to_string();
+    }
+    std::ffi::CStr::from_ptr(ffi::sqlite3_errmsg(db))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The file behind a `sqlite:` database URL, for commands that work on the
+/// file itself rather than through a connection.
+pub fn database_path(url: &str) -> Option<&Path> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))?;
+    let path = path.split('?').next()?;
+    (!path.is_empty() && path != ":memory:").then(|| Path::new(path))
+}
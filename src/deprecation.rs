@@ -0,0 +1,126 @@
+//! Machine-readable notice that `/api/v0` is going away, ahead of removing
+//! it.
+//!
+//! Off until `V0_DEPRECATED_AT` is set, as unix seconds or an HTTP-date.
+//! From then every v0 response carries `Deprecation: @<unix seconds>`
+//! (RFC 9745) and a `Link` to the same endpoint under `/api/v1`
+//! (`rel="successor-version"`). `V0_SUNSET_AT` adds a `Sunset` HTTP-date
+//! (RFC 8594) for when v0 will stop answering, and `V0_DEPRECATION_LINK`
+//! a `rel="deprecation"` link to migration notes. The settings are logged
+//! as a warning at startup, and `gaia_deprecated_requests_total` counts
+//! the v0 requests still coming in.
+
+use std::{
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{OriginalUri, Request},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::metrics;
+
+#[derive(Debug)]
+struct Settings {
+    deprecated_at: u64,
+    sunset_at: Option<SystemTime>,
+    link: Option<String>,
+}
+
+/// `name` as unix seconds or an HTTP-date, if set.
+fn time_var(name: &str) -> Option<SystemTime> {
+    let value = std::env::var(name).ok()?;
+    let at = match value.trim().parse::<u64>() {
+        Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        Err(_) => {
+            httpdate::parse_http_date(&value).unwrap_or_else(|e| panic!("Invalid {}: {}", name, e))
+        }
+    };
+    Some(at)
+}
+
+/// Parse the `V0_*` times without logging them.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> Option<&'static Settings> {
+    static SETTINGS: OnceLock<Option<Settings>> = OnceLock::new();
+    SETTINGS
+        .get_or_init(|| {
+            let deprecated_at = time_var("V0_DEPRECATED_AT")?;
+            Some(Settings {
+                deprecated_at: deprecated_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                sunset_at: time_var("V0_SUNSET_AT"),
+                link: std::env::var("V0_DEPRECATION_LINK").ok(),
+            })
+        })
+        .as_ref()
+}
+
+/// Log the deprecation at startup, so operators see it too.
+pub fn warn() {
+    let Some(settings) = settings() else {
+        return;
+    };
+    let deprecated_at =
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(settings.deprecated_at));
+    match settings.sunset_at {
+        Some(sunset_at) => tracing::warn!(
+            "/api/v0 is deprecated as of {} and will be removed at {}; clients should move to /api/v1",
+            deprecated_at,
+            httpdate::fmt_http_date(sunset_at)
+        ),
+        None => tracing::warn!(
+            "/api/v0 is deprecated as of {}; clients should move to /api/v1",
+            deprecated_at
+        ),
+    }
+}
+
+/// Middleware adding the deprecation headers to v0 responses.
+pub async fn v0(request: Request, next: Next) -> Response {
+    let Some(settings) = settings() else {
+        return next.run(request).await;
+    };
+    metrics::increment("gaia_deprecated_requests_total", &[]);
+    // nested routers see the path below their prefix
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| request.uri().clone(), |original| original.0.clone());
+    let successor = uri
+        .path_and_query()
+        .map(|p| p.as_str().replacen("/api/v0/", "/api/v1/", 1));
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("@{}", settings.deprecated_at)) {
+        headers.insert("deprecation", value);
+    }
+    if let Some(sunset_at) = settings.sunset_at {
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(sunset_at)) {
+            headers.insert("sunset", value);
+        }
+    }
+    let links = [
+        successor.map(|s| format!("<{}>; rel=\"successor-version\"", s)),
+        settings
+            .link
+            .as_ref()
+            .map(|l| format!("<{}>; rel=\"deprecation\"", l)),
+    ];
+    for link in links.into_iter().flatten() {
+        if let Ok(value) = HeaderValue::from_str(&link) {
+            headers.append(header::LINK, value);
+        }
+    }
+    response
+}
@@ -0,0 +1,156 @@
+//! Dry-run mode, for sizing up a new provider or region before paying for
+//! it: cache misses are answered as if `cacheOnly` were set, and counted as
+//! what would have been fetched instead of being fetched.
+//!
+//! `DRY_RUN=true` turns it on. `GET /api/v0/admin/dry-run?since=&until=`
+//! reports the misses seen, how many distinct cells they were for (each one
+//! a provider call, since after the first the cell would be cached) and the
+//! same per day and per tenant. Cells are stored as `PRIVACY_MODE` allows,
+//! so with `truncate` nearby misses merge and the estimate runs low.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{auth::Admin, config, db, metrics, params, privacy};
+
+/// Whether `DRY_RUN` is on.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| config::var("DRY_RUN", false))
+}
+
+/// Count a miss for `lat`/`lon` that would have gone upstream. Done off the
+/// request path.
+pub fn record(pool: Arc<Pool<Sqlite>>, lat: &str, lon: &str, tenant: Option<&str>) {
+    metrics::increment("gaia_dry_run_misses_total", &[]);
+    tracing::info!("dry run: would fetch {}", privacy::coordinates(lat, lon));
+    let (lat, lon) = privacy::stored(lat, lon, None);
+    let tenant = tenant.unwrap_or_default().to_string();
+    tokio::spawn(async move {
+        let now = db::now();
+        if let Err(e) = sqlx::query(
+            "INSERT INTO dry_run_misses(lat, lon, tenant, first_seen, last_seen, requests) \
+             VALUES (?, ?, ?, ?, ?, 1) \
+             ON CONFLICT DO UPDATE SET last_seen = excluded.last_seen, requests = requests + 1",
+        )
+        .bind(lat)
+        .bind(lon)
+        .bind(tenant)
+        .bind(now)
+        .bind(now)
+        .execute(&*pool)
+        .await
+        {
+            tracing::warn!("failed to record dry run miss: {}", e);
+        }
+    });
+}
+
+#[derive(Serialize, FromRow, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Totals {
+    /// Lookups that missed the cache.
+    pub misses: i64,
+    /// Distinct cells among them: the provider calls they would have cost.
+    pub cells: i64,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DayTotals {
+    pub day: i64,
+    pub misses: i64,
+    pub cells: i64,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantTotals {
+    pub tenant: Option<String>,
+    pub misses: i64,
+    pub cells: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DryRunReport {
+    pub enabled: bool,
+    pub since: i64,
+    #[serde(flatten)]
+    pub totals: Totals,
+    pub by_day: Vec<DayTotals>,
+    pub by_tenant: Vec<TenantTotals>,
+}
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("dry run query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+/// `GET /api/v0/admin/dry-run?since=&until=`: what the misses first seen in
+/// the window would have fetched. `since` defaults to 30 days ago.
+pub async fn get_dry_run(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> Response {
+    let window = params::optional::<i64>(&params, "since", db::now() - 30 * 86400)
+        .and_then(|since| Ok((since, params::optional::<i64>(&params, "until", i64::MAX)?)));
+    let (since, until) = match window {
+        Ok(window) => window,
+        Err(e) => return e.into_response(),
+    };
+    // distinct cells across tenants, as the cache is shared unless isolated
+    let totals = sqlx::query_as::<_, Totals>(
+        "SELECT COALESCE(SUM(requests), 0) AS misses, \
+         (SELECT COUNT(*) FROM (SELECT DISTINCT lat, lon FROM dry_run_misses \
+          WHERE first_seen >= ?1 AND first_seen < ?2)) AS cells \
+         FROM dry_run_misses WHERE first_seen >= ?1 AND first_seen < ?2",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_one(&*pool)
+    .await;
+    let by_day = sqlx::query_as::<_, DayTotals>(
+        "SELECT first_seen / 86400 * 86400 AS day, SUM(requests) AS misses, \
+         COUNT(DISTINCT lat || ',' || lon) AS cells FROM dry_run_misses \
+         WHERE first_seen >= ? AND first_seen < ? GROUP BY day ORDER BY day",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_all(&*pool)
+    .await;
+    let by_tenant = sqlx::query_as::<_, TenantTotals>(
+        "SELECT NULLIF(tenant, '') AS tenant, SUM(requests) AS misses, COUNT(*) AS cells \
+         FROM dry_run_misses WHERE first_seen >= ? AND first_seen < ? \
+         GROUP BY tenant ORDER BY misses DESC",
+    )
+    .bind(since)
+    .bind(until)
+    .fetch_all(&*pool)
+    .await;
+    match (totals, by_day, by_tenant) {
+        (Ok(totals), Ok(by_day), Ok(by_tenant)) => Json(DryRunReport {
+            enabled: enabled(),
+            since,
+            totals,
+            by_day,
+            by_tenant,
+        })
+        .into_response(),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => database_error(e),
+    }
+}
@@ -0,0 +1,226 @@
+//! Targeted deletion for data-removal requests: everything cached or logged
+//! about a circle or polygon, optionally limited to a time range, without
+//! throwing away the rest of the cache.
+//!
+//! A cache entry is erased when either the coordinate it was looked up at or
+//! the address it resolved to falls inside the region. Query analytics are
+//! kept per rounded cell, so those go when the cell's center is inside, as
+//! do audited provider calls for coordinates inside the region.
+
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{auth::Admin, cache::METERS_PER_DEGREE, geofence::GeofenceShape, metrics};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ErasureRequest {
+    #[serde(flatten)]
+    pub shape: GeofenceShape,
+    /// Only data recorded at or after this unix time.
+    pub from: Option<i64>,
+    /// Only data recorded before this unix time.
+    pub to: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ErasureResult {
+    pub geocodes: u64,
+    pub raw_responses: u64,
+    pub query_stats: u64,
+    pub upstream_calls: u64,
+}
+
+#[derive(FromRow, Debug)]
+struct Candidate {
+    rowid: i64,
+    cell_lat: Option<f64>,
+    cell_lon: Option<f64>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    raw_id: Option<i64>,
+}
+
+/// `(min_lat, min_lon, max_lat, max_lon)` around the shape.
+fn bounds(shape: &GeofenceShape) -> (f64, f64, f64, f64) {
+    match shape {
+        GeofenceShape::Circle { lat, lon, radius } => {
+            let lat_span = radius / METERS_PER_DEGREE;
+            let lon_span = lat_span / lat.to_radians().cos().max(0.01);
+            (
+                lat - lat_span,
+                lon - lon_span,
+                lat + lat_span,
+                lon + lon_span,
+            )
+        }
+        GeofenceShape::Polygon { points } => points.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(min_lat, min_lon, max_lat, max_lon), p| {
+                (
+                    min_lat.min(p.lat),
+                    min_lon.min(p.lon),
+                    max_lat.max(p.lat),
+                    max_lon.max(p.lon),
+                )
+            },
+        ),
+    }
+}
+
+async fn erase(pool: &Pool<Sqlite>, req: &ErasureRequest) -> Result<ErasureResult, sqlx::Error> {
+    let (min_lat, min_lon, max_lat, max_lon) = bounds(&req.shape);
+    let (from, to) = (req.from.unwrap_or(i64::MIN), req.to.unwrap_or(i64::MAX));
+    let inside = |lat: Option<f64>, lon: Option<f64>| match (lat, lon) {
+        (Some(lat), Some(lon)) => req.shape.contains(lat, lon),
+        _ => false,
+    };
+
+    let geocodes = sqlx::query_as::<_, Candidate>(
+        "SELECT rowid, CAST(lat AS REAL) AS cell_lat, CAST(lon AS REAL) AS cell_lon, \
+         latitude, longitude, raw_id \
+         FROM geocode \
+         WHERE ((CAST(lat AS REAL) BETWEEN ? AND ? AND CAST(lon AS REAL) BETWEEN ? AND ?) \
+         OR (latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ?)) \
+         AND COALESCE(created_at, 0) >= ? AND COALESCE(created_at, 0) < ?",
+    )
+    .bind(min_lat)
+    .bind(max_lat)
+    .bind(min_lon)
+    .bind(max_lon)
+    .bind(min_lat)
+    .bind(max_lat)
+    .bind(min_lon)
+    .bind(max_lon)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter(|c| inside(c.cell_lat, c.cell_lon) || inside(c.latitude, c.longitude))
+    .collect::<Vec<_>>();
+
+    let cells = sqlx::query_as::<_, (String, String, i64)>(
+        "SELECT cell_lat, cell_lon, hour FROM query_stats \
+         WHERE CAST(cell_lat AS REAL) BETWEEN ? AND ? AND CAST(cell_lon AS REAL) BETWEEN ? AND ? \
+         AND hour >= ? AND hour < ?",
+    )
+    .bind(min_lat)
+    .bind(max_lat)
+    .bind(min_lon)
+    .bind(max_lon)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter(|(lat, lon, _)| inside(lat.parse().ok(), lon.parse().ok()))
+    .collect::<Vec<_>>();
+
+    let calls = sqlx::query_as::<_, (i64, String, String)>(
+        "SELECT id, lat, lon FROM upstream_calls \
+         WHERE CAST(lat AS REAL) BETWEEN ? AND ? AND CAST(lon AS REAL) BETWEEN ? AND ? \
+         AND called_at >= ? AND called_at < ?",
+    )
+    .bind(min_lat)
+    .bind(max_lat)
+    .bind(min_lon)
+    .bind(max_lon)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter(|(_, lat, lon)| inside(lat.parse().ok(), lon.parse().ok()))
+    .collect::<Vec<_>>();
+
+    let mut result = ErasureResult::default();
+    let mut tx = pool.begin().await?;
+    for candidate in &geocodes {
+        result.geocodes += sqlx::query("DELETE FROM geocode WHERE rowid = ?")
+            .bind(candidate.rowid)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+    }
+    // raw responses still referenced by entries outside the region stay
+    let mut raw_ids = geocodes.iter().filter_map(|c| c.raw_id).collect::<Vec<_>>();
+    raw_ids.sort();
+    raw_ids.dedup();
+    for raw_id in raw_ids {
+        result.raw_responses += sqlx::query(
+            "DELETE FROM geocode_raw WHERE id = ? \
+             AND NOT EXISTS (SELECT 1 FROM geocode WHERE raw_id = geocode_raw.id)",
+        )
+        .bind(raw_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    }
+    for (lat, lon, hour) in &cells {
+        result.query_stats +=
+            sqlx::query("DELETE FROM query_stats WHERE cell_lat = ? AND cell_lon = ? AND hour = ?")
+                .bind(lat)
+                .bind(lon)
+                .bind(hour)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+    }
+    for (id, ..) in &calls {
+        result.upstream_calls += sqlx::query("DELETE FROM upstream_calls WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+    }
+    tx.commit().await?;
+    Ok(result)
+}
+
+/// `POST /api/v0/admin/erase`: delete cached entries and query logs inside a
+/// region. The body is a geofence shape plus optional `from`/`to`.
+pub async fn post_erase(
+    _: Admin,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Json(req): Json<ErasureRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = req.shape.validate() {
+        return (StatusCode::BAD_REQUEST, Json(json!(e))).into_response();
+    }
+    if matches!((req.from, req.to), (Some(from), Some(to)) if from >= to) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!("from must be before to")),
+        )
+            .into_response();
+    }
+
+    match erase(&pool, &req).await {
+        Ok(result) => {
+            tracing::info!("erased {:?} for {:?}", result, req);
+            for (table, removed) in [
+                ("geocode", result.geocodes),
+                ("geocode_raw", result.raw_responses),
+                ("query_stats", result.query_stats),
+                ("upstream_calls", result.upstream_calls),
+            ] {
+                metrics::increment_by("gaia_erasure_removed_total", &[("table", table)], removed);
+            }
+            (StatusCode::OK, Json(result)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("erasure failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("database error")),
+            )
+                .into_response()
+        }
+    }
+}
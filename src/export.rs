@@ -0,0 +1,176 @@
+//! Dump the cache as a GeoJSON FeatureCollection, one Point per cached
+//! address, for inspecting coverage in QGIS or geojson.io.
+//!
+//! Available as `gaia export --format geojson [--output <path>]` and as
+//! `GET /api/v0/admin/export?format=geojson&limit=&cursor=`. Without
+//! `limit` the whole cache comes in one collection; with it, one page of
+//! that many addresses, and the next page's cursor in `X-Gaia-Next-Cursor`.
+
+use std::{collections::HashMap, io::Write, sync::Arc};
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use futures_util::TryStreamExt;
+use serde_json::{json, Value};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{auth::Admin, compression, params, schema, RadarAddress};
+
+#[derive(FromRow, Debug)]
+struct ExportRow {
+    rowid: i64,
+    lat: String,
+    lon: String,
+    address: compression::Stored<RadarAddress>,
+    created_at: Option<i64>,
+    hits: i64,
+    schema_version: i64,
+}
+
+fn feature(row: ExportRow) -> Option<Value> {
+    let mut address = row.address.0;
+    schema::upgrade(&mut address, row.schema_version);
+    let (latitude, longitude) = (address.latitude?, address.longitude?);
+    let mut properties = json!(address);
+    properties["queryLat"] = json!(row.lat);
+    properties["queryLon"] = json!(row.lon);
+    properties["createdAt"] = json!(row.created_at);
+    properties["hits"] = json!(row.hits);
+    Some(json!({
+        "type": "Feature",
+        "geometry": {"type": "Point", "coordinates": [longitude, latitude]},
+        "properties": properties,
+    }))
+}
+
+/// Which rows to export: those after rowid `after`, at most `limit` of them.
+#[derive(Debug, Default, Clone, Copy)]
+struct Range {
+    after: i64,
+    limit: Option<i64>,
+}
+
+/// Write the FeatureCollection a feature at a time, reading rows as they are
+/// written rather than all up front. Returns the features written and, when
+/// `range` was cut short by its limit, the last rowid read.
+async fn write_geojson(
+    pool: &Pool<Sqlite>,
+    range: Range,
+    out: &mut impl Write,
+) -> Result<(u64, Option<i64>), String> {
+    let mut rows = sqlx::query_as::<_, ExportRow>(
+        "SELECT rowid, lat, lon, address, created_at, hits, schema_version FROM geocode \
+         WHERE (json_valid(address) OR typeof(address) = 'blob') AND rowid > ? ORDER BY rowid LIMIT ?",
+    )
+    .bind(range.after)
+    .bind(range.limit.unwrap_or(-1))
+    .fetch(pool);
+
+    let (mut written, mut read, mut last) = (0, 0, None);
+    let io_error = |e: std::io::Error| e.to_string();
+    write!(out, r#"{{"type":"FeatureCollection","features":["#).map_err(io_error)?;
+    while let Some(row) = rows.try_next().await.map_err(|e| e.to_string())? {
+        read += 1;
+        last = Some(row.rowid);
+        let Some(feature) = feature(row) else {
+            continue;
+        };
+        if written > 0 {
+            write!(out, ",").map_err(io_error)?;
+        }
+        serde_json::to_writer(&mut *out, &feature).map_err(|e| e.to_string())?;
+        written += 1;
+    }
+    writeln!(out, "]}}").map_err(io_error)?;
+    let more = range.limit.is_some_and(|limit| read == limit);
+    Ok((written, last.filter(|_| more)))
+}
+
+fn check_format(format: Option<&str>) -> Result<(), String> {
+    match format {
+        None | Some("geojson") => Ok(()),
+        Some(other) => Err(format!("unsupported format {:?}", other)),
+    }
+}
+
+pub async fn run(args: &[String], pool: &Pool<Sqlite>) -> Result<(), String> {
+    let mut format = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next().map(String::as_str),
+            "--output" | "-o" => output = args.next(),
+            other => return Err(format!("unexpected argument {:?}", other)),
+        }
+    }
+    check_format(format)?;
+
+    let written = match output {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+            let mut out = std::io::BufWriter::new(file);
+            let written = write_geojson(pool, Range::default(), &mut out).await?.0;
+            out.flush().map_err(|e| e.to_string())?;
+            written
+        }
+        None => {
+            write_geojson(pool, Range::default(), &mut std::io::stdout().lock())
+                .await?
+                .0
+        }
+    };
+    tracing::info!("exported {} features", written);
+    Ok(())
+}
+
+pub async fn get_export(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    if let Err(e) = check_format(params.get("format").map(String::as_str)) {
+        return (StatusCode::BAD_REQUEST, Json(json!(e))).into_response();
+    }
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        None => None,
+        Some(Ok(limit)) if (1..=100_000).contains(&limit) => Some(limit),
+        Some(_) => {
+            return params::bad_request("limit must be between 1 and 100000").into_response()
+        }
+    };
+    let (after,) = match params::cursor::<(i64,)>(&params) {
+        Ok(cursor) => cursor.unwrap_or_default(),
+        Err(e) => return e.into_response(),
+    };
+    let mut body = vec![];
+    match write_geojson(&pool, Range { after, limit }, &mut body).await {
+        Ok((_, last)) => {
+            let mut response = (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "application/geo+json")],
+                body,
+            )
+                .into_response();
+            if let Some(last) = last {
+                let cursor = params::encode_cursor(&(last,));
+                if let Ok(cursor) = HeaderValue::from_str(&cursor) {
+                    response.headers_mut().insert(params::NEXT_CURSOR, cursor);
+                }
+            }
+            response
+        }
+        Err(e) => {
+            tracing::error!("export failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("database error")),
+            )
+                .into_response()
+        }
+    }
+}
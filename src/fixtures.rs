@@ -0,0 +1,68 @@
+//! Recorded provider responses for deterministic end-to-end tests.
+//!
+//! With `UPSTREAM_FIXTURES_DIR` set, `UPSTREAM_FIXTURES_MODE=record` saves
+//! every successful provider response there as `<lat>_<lon>.json`, and
+//! `replay` answers from those files without calling the provider (or
+//! needing a key); a lookup with no recording fails instead of going out.
+
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fixtures {
+    dir: PathBuf,
+    pub mode: Mode,
+}
+
+impl Fixtures {
+    /// `None` unless `UPSTREAM_FIXTURES_DIR` is set.
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var_os("UPSTREAM_FIXTURES_DIR")?);
+        let mode = match config::var("UPSTREAM_FIXTURES_MODE", String::from("replay")).as_str() {
+            "record" => Mode::Record,
+            "replay" => Mode::Replay,
+            other => panic!("Invalid UPSTREAM_FIXTURES_MODE: {}", other),
+        };
+        if mode == Mode::Record {
+            std::fs::create_dir_all(&dir).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to create UPSTREAM_FIXTURES_DIR {}: {}",
+                    dir.display(),
+                    e
+                )
+            });
+        }
+        Some(Fixtures { dir, mode })
+    }
+
+    fn path(&self, lat: &str, lon: &str) -> PathBuf {
+        self.dir.join(format!("{}_{}.json", lat, lon))
+    }
+
+    /// The recorded response for `lat`/`lon`, if there is one.
+    pub async fn load(&self, lat: &str, lon: &str) -> Result<Option<Value>, String> {
+        match tokio::fs::read(self.path(lat, lon)).await {
+            Ok(body) => serde_json::from_slice(&body)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub async fn save(&self, lat: &str, lon: &str, raw: &Value) {
+        let body = serde_json::to_vec_pretty(raw).unwrap_or_default();
+        if let Err(e) = tokio::fs::write(self.path(lat, lon), body).await {
+            tracing::warn!("failed to record fixture: {}", e);
+        }
+    }
+}
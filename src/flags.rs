@@ -0,0 +1,166 @@
+//! Runtime feature flags, so a risky subsystem can be switched off without a
+//! redeploy, or a new one configured but kept dark (`merge=off` with
+//! `UPSTREAM_MERGE` set) until it is switched on.
+//!
+//! `FEATURE_FLAGS` sets the starting state as a comma-separated list of
+//! `name=on|off`, e.g. `FEATURE_FLAGS=merge=off,jobs=on`; flags it doesn't
+//! mention start on. `GET /api/v0/admin/flags` lists them and
+//! `PUT /api/v0/admin/flags/:name` (`{"enabled": false}`) flips one on this
+//! instance until it restarts.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{auth::Admin, metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    /// `POST /geocode/reverse/bulk`.
+    Bulk,
+    /// Submitting jobs; existing ones can still be read.
+    Jobs,
+    /// Calling the provider on a cache miss; off answers from the cache only.
+    Upstream,
+    /// Asking the `UPSTREAM_MERGE` source alongside the primary.
+    Merge,
+    /// The background cache refresher.
+    Refresher,
+    /// Polling watches.
+    Watches,
+}
+
+const FLAGS: [Flag; 6] = [
+    Flag::Bulk,
+    Flag::Jobs,
+    Flag::Upstream,
+    Flag::Merge,
+    Flag::Refresher,
+    Flag::Watches,
+];
+
+impl Flag {
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::Bulk => "bulk",
+            Flag::Jobs => "jobs",
+            Flag::Upstream => "upstream",
+            Flag::Merge => "merge",
+            Flag::Refresher => "refresher",
+            Flag::Watches => "watches",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        FLAGS.into_iter().find(|f| f.name() == name)
+    }
+
+    fn index(self) -> usize {
+        FLAGS.iter().position(|&f| f == self).unwrap_or_default()
+    }
+}
+
+fn flags() -> &'static [AtomicBool; FLAGS.len()] {
+    static STATE: OnceLock<[AtomicBool; FLAGS.len()]> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let state = FLAGS.map(|_| AtomicBool::new(true));
+        let configured = std::env::var("FEATURE_FLAGS").unwrap_or_default();
+        for setting in configured
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let parsed = setting.split_once('=').and_then(|(name, value)| {
+                let enabled = match value.trim() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return None,
+                };
+                Some((Flag::parse(name.trim())?, enabled))
+            });
+            let Some((flag, enabled)) = parsed else {
+                panic!("Invalid FEATURE_FLAGS: {}", setting);
+            };
+            state[flag.index()].store(enabled, Ordering::Relaxed);
+        }
+        state
+    })
+}
+
+pub fn enabled(flag: Flag) -> bool {
+    flags()[flag.index()].load(Ordering::Relaxed)
+}
+
+fn set(flag: Flag, enabled: bool) {
+    let was = flags()[flag.index()].swap(enabled, Ordering::Relaxed);
+    if was != enabled {
+        tracing::warn!(
+            "feature flag {} turned {}",
+            flag.name(),
+            if enabled { "on" } else { "off" }
+        );
+    }
+    report();
+}
+
+/// Publish every flag as a gauge.
+pub fn report() {
+    for flag in FLAGS {
+        metrics::set_gauge(
+            "gaia_feature_flag",
+            &[("flag", flag.name())],
+            if enabled(flag) { 1.0 } else { 0.0 },
+        );
+    }
+}
+
+/// The response for a request needing `flag` while it is off.
+pub fn disabled(flag: Flag) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!(format!("{} is disabled", flag.name()))),
+    )
+}
+
+fn all() -> BTreeMap<&'static str, bool> {
+    FLAGS.into_iter().map(|f| (f.name(), enabled(f))).collect()
+}
+
+pub async fn get_flags(_: Admin) -> impl IntoResponse {
+    Json(all())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FlagRequest {
+    enabled: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct FlagState {
+    name: &'static str,
+    enabled: bool,
+}
+
+pub async fn put_flag(
+    _: Admin,
+    Path(name): Path<String>,
+    Json(request): Json<FlagRequest>,
+) -> impl IntoResponse {
+    let Some(flag) = Flag::parse(&name) else {
+        return (StatusCode::NOT_FOUND, Json(json!("no such flag"))).into_response();
+    };
+    set(flag, request.enabled);
+    Json(FlagState {
+        name: flag.name(),
+        enabled: enabled(flag),
+    })
+    .into_response()
+}
@@ -0,0 +1,275 @@
+//! Country-aware address formatting: `formattedAddress` rendered from an
+//! address's components with the conventions of its country, using templates
+//! in the style of OpenCage's address-formatting project (`{{{road}}}`
+//! components and `{{#first}} a || b {{/first}}` fallbacks).
+//!
+//! Templates for the common countries are built in; `ADDRESS_FORMATS_FILE`
+//! may point at a JSON object of `country code: template` to add or replace
+//! them, e.g. converted from OpenCage's `worldwide.yaml`.
+//!
+//! `ADDRESS_FORMAT=template` (the default) re-renders every result this way;
+//! `provider` keeps whatever the provider sent. Addresses with neither a
+//! street nor a city are left alone either way. `POST /api/v0/address/format`
+//! renders a single address.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{config, tenants::Tenant, RadarAddress};
+
+/// Number then street, city with state code and postcode.
+const NORTH_AMERICAN: &str = "{{{house_number}}} {{{road}}}
+{{#first}} {{{city}}} || {{{county}}} {{/first}}, {{#first}} {{{state_code}}} || {{{state}}} {{/first}} {{{postcode}}}
+{{{country}}}";
+
+/// Street then number, postcode before the city.
+const CONTINENTAL: &str = "{{{road}}} {{{house_number}}}
+{{{postcode}}} {{#first}} {{{city}}} || {{{county}}} {{/first}}
+{{{country}}}";
+
+const DEFAULT: &str = "{{{house_number}}} {{{road}}}
+{{#first}} {{{city}}} || {{{county}}} {{/first}}
+{{#first}} {{{state}}} || {{{state_code}}} {{/first}} {{{postcode}}}
+{{{country}}}";
+
+const BUILT_IN: &[(&str, &str)] = &[
+    ("US", NORTH_AMERICAN),
+    ("CA", NORTH_AMERICAN),
+    (
+        "AU",
+        "{{{house_number}}} {{{road}}}
+{{{city}}} {{{state_code}}} {{{postcode}}}
+{{{country}}}",
+    ),
+    (
+        "GB",
+        "{{{house_number}}} {{{road}}}
+{{#first}} {{{city}}} || {{{county}}} {{/first}}
+{{{postcode}}}
+{{{country}}}",
+    ),
+    (
+        "FR",
+        "{{{house_number}}} {{{road}}}
+{{{postcode}}} {{{city}}}
+{{{country}}}",
+    ),
+    (
+        "IT",
+        "{{{road}}} {{{house_number}}}
+{{{postcode}}} {{{city}}} {{{state_code}}}
+{{{country}}}",
+    ),
+    (
+        "BR",
+        "{{{road}}}, {{{house_number}}}
+{{{city}}} - {{{state_code}}}
+{{{postcode}}}
+{{{country}}}",
+    ),
+    (
+        "MX",
+        "{{{road}}} {{{house_number}}}
+{{{postcode}}} {{{city}}}, {{{state_code}}}
+{{{country}}}",
+    ),
+    (
+        "IN",
+        "{{{house_number}}} {{{road}}}
+{{{city}}} {{{postcode}}}
+{{{state}}}
+{{{country}}}",
+    ),
+    (
+        "JP",
+        "{{{country}}}
+{{{postcode}}}
+{{{state}}} {{{city}}}
+{{{road}}} {{{house_number}}}",
+    ),
+    ("DE", CONTINENTAL),
+    ("AT", CONTINENTAL),
+    ("CH", CONTINENTAL),
+    ("NL", CONTINENTAL),
+    ("BE", CONTINENTAL),
+    ("DK", CONTINENTAL),
+    ("NO", CONTINENTAL),
+    ("SE", CONTINENTAL),
+    ("FI", CONTINENTAL),
+    ("PL", CONTINENTAL),
+    ("CZ", CONTINENTAL),
+    ("ES", CONTINENTAL),
+    ("PT", CONTINENTAL),
+];
+
+#[derive(Debug)]
+struct Settings {
+    templates: HashMap<String, String>,
+    rerender: bool,
+}
+
+/// Load `ADDRESS_FORMAT` and any `ADDRESS_FORMATS_FILE` up front.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let mut templates = BUILT_IN
+            .iter()
+            .map(|&(country, template)| (country.to_string(), template.to_string()))
+            .collect::<HashMap<_, _>>();
+        if let Ok(path) = std::env::var("ADDRESS_FORMATS_FILE") {
+            let file = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read ADDRESS_FORMATS_FILE {}: {}", path, e));
+            let extra: HashMap<String, String> = serde_json::from_str(&file)
+                .unwrap_or_else(|e| panic!("Invalid ADDRESS_FORMATS_FILE {}: {}", path, e));
+            templates.extend(extra.into_iter().map(|(c, t)| (c.to_uppercase(), t)));
+        }
+        let rerender = match config::var("ADDRESS_FORMAT", String::from("template")).as_str() {
+            "template" => true,
+            "provider" => false,
+            other => panic!("Invalid ADDRESS_FORMAT: {}", other),
+        };
+        Settings {
+            templates,
+            rerender,
+        }
+    })
+}
+
+/// `address` under the component names templates use.
+fn components(address: &RadarAddress) -> HashMap<&'static str, &str> {
+    [
+        ("house_number", &address.number),
+        ("road", &address.street),
+        ("city", &address.city),
+        ("county", &address.county),
+        ("state", &address.state),
+        ("state_code", &address.state_code),
+        ("postcode", &address.postal_code),
+        ("country", &address.country),
+        ("country_code", &address.country_code),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value.as_deref()?.trim())))
+    .filter(|(_, value)| !value.is_empty())
+    .collect()
+}
+
+/// `text` with every `{{{name}}}` replaced by that component.
+fn substitute(text: &str, components: &HashMap<&str, &str>) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}}") else {
+            break;
+        };
+        let name = rest[start + 3..start + end].trim();
+        out.push_str(components.get(name).copied().unwrap_or_default());
+        rest = &rest[start + end + 3..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Tidy a rendered line: no doubled spaces, and no separators left dangling
+/// by missing components.
+fn clean(line: &str) -> String {
+    let mut line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+    for (from, to) in [(" ,", ","), (",,", ","), ("- -", "-")] {
+        while line.contains(from) {
+            line = line.replace(from, to);
+        }
+    }
+    line.trim_matches(|c: char| c == ',' || c == '-' || c.is_whitespace())
+        .to_string()
+}
+
+/// The lines of `template` rendered for `components`, empty ones dropped.
+fn render(template: &str, components: &HashMap<&str, &str>) -> Vec<String> {
+    let mut text = template.to_string();
+    while let Some(start) = text.find("{{#first}}") {
+        let Some(end) = text[start..].find("{{/first}}").map(|e| start + e) else {
+            break;
+        };
+        let chosen = text[start + "{{#first}}".len()..end]
+            .split("||")
+            .map(|alternative| substitute(alternative, components))
+            .find(|rendered| !rendered.trim().is_empty())
+            .unwrap_or_default();
+        text.replace_range(start..end + "{{/first}}".len(), &chosen);
+    }
+    substitute(&text, components)
+        .lines()
+        .map(clean)
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// `address` formatted for its country, a line at a time, or `None` when it
+/// has too little to format.
+pub fn lines(address: &RadarAddress) -> Option<Vec<String>> {
+    let components = components(address);
+    if !components.contains_key("road") && !components.contains_key("city") {
+        return None;
+    }
+    let templates = &settings().templates;
+    let country = address
+        .country_code
+        .as_deref()
+        .unwrap_or_default()
+        .to_uppercase();
+    let template = templates
+        .get(&country)
+        .map(String::as_str)
+        .unwrap_or(DEFAULT);
+    Some(render(template, &components))
+}
+
+/// Replace the provider's `formatted_address` with the rendered one, unless
+/// `ADDRESS_FORMAT=provider`.
+pub fn apply(address: &mut RadarAddress) {
+    if !settings().rerender {
+        return;
+    }
+    if let Some(lines) = lines(address) {
+        address.formatted_address = Some(lines.join(", "));
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FormattedAddress {
+    pub formatted_address: String,
+    pub lines: Vec<String>,
+}
+
+/// `POST /api/v0/address/format`: the body is an address in the shape
+/// results use (`number`, `street`, `city`, `stateCode`, `countryCode`...).
+pub async fn post_address_format(
+    // for the key check and usage count
+    _tenant: Tenant,
+    Json(address): Json<RadarAddress>,
+) -> impl IntoResponse {
+    match lines(&address) {
+        Some(lines) => (
+            StatusCode::OK,
+            Json(FormattedAddress {
+                formatted_address: lines.join(", "),
+                lines,
+            }),
+        )
+            .into_response(),
+        None => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!("address needs at least a street or a city")),
+        )
+            .into_response(),
+    }
+}
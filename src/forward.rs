@@ -0,0 +1,479 @@
+//! Forward geocoding: addresses for a free-text query, cached like reverse
+//! lookups are.
+//!
+//! `GET /api/v0/geocode/forward?query=` answers one query with a list of
+//! `{confidence, address}`, best first as `ranking` has it (`near=lat,lon`
+//! favours results close to a point, when the deployment weighs that). When
+//! nothing answers, cached addresses spelled like the query do, flagged
+//! `fuzzy` (see `fuzzy`), and with `suggest=true` the answer is
+//! `{results, suggestions}`, with queries to try instead when there are no
+//! results (see `suggest`). Answers are cached per query, spaces and case
+//! aside, and per tenant namespace; an empty answer is cached too,
+//! so rerunning a batch doesn't pay again for addresses nobody could find.
+//! They stay fresh for `CACHE_TTL_DAYS`, or the `CACHE_TTL_LAYER_DAYS` of
+//! their layers (`CACHE_TTL_EMPTY_DAYS` for empty ones, see `janitor`), and
+//! are removed after `RETENTION_FORWARD_GEOCODE_DAYS`.
+//!
+//! `POST /api/v0/geocode/forward/csv` geocodes a CSV file (`text/csv`, with a
+//! header row) and answers it back with `latitude`, `longitude` and
+//! `confidence` columns appended, from the best answer for each row. Which
+//! columns hold the address is set in the query: `address=` names one
+//! holding it whole (by default the column `address`), or any of `street=`,
+//! `city=`, `state=`, `postalCode=` and `country=` name the parts, which are
+//! joined in that order. `header=false` says the file has no header row;
+//! columns are then numbered from 1. Rows nobody could find get empty
+//! columns; a provider failure fails the whole file, which is cheap to retry
+//! since every row answered so far is cached. Files are limited to
+//! `FORWARD_CSV_MAX_BODY_BYTES` (default 2 MiB, and may be gzipped, see
+//! `decompress`) and `FORWARD_CSV_MAX_ROWS` rows (default 10000).
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::{DefaultBodyLimit, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    confidence, config, db, dry_run, formatting, fuzzy, janitor, localize, metrics,
+    negotiate::csv_escape,
+    params, ranking,
+    suggest::{self, Suggestion},
+    tenants::{self, Tenant},
+    upstream::{Caller, Upstream, UpstreamError},
+    LookupOptions, RadarAddress,
+};
+
+#[derive(Debug)]
+struct Settings {
+    max_body_bytes: usize,
+    max_rows: usize,
+}
+
+/// Read the CSV size limits.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        max_body_bytes: config::var("FORWARD_CSV_MAX_BODY_BYTES", 2 * 1024 * 1024usize),
+        max_rows: config::var("FORWARD_CSV_MAX_ROWS", 10_000usize),
+    })
+}
+
+/// The body limit for the CSV route, replacing axum's default.
+pub fn body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(settings().max_body_bytes)
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardGeocode {
+    pub confidence: f64,
+    pub address: RadarAddress,
+    /// Matched by spelling against the cache rather than looked up; its
+    /// confidence is scaled by how alike the two are.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub fuzzy: bool,
+}
+
+/// A forward geocode answer with `suggest=true`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Suggested {
+    pub results: Vec<ForwardGeocode>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+fn database_error(e: sqlx::Error) -> UpstreamError {
+    tracing::error!("forward geocode cache query failed: {}", e);
+    UpstreamError::Transport(String::from("database error"))
+}
+
+/// `query` as cached: spaces collapsed and lowercased.
+pub fn normalized(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The cached answer for `query`, if there is a fresh one.
+async fn cached(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Option<Vec<RadarAddress>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (sqlx::types::Json<Vec<RadarAddress>>, i64)>(
+        "SELECT addresses, created_at FROM forward_geocode WHERE namespace = ? AND query = ?",
+    )
+    .bind(namespace)
+    .bind(query)
+    .fetch_optional(db::reads(pool))
+    .await?;
+    Ok(row
+        .filter(|(addresses, created_at)| {
+            gaia_core::matching::fresh(Some(*created_at), ttl(addresses), db::now())
+        })
+        .map(|(addresses, _)| addresses.0))
+}
+
+/// How long an answer of `addresses` stays fresh: the shortest TTL of their
+/// layers, or the one for empty answers. Zero is forever.
+pub fn ttl(addresses: &[RadarAddress]) -> i64 {
+    if addresses.is_empty() {
+        return janitor::empty_ttl();
+    }
+    addresses
+        .iter()
+        .map(|a| janitor::layer_ttl(a.layer.as_deref()))
+        .filter(|ttl| *ttl > 0)
+        .min()
+        .unwrap_or(0)
+}
+
+/// The addresses for `query`, keyed `key`, from the cache or else the
+/// provider.
+async fn lookup(
+    key: &str,
+    query: &str,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    options: &LookupOptions,
+) -> Result<Vec<RadarAddress>, UpstreamError> {
+    let cached = match options.refresh {
+        true => None,
+        false => cached(pool, &options.namespace, key)
+            .await
+            .map_err(database_error)?,
+    };
+    metrics::increment(
+        "gaia_forward_lookups_total",
+        &[("result", if cached.is_some() { "hit" } else { "miss" })],
+    );
+    if let Some(addresses) = cached {
+        return Ok(addresses);
+    }
+    if options.cache_only || dry_run::enabled() {
+        return Ok(vec![]);
+    }
+    let caller = Caller {
+        tenant: options.tenant.as_deref(),
+        client: options.client,
+    };
+    let addresses = upstream.forward_geocode(pool, query, caller).await?;
+    tenants::record_upstream_call(pool.clone(), options.tenant.clone());
+    sqlx::query(
+        "INSERT OR REPLACE INTO forward_geocode(namespace, query, addresses, created_at) \
+         VALUES (?, ?, ?, ?)",
+    )
+    .bind(&options.namespace)
+    .bind(key)
+    .bind(json!(addresses))
+    .bind(db::now())
+    .execute(&**pool)
+    .await
+    .map_err(database_error)?;
+    Ok(addresses)
+}
+
+/// Addresses for `query`, from the cache or else the provider, or failing
+/// both ones like it from the cache, localized, formatted, scored and best
+/// first by `ranking`, favouring those near `bias`.
+pub async fn forward(
+    query: &str,
+    bias: Option<(f64, f64)>,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    options: &LookupOptions,
+) -> Result<Vec<ForwardGeocode>, UpstreamError> {
+    let key = normalized(query);
+    if key.is_empty() {
+        return Ok(vec![]);
+    }
+    let addresses = match lookup(&key, query, pool, upstream, options).await {
+        Ok(addresses) if !addresses.is_empty() => {
+            addresses.into_iter().map(|a| (None, a)).collect()
+        }
+        found => {
+            let similar = fuzzy::search(pool, &options.namespace, &key)
+                .await
+                .map_err(database_error)?;
+            match found {
+                Err(e) if similar.is_empty() => return Err(e),
+                Err(e) => tracing::warn!("forward geocode failed, answering fuzzily: {}", e),
+                Ok(_) => {}
+            }
+            similar
+                .into_iter()
+                .map(|(similarity, a)| (Some(similarity), a))
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let mut geocodes = addresses
+        .into_iter()
+        .map(|(similarity, mut address)| {
+            if let Some(lang) = &options.lang {
+                localize::apply(&mut address, lang);
+            }
+            formatting::apply(&mut address);
+            ForwardGeocode {
+                confidence: confidence::forward_score(&address) * similarity.unwrap_or(1.0),
+                address,
+                fuzzy: similarity.is_some(),
+            }
+        })
+        .filter(|g| g.confidence >= options.min_confidence)
+        .map(|g| (ranking::forward_score(g.confidence, &g.address, bias), g))
+        .collect::<Vec<_>>();
+    geocodes.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    Ok(geocodes.into_iter().map(|(_, g)| g).collect())
+}
+
+/// `GET /api/v0/geocode/forward?query=`.
+pub async fn get_geocode_forward(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    let query = match params::required::<String>(&params, "query") {
+        Ok(query) if !query.trim().is_empty() => query,
+        Ok(_) => return params::bad_request("query must not be empty").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let bias = match ranking::forward_bias(&params) {
+        Ok(bias) => bias,
+        Err(e) => return e.into_response(),
+    };
+    let suggest = match params::flag(&params, "suggest") {
+        Ok(suggest) => suggest,
+        Err(e) => return e.into_response(),
+    };
+    let geocodes = match forward(&query, bias, &pool, &upstream, &options).await {
+        Ok(geocodes) if geocodes.is_empty() && options.cache_only => {
+            return (StatusCode::NOT_FOUND, Json(json!("not in cache"))).into_response()
+        }
+        Ok(geocodes) => geocodes,
+        Err(e) => return e.into_response(),
+    };
+    if !suggest {
+        return Json(geocodes).into_response();
+    }
+    let suggestions = match geocodes.is_empty() {
+        true => match suggest::suggestions(&pool, &options.namespace, &query).await {
+            Ok(suggestions) => suggestions,
+            Err(e) => return database_error(e).into_response(),
+        },
+        false => vec![],
+    };
+    Json(Suggested {
+        results: geocodes,
+        suggestions,
+    })
+    .into_response()
+}
+
+/// The records of a CSV document: fields separated by commas, quoted with
+/// `"` when they hold commas, quotes or line breaks.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!(
+            "unterminated quote in record {}",
+            records.len() + 1
+        ));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    // blank lines carry nothing to geocode
+    records.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    Ok(records)
+}
+
+/// The address parts of a CSV file, in the order they are joined.
+const PARTS: &[&str] = &["street", "city", "state", "postalCode", "country"];
+
+/// The column indexes the query points at: `address`, or else the parts.
+fn columns(
+    params: &HashMap<String, String>,
+    header: Option<&[String]>,
+) -> Result<Vec<usize>, params::ParamError> {
+    let find = |name: &str, column: &str| match header {
+        Some(header) => header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(column.trim()))
+            .ok_or_else(|| params::bad_request(&format!("no column {} for {}", column, name))),
+        None => match column.trim().parse::<usize>() {
+            Ok(n) if n >= 1 => Ok(n - 1),
+            _ => Err(params::bad_request(&format!(
+                "{} must be a column number from 1 without a header row",
+                name
+            ))),
+        },
+    };
+    let parts = PARTS
+        .iter()
+        .filter_map(|&name| params.get(name).map(|column| find(name, column)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match (params.get("address"), parts.is_empty()) {
+        (Some(_), false) => Err(params::bad_request(
+            "address can't be combined with street, city, state, postalCode or country",
+        )),
+        (Some(column), true) => Ok(vec![find("address", column)?]),
+        (None, false) => Ok(parts),
+        (None, true) if header.is_some() => Ok(vec![find("address", "address")?]),
+        (None, true) => Err(params::bad_request(
+            "address or the address parts are needed without a header row",
+        )),
+    }
+}
+
+/// `POST /api/v0/geocode/forward/csv`.
+pub async fn post_geocode_forward_csv(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+    body: Result<String, axum::extract::rejection::StringRejection>,
+) -> Response {
+    let body = match body {
+        Ok(body) => body,
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!(format!(
+                    "request body exceeds {} bytes",
+                    settings().max_body_bytes
+                ))),
+            )
+                .into_response()
+        }
+        Err(e) => return (e.status(), Json(json!(e.body_text()))).into_response(),
+    };
+    let has_header = match params::optional(&params, "header", true) {
+        Ok(has_header) => has_header,
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let bias = match ranking::forward_bias(&params) {
+        Ok(bias) => bias,
+        Err(e) => return e.into_response(),
+    };
+    let mut records = match parse_csv(body.strip_prefix('\u{feff}').unwrap_or(&body)) {
+        Ok(records) => records,
+        Err(e) => return params::bad_request(&e).into_response(),
+    };
+    let header = match has_header && !records.is_empty() {
+        true => Some(records.remove(0)),
+        false => None,
+    };
+    if records.len() > settings().max_rows {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!(format!(
+                "file has {} rows, at most {} are allowed",
+                records.len(),
+                settings().max_rows
+            ))),
+        )
+            .into_response();
+    }
+    let columns = match columns(&params, header.as_deref()) {
+        Ok(columns) => columns,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut out = String::new();
+    let mut write = |fields: &[String], appended: [String; 3]| {
+        let fields = fields.iter().map(|f| csv_escape(f)).chain(appended);
+        out.push_str(&fields.collect::<Vec<_>>().join(","));
+        out.push_str("\r\n");
+    };
+    if let Some(header) = &header {
+        write(
+            header,
+            ["latitude", "longitude", "confidence"].map(String::from),
+        );
+    }
+    // address lists repeat addresses; look each one up only once
+    let mut answers: HashMap<String, Option<ForwardGeocode>> = HashMap::new();
+    for record in &records {
+        let query = columns
+            .iter()
+            .filter_map(|&i| record.get(i).map(|f| f.trim()))
+            .filter(|f| !f.is_empty())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let key = normalized(&query);
+        if !answers.contains_key(&key) {
+            let best = match forward(&query, bias, &pool, &upstream, &options).await {
+                Ok(geocodes) => geocodes.into_iter().next(),
+                Err(e) => return e.into_response(),
+            };
+            answers.insert(key.clone(), best);
+        }
+        let appended = match &answers[&key] {
+            Some(ForwardGeocode {
+                confidence,
+                address,
+                ..
+            }) => [
+                address.latitude.map(|v| v.to_string()).unwrap_or_default(),
+                address.longitude.map(|v| v.to_string()).unwrap_or_default(),
+                confidence.to_string(),
+            ],
+            None => Default::default(),
+        };
+        write(record, appended);
+    }
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+        out,
+    )
+        .into_response()
+}
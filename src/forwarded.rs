@@ -0,0 +1,317 @@
+//! Finding the real client behind reverse proxies.
+//!
+//! `TRUSTED_PROXIES` lists the proxies allowed to say who they forwarded a
+//! request for, as comma-separated CIDRs or bare addresses, e.g.
+//! `10.0.0.0/8,fd00::/8,127.0.0.1`. A request from one of them has its
+//! `Forwarded` header (or `X-Forwarded-For` when there is none) walked from
+//! the nearest hop back, and the first address that isn't a trusted proxy
+//! is the client. Everyone else's headers are ignored, so a client can't
+//! claim to be someone else by sending its own; with `TRUSTED_PROXIES` unset
+//! the client is always the connection's peer.
+//!
+//! The client shows up in the `request` span around every log line while a
+//! request is handled, keys the per-client limit in `shed` and is recorded
+//! with the provider calls in the audit log.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+
+/// The address a request is being handled for, as resolved by `resolve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u32>().ok()?)),
+            None => (value, None),
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Cidr { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // a shift by the full width leaves no network bits
+        let shift = |bits: u32| bits - self.prefix;
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(shift(32)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(shift(128)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse `TRUSTED_PROXIES`.
+pub fn check() {
+    trusted_proxies();
+}
+
+fn trusted_proxies() -> &'static [Cidr] {
+    static TRUSTED: OnceLock<Vec<Cidr>> = OnceLock::new();
+    TRUSTED.get_or_init(|| {
+        std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| Cidr::parse(c).unwrap_or_else(|| panic!("Invalid TRUSTED_PROXIES: {}", c)))
+            .collect()
+    })
+}
+
+/// A node from either header: `192.0.2.1`, `192.0.2.1:4711`,
+/// `[2001:db8::1]:4711` or `2001:db8::1`. `None` for `unknown` and
+/// obfuscated identifiers.
+fn node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(bracketed) = value.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+/// The hops each header lists, nearest to the client first; `None` for a
+/// hop that can't be read.
+fn hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") {
+                        node(value)
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+    }
+    values("x-forwarded-for").iter().map(|v| node(v)).collect()
+}
+
+/// Who a request from `peer` with `headers` is for.
+pub fn client(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    client_behind(peer, headers, trusted_proxies())
+}
+
+/// `client`, trusting `proxies`.
+fn client_behind(peer: IpAddr, headers: &HeaderMap, proxies: &[Cidr]) -> IpAddr {
+    let trusted = |ip: IpAddr| proxies.iter().any(|cidr| cidr.contains(ip));
+    let mut client = peer.to_canonical();
+    if !trusted(client) {
+        return client;
+    }
+    for hop in hops(headers).into_iter().rev() {
+        // past an unreadable hop the chain can't be trusted
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop.to_canonical();
+        if !trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Middleware resolving the client of every request.
+pub async fn resolve(mut request: Request, next: Next) -> Response {
+    let Some(&ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return next.run(request).await;
+    };
+    let ip = client(peer.ip(), request.headers());
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request)
+        .instrument(tracing::info_span!("request", client = %ip))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::parse(value).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn cidrs_parse_with_and_without_a_prefix() {
+        assert_eq!(cidr("127.0.0.1").prefix, 32);
+        assert_eq!(cidr("::1").prefix, 128);
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("fd00::/129").is_none());
+        assert!(Cidr::parse("10.0.0.0/x").is_none());
+        assert!(Cidr::parse("example.com").is_none());
+    }
+
+    #[test]
+    fn ipv4_cidr_edges() {
+        let everything = cidr("0.0.0.0/0");
+        assert!(everything.contains(ip("0.0.0.0")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("::1")));
+
+        let one = cidr("192.0.2.1/32");
+        assert!(one.contains(ip("192.0.2.1")));
+        assert!(!one.contains(ip("192.0.2.0")));
+        assert!(!one.contains(ip("192.0.2.2")));
+
+        let private = cidr("10.0.0.0/8");
+        assert!(private.contains(ip("10.0.0.0")));
+        assert!(private.contains(ip("10.255.255.255")));
+        assert!(!private.contains(ip("11.0.0.0")));
+        assert!(!private.contains(ip("9.255.255.255")));
+        // as an IPv4 client arrives on a dual-stack socket
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn ipv6_cidr_edges() {
+        let everything = cidr("::/0");
+        assert!(everything.contains(ip("::")));
+        assert!(everything.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!everything.contains(ip("10.0.0.1")));
+
+        let one = cidr("2001:db8::1/128");
+        assert!(one.contains(ip("2001:db8::1")));
+        assert!(!one.contains(ip("2001:db8::")));
+        assert!(!one.contains(ip("2001:db8::2")));
+
+        let unique_local = cidr("fd00::/8");
+        assert!(unique_local.contains(ip("fd00::")));
+        assert!(unique_local.contains(ip("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!unique_local.contains(ip("fc00::1")));
+        assert!(!unique_local.contains(ip("fe00::")));
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(node(" 192.0.2.1:4711"), Some(ip("192.0.2.1")));
+        assert_eq!(node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(node("\"[2001:db8::1]:443\""), Some(ip("2001:db8::1")));
+        assert_eq!(node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(node("unknown"), None);
+        assert_eq!(node("_hidden"), None);
+        assert_eq!(node("\"_SEVKISEK\""), None);
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let proxies = [cidr("10.0.0.0/8")];
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(
+            client_behind(ip("203.0.113.7"), &headers, &proxies),
+            ip("203.0.113.7")
+        );
+        assert_eq!(client_behind(ip("10.0.0.1"), &headers, &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn x_forwarded_for_is_walked_from_the_nearest_hop() {
+        let proxies = [cidr("10.0.0.0/8")];
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1, 10.0.0.2")]);
+        assert_eq!(
+            client_behind(ip("10.0.0.1"), &headers, &proxies),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn the_walk_stops_at_an_untrusted_hop() {
+        let proxies = [cidr("10.0.0.0/8")];
+        // the first address was supplied by whoever 203.0.113.7 is
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1, 203.0.113.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_behind(ip("10.0.0.1"), &headers, &proxies),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn forwarded_is_preferred_and_quoted_nodes_read() {
+        let proxies = [cidr("10.0.0.0/8"), cidr("fd00::/8")];
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:443\";proto=https, for=\"[fd00::2]\";by=10.0.0.1",
+            ),
+        ]);
+        assert_eq!(
+            client_behind(ip("10.0.0.1"), &headers, &proxies),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn an_obfuscated_hop_ends_the_walk() {
+        let proxies = [cidr("10.0.0.0/8")];
+        let hidden = headers(&[("forwarded", "for=192.0.2.1, for=_hidden")]);
+        assert_eq!(
+            client_behind(ip("10.0.0.1"), &hidden, &proxies),
+            ip("10.0.0.1")
+        );
+        let behind = headers(&[("forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(
+            client_behind(ip("10.0.0.1"), &behind, &proxies),
+            ip("10.0.0.2")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! The fallback for forward lookups nobody could answer: cached addresses
+//! whose formatted address is close to the query, typos and all, rather than
+//! nothing.
+//!
+//! When a forward lookup comes back empty, from the provider or because it
+//! can't be asked (`cacheOnly`, dry runs, offline), or the provider fails,
+//! the reverse geocode cache of the tenant namespace is searched by trigram.
+//! The `FORWARD_FUZZY_CANDIDATES` (default 200) best candidates the index
+//! finds are scored by the share of the query's trigrams their formatted
+//! address has, and those scoring at least `FORWARD_FUZZY_MIN_SIMILARITY`
+//! (default 0.6) answer, best first, at most `MAX_RESULTS` of them. The
+//! answers are flagged `fuzzy`, with their confidence scaled by their score.
+//! `FORWARD_FUZZY=false` turns the fallback off.
+
+use std::{collections::HashSet, sync::OnceLock};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{compression, config, metrics, schema, RadarAddress};
+
+const MAX_RESULTS: usize = 5;
+
+#[derive(Debug)]
+struct Settings {
+    enabled: bool,
+    min_similarity: f64,
+    candidates: u32,
+}
+
+/// Read `FORWARD_FUZZY`, `FORWARD_FUZZY_MIN_SIMILARITY` and
+/// `FORWARD_FUZZY_CANDIDATES`.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        enabled: config::var("FORWARD_FUZZY", true),
+        min_similarity: config::var("FORWARD_FUZZY_MIN_SIMILARITY", 0.6),
+        candidates: config::var("FORWARD_FUZZY_CANDIDATES", 200u32),
+    })
+}
+
+/// An FTS5 query for anything sharing a trigram with `query`'s words. Words
+/// shorter than three letters have none; a query of only those finds
+/// nothing.
+pub fn fts_query(query: &str) -> Option<String> {
+    let mut seen = HashSet::new();
+    let trigrams = gaia_core::matching::words(query)
+        .flat_map(|word| {
+            let chars = word.chars().collect::<Vec<_>>();
+            chars
+                .windows(3)
+                .map(|w| w.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .filter(|trigram| seen.insert(trigram.clone()))
+        .map(|trigram| format!("\"{}\"", trigram))
+        .collect::<Vec<_>>();
+    (!trigrams.is_empty()).then(|| trigrams.join(" OR "))
+}
+
+/// Cached addresses in `namespace` like `query`, best first, each with its
+/// similarity; none when the fallback is off.
+pub async fn search(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Vec<(f64, RadarAddress)>, sqlx::Error> {
+    let settings = settings();
+    let Some(fts_query) = fts_query(query).filter(|_| settings.enabled) else {
+        return Ok(vec![]);
+    };
+    // the same address is usually cached for several nearby cells
+    let candidates = sqlx::query_as::<_, (compression::Stored<RadarAddress>, i64)>(
+        "SELECT g.address, g.schema_version FROM geocode_trigrams \
+         JOIN geocode g ON g.rowid = geocode_trigrams.rowid \
+         WHERE geocode_trigrams MATCH ? AND g.namespace = ? \
+         GROUP BY g.formatted_address ORDER BY MIN(geocode_trigrams.rank) LIMIT ?",
+    )
+    .bind(fts_query)
+    .bind(namespace)
+    .bind(settings.candidates)
+    .fetch_all(pool)
+    .await?;
+
+    let mut matches = candidates
+        .into_iter()
+        .filter_map(|(mut address, version)| {
+            schema::upgrade(&mut address.0, version);
+            let formatted = address.formatted_address.as_deref()?;
+            let similarity = gaia_core::matching::similarity(query, formatted);
+            (similarity >= settings.min_similarity).then_some((similarity, address.0))
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    matches.truncate(MAX_RESULTS);
+    metrics::increment(
+        "gaia_forward_fuzzy_total",
+        &[("result", if matches.is_empty() { "miss" } else { "hit" })],
+    );
+    Ok(matches)
+}
@@ -0,0 +1,186 @@
+//! `gaia generate`: fill the cache with made-up entries, for performance work
+//! on the query paths and eviction without real data or provider calls.
+//!
+//! ```text
+//! gaia generate --points 1M [--bbox minLon,minLat,maxLon,maxLat]
+//!     [--namespace NS] [--age-days 0]
+//! ```
+//!
+//! `--points` takes a `k` or `M` suffix. Points fall in `--bbox` (GeoJSON
+//! order; the contiguous United States by default), bunched around a
+//! thousandth as many town centres as there are points (up to 10,000), as
+//! real caches are dense where people are and empty elsewhere. Each is cached at its
+//! `COORDINATE_PRECISION` cell with the mock provider's address for it.
+//! `--age-days` spreads the entries' fetch times over that many past days for
+//! the janitor and refresher to find, and popularity is skewed so a few
+//! entries take most of the hits. Rows are added to whatever the database
+//! already holds.
+
+use std::time::Instant;
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{compression, db, mock, precision, schema};
+
+const BATCH: usize = 1000;
+
+#[derive(Debug)]
+struct Options {
+    points: u64,
+    /// `(min_lon, min_lat, max_lon, max_lat)`
+    bbox: (f64, f64, f64, f64),
+    namespace: String,
+    age_days: f64,
+}
+
+fn count(value: &str) -> Option<u64> {
+    let (number, scale) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], 1_000.0),
+        'm' | 'M' => (&value[..value.len() - 1], 1_000_000.0),
+        _ => (value, 1.0),
+    };
+    let count = number.parse::<f64>().ok()? * scale;
+    Some(count as u64).filter(|_| count >= 1.0)
+}
+
+fn bbox(value: &str) -> Option<(f64, f64, f64, f64)> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [min_lon, min_lat, max_lon, max_lat]
+            if min_lon < max_lon
+                && min_lat < max_lat
+                && (-90.0..=90.0).contains(&min_lat)
+                && (-90.0..=90.0).contains(&max_lat)
+                && (-180.0..=180.0).contains(&min_lon)
+                && (-180.0..=180.0).contains(&max_lon) =>
+        {
+            Some((min_lon, min_lat, max_lon, max_lat))
+        }
+        _ => None,
+    }
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            points: 0,
+            bbox: (-124.8, 24.5, -66.9, 49.0),
+            namespace: String::new(),
+            age_days: 0.0,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--points" => {
+                    options.points = count(value()?).ok_or("invalid --points")?;
+                }
+                "--bbox" => options.bbox = bbox(value()?).ok_or("invalid --bbox")?,
+                "--namespace" => options.namespace = value()?.clone(),
+                "--age-days" => {
+                    options.age_days = value()?
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|days| *days >= 0.0)
+                        .ok_or("invalid --age-days")?;
+                }
+                other => return Err(format!("unexpected argument {:?}", other)),
+            }
+        }
+        if options.points == 0 {
+            return Err(String::from("--points is required"));
+        }
+        Ok(options)
+    }
+}
+
+/// A standard normal draw (Box-Muller).
+fn normal() -> f64 {
+    let (u, v) = (1.0 - rand::random::<f64>(), rand::random::<f64>());
+    (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+}
+
+pub async fn run(args: &[String], pool: &Pool<Sqlite>) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    let (min_lon, min_lat, max_lon, max_lat) = options.bbox;
+    let towns = (0..(options.points / 1000).clamp(1, 10_000))
+        .map(|_| {
+            (
+                min_lat + rand::random::<f64>() * (max_lat - min_lat),
+                min_lon + rand::random::<f64>() * (max_lon - min_lon),
+            )
+        })
+        .collect::<Vec<_>>();
+    // towns sprawl a few kilometres, or less in a small box
+    let spread = (0.02f64).min((max_lat - min_lat).min(max_lon - min_lon) / 4.0);
+    let digits = precision::default();
+    let now = db::now();
+    let started = Instant::now();
+
+    let mut written = 0;
+    while written < options.points {
+        let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+        for _ in 0..BATCH.min((options.points - written) as usize) {
+            let (town_lat, town_lon) = towns[rand::random::<usize>() % towns.len()];
+            // redrawn rather than clamped, which would pile points on the edges
+            let (latitude, longitude) = loop {
+                let point = (town_lat + normal() * spread, town_lon + normal() * spread);
+                if (min_lat..=max_lat).contains(&point.0) && (min_lon..=max_lon).contains(&point.1)
+                {
+                    break point;
+                }
+            };
+            let (lat, lon) = (
+                precision::round(latitude, digits),
+                precision::round_lon(longitude, digits),
+            );
+            let mut address = mock::reverse_geocode(&lat, &lon).addresses.remove(0);
+            (address.latitude, address.longitude) = (Some(latitude), Some(longitude));
+            let created_at = now - (rand::random::<f64>() * options.age_days * 86400.0) as i64;
+            // most entries are looked up once; a few are looked up constantly
+            let hits = (rand::random::<f64>().powi(8) * 1000.0) as i64;
+            let payload = compression::encode(&address);
+            sqlx::query(
+                "INSERT INTO geocode(lat, lon, namespace, address, address_format, \
+                 schema_version, created_at, hits, last_hit_at, country_code, state_code, \
+                 postal_code, city, layer, formatted_address, latitude, longitude) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&lat)
+            .bind(&lon)
+            .bind(&options.namespace)
+            .bind(&payload)
+            .bind(payload.format.id())
+            .bind(schema::CURRENT)
+            .bind(created_at)
+            .bind(hits)
+            .bind(Some(now).filter(|_| hits > 0))
+            .bind(&address.country_code)
+            .bind(&address.state_code)
+            .bind(&address.postal_code)
+            .bind(&address.city)
+            .bind(&address.layer)
+            .bind(&address.formatted_address)
+            .bind(address.latitude)
+            .bind(address.longitude)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+            written += 1;
+        }
+        tx.commit().await.map_err(|e| e.to_string())?;
+        if written % 100_000 == 0 {
+            tracing::info!("generated {} of {} entries", written, options.points);
+        }
+    }
+    tracing::info!(
+        "generated {} entries around {} towns in {:.1}s",
+        written,
+        towns.len(),
+        started.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
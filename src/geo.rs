@@ -0,0 +1,23 @@
+//! `DISTANCE_ALGORITHM`, `vincenty` (the default) or `haversine`, for the
+//! distances in `gaia_core::geo`. Responses name the one used in
+//! `X-Gaia-Distance-Algorithm`.
+
+use std::sync::OnceLock;
+
+pub use gaia_core::geo::{ring_contains, Algorithm};
+
+use crate::config;
+
+/// The configured `DISTANCE_ALGORITHM`.
+pub fn algorithm() -> Algorithm {
+    static ALGORITHM: OnceLock<Algorithm> = OnceLock::new();
+    *ALGORITHM.get_or_init(|| {
+        let name = config::var("DISTANCE_ALGORITHM", String::from("vincenty"));
+        Algorithm::parse(&name).unwrap_or_else(|| panic!("Invalid DISTANCE_ALGORITHM: {}", name))
+    })
+}
+
+/// Meters between two points by `algorithm()`.
+pub fn distance_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    gaia_core::geo::distance_meters(algorithm(), lat1, lon1, lat2, lon2)
+}
@@ -0,0 +1,238 @@
+//! The server's side of `gaia_core::lookup`: the `geocode` table as its
+//! `Storage` and the upstream provider as its `Provider`, so reverse lookups
+//! match, expire and cache addresses by the core crate's rules.
+//!
+//! `Cache` reads the addresses in a box around the cell (see `match_box`),
+//! upgrading old rows on the way (see `schema`), and writes a provider's
+//! answer in one transaction with its raw response when `STORE_RAW_RESPONSES`
+//! keeps those. A refresh replaces the cell in the same transaction; bulk
+//! lookups may hand their inserts to `write_behind` instead. Reads may go to
+//! the `DATABASE_READ_URL` replica; writes always go to the primary.
+
+use std::sync::{Arc, OnceLock};
+
+use gaia_core::lookup::{Cached, Policy, Provider, Storage};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    compression, db, geo, janitor, precision, ranking, schema, tenants,
+    upstream::{Caller, Upstream, UpstreamError},
+    write_behind, Geocode, LookupOptions, NewAddress, RadarAddress, RadarReverseGeocodeResponse,
+};
+
+/// The lookup policy from the server's settings. Confidence and ranking are
+/// applied per request, after localizing, so `min_confidence` stays zero.
+pub fn policy() -> &'static Policy {
+    static POLICY: OnceLock<Policy> = OnceLock::new();
+    POLICY.get_or_init(|| {
+        let (ttl, layer_ttls) = janitor::ttls();
+        Policy {
+            precision: precision::default(),
+            ttl,
+            layer_ttls,
+            min_confidence: 0.0,
+            ranking: ranking::ranking().clone(),
+        }
+    })
+}
+
+/// The cell `lat`/`lon` in degrees.
+fn cell(lat: &str, lon: &str) -> Result<(f64, f64), sqlx::Error> {
+    match (lat.parse::<f64>(), lon.parse::<f64>()) {
+        (Ok(lat), Ok(lon)) if lat.is_finite() && lon.is_finite() => Ok((lat, lon)),
+        _ => Err(sqlx::Error::Decode(
+            format!("invalid cell {},{}", lat, lon).into(),
+        )),
+    }
+}
+
+/// A box around the cell `lat`/`lon` holding every address within
+/// `MATCH_RADIUS_METERS` of it, for a range query on the indexed
+/// `latitude`/`longitude` columns.
+fn match_box(lat: &str, lon: &str) -> Result<gaia_core::geo::Bounds, sqlx::Error> {
+    let (lat, lon) = cell(lat, lon)?;
+    Ok(gaia_core::geo::around(
+        lat,
+        lon,
+        precision::MATCH_RADIUS_METERS,
+    ))
+}
+
+/// The `geocode` table.
+#[derive(Debug, Clone, Copy)]
+pub struct Cache<'a> {
+    pub pool: &'a Arc<Pool<Sqlite>>,
+    /// Keep the provider's raw response with the addresses cached from it.
+    pub store_raw: bool,
+    /// Buffer new addresses in `write_behind` rather than inserting them.
+    pub write_behind: bool,
+    /// Read candidates from the replica, if there is one (see `db::reads`).
+    pub replica: bool,
+}
+
+impl Storage for Cache<'_> {
+    type Error = sqlx::Error;
+
+    async fn candidates(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+    ) -> Result<Vec<Cached>, Self::Error> {
+        let bounds = match_box(lat, lon)?;
+        let rows = sqlx::query_as::<_, Geocode>(
+            "SELECT rowid, lat, lon, address, created_at, schema_version FROM geocode \
+             WHERE latitude BETWEEN ? AND ? \
+             AND (longitude BETWEEN ? AND ? OR longitude BETWEEN ? AND ?) AND namespace = ?",
+        )
+        .bind(bounds.lat.0)
+        .bind(bounds.lat.1)
+        .bind(bounds.lon[0].0)
+        .bind(bounds.lon[0].1)
+        .bind(bounds.lon[1].0)
+        .bind(bounds.lon[1].1)
+        .bind(namespace)
+        .fetch_all(match self.replica {
+            true => db::reads(self.pool),
+            false => self.pool,
+        })
+        .await?;
+
+        let mut upgraded = vec![];
+        let cached = rows
+            .into_iter()
+            .map(|mut g| {
+                if schema::upgrade(&mut g.address.0, g.schema_version) {
+                    upgraded.push((g.rowid, g.address.0.clone()));
+                }
+                Cached {
+                    cell: (g.lat, g.lon),
+                    address: g.address.0,
+                    fetched_at: g.created_at,
+                }
+            })
+            .collect();
+        schema::write_back_later(self.pool.clone(), upgraded);
+        Ok(cached)
+    }
+
+    async fn store(
+        &self,
+        namespace: &str,
+        lat: &str,
+        lon: &str,
+        response: &RadarReverseGeocodeResponse,
+        replace: bool,
+        now: i64,
+    ) -> Result<(), Self::Error> {
+        // drop exactly the entries a normal lookup would have served
+        let stale = match replace {
+            false => vec![],
+            true => {
+                let bounds = match_box(lat, lon)?;
+                let (cell_lat, cell_lon) = cell(lat, lon)?;
+                sqlx::query_as::<_, (i64, compression::Stored<RadarAddress>)>(
+                    "SELECT rowid, address FROM geocode WHERE latitude BETWEEN ? AND ? \
+                     AND (longitude BETWEEN ? AND ? OR longitude BETWEEN ? AND ?) \
+                     AND namespace = ?",
+                )
+                .bind(bounds.lat.0)
+                .bind(bounds.lat.1)
+                .bind(bounds.lon[0].0)
+                .bind(bounds.lon[0].1)
+                .bind(bounds.lon[1].0)
+                .bind(bounds.lon[1].1)
+                .bind(namespace)
+                .fetch_all(&**self.pool)
+                .await?
+                .into_iter()
+                .filter(|(_, a)| match (a.latitude, a.longitude) {
+                    (Some(lat), Some(lon)) => {
+                        geo::distance_meters(lat, lon, cell_lat, cell_lon)
+                            < precision::MATCH_RADIUS_METERS
+                    }
+                    _ => false,
+                })
+                .map(|(rowid, _)| rowid)
+                .collect::<Vec<_>>()
+            }
+        };
+
+        // the cell is replaced whole or not at all
+        let mut tx = self.pool.begin().await?;
+        for rowid in stale.iter() {
+            sqlx::query("DELETE FROM geocode WHERE rowid = ?")
+                .bind(rowid)
+                .execute(&mut *tx)
+                .await?;
+        }
+        if replace {
+            tracing::info!("refresh replaced {} cached entries", stale.len());
+        }
+
+        let raw_id = match self.store_raw && !response.addresses.is_empty() {
+            true => Some(
+                sqlx::query("INSERT INTO geocode_raw(response, fetched_at) VALUES (?, ?)")
+                    .bind(&response.raw)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?
+                    .last_insert_rowid(),
+            ),
+            false => None,
+        };
+
+        let new = response
+            .addresses
+            .iter()
+            .map(|address| NewAddress {
+                lat,
+                lon,
+                namespace,
+                address,
+                raw_id,
+            })
+            .collect::<Vec<_>>();
+        let write_behind = self.write_behind && !replace;
+        if !write_behind {
+            crate::insert_addresses(&mut *tx, &new).await?;
+        }
+        tx.commit().await?;
+        if write_behind {
+            write_behind::push(&new);
+        }
+        Ok(())
+    }
+}
+
+/// The upstream provider, called on behalf of a lookup's tenant and client.
+#[derive(Debug, Clone, Copy)]
+pub struct Fetch<'a> {
+    pub upstream: &'a Upstream,
+    pub pool: &'a Arc<Pool<Sqlite>>,
+    pub options: &'a LookupOptions,
+}
+
+impl Provider for Fetch<'_> {
+    type Error = UpstreamError;
+
+    async fn reverse(
+        &self,
+        lat: &str,
+        lon: &str,
+    ) -> Result<RadarReverseGeocodeResponse, Self::Error> {
+        let caller = Caller {
+            tenant: self.options.tenant.as_deref(),
+            client: self.options.client,
+        };
+        let mut response = self
+            .upstream
+            .reverse_geocode(self.pool, lat, lon, caller)
+            .await?;
+        tenants::record_upstream_call(self.pool.clone(), self.options.tenant.clone());
+        for address in &mut response.addresses {
+            schema::fresh(address);
+        }
+        Ok(response)
+    }
+}
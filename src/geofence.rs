@@ -0,0 +1,331 @@
+//! Named circles and polygons, and which of them a point falls in.
+//!
+//! Geofences belong to the tenant that created them, and are only seen,
+//! changed and checked against by it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{
+    db,
+    geo::{distance_meters, ring_contains},
+    params,
+    tenants::Tenant,
+    units::Unit,
+};
+
+/// Columns of `geofences` read into a `Geofence`.
+const GEOFENCE_COLUMNS: &str = "id, name, shape, created_at, updated_at";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GeofenceShape {
+    /// Everything within `radius` meters of the center (or `units`, in
+    /// requests that give them).
+    Circle { lat: f64, lon: f64, radius: f64 },
+    /// A simple polygon; the ring is closed implicitly.
+    Polygon { points: Vec<Point> },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeofenceRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: GeofenceShape,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Geofence {
+    pub id: i64,
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: sqlx::types::Json<GeofenceShape>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Geofence {
+    /// The geofence with its radius reported in `units`.
+    fn in_units(mut self, units: Unit) -> Self {
+        self.shape = sqlx::types::Json(self.shape.0.map_radius(|r| units.of(r)));
+        self
+    }
+}
+
+impl GeofenceShape {
+    /// The shape with its radius, if it has one, passed through `convert`.
+    pub fn map_radius(self, convert: impl Fn(f64) -> f64) -> Self {
+        match self {
+            GeofenceShape::Circle { lat, lon, radius } => GeofenceShape::Circle {
+                lat,
+                lon,
+                radius: convert(radius),
+            },
+            polygon => polygon,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let valid_point =
+            |lat: f64, lon: f64| (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon);
+        match self {
+            GeofenceShape::Circle { lat, lon, radius } => {
+                if !valid_point(*lat, *lon) {
+                    return Err("circle center out of range");
+                }
+                if !(radius.is_finite() && *radius > 0.0) {
+                    return Err("radius must be positive");
+                }
+            }
+            GeofenceShape::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err("polygon needs at least 3 points");
+                }
+                if !points.iter().all(|p| valid_point(p.lat, p.lon)) {
+                    return Err("polygon point out of range");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match self {
+            GeofenceShape::Circle {
+                lat: center_lat,
+                lon: center_lon,
+                radius,
+            } => distance_meters(*center_lat, *center_lon, lat, lon) <= *radius,
+            GeofenceShape::Polygon { points } => {
+                let ring = points.iter().map(|p| (p.lon, p.lat)).collect::<Vec<_>>();
+                ring_contains(&ring, lon, lat)
+            }
+        }
+    }
+}
+
+fn database_error(e: sqlx::Error) -> axum::response::Response {
+    tracing::error!("geofence query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+fn not_found() -> axum::response::Response {
+    (StatusCode::NOT_FOUND, Json(json!("geofence not found"))).into_response()
+}
+
+async fn fetch_geofence(
+    pool: &Pool<Sqlite>,
+    tenant: &Tenant,
+    id: i64,
+) -> Result<Option<Geofence>, sqlx::Error> {
+    sqlx::query_as::<_, Geofence>(&format!(
+        "SELECT {} FROM geofences WHERE id = ? AND tenant IS ?",
+        GEOFENCE_COLUMNS
+    ))
+    .bind(id)
+    .bind(&tenant.name)
+    .fetch_optional(pool)
+    .await
+}
+
+/// The tenant's geofences, in creation order.
+async fn fetch_geofences(
+    pool: &Pool<Sqlite>,
+    tenant: &Tenant,
+) -> Result<Vec<Geofence>, sqlx::Error> {
+    sqlx::query_as::<_, Geofence>(&format!(
+        "SELECT {} FROM geofences WHERE tenant IS ? ORDER BY id",
+        GEOFENCE_COLUMNS
+    ))
+    .bind(&tenant.name)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_geofences(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    match fetch_geofences(&pool, &tenant).await {
+        Ok(geofences) => (
+            StatusCode::OK,
+            Json(
+                geofences
+                    .into_iter()
+                    .map(|g| g.in_units(units))
+                    .collect::<Vec<_>>(),
+            ),
+        )
+            .into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn get_geofence(
+    Path(id): Path<i64>,
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    match fetch_geofence(&pool, &tenant, id).await {
+        Ok(Some(geofence)) => (StatusCode::OK, Json(geofence.in_units(units))).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn post_geofence(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Json(mut req): Json<GeofenceRequest>,
+) -> impl IntoResponse {
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    req.shape = req.shape.map_radius(|r| units.in_meters(r));
+    if let Err(e) = req.shape.validate() {
+        return (StatusCode::BAD_REQUEST, Json(json!(e))).into_response();
+    }
+
+    let now = db::now();
+    let id = match sqlx::query(
+        "INSERT INTO geofences(name, shape, created_at, updated_at, tenant) \
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&req.name)
+    .bind(json!(req.shape))
+    .bind(now)
+    .bind(now)
+    .bind(&tenant.name)
+    .execute(&*pool)
+    .await
+    {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => return database_error(e),
+    };
+
+    match fetch_geofence(&pool, &tenant, id).await {
+        Ok(Some(geofence)) => (StatusCode::CREATED, Json(geofence.in_units(units))).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn put_geofence(
+    Path(id): Path<i64>,
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Json(mut req): Json<GeofenceRequest>,
+) -> impl IntoResponse {
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    req.shape = req.shape.map_radius(|r| units.in_meters(r));
+    if let Err(e) = req.shape.validate() {
+        return (StatusCode::BAD_REQUEST, Json(json!(e))).into_response();
+    }
+
+    match sqlx::query(
+        "UPDATE geofences SET name = ?, shape = ?, updated_at = ? WHERE id = ? AND tenant IS ?",
+    )
+    .bind(&req.name)
+    .bind(json!(req.shape))
+    .bind(db::now())
+    .bind(id)
+    .bind(&tenant.name)
+    .execute(&*pool)
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => return not_found(),
+        Ok(_) => {}
+        Err(e) => return database_error(e),
+    }
+
+    match fetch_geofence(&pool, &tenant, id).await {
+        Ok(Some(geofence)) => (StatusCode::OK, Json(geofence.in_units(units))).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn delete_geofence(
+    Path(id): Path<i64>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query("DELETE FROM geofences WHERE id = ? AND tenant IS ?")
+        .bind(id)
+        .bind(&tenant.name)
+        .execute(&*pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => not_found(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn get_geofences_check(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (lat, lon) = match params::point(&params) {
+        Ok(point) => point,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+
+    match fetch_geofences(&pool, &tenant).await {
+        Ok(geofences) => (
+            StatusCode::OK,
+            Json(
+                geofences
+                    .into_iter()
+                    .filter(|g| g.shape.contains(lat, lon))
+                    .map(|g| g.in_units(units))
+                    .collect::<Vec<_>>(),
+            ),
+        )
+            .into_response(),
+        Err(e) => database_error(e),
+    }
+}
@@ -0,0 +1,258 @@
+//! A compatibility facade for the Google Geocoding API response format, so
+//! applications hardcoded against `maps.googleapis.com` can be pointed at gaia.
+//!
+//! Only reverse geocoding (`latlng=`) is supported; `language=` localizes
+//! country and state names as `lang=` does. As with Google, errors are
+//! reported through the `status` field of a 200 response.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    flags::{self, Flag},
+    geo_reverse, http_cache, localize, maintenance, precision,
+    tenants::Tenant,
+    upstream::{Upstream, UpstreamError},
+    LookupOptions, RadarAddress,
+};
+
+#[derive(Serialize, Debug, Default)]
+pub struct GoogleGeocodeResponse {
+    pub results: Vec<GoogleResult>,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GoogleResult {
+    pub address_components: Vec<AddressComponent>,
+    pub formatted_address: String,
+    pub geometry: Geometry,
+    pub types: Vec<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AddressComponent {
+    pub long_name: String,
+    pub short_name: String,
+    pub types: Vec<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Geometry {
+    pub location: LatLng,
+    pub location_type: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LatLng {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+impl GoogleGeocodeResponse {
+    fn error(status: &'static str, message: &str) -> Self {
+        GoogleGeocodeResponse {
+            results: vec![],
+            status,
+            error_message: Some(message.to_string()),
+        }
+    }
+}
+
+impl From<&RadarAddress> for GoogleResult {
+    fn from(address: &RadarAddress) -> Self {
+        let mut components = vec![];
+        let mut push = |long: &Option<String>, short: &Option<String>, types: Vec<&'static str>| {
+            if let Some(long) = long {
+                components.push(AddressComponent {
+                    long_name: long.clone(),
+                    short_name: short.clone().unwrap_or_else(|| long.clone()),
+                    types,
+                });
+            }
+        };
+        push(&address.number, &None, vec!["street_number"]);
+        push(&address.street, &None, vec!["route"]);
+        push(&address.city, &None, vec!["locality", "political"]);
+        push(
+            &address.county,
+            &None,
+            vec!["administrative_area_level_2", "political"],
+        );
+        push(
+            &address.state,
+            &address.state_code,
+            vec!["administrative_area_level_1", "political"],
+        );
+        push(
+            &address.country,
+            &address.country_code,
+            vec!["country", "political"],
+        );
+        push(&address.postal_code, &None, vec!["postal_code"]);
+
+        let (types, location_type) = match address.layer.as_deref() {
+            Some("address") => (vec!["street_address"], "ROOFTOP"),
+            Some("street") => (vec!["route"], "GEOMETRIC_CENTER"),
+            Some("postalCode") => (vec!["postal_code"], "APPROXIMATE"),
+            Some("locality") => (vec!["locality", "political"], "APPROXIMATE"),
+            Some("county") => (
+                vec!["administrative_area_level_2", "political"],
+                "APPROXIMATE",
+            ),
+            Some("state") => (
+                vec!["administrative_area_level_1", "political"],
+                "APPROXIMATE",
+            ),
+            Some("country") => (vec!["country", "political"], "APPROXIMATE"),
+            _ => (vec![], "APPROXIMATE"),
+        };
+
+        GoogleResult {
+            address_components: components,
+            formatted_address: address.formatted_address.clone().unwrap_or_default(),
+            geometry: Geometry {
+                location: LatLng {
+                    lat: address.latitude.unwrap_or_default(),
+                    lng: address.longitude.unwrap_or_default(),
+                },
+                location_type,
+            },
+            types,
+        }
+    }
+}
+
+pub async fn get_geocode_json(
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Result<Tenant, (StatusCode, Json<serde_json::Value>)>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    let tenant = match tenant {
+        Ok(tenant) => tenant,
+        Err((StatusCode::TOO_MANY_REQUESTS, _)) => {
+            return Json(GoogleGeocodeResponse::error(
+                "OVER_QUERY_LIMIT",
+                "daily quota exceeded",
+            ))
+            .into_response()
+        }
+        Err((StatusCode::UNAUTHORIZED, _)) => {
+            return Json(GoogleGeocodeResponse::error(
+                "REQUEST_DENIED",
+                "the provided API key is invalid",
+            ))
+            .into_response()
+        }
+        Err(_) => {
+            return Json(GoogleGeocodeResponse::error(
+                "UNKNOWN_ERROR",
+                "failed to check API key",
+            ))
+            .into_response()
+        }
+    };
+    let latlng = match params.get("latlng") {
+        Some(latlng) => latlng,
+        None if params.contains_key("address") || params.contains_key("place_id") => {
+            return Json(GoogleGeocodeResponse::error(
+                "INVALID_REQUEST",
+                "only reverse geocoding (latlng) is supported",
+            ))
+            .into_response()
+        }
+        None => {
+            return Json(GoogleGeocodeResponse::error(
+                "INVALID_REQUEST",
+                "missing latlng",
+            ))
+            .into_response()
+        }
+    };
+    let (lat, lon) = match latlng
+        .split_once(',')
+        .map(|(lat, lon)| (lat.trim().parse::<f64>(), lon.trim().parse::<f64>()))
+    {
+        Some((Ok(lat), Ok(lon))) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => (lat, lon),
+        _ => {
+            return Json(GoogleGeocodeResponse::error(
+                "INVALID_REQUEST",
+                "invalid latlng",
+            ))
+            .into_response()
+        }
+    };
+    let result_types = params
+        .get("result_type")
+        .map(|t| t.split('|').map(String::from).collect::<Vec<_>>());
+
+    let geocodes = match geo_reverse(
+        precision::round(lat, precision::default()),
+        precision::round_lon(lon, precision::default()),
+        pool,
+        upstream.clone(),
+        &LookupOptions {
+            cache_only: upstream.offline
+                || maintenance::active()
+                || !flags::enabled(Flag::Upstream),
+            namespace: tenant.namespace(),
+            tenant: tenant.name,
+            client: tenant.client,
+            lang: params
+                .get("language")
+                .and_then(|language| localize::parse(language).ok()),
+            ..Default::default()
+        },
+    )
+    .await
+    {
+        Ok(geocodes) => geocodes,
+        Err(e) => {
+            tracing::error!("google facade lookup failed: {}", e);
+            let status = match e {
+                UpstreamError::RateLimited(_) => "OVER_QUERY_LIMIT",
+                _ => "UNKNOWN_ERROR",
+            };
+            return Json(GoogleGeocodeResponse::error(status, &e.to_string())).into_response();
+        }
+    };
+
+    let results = geocodes
+        .iter()
+        .map(|g| GoogleResult::from(&g.address))
+        .filter(|r| match &result_types {
+            Some(wanted) => r.types.iter().any(|t| wanted.iter().any(|w| w == t)),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    // a filter that left nothing answers ZERO_RESULTS, which mustn't be reused
+    let caching = match results.is_empty() {
+        true => http_cache::no_store(),
+        false => http_cache::headers(&geocodes, false),
+    };
+    (
+        caching,
+        Json(GoogleGeocodeResponse {
+            status: if results.is_empty() {
+                "ZERO_RESULTS"
+            } else {
+                "OK"
+            },
+            results,
+            error_message: None,
+        }),
+    )
+        .into_response()
+}
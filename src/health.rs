@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{breaker::CircuitState, maintenance, upstream::Upstream};
+
+/// Liveness plus a summary of dependencies. Returns 503 only when the database
+/// is unreachable; an open upstream circuit is reported as `degraded` since
+/// cached lookups keep working, and maintenance mode as `maintenance`.
+pub async fn get_health(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> impl IntoResponse {
+    let database_ok = sqlx::query("SELECT 1").execute(&*pool).await.is_ok();
+    let circuit = upstream.breaker.state();
+
+    let maintenance = maintenance::current();
+
+    let (status, code) = match (database_ok, circuit) {
+        (false, _) => ("error", StatusCode::SERVICE_UNAVAILABLE),
+        _ if maintenance.enabled => ("maintenance", StatusCode::OK),
+        (true, CircuitState::Closed) => ("ok", StatusCode::OK),
+        (true, _) => ("degraded", StatusCode::OK),
+    };
+
+    (
+        code,
+        Json(json!({
+            "status": status,
+            "database": if database_ok { "ok" } else { "error" },
+            "upstream": {
+                "circuit": circuit.to_string(),
+            },
+            "maintenance": maintenance,
+        })),
+    )
+}
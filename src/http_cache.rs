@@ -0,0 +1,118 @@
+//! `Cache-Control` and `Age` on reverse geocode responses, so browsers and
+//! HTTP caches in front of gaia can reuse an answer instead of asking again.
+//!
+//! An answer may be reused for `HTTP_CACHE_MAX_AGE_SECS` (default 86400),
+//! cut short to the least any of its addresses has left to live in the cache
+//! when the cache expires entries (`CACHE_TTL_DAYS`, or the address's layer's
+//! `CACHE_TTL_LAYER_DAYS`); `Age` is how long ago the oldest of them was
+//! fetched.
+//! `HTTP_CACHE_VISIBILITY` is `public` or `private`, by default `private`
+//! once `TENANTS` is set so a shared cache can't serve one tenant's answers
+//! to another or let requests skip their quota. `HTTP_CACHE_STALE_SECS`
+//! adds `stale-while-revalidate` for caches that support it.
+//!
+//! Empty answers and refreshes are marked `no-store`, so a miss isn't cached
+//! in place of the address it will have later. `HTTP_CACHE_MAX_AGE_SECS=0`
+//! sends no caching headers at all.
+
+use std::sync::OnceLock;
+
+use axum::{
+    http::{header, HeaderName, HeaderValue},
+    response::AppendHeaders,
+};
+
+use crate::{config, db, janitor, tenants, GeocodeResponse};
+
+#[derive(Debug)]
+struct Settings {
+    max_age: i64,
+    public: bool,
+    stale: i64,
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let visibility = if tenants::configured() {
+            "private"
+        } else {
+            "public"
+        };
+        Settings {
+            max_age: config::var("HTTP_CACHE_MAX_AGE_SECS", 86400i64).max(0),
+            public: match config::var("HTTP_CACHE_VISIBILITY", String::from(visibility)).as_str() {
+                "public" => true,
+                "private" => false,
+                other => panic!("Invalid HTTP_CACHE_VISIBILITY: {}", other),
+            },
+            stale: config::var("HTTP_CACHE_STALE_SECS", 0i64).max(0),
+        }
+    })
+}
+
+/// Read the `HTTP_CACHE_*` settings.
+pub fn check() {
+    settings();
+}
+
+pub type Headers = AppendHeaders<Vec<(HeaderName, HeaderValue)>>;
+
+/// The caching headers for an answer of `geocodes`; `refresh` for one that
+/// replaced what was cached.
+pub fn headers(geocodes: &[GeocodeResponse], refresh: bool) -> Headers {
+    let settings = settings();
+    if settings.max_age == 0 {
+        return AppendHeaders(vec![]);
+    }
+    if geocodes.is_empty() || refresh {
+        return no_store();
+    }
+    let mut headers = vec![];
+    // a peer's answer doesn't say how old it is
+    let oldest = geocodes
+        .iter()
+        .map(|g| g.fetched_at)
+        .collect::<Option<Vec<_>>>()
+        .and_then(|fetched| fetched.into_iter().min());
+    let now = db::now();
+    let age = oldest.map(|fetched| (now - fetched).max(0));
+    let max_age = geocodes
+        .iter()
+        .filter_map(|g| {
+            let ttl = janitor::layer_ttl(g.address.layer.as_deref());
+            let fetched = g.fetched_at.filter(|_| ttl > 0)?;
+            Some(ttl - (now - fetched).max(0))
+        })
+        .fold(settings.max_age, i64::min)
+        .max(0);
+
+    let mut directives = vec![
+        String::from(if settings.public { "public" } else { "private" }),
+        format!("max-age={}", max_age),
+    ];
+    if settings.stale > 0 {
+        directives.push(format!("stale-while-revalidate={}", settings.stale));
+    }
+    if let Ok(value) = HeaderValue::from_str(&directives.join(", ")) {
+        headers.push((header::CACHE_CONTROL, value));
+    }
+    if let Some(age) = age {
+        headers.push((header::AGE, HeaderValue::from(age)));
+    }
+    if tenants::configured() {
+        headers.push((header::VARY, HeaderValue::from_static("x-api-key")));
+    }
+    AppendHeaders(headers)
+}
+
+/// For answers that mustn't be reused.
+pub fn no_store() -> Headers {
+    if settings().max_age == 0 {
+        return AppendHeaders(vec![]);
+    }
+    AppendHeaders(vec![(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("no-store"),
+    )])
+}
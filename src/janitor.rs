@@ -0,0 +1,278 @@
+//! Periodic cleanup of the geocode cache: rows past `CACHE_TTL_DAYS`, rows
+//! that can never be served (missing coordinates or an unparseable address)
+//! and stored raw responses no cache row refers to any more.
+//!
+//! It also enforces the retention policy for everything else gaia keeps.
+//! `RETENTION_DAYS` applies to every table below, and `RETENTION_<TABLE>_DAYS`
+//! (e.g. `RETENTION_QUERY_STATS_DAYS=30`) overrides it for one of them; zero
+//! keeps rows forever. For the cache itself `RETENTION_GEOCODE_DAYS` is the
+//! same setting as `CACHE_TTL_DAYS`.
+//!
+//! Some layers go stale much slower than others, so `CACHE_TTL_LAYER_DAYS`
+//! gives them their own TTL as a comma-separated list of `layer=days`, e.g.
+//! `CACHE_TTL_LAYER_DAYS=address=365,locality=1825`; zero keeps that layer
+//! forever. `CACHE_TTL_EMPTY_DAYS` (default `CACHE_TTL_DAYS`) is for cached
+//! answers that found nothing, which only forward lookups, autocompletes and
+//! place searches keep (see `forward`, `autocomplete` and `places`).
+//!
+//! Deletes run in small batches so no single statement holds the write lock
+//! for long.
+
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{config, db, maintenance, metrics};
+
+#[derive(Debug, Clone)]
+struct Settings {
+    interval: Duration,
+    batch_size: i64,
+    /// Zero disables expiry; orphaned rows are still removed.
+    ttl: i64,
+    /// `(layer, seconds)` overriding `ttl` for addresses of that layer.
+    layer_ttls: Vec<(String, i64)>,
+    /// For cached answers with no addresses.
+    empty_ttl: i64,
+    /// `(table, column, seconds)` for each of `RETAINED`; zero seconds keeps
+    /// rows forever.
+    retention: Vec<(&'static str, &'static str, i64)>,
+}
+
+/// Tables with a retention policy besides the cache, and the column holding
+/// when each row was recorded.
+const RETAINED: &[(&str, &str)] = &[
+    ("geocode_raw", "fetched_at"),
+    ("query_stats", "hour"),
+    ("watch_changes", "detected_at"),
+    ("tenant_usage", "day"),
+    ("upstream_calls", "called_at"),
+    ("jobs", "created_at"),
+    ("dry_run_misses", "last_seen"),
+    ("forward_geocode", "created_at"),
+    ("place_search", "created_at"),
+    ("point_context", "created_at"),
+    ("autocomplete", "created_at"),
+];
+
+impl Settings {
+    fn from_env() -> Self {
+        let default_days = config::var("RETENTION_DAYS", 0i64);
+        let days = |table: &str, default: i64| {
+            config::var(&format!("RETENTION_{}_DAYS", table.to_uppercase()), default)
+        };
+        let ttl_days = days("geocode", config::var("CACHE_TTL_DAYS", default_days));
+        Settings {
+            interval: Duration::from_secs(config::var("CACHE_JANITOR_INTERVAL_SECS", 3600).max(1)),
+            batch_size: config::var("CACHE_JANITOR_BATCH_SIZE", 500i64).max(1),
+            ttl: ttl_days * 86400,
+            layer_ttls: layer_ttls(),
+            empty_ttl: config::var("CACHE_TTL_EMPTY_DAYS", ttl_days) * 86400,
+            retention: RETAINED
+                .iter()
+                .map(|&(table, column)| (table, column, days(table, default_days) * 86400))
+                .collect(),
+        }
+    }
+}
+
+/// `CACHE_TTL_LAYER_DAYS`, in seconds.
+fn layer_ttls() -> Vec<(String, i64)> {
+    let configured = std::env::var("CACHE_TTL_LAYER_DAYS").unwrap_or_default();
+    configured
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            // layers end up in SQL below, so keep them to plain names
+            match entry.split_once('=').and_then(|(layer, days)| {
+                let layer = layer.trim();
+                let days = days.trim().parse::<i64>().ok().filter(|d| *d >= 0)?;
+                layer
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric())
+                    .then(|| (layer.to_string(), days * 86400))
+            }) {
+                Some(ttl) => ttl,
+                None => panic!("Invalid CACHE_TTL_LAYER_DAYS: {}", entry),
+            }
+        })
+        .collect()
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(Settings::from_env)
+}
+
+/// `CACHE_TTL_DAYS` and `CACHE_TTL_LAYER_DAYS` in seconds, so lookups can
+/// pass over what the janitor hasn't swept yet.
+pub fn ttls() -> (i64, Vec<(String, i64)>) {
+    let settings = settings();
+    (settings.ttl, settings.layer_ttls.clone())
+}
+
+/// How long a cached address of `layer` lives in seconds, zero if forever.
+pub fn layer_ttl(layer: Option<&str>) -> i64 {
+    let settings = settings();
+    layer
+        .and_then(|layer| settings.layer_ttls.iter().find(|(l, _)| l == layer))
+        .map_or(settings.ttl, |&(_, ttl)| ttl)
+}
+
+/// How long a cached answer with no addresses lives in seconds, zero if
+/// forever.
+pub fn empty_ttl() -> i64 {
+    settings().empty_ttl
+}
+
+/// Read the TTL and `RETENTION_*` settings.
+pub fn check() {
+    settings();
+}
+
+/// Compressed addresses are blobs, which `json_valid` can't see into.
+const ORPHANED: &str = "lat IS NULL OR lon IS NULL OR address IS NULL \
+                        OR (typeof(address) != 'blob' AND NOT json_valid(address))";
+const ORPHANED_RAW: &str = "id NOT IN (SELECT raw_id FROM geocode WHERE raw_id IS NOT NULL)";
+
+pub fn spawn(pool: Arc<Pool<Sqlite>>) {
+    let settings = settings().clone();
+    tracing::info!("cache janitor: {:?}", settings);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(settings.interval).await;
+            if maintenance::active() {
+                continue;
+            }
+            if let Err(e) = sweep(&settings, &pool).await {
+                tracing::warn!("cache janitor failed: {}", e);
+            }
+        }
+    });
+}
+
+async fn sweep(settings: &Settings, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let orphaned = delete_batched(pool, "geocode", ORPHANED, None, settings.batch_size).await?;
+    let mut expired = 0;
+    for (layer, ttl) in &settings.layer_ttls {
+        if *ttl > 0 {
+            expired += delete_batched(
+                pool,
+                "geocode",
+                &format!("layer = '{}' AND COALESCE(created_at, 0) < ?", layer),
+                Some(db::now() - ttl),
+                settings.batch_size,
+            )
+            .await?;
+        }
+    }
+    if settings.ttl > 0 {
+        let overridden = settings
+            .layer_ttls
+            .iter()
+            .map(|(layer, _)| format!("'{}'", layer))
+            .collect::<Vec<_>>()
+            .join(", ");
+        expired += delete_batched(
+            pool,
+            "geocode",
+            &format!(
+                "COALESCE(layer, '') NOT IN ({}) AND COALESCE(created_at, 0) < ?",
+                overridden
+            ),
+            Some(db::now() - settings.ttl),
+            settings.batch_size,
+        )
+        .await?;
+    }
+    for &(table, column, keep) in &settings.retention {
+        if keep <= 0 {
+            continue;
+        }
+        let cutoff = db::now() - keep;
+        if table == "geocode_raw" {
+            // cache rows outlive the response they were parsed from
+            sqlx::query(
+                "UPDATE geocode SET raw_id = NULL \
+                 WHERE raw_id IN (SELECT id FROM geocode_raw WHERE fetched_at < ?)",
+            )
+            .bind(cutoff)
+            .execute(pool)
+            .await?;
+        }
+        let removed = delete_batched(
+            pool,
+            table,
+            &format!("{} < ?", column),
+            Some(cutoff),
+            settings.batch_size,
+        )
+        .await?;
+        metrics::increment_by("gaia_retention_removed_total", &[("table", table)], removed);
+        if removed > 0 {
+            tracing::info!("retention removed {} rows from {}", removed, table);
+        }
+    }
+
+    // after the geocode deletes, which are what orphan raw responses
+    let orphaned_raw =
+        delete_batched(pool, "geocode_raw", ORPHANED_RAW, None, settings.batch_size).await?;
+
+    metrics::increment_by(
+        "gaia_cache_janitor_removed_total",
+        &[("reason", "orphaned")],
+        orphaned,
+    );
+    metrics::increment_by(
+        "gaia_cache_janitor_removed_total",
+        &[("reason", "expired")],
+        expired,
+    );
+    metrics::increment_by(
+        "gaia_cache_janitor_removed_total",
+        &[("reason", "orphaned_raw")],
+        orphaned_raw,
+    );
+    if orphaned + expired + orphaned_raw > 0 {
+        tracing::info!(
+            "cache janitor removed {} expired and {} orphaned rows, {} raw responses",
+            expired,
+            orphaned,
+            orphaned_raw
+        );
+    }
+    Ok(())
+}
+
+/// Delete rows of `table` matching `condition` until none are left,
+/// `batch_size` at a time, yielding between batches so request traffic can
+/// get the lock.
+async fn delete_batched(
+    pool: &Pool<Sqlite>,
+    table: &str,
+    condition: &str,
+    bind: Option<i64>,
+    batch_size: i64,
+) -> Result<u64, sqlx::Error> {
+    let sql = format!(
+        "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE {} LIMIT ?)",
+        condition
+    );
+    let mut removed = 0;
+    loop {
+        let mut query = sqlx::query(&sql);
+        if let Some(bind) = bind {
+            query = query.bind(bind);
+        }
+        let deleted = query.bind(batch_size).execute(pool).await?.rows_affected();
+        removed += deleted;
+        if deleted < batch_size as u64 {
+            return Ok(removed);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,535 @@
+//! Asynchronous bulk lookups, for batches too big to answer in one response.
+//!
+//! `POST /api/v0/jobs` takes the same body as the bulk endpoint, up to
+//! `JOB_MAX_BODY_BYTES` (default 64 MiB) and `JOB_MAX_ITEMS` (default
+//! 1000000), and answers 202 with the job straight away. `GET
+//! /api/v0/jobs/:id` reports its status; once it is `done`, `GET
+//! /api/v0/jobs/:id/result` downloads the per-item results as JSON lines
+//! (`format=jsonl`, the default) or CSV (`format=csv`), gzipped with
+//! `gzip=true`. Results are kept for `JOB_RESULT_TTL_HOURS` (default 24) and
+//! answer 410 after that.
+//!
+//! A submission with an `Idempotency-Key` header creates at most one job per
+//! key and tenant: retrying it answers 200 with the job the first attempt
+//! created instead of starting another, for as long as that job is kept.
+//! Reusing a key for a different body or query is a 422.
+//!
+//! Jobs run on the instance that accepted them. One still running when that
+//! instance stops is marked failed when it starts again. In cluster mode an
+//! instance knows its jobs by `CLUSTER_NODE_ID` and leaves the others' alone,
+//! so only instances started with the ID they had before recover theirs.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, MatchedPath, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{
+    bulk::{self, BulkItem},
+    config, db,
+    flags::{self, Flag},
+    metrics, negotiate, panics, params, precision, server,
+    tenants::Tenant,
+    units::Unit,
+    upstream::Upstream,
+    LookupOptions,
+};
+
+#[derive(Debug)]
+struct Settings {
+    max_body_bytes: usize,
+    max_items: usize,
+    /// Seconds a finished job's result stays downloadable.
+    result_ttl: i64,
+}
+
+/// Read the job size limits and result TTL.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        max_body_bytes: config::var("JOB_MAX_BODY_BYTES", 64 * 1024 * 1024usize),
+        max_items: config::var("JOB_MAX_ITEMS", 1_000_000usize),
+        result_ttl: config::var("JOB_RESULT_TTL_HOURS", 24i64) * 3600,
+    })
+}
+
+/// The body limit for job submissions, replacing axum's default.
+pub fn body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(settings().max_body_bytes)
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: String,
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    /// `queued`, `running`, `done` or `failed`.
+    pub status: String,
+    pub items: i64,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub error: Option<String>,
+}
+
+/// A job with what it was submitted for.
+#[derive(FromRow, Debug)]
+struct Submitted {
+    fingerprint: Option<String>,
+    #[sqlx(flatten)]
+    job: Job,
+}
+
+const JOB_COLUMNS: &str = "id, tenant, status, items, created_at, finished_at, expires_at, error";
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("job query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, Json(json!("job not found"))).into_response()
+}
+
+/// Jobs this process is running.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a job in `RUNNING` for as long as it is held, however it ends.
+struct Running;
+
+impl Running {
+    fn start() -> Self {
+        RUNNING.fetch_add(1, Ordering::Relaxed);
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wait for the jobs this process is running to finish.
+pub async fn drain() {
+    while RUNNING.load(Ordering::Relaxed) > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Fail jobs a previous run of this node (`node`, in cluster mode) left
+/// unfinished, then keep dropping expired results to reclaim their space.
+/// After an upgrade the previous process finishes its own jobs, so they are
+/// left alone.
+pub fn spawn(pool: Arc<Pool<Sqlite>>, node: Option<String>) {
+    let recover = !server::inherited();
+    tokio::spawn(async move {
+        let recovered = match recover {
+            false => Ok(None),
+            true => sqlx::query(
+                "UPDATE jobs SET status = 'failed', error = 'interrupted by a restart', \
+                 finished_at = ? WHERE status IN ('queued', 'running') \
+                 AND (? IS NULL OR node = ?)",
+            )
+            .bind(db::now())
+            .bind(&node)
+            .bind(&node)
+            .execute(&*pool)
+            .await
+            .map(Some),
+        };
+        match recovered {
+            Ok(Some(done)) if done.rows_affected() > 0 => {
+                tracing::warn!("marked {} interrupted jobs failed", done.rows_affected())
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("failed to recover interrupted jobs: {}", e),
+        }
+        loop {
+            if let Err(e) = sqlx::query(
+                "UPDATE jobs SET result = NULL WHERE expires_at <= ? AND result IS NOT NULL",
+            )
+            .bind(db::now())
+            .execute(&*pool)
+            .await
+            {
+                tracing::warn!("failed to drop expired job results: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(600)).await;
+        }
+    });
+}
+
+/// The results as gzipped JSON lines, the form they are stored in.
+fn artifact(results: &[BulkItem]) -> std::io::Result<Vec<u8>> {
+    let mut out = GzEncoder::new(vec![], Compression::default());
+    for item in results {
+        serde_json::to_writer(&mut out, item)?;
+        out.write_all(b"\n")?;
+    }
+    out.finish()
+}
+
+async fn run(
+    pool: Arc<Pool<Sqlite>>,
+    upstream: Arc<Upstream>,
+    id: String,
+    data: Vec<Value>,
+    precision: usize,
+    units: Unit,
+    options: LookupOptions,
+) {
+    let _running = Running::start();
+    if let Err(e) = sqlx::query("UPDATE jobs SET status = 'running' WHERE id = ?")
+        .bind(&id)
+        .execute(&*pool)
+        .await
+    {
+        tracing::warn!("failed to start job {}: {}", id, e);
+    }
+    // a panic resolving the items fails the job rather than leaving it running
+    let resolved = tokio::spawn({
+        let (pool, upstream) = (pool.clone(), upstream.clone());
+        async move { bulk::resolve(data, precision, units, &pool, &upstream, &options).await }
+    })
+    .await
+    .map_err(|e| match e.try_into_panic() {
+        Ok(panic) => panics::message(panic.as_ref()).to_string(),
+        Err(e) => e.to_string(),
+    });
+    let (status, error, result) = match resolved.map(|results| artifact(&results)) {
+        Ok(Ok(result)) => ("done", None, Some(result)),
+        Ok(Err(e)) => {
+            tracing::warn!("failed to write result of job {}: {}", id, e);
+            ("failed", Some(e.to_string()), None)
+        }
+        Err(e) => {
+            tracing::error!("job {} failed: {}", id, e);
+            ("failed", Some(e), None)
+        }
+    };
+    let now = db::now();
+    if let Err(e) = sqlx::query(
+        "UPDATE jobs SET status = ?, error = ?, result = ?, finished_at = ?, expires_at = ? \
+         WHERE id = ?",
+    )
+    .bind(status)
+    .bind(error)
+    .bind(result)
+    .bind(now)
+    .bind(now + settings().result_ttl)
+    .bind(&id)
+    .execute(&*pool)
+    .await
+    {
+        tracing::warn!("failed to finish job {}: {}", id, e);
+    }
+    metrics::increment("gaia_jobs_finished_total", &[("status", status)]);
+}
+
+pub async fn post_job(
+    // `/api/v0/jobs` or `/api/v1/jobs`, where the job is found too
+    matched: MatchedPath,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+    body: Result<Json<Vec<Value>>, JsonRejection>,
+) -> impl IntoResponse {
+    if !flags::enabled(Flag::Jobs) {
+        return flags::disabled(Flag::Jobs).into_response();
+    }
+    let settings = settings();
+    let data = match bulk::items(body, settings.max_body_bytes, settings.max_items) {
+        Ok(data) => data,
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let precision = match precision::from_params(&params) {
+        Ok(precision) => precision,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+
+    let idempotency_key = match headers.get("idempotency-key").map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(key)) if !key.is_empty() && key.len() <= 255 => Some(key.to_string()),
+        Some(_) => {
+            return params::bad_request("Idempotency-Key must be 1 to 255 visible characters")
+                .into_response()
+        }
+    };
+
+    let job = Job {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        tenant: tenant.name,
+        status: String::from("queued"),
+        items: data.len() as i64,
+        created_at: db::now(),
+        finished_at: None,
+        expires_at: None,
+        error: None,
+    };
+    let fingerprint = fingerprint(&data, &params);
+    // a concurrent retry loses the race on the unique index and is answered
+    // with the winner's job below
+    let inserted = match sqlx::query(
+        "INSERT INTO jobs(id, tenant, status, items, created_at, idempotency_key, fingerprint, \
+         node) VALUES (?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING",
+    )
+    .bind(&job.id)
+    .bind(&job.tenant)
+    .bind(&job.status)
+    .bind(job.items)
+    .bind(job.created_at)
+    .bind(&idempotency_key)
+    .bind(&fingerprint)
+    .bind(upstream.cluster.node())
+    .execute(&*pool)
+    .await
+    {
+        Ok(done) => done.rows_affected() > 0,
+        Err(e) => return database_error(e),
+    };
+    if !inserted {
+        return replay(
+            &pool,
+            matched.as_str(),
+            &job.tenant,
+            idempotency_key.as_deref(),
+            &fingerprint,
+        )
+        .await;
+    }
+    metrics::increment("gaia_jobs_submitted_total", &[]);
+    tokio::spawn(run(
+        pool,
+        upstream,
+        job.id.clone(),
+        data,
+        precision,
+        units,
+        options,
+    ));
+
+    (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("{}/{}", matched.as_str(), job.id))],
+        Json(job),
+    )
+        .into_response()
+}
+
+/// What a submission asks for, to tell a retry from a different request
+/// reusing its key.
+fn fingerprint(data: &[Value], params: &HashMap<String, String>) -> String {
+    let mut params = params.iter().collect::<Vec<_>>();
+    params.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&json!([data, params])).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+/// The answer to a submission to `jobs` whose `Idempotency-Key` was already
+/// used.
+async fn replay(
+    pool: &Pool<Sqlite>,
+    jobs: &str,
+    tenant: &Option<String>,
+    idempotency_key: Option<&str>,
+    fingerprint: &str,
+) -> Response {
+    match sqlx::query_as::<_, Submitted>(&format!(
+        "SELECT fingerprint, {} FROM jobs WHERE COALESCE(tenant, '') = COALESCE(?, '') \
+         AND idempotency_key = ?",
+        JOB_COLUMNS
+    ))
+    .bind(tenant)
+    .bind(idempotency_key)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(submitted)) if submitted.fingerprint.as_deref() != Some(fingerprint) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!(
+                "Idempotency-Key was already used for a different request"
+            )),
+        )
+            .into_response(),
+        Ok(Some(Submitted { job, .. })) => {
+            metrics::increment("gaia_jobs_idempotent_replays_total", &[]);
+            (
+                StatusCode::OK,
+                [(header::LOCATION, format!("{}/{}", jobs, job.id))],
+                Json(job),
+            )
+                .into_response()
+        }
+        // the conflicting job was removed in between; rare enough to just ask again
+        Ok(None) => (
+            StatusCode::CONFLICT,
+            Json(json!("conflicting submission, try again")),
+        )
+            .into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+/// Jobs are only visible to the tenant that submitted them.
+pub async fn get_job(
+    Path(id): Path<String>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query_as::<_, Job>(&format!(
+        "SELECT {} FROM jobs WHERE id = ? AND tenant IS ?",
+        JOB_COLUMNS
+    ))
+    .bind(&id)
+    .bind(&tenant.name)
+    .fetch_optional(&*pool)
+    .await
+    {
+        Ok(Some(job)) => (StatusCode::OK, Json(job)).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => database_error(e),
+    }
+}
+
+/// JSON lines result as CSV, one row per address found and a row without
+/// address columns for items that found none.
+fn csv(jsonl: &str) -> serde_json::Result<String> {
+    let items = jsonl
+        .lines()
+        .map(serde_json::from_str::<Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(negotiate::csv(&items))
+}
+
+/// The stored result in the requested `format`, gzipped or not.
+fn render(stored: Vec<u8>, format: &str, gzip: bool) -> std::io::Result<Vec<u8>> {
+    if format == "jsonl" && gzip {
+        return Ok(stored);
+    }
+    let mut jsonl = String::new();
+    GzDecoder::new(&stored[..]).read_to_string(&mut jsonl)?;
+    let body = match format {
+        "csv" => csv(&jsonl)?,
+        _ => jsonl,
+    };
+    if !gzip {
+        return Ok(body.into_bytes());
+    }
+    let mut out = GzEncoder::new(vec![], Compression::default());
+    out.write_all(body.as_bytes())?;
+    out.finish()
+}
+
+/// `GET /api/v0/jobs/:id/result?format=jsonl|csv&gzip=`
+pub async fn get_job_result(
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    let (format, content_type) = match params.get("format").map(String::as_str) {
+        None | Some("jsonl") => ("jsonl", "application/x-ndjson"),
+        Some("csv") => ("csv", "text/csv"),
+        Some(other) => {
+            return params::bad_request(&format!("unsupported format {:?}", other)).into_response()
+        }
+    };
+    let gzip = match params::flag(&params, "gzip") {
+        Ok(gzip) => gzip,
+        Err(e) => return e.into_response(),
+    };
+
+    let stored = match sqlx::query_as::<_, (String, Option<i64>, Option<Vec<u8>>)>(
+        "SELECT status, expires_at, result FROM jobs WHERE id = ? AND tenant IS ?",
+    )
+    .bind(&id)
+    .bind(&tenant.name)
+    .fetch_optional(&*pool)
+    .await
+    {
+        Ok(None) => return not_found(),
+        Ok(Some((status, _, _))) if status != "done" => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!(format!("job is {}", status))),
+            )
+                .into_response()
+        }
+        Ok(Some((_, expires_at, Some(stored)))) if expires_at.unwrap_or(0) > db::now() => stored,
+        Ok(Some(_)) => {
+            return (StatusCode::GONE, Json(json!("job result expired"))).into_response()
+        }
+        Err(e) => return database_error(e),
+    };
+
+    let format_name = format.to_string();
+    let rendered = tokio::task::spawn_blocking(move || render(stored, &format_name, gzip))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|rendered| rendered.map_err(|e| e.to_string()));
+    let body = match rendered {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("failed to render result of job {}: {}", id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("failed to read job result")),
+            )
+                .into_response();
+        }
+    };
+    let (content_type, extension) = if gzip {
+        ("application/gzip", format!("{}.gz", format))
+    } else {
+        (content_type, format.to_string())
+    };
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"job-{}.{}\"", id, extension),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
@@ -0,0 +1,204 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{config, metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Spread calls evenly over every key that isn't rate limited.
+    RoundRobin,
+    /// Use the first key until it's rate limited, then the next, and so on.
+    Failover,
+}
+
+#[derive(Debug)]
+pub struct ApiKey {
+    value: String,
+    limited_until: Mutex<Option<Instant>>,
+}
+
+impl ApiKey {
+    pub fn new(value: &str) -> Self {
+        ApiKey {
+            value: value.to_string(),
+            limited_until: Mutex::new(None),
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// A short identifier that's safe to put in logs and metrics.
+    pub fn label(&self) -> String {
+        let tail: String = self
+            .value
+            .chars()
+            .rev()
+            .take(4)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        format!("...{}", tail)
+    }
+
+    pub fn record_request(&self) {
+        metrics::increment(
+            "gaia_upstream_key_requests_total",
+            &[("key", &self.label())],
+        );
+    }
+
+    pub fn mark_rate_limited(&self, delay: Duration) {
+        metrics::increment(
+            "gaia_upstream_key_rate_limited_total",
+            &[("key", &self.label())],
+        );
+        let until = Instant::now() + delay;
+        let mut limited = self.limited_until.lock().unwrap();
+        *limited = Some(limited.map_or(until, |t| t.max(until)));
+    }
+
+    /// How long until this key may be used again, or zero if it's usable now.
+    fn remaining(&self) -> Duration {
+        self.limited_until
+            .lock()
+            .unwrap()
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
+    }
+}
+
+/// The provider API keys configured for this deployment.
+#[derive(Debug)]
+pub struct ApiKeys {
+    keys: RwLock<Vec<Arc<ApiKey>>>,
+    rotation: Rotation,
+    cursor: AtomicUsize,
+    /// Where the keys were read from, if a `_FILE` variable was used.
+    source: Option<PathBuf>,
+}
+
+/// Keys may be separated by commas or newlines.
+fn split_keys(raw: &str) -> Vec<String> {
+    raw.split([',', '\n'])
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl ApiKeys {
+    /// Keys come from the comma-separated `RADAR_API_KEYS`, falling back to the
+    /// single `RADAR_API_KEY`; either can be given as a `_FILE` instead.
+    /// `RADAR_API_KEY_ROTATION` is `round-robin` (default) or `failover`.
+    pub fn from_env() -> Self {
+        let (raw, source) = if config::secret_file("RADAR_API_KEYS").is_some()
+            || env::var_os("RADAR_API_KEYS").is_some()
+        {
+            (
+                config::secret("RADAR_API_KEYS"),
+                config::secret_file("RADAR_API_KEYS"),
+            )
+        } else {
+            (
+                config::secret("RADAR_API_KEY"),
+                config::secret_file("RADAR_API_KEY"),
+            )
+        };
+        let rotation = match env::var("RADAR_API_KEY_ROTATION").as_deref() {
+            Ok("failover") => Rotation::Failover,
+            Ok("round-robin") | Err(_) => Rotation::RoundRobin,
+            Ok(other) => panic!("Invalid RADAR_API_KEY_ROTATION: {}", other),
+        };
+        ApiKeys {
+            keys: RwLock::new(
+                split_keys(&raw.unwrap_or_default())
+                    .iter()
+                    .map(|k| Arc::new(ApiKey::new(k)))
+                    .collect(),
+            ),
+            rotation,
+            cursor: AtomicUsize::new(0),
+            source,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().unwrap().is_empty()
+    }
+
+    /// Pick a key that isn't rate limited. If every key is, returns how long
+    /// until the first one frees up.
+    pub fn pick(&self) -> Result<Arc<ApiKey>, Duration> {
+        let keys = self.keys.read().unwrap();
+        if keys.is_empty() {
+            return Err(Duration::MAX);
+        }
+        let start = match self.rotation {
+            Rotation::RoundRobin => self.cursor.fetch_add(1, Ordering::Relaxed),
+            Rotation::Failover => 0,
+        };
+        let mut soonest = Duration::MAX;
+        for i in 0..keys.len() {
+            let key = &keys[(start + i) % keys.len()];
+            let remaining = key.remaining();
+            if remaining.is_zero() {
+                return Ok(key.clone());
+            }
+            soonest = soonest.min(remaining);
+        }
+        Err(soonest)
+    }
+
+    /// Where the keys were read from, if a `_FILE` variable was used.
+    pub fn source(&self) -> Option<&PathBuf> {
+        self.source.as_ref()
+    }
+
+    /// The keys now in the key file, if they differ from the ones in use.
+    pub fn changed(&self, path: &PathBuf) -> Option<Vec<String>> {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) => {
+                tracing::warn!("failed to re-read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let wanted = split_keys(&raw);
+        let keys = self.keys.read().unwrap();
+        (!keys.iter().map(|k| &k.value).eq(wanted.iter())).then_some(wanted)
+    }
+
+    /// Whether `value` is one of the keys in use.
+    pub fn contains(&self, value: &str) -> bool {
+        self.keys.read().unwrap().iter().any(|k| k.value == value)
+    }
+
+    /// Switch to `wanted`, keeping rate limit state for keys that remain.
+    pub fn replace(&self, wanted: &[String]) {
+        let mut keys = self.keys.write().unwrap();
+        *keys = wanted
+            .iter()
+            .map(|value| {
+                keys.iter()
+                    .find(|k| &k.value == value)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(ApiKey::new(value)))
+            })
+            .collect();
+    }
+
+    /// Every key in use.
+    pub fn all(&self) -> Vec<Arc<ApiKey>> {
+        self.keys.read().unwrap().clone()
+    }
+}
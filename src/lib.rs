@@ -0,0 +1,707 @@
+//! gaia, a caching reverse geocoding server. The `gaia` binary is `run`;
+//! `testing` starts an instance inside another service's tests.
+
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use flags::Flag;
+use gaia_core::lookup::{Geocoder, LookupError};
+pub use gaia_core::{GeocodeResponse, RadarAddress, RadarReverseGeocodeResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+use tenants::Tenant;
+use upstream::{Upstream, UpstreamError};
+
+mod alerts;
+mod analytics;
+mod audit;
+mod auth;
+mod autocomplete;
+mod backup;
+mod bench;
+mod boundaries;
+mod breaker;
+mod bulk;
+mod cache;
+mod cluster;
+mod compression;
+mod confidence;
+mod config;
+mod context;
+mod cost;
+mod credentials;
+mod cron;
+mod dashboard;
+mod db;
+mod decompress;
+mod deprecation;
+mod dry_run;
+mod erasure;
+mod export;
+mod fixtures;
+mod flags;
+mod formatting;
+mod forward;
+mod forwarded;
+mod fuzzy;
+mod generate;
+mod geo;
+mod geocoder;
+mod geofence;
+mod google;
+mod health;
+mod http_cache;
+mod janitor;
+mod jobs;
+mod keys;
+mod localize;
+mod maintenance;
+mod merge;
+mod metrics;
+mod mock;
+mod negotiate;
+mod nominatim;
+mod panics;
+mod params;
+mod peers;
+mod places;
+mod precision;
+mod privacy;
+mod profile;
+mod proxy_protocol;
+mod ranking;
+mod refresher;
+mod roads;
+mod route;
+mod routing;
+mod s3;
+mod schema;
+mod server;
+mod shapefile;
+mod shed;
+mod suggest;
+mod tenants;
+pub mod testing;
+mod timeouts;
+mod track;
+mod units;
+mod upstream;
+mod v1;
+mod validate;
+mod watch;
+mod write_behind;
+
+/// The `gaia` command: the server, or the subcommand named in the arguments.
+pub async fn run() {
+    dotenvy::dotenv().ok();
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("--version") {
+        println!(
+            "{}",
+            option_env!("CARGO_PKG_VERSION").unwrap_or_else(|| "unknown")
+        );
+        return;
+    }
+
+    // before anything reads its settings, so every bad one is reported
+    if args.is_empty() {
+        validate::run();
+    }
+
+    if env::var_os("RUST_LOG").is_none() {
+        env::set_var("RUST_LOG", "debug,gaia=debug,tower_http=debug");
+    }
+    if let Some(directives) = privacy::log_directives() {
+        let filter = env::var("RUST_LOG").unwrap_or_default();
+        if !filter.contains("ureq") {
+            env::set_var("RUST_LOG", format!("{},{}", filter, directives));
+        }
+    }
+
+    // subcommands keep stdout for their own output
+    if let Some(command) = args.first() {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+        let result = match command.as_str() {
+            "bench" => bench::run(&args[1..]).await,
+            "export" => export::run(&args[1..], &db::connect().await).await,
+            "generate" => generate::run(&args[1..], &db::connect().await).await,
+            "migrate-cache" => schema::run(&args[1..], &db::connect().await).await,
+            "restore" => backup::restore(&args[1..]).await,
+            other => Err(format!("unknown command {:?}", other)),
+        };
+        if let Err(e) = result {
+            eprintln!("gaia {}: {}", command, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    tracing_subscriber::fmt::init();
+
+    tracing::info!(
+        "Starting gaia v{}",
+        option_env!("CARGO_PKG_VERSION").unwrap_or_else(|| "unknown")
+    );
+
+    deprecation::warn();
+
+    let sqlite_pool = Arc::new(db::connect().await);
+    db::spawn_metrics(sqlite_pool.clone());
+
+    let upstream = Arc::new(Upstream::from_env());
+    credentials::verify(&sqlite_pool, &upstream).await;
+    credentials::watch(sqlite_pool.clone(), upstream.clone());
+    refresher::spawn(sqlite_pool.clone(), upstream.clone());
+    watch::spawn(sqlite_pool.clone(), upstream.clone());
+    alerts::spawn(sqlite_pool.clone(), upstream.clone());
+    janitor::spawn(sqlite_pool.clone());
+    jobs::spawn(
+        sqlite_pool.clone(),
+        upstream.cluster.node().map(String::from),
+    );
+    backup::spawn(sqlite_pool.clone());
+    write_behind::spawn(sqlite_pool.clone());
+    maintenance::spawn();
+    flags::report();
+
+    let public = server::addresses("BIND_ADDRESS")
+        .unwrap_or_else(|| vec![SocketAddr::from(([0, 0, 0, 0], 8081))]);
+    let admin = server::addresses("ADMIN_BIND_ADDRESS");
+    let app = |surface| service(surface, &sqlite_pool, &upstream);
+    let servers = match admin {
+        None => vec![(public, app(Surface::All))],
+        Some(admin) => vec![(public, app(Surface::Public)), (admin, app(Surface::Admin))],
+    };
+    server::serve(servers).await;
+}
+
+/// Which endpoints a listener serves. With `ADMIN_BIND_ADDRESS` set the
+/// admin endpoints and metrics move off the `BIND_ADDRESS` listeners onto
+/// their own; health checks are on both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Surface {
+    All,
+    Public,
+    Admin,
+}
+
+/// `app` with the state its handlers take.
+fn service(surface: Surface, pool: &Arc<Pool<Sqlite>>, upstream: &Arc<Upstream>) -> Router {
+    app(surface)
+        .layer(Extension(pool.clone()))
+        .layer(Extension(upstream.boundaries.clone()))
+        .layer(Extension(upstream.roads.clone()))
+        .layer(Extension(upstream.clone()))
+}
+
+fn app(surface: Surface) -> Router {
+    let mut app = Router::new();
+    if surface != Surface::Admin {
+        app = app.route(
+            "/maps/api/geocode/json",
+            get(google::get_geocode_json)
+                .route_layer(axum::middleware::from_fn(panics::catch))
+                .route_layer(axum::middleware::from_fn(timeouts::limit)),
+        );
+    }
+    if surface != Surface::Public {
+        app = app.route("/metrics", get(metrics::get_metrics));
+    }
+    app.nest(
+        "/api",
+        Router::new()
+            .nest(
+                "/v0",
+                routes(surface)
+                    .route_layer(axum::middleware::from_fn(negotiate::respond))
+                    .layer(axum::middleware::from_fn(deprecation::v0)),
+            )
+            .nest(
+                "/v1",
+                routes(surface)
+                    .route_layer(axum::middleware::from_fn(v1::envelope))
+                    .route_layer(axum::middleware::from_fn(negotiate::respond)),
+            ),
+    )
+    .layer(axum::middleware::from_fn(shed::limit))
+    .layer(axum::middleware::from_fn(forwarded::resolve))
+}
+
+/// The API's endpoints on `surface`, served under each version prefix.
+fn routes(surface: Surface) -> Router {
+    let mut routes = Router::new().route("/health", get(health::get_health));
+    if surface != Surface::Admin {
+        routes = routes.merge(public_routes());
+    }
+    if surface != Surface::Public {
+        routes = routes.merge(admin_routes());
+    }
+    routes
+        .route_layer(axum::middleware::from_fn(panics::catch))
+        .route_layer(axum::middleware::from_fn(maintenance::guard))
+        .route_layer(axum::middleware::from_fn(timeouts::limit))
+}
+
+fn public_routes() -> Router {
+    Router::new()
+        .route("/geocode/reverse", get(get_geo_reverse))
+        .route(
+            "/geocode/reverse/bulk",
+            post(bulk::post_geo_reverse_bulk)
+                .layer(bulk::body_limit())
+                .layer(axum::middleware::from_fn(decompress::inflate)),
+        )
+        .route(
+            "/geocode/reverse/route",
+            post(route::post_geo_reverse_route)
+                .layer(bulk::body_limit())
+                .layer(axum::middleware::from_fn(decompress::inflate)),
+        )
+        .route(
+            "/geocode/reverse/track",
+            post(track::post_geo_reverse_track)
+                .layer(bulk::body_limit())
+                .layer(axum::middleware::from_fn(decompress::inflate)),
+        )
+        .route(
+            "/jobs",
+            post(jobs::post_job)
+                .layer(jobs::body_limit())
+                .layer(axum::middleware::from_fn(decompress::inflate)),
+        )
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/jobs/:id/result", get(jobs::get_job_result))
+        .route(
+            "/geocode/autocomplete",
+            get(autocomplete::get_geocode_autocomplete),
+        )
+        .route("/geocode/forward", get(forward::get_geocode_forward))
+        .route(
+            "/geocode/forward/csv",
+            post(forward::post_geocode_forward_csv)
+                .layer(forward::body_limit())
+                .layer(axum::middleware::from_fn(decompress::inflate)),
+        )
+        .route("/geocode/nearest", get(cache::get_geocode_nearest))
+        .route("/geocode/snap", get(roads::get_snap))
+        .route("/search/places", get(places::get_search_places))
+        .route("/context", get(context::get_context))
+        .route("/cache/search", get(cache::get_cache_search))
+        .route("/cache/bbox", get(cache::get_cache_bbox))
+        .route("/boundaries", get(boundaries::get_boundaries))
+        .route("/address/format", post(formatting::post_address_format))
+        .route(
+            "/geofences",
+            get(geofence::get_geofences).post(geofence::post_geofence),
+        )
+        .route("/geofences/check", get(geofence::get_geofences_check))
+        .route(
+            "/geofences/:id",
+            get(geofence::get_geofence)
+                .put(geofence::put_geofence)
+                .delete(geofence::delete_geofence),
+        )
+}
+
+fn admin_routes() -> Router {
+    Router::new()
+        .route(
+            "/admin/watches",
+            get(watch::get_watches).post(watch::post_watch),
+        )
+        .route(
+            "/admin/watches/:id",
+            get(watch::get_watch).delete(watch::delete_watch),
+        )
+        .route("/admin/watches/:id/changes", get(watch::get_watch_changes))
+        .route("/admin/analytics", get(analytics::get_analytics))
+        .route("/admin/export", get(export::get_export))
+        .route("/admin/backup", get(backup::get_backup))
+        .route("/admin/compression", get(compression::get_compression))
+        .route(
+            "/admin/compression/dictionaries",
+            post(compression::post_compression_dictionary),
+        )
+        .route(
+            "/admin/compression/rewrite",
+            post(compression::post_compression_rewrite),
+        )
+        .route("/admin/erase", post(erasure::post_erase))
+        .route("/admin/audit", get(audit::get_audit))
+        .route("/admin/audit/summary", get(audit::get_audit_summary))
+        .route("/admin/audit/providers", get(audit::get_audit_providers))
+        .route("/admin/tenants/usage", get(tenants::get_tenant_usage))
+        .route("/admin/cost-estimate", get(cost::get_cost_estimate))
+        .route("/admin/dashboard", get(dashboard::get_dashboard))
+        .route("/admin/profile", get(profile::get_profile))
+        .route("/admin/dry-run", get(dry_run::get_dry_run))
+        .route("/admin/flags", get(flags::get_flags))
+        .route("/admin/flags/:name", put(flags::put_flag))
+        .route(
+            "/admin/maintenance",
+            get(maintenance::get_maintenance).put(maintenance::put_maintenance),
+        )
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Geocode {
+    #[serde(skip)]
+    pub rowid: i64,
+    pub lat: String,
+    pub lon: String,
+    pub address: compression::Stored<RadarAddress>,
+    pub created_at: Option<i64>,
+    #[serde(skip)]
+    pub schema_version: i64,
+}
+
+/// Per-request knobs for `geo_reverse`.
+#[derive(Debug, Default, Clone)]
+pub struct LookupOptions {
+    /// Answer from the cache only and never call upstream.
+    pub cache_only: bool,
+    /// Skip the cache, fetch from upstream and replace the cached cell.
+    pub refresh: bool,
+    /// Which slice of the cache to read and write; empty is the shared one.
+    pub namespace: String,
+    /// Whose usage an upstream call counts against.
+    pub tenant: Option<String>,
+    /// The client the lookup is for, as audited.
+    pub client: Option<IpAddr>,
+    /// Drop results with a lower `confidence`.
+    pub min_confidence: f64,
+    /// Language to localize country and state names into.
+    pub lang: Option<String>,
+    /// Buffer what a miss caches rather than inserting it right away (see
+    /// `write_behind`).
+    pub write_behind: bool,
+}
+
+impl LookupOptions {
+    fn from_params(
+        params: &HashMap<String, String>,
+        headers: &HeaderMap,
+        upstream: &Upstream,
+        tenant: &Tenant,
+    ) -> Result<Self, params::ParamError> {
+        let options = LookupOptions {
+            cache_only: params::flag(params, "cacheOnly")?
+                || upstream.offline
+                || maintenance::active()
+                || !flags::enabled(Flag::Upstream),
+            refresh: params::flag(params, "refresh")?,
+            namespace: tenant.namespace(),
+            tenant: tenant.name.clone(),
+            client: tenant.client,
+            min_confidence: confidence::from_params(params)?,
+            lang: localize::from_params(params)?,
+            write_behind: false,
+        };
+        if options.refresh && !auth::is_admin(headers) {
+            return Err(auth::forbidden());
+        }
+        if options.refresh && options.cache_only {
+            return Err(params::bad_request(
+                "refresh needs upstream, which cacheOnly, OFFLINE_MODE, maintenance mode or the upstream flag rule out",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+async fn get_geo_reverse(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> impl IntoResponse {
+    if let Some(coords) = params.get("coords") {
+        return bulk::get_geo_reverse_coords(coords, &params, &headers, &tenant, &pool, &upstream)
+            .await;
+    }
+    let precision = match precision::from_params(&params) {
+        Ok(precision) => precision,
+        Err(e) => return e.into_response(),
+    };
+    let (lat, lon) = match params::point(&params) {
+        Ok((lat, lon)) => (
+            precision::round(lat, precision),
+            precision::round_lon(lon, precision),
+        ),
+        Err(e) => return e.into_response(),
+    };
+    let units = match units::Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+
+    let meta = [
+        ("x-gaia-cell", format!("{},{}", lat, lon)),
+        ("x-gaia-precision", precision.to_string()),
+        (
+            "x-gaia-distance-algorithm",
+            geo::algorithm().name().to_string(),
+        ),
+    ];
+    match geo_reverse(lat, lon, pool, upstream, &options).await {
+        Ok(geocodes) if geocodes.is_empty() && options.cache_only => (
+            StatusCode::NOT_FOUND,
+            meta,
+            http_cache::no_store(),
+            Json(json!("not in cache")),
+        )
+            .into_response(),
+        Ok(mut geocodes) => {
+            units.convert(&mut geocodes);
+            let caching = http_cache::headers(&geocodes, options.refresh);
+            (StatusCode::OK, meta, caching, Json(geocodes)).into_response()
+        }
+        Err(e) => (meta, e).into_response(),
+    }
+}
+
+/// Addresses for the `lat`/`lon` cell, localized, formatted, scored and in
+/// ranking order.
+async fn geo_reverse(
+    lat: String,
+    lon: String,
+    pool: Arc<Pool<Sqlite>>,
+    upstream: Arc<Upstream>,
+    options: &LookupOptions,
+) -> Result<Vec<GeocodeResponse>, UpstreamError> {
+    let mut geocodes = lookup(lat, lon, pool, upstream, options).await?;
+    for geocode in &mut geocodes {
+        if let Some(lang) = &options.lang {
+            localize::apply(&mut geocode.address, lang);
+        }
+        formatting::apply(&mut geocode.address);
+    }
+    confidence::apply(&mut geocodes, options.min_confidence);
+    ranking::sort(&mut geocodes);
+    ranking::dedup(&mut geocodes);
+    Ok(geocodes)
+}
+
+async fn lookup(
+    lat: String,
+    lon: String,
+    pool: Arc<Pool<Sqlite>>,
+    upstream: Arc<Upstream>,
+    options: &LookupOptions,
+) -> Result<Vec<GeocodeResponse>, UpstreamError> {
+    let cache = geocoder::Cache {
+        pool: &pool,
+        store_raw: upstream.store_raw,
+        write_behind: options.write_behind,
+        replica: true,
+    };
+    let fetch = geocoder::Fetch {
+        upstream: &upstream,
+        pool: &pool,
+        options,
+    };
+    let geocoder = Geocoder::new(cache, fetch, geocoder::policy());
+    let namespace = &options.namespace;
+
+    if !options.refresh {
+        let (geocodes, hit_keys) = geocoder
+            .cached(namespace, &lat, &lon, db::now())
+            .await
+            .map_err(database_error)?;
+        analytics::record(pool.clone(), &lat, &lon, !geocodes.is_empty());
+        if !geocodes.is_empty() {
+            tracing::info!("got from cache");
+            record_hits(pool.clone(), namespace, hit_keys);
+            return Ok(geocodes);
+        }
+
+        // jobs already running when maintenance began stop calling upstream too
+        if options.cache_only || maintenance::active() || !flags::enabled(Flag::Upstream) {
+            return Ok(geocodes);
+        }
+
+        // peers only know the shared cache
+        if !upstream.peers.is_empty() && namespace.is_empty() {
+            if let Some(geocodes) = upstream.peers.lookup(&lat, &lon).await {
+                tracing::info!("got from peer");
+                if upstream.peers.write_through {
+                    let addresses = geocodes
+                        .iter()
+                        .map(|g| g.address.clone())
+                        .collect::<Vec<_>>();
+                    // the peer's answer is still good if we can't keep it
+                    if let Err(e) =
+                        cache_addresses(&pool, &lat, &lon, namespace, &addresses, None).await
+                    {
+                        tracing::error!("failed to cache the peer's answer: {}", e);
+                    }
+                }
+                return Ok(geocodes);
+            }
+        }
+    }
+
+    if dry_run::enabled() {
+        dry_run::record(pool.clone(), &lat, &lon, options.tenant.as_deref());
+        return Ok(vec![]);
+    }
+
+    // wait out anyone (here or on another instance) already fetching this cell
+    let _claim = upstream.cluster.claim(&pool, &lat, &lon).await;
+    // what the other fetch cached may not have reached the replica yet
+    let geocoder = Geocoder::new(
+        geocoder::Cache {
+            replica: false,
+            ..cache
+        },
+        fetch,
+        geocoder::policy(),
+    );
+    if !options.refresh {
+        let (geocodes, hit_keys) = geocoder
+            .cached(namespace, &lat, &lon, db::now())
+            .await
+            .map_err(database_error)?;
+        if !geocodes.is_empty() {
+            tracing::info!("got from cache after waiting on another fetch");
+            metrics::increment("gaia_fetch_coalesced_total", &[]);
+            record_hits(pool.clone(), namespace, hit_keys);
+            return Ok(geocodes);
+        }
+    }
+
+    match geocoder
+        .fetch(namespace, &lat, &lon, options.refresh, db::now())
+        .await
+    {
+        Ok(geocodes) => Ok(geocodes),
+        Err(LookupError::Provider(UpstreamError::CircuitOpen)) if !options.refresh => {
+            tracing::warn!("upstream circuit open, serving from cache only");
+            Ok(vec![])
+        }
+        Err(LookupError::Provider(e)) => Err(e),
+        Err(LookupError::Storage(e)) => Err(database_error(e)),
+    }
+}
+
+fn database_error(e: sqlx::Error) -> UpstreamError {
+    tracing::error!("reverse cache query failed: {}", e);
+    UpstreamError::Database
+}
+
+/// Store addresses for the `lat`/`lon` cell they were looked up at.
+async fn cache_addresses(
+    pool: &Pool<Sqlite>,
+    lat: &str,
+    lon: &str,
+    namespace: &str,
+    addresses: &[RadarAddress],
+    raw_id: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    let new = addresses
+        .iter()
+        .map(|address| NewAddress {
+            lat,
+            lon,
+            namespace,
+            address,
+            raw_id,
+        })
+        .collect::<Vec<_>>();
+    insert_addresses(pool, &new).await
+}
+
+/// An address to cache at the `lat`/`lon` cell it was looked up at.
+#[derive(Debug, Clone, Copy)]
+struct NewAddress<'a> {
+    lat: &'a str,
+    lon: &'a str,
+    namespace: &'a str,
+    address: &'a RadarAddress,
+    raw_id: Option<i64>,
+}
+
+/// Insert `new`, with the columns kept from each address, in one statement.
+async fn insert_addresses<'e>(
+    executor: impl sqlx::SqliteExecutor<'e>,
+    new: &[NewAddress<'_>],
+) -> Result<(), sqlx::Error> {
+    if new.is_empty() {
+        return Ok(());
+    }
+    let now = db::now();
+    let mut insert = sqlx::QueryBuilder::<Sqlite>::new(
+        "INSERT INTO geocode(lat, lon, namespace, address, address_format, schema_version, \
+         created_at, raw_id, country_code, state_code, postal_code, city, layer, \
+         formatted_address, latitude, longitude) ",
+    );
+    insert.push_values(new, |mut row, new| {
+        let (address, payload) = (new.address, compression::encode(new.address));
+        let format = payload.format.id();
+        row.push_bind(new.lat)
+            .push_bind(new.lon)
+            .push_bind(new.namespace)
+            .push_bind(payload)
+            .push_bind(format)
+            .push_bind(schema::CURRENT)
+            .push_bind(now)
+            .push_bind(new.raw_id)
+            .push_bind(&address.country_code)
+            .push_bind(&address.state_code)
+            .push_bind(&address.postal_code)
+            .push_bind(&address.city)
+            .push_bind(&address.layer)
+            .push_bind(&address.formatted_address)
+            .push_bind(address.latitude)
+            .push_bind(address.longitude);
+    });
+    insert.build().execute(executor).await?;
+    Ok(())
+}
+
+/// Bump the hit counters the background refresher uses to find popular
+/// entries. Done off the request path so cache hits never wait on a write.
+fn record_hits(pool: Arc<Pool<Sqlite>>, namespace: &str, mut keys: Vec<(String, String)>) {
+    keys.sort();
+    keys.dedup();
+    let namespace = namespace.to_string();
+    tokio::spawn(async move {
+        for (lat, lon) in keys {
+            if let Err(e) = sqlx::query(
+                "UPDATE geocode SET hits = hits + 1, last_hit_at = ? \
+                 WHERE lat = ? AND lon = ? AND namespace = ?",
+            )
+            .bind(db::now())
+            .bind(&lat)
+            .bind(&lon)
+            .bind(&namespace)
+            .execute(&*pool)
+            .await
+            {
+                tracing::warn!("failed to record cache hit: {}", e);
+            }
+        }
+    });
+}
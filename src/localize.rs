@@ -0,0 +1,78 @@
+//! `lang=`: country and state names in a requested language, from the
+//! dataset embedded at build time (`data/place-names.json`), whatever
+//! language the provider answered in. `lang=de` turns "Germany" into
+//! "Deutschland" and `lang=fr` turns "California" into "Californie".
+//!
+//! Names are looked up by `countryCode` and `stateCode`, so only results
+//! carrying codes are localized; a name the dataset lacks in that language
+//! is left as the provider gave it. Region subtags are ignored (`pt-BR` is
+//! `pt`). The `formattedAddress` is rendered after localizing, so it uses the
+//! localized names too.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use serde::Deserialize;
+
+use crate::{params, RadarAddress};
+
+/// Names by language code.
+type Names = HashMap<String, String>;
+
+#[derive(Deserialize, Debug)]
+struct PlaceNames {
+    /// By country code.
+    countries: HashMap<String, Names>,
+    /// By country code, then state code.
+    states: HashMap<String, HashMap<String, Names>>,
+}
+
+fn place_names() -> &'static PlaceNames {
+    static PLACE_NAMES: OnceLock<PlaceNames> = OnceLock::new();
+    PLACE_NAMES.get_or_init(|| {
+        serde_json::from_str(include_str!("../data/place-names.json"))
+            .expect("Invalid data/place-names.json")
+    })
+}
+
+/// `tag`'s primary language, e.g. `pt` for `pt-BR`.
+pub fn parse(tag: &str) -> Result<String, params::ParamError> {
+    let language = tag.split(['-', '_']).next().unwrap_or_default();
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(params::bad_request(
+            "lang must be a language code such as en or de",
+        ));
+    }
+    Ok(language.to_ascii_lowercase())
+}
+
+/// The request's `lang`, if any.
+pub fn from_params(params: &HashMap<String, String>) -> Result<Option<String>, params::ParamError> {
+    params.get("lang").map(|tag| parse(tag)).transpose()
+}
+
+/// Replace `address`'s country and state names with their `lang` names.
+pub fn apply(address: &mut RadarAddress, lang: &str) {
+    let Some(country_code) = address.country_code.as_deref() else {
+        return;
+    };
+    let country_code = country_code.to_uppercase();
+    let place_names = place_names();
+    if let Some(name) = place_names
+        .countries
+        .get(&country_code)
+        .and_then(|names| names.get(lang))
+    {
+        address.country = Some(name.clone());
+    }
+    let Some(state_code) = address.state_code.as_deref() else {
+        return;
+    };
+    if let Some(name) = place_names
+        .states
+        .get(&country_code)
+        .and_then(|states| states.get(&state_code.to_uppercase()))
+        .and_then(|names| names.get(lang))
+    {
+        address.state = Some(name.clone());
+    }
+}
@@ -0,0 +1,156 @@
+//! Maintenance mode, for database migrations and provider key rotation:
+//! lookups are answered from the cache only, and new jobs and other writes
+//! are turned away with a 503 until it is switched off again.
+//!
+//! It starts on with `MAINTENANCE_MODE=true`, and can be switched at runtime
+//! with `PUT /api/v0/admin/maintenance` (`{"enabled": true, "reason":
+//! "..."}`) or by sending the process `SIGUSR2`, which toggles it. While it
+//! is on the background refresher, watches and janitor skip their runs and
+//! health checks report `maintenance`. Reads, bulk lookups and address
+//! formatting keep working.
+
+use std::sync::{Mutex, OnceLock};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{auth::Admin, config, db, metrics};
+
+/// Writes still allowed while in maintenance, by route path below the
+/// version prefix: the first two only read, the last switches it off.
+const ALLOWED_WRITES: &[&str] = &[
+    "/geocode/reverse/bulk",
+    "/address/format",
+    "/admin/maintenance",
+];
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Maintenance {
+    pub enabled: bool,
+    /// When it was last switched on.
+    pub since: Option<i64>,
+    pub reason: Option<String>,
+}
+
+fn state() -> &'static Mutex<Maintenance> {
+    static STATE: OnceLock<Mutex<Maintenance>> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let enabled = config::var("MAINTENANCE_MODE", false);
+        Mutex::new(Maintenance {
+            enabled,
+            since: enabled.then(db::now),
+            reason: enabled.then(|| String::from("MAINTENANCE_MODE")),
+        })
+    })
+}
+
+/// Whether maintenance mode is on.
+pub fn active() -> bool {
+    state().lock().unwrap().enabled
+}
+
+pub fn current() -> Maintenance {
+    state().lock().unwrap().clone()
+}
+
+fn set(enabled: bool, reason: Option<String>) -> Maintenance {
+    let mut state = state().lock().unwrap();
+    if enabled != state.enabled {
+        tracing::warn!(
+            "maintenance mode {}{}",
+            if enabled { "on" } else { "off" },
+            reason
+                .as_deref()
+                .map(|r| format!(": {}", r))
+                .unwrap_or_default()
+        );
+    }
+    *state = Maintenance {
+        enabled,
+        since: if enabled {
+            state.since.filter(|_| state.enabled).or(Some(db::now()))
+        } else {
+            None
+        },
+        reason: reason.filter(|_| enabled),
+    };
+    metrics::set_gauge(
+        "gaia_maintenance_mode",
+        &[],
+        if enabled { 1.0 } else { 0.0 },
+    );
+    state.clone()
+}
+
+/// Toggle maintenance mode on `SIGUSR2`.
+pub fn spawn() {
+    metrics::set_gauge(
+        "gaia_maintenance_mode",
+        &[],
+        if active() { 1.0 } else { 0.0 },
+    );
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                tracing::warn!("cannot listen for SIGUSR2: {}", e);
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            set(!active(), Some(String::from("SIGUSR2")));
+        }
+    });
+}
+
+/// Middleware turning writes away while in maintenance.
+pub async fn guard(request: Request, next: Next) -> Response {
+    let read = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    let allowed = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| ALLOWED_WRITES.iter().any(|r| path.as_str().ends_with(r)));
+    if read || allowed || !active() {
+        return next.run(request).await;
+    }
+    let message = match current().reason {
+        Some(reason) => format!("gaia is in maintenance mode ({}), try again later", reason),
+        None => String::from("gaia is in maintenance mode, try again later"),
+    };
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "60")],
+        Json(json!(message)),
+    )
+        .into_response()
+}
+
+pub async fn get_maintenance(_: Admin) -> impl IntoResponse {
+    Json(current())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceRequest {
+    enabled: bool,
+    reason: Option<String>,
+}
+
+pub async fn put_maintenance(
+    _: Admin,
+    Json(request): Json<MaintenanceRequest>,
+) -> impl IntoResponse {
+    Json(set(request.enabled, request.reason))
+}
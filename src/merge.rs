@@ -0,0 +1,122 @@
+//! Combining two sources of reverse geocodes into one answer.
+//!
+//! `UPSTREAM_MERGE` names a second source asked alongside `UPSTREAM_PROVIDER`
+//! on every miss: `boundaries` (the offline `BOUNDARIES_DIR` polygons, which
+//! add a locality-level result), `mock`, or `radar`. Results from both are
+//! put in one list; an address both returned (see `ranking::same_address`)
+//! appears once, with gaps in the primary's copy filled from the other's.
+//! Every merged result lists the sources that gave it in `sources`.
+//!
+//! The primary source decides the outcome: if it fails the lookup fails, while
+//! a failing second source only leaves its results out. The raw response kept
+//! with `STORE_RAW_RESPONSES` is the primary's.
+
+use crate::{
+    boundaries::{BoundaryMatch, BoundaryResponse},
+    ranking,
+    upstream::Provider,
+    RadarAddress, RadarReverseGeocodeResponse,
+};
+
+/// The configured `UPSTREAM_MERGE` source, if any.
+pub fn from_env(primary: Provider) -> Option<Provider> {
+    let name = std::env::var("UPSTREAM_MERGE").ok()?;
+    let Some(merge) = Provider::parse(&name) else {
+        panic!("Invalid UPSTREAM_MERGE: {}", name);
+    };
+    if merge == primary {
+        panic!("UPSTREAM_MERGE must differ from UPSTREAM_PROVIDER");
+    }
+    Some(merge)
+}
+
+/// The smallest boundary containing `lat`/`lon` as an address.
+pub fn boundary_address(lat: f64, lon: f64, found: BoundaryResponse) -> Option<RadarAddress> {
+    let layer = match &found {
+        BoundaryResponse { city: Some(_), .. } => "locality",
+        BoundaryResponse {
+            county: Some(_), ..
+        } => "county",
+        BoundaryResponse { state: Some(_), .. } => "state",
+        BoundaryResponse {
+            country: Some(_), ..
+        } => "country",
+        _ => return None,
+    };
+    let name = |level: &Option<BoundaryMatch>| level.as_ref().map(|b| b.name.clone());
+    let code = |level: &Option<BoundaryMatch>| level.as_ref().and_then(|b| b.code.clone());
+    let formatted_address = [
+        name(&found.city),
+        code(&found.state).or(name(&found.state)),
+        code(&found.country).or(name(&found.country)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(", ");
+    Some(RadarAddress {
+        city: name(&found.city),
+        county: name(&found.county),
+        state: name(&found.state),
+        state_code: code(&found.state),
+        country: name(&found.country),
+        country_code: code(&found.country),
+        formatted_address: Some(formatted_address),
+        latitude: Some(lat),
+        longitude: Some(lon),
+        layer: Some(layer.to_string()),
+        ..Default::default()
+    })
+}
+
+/// Take fields `address` lacks from `other`.
+fn fill_from(address: &mut RadarAddress, other: &RadarAddress) {
+    let fields = [
+        (&mut address.address_label, &other.address_label),
+        (&mut address.city, &other.city),
+        (&mut address.confidence, &other.confidence),
+        (&mut address.country, &other.country),
+        (&mut address.country_code, &other.country_code),
+        (&mut address.county, &other.county),
+        (&mut address.formatted_address, &other.formatted_address),
+        (&mut address.layer, &other.layer),
+        (&mut address.number, &other.number),
+        (&mut address.postal_code, &other.postal_code),
+        (&mut address.state, &other.state),
+        (&mut address.state_code, &other.state_code),
+        (&mut address.street, &other.street),
+    ];
+    for (mine, theirs) in fields {
+        if mine.is_none() {
+            mine.clone_from(theirs);
+        }
+    }
+}
+
+/// Fold `secondary`'s addresses into `primary`'s.
+pub fn combine(
+    primary: &mut RadarReverseGeocodeResponse,
+    primary_source: Provider,
+    secondary: RadarReverseGeocodeResponse,
+    secondary_source: Provider,
+) {
+    for address in &mut primary.addresses {
+        address.sources = vec![primary_source.name().to_string()];
+    }
+    for mut address in secondary.addresses {
+        match primary
+            .addresses
+            .iter_mut()
+            .find(|a| ranking::same_address(a, &address))
+        {
+            Some(existing) => {
+                fill_from(existing, &address);
+                existing.sources.push(secondary_source.name().to_string());
+            }
+            None => {
+                address.sources = vec![secondary_source.name().to_string()];
+                primary.addresses.push(address);
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! A tiny process-wide metrics registry rendered in the Prometheus text
+//! exposition format at `GET /metrics`.
+//!
+//! Summaries report quantiles over their last `SUMMARY_WINDOW` observations,
+//! so they follow recent behaviour rather than the whole uptime.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::Mutex,
+};
+
+use axum::{http::header, response::IntoResponse};
+
+type Key = (&'static str, String);
+
+static COUNTERS: Mutex<BTreeMap<Key, u64>> = Mutex::new(BTreeMap::new());
+static GAUGES: Mutex<BTreeMap<Key, f64>> = Mutex::new(BTreeMap::new());
+static SUMMARIES: Mutex<BTreeMap<Key, Summary>> = Mutex::new(BTreeMap::new());
+
+const SUMMARY_WINDOW: usize = 1000;
+const QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+#[derive(Debug, Default)]
+struct Summary {
+    recent: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Summary {
+    /// The nearest-rank `q` quantile of the recent observations.
+    fn quantile(sorted: &[f64], q: f64) -> f64 {
+        let rank = (q * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+}
+
+fn key(name: &'static str, labels: &[(&str, &str)]) -> Key {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",");
+    (name, labels)
+}
+
+pub fn increment(name: &'static str, labels: &[(&str, &str)]) {
+    increment_by(name, labels, 1);
+}
+
+pub fn increment_by(name: &'static str, labels: &[(&str, &str)], value: u64) {
+    *COUNTERS
+        .lock()
+        .unwrap()
+        .entry(key(name, labels))
+        .or_default() += value;
+}
+
+pub fn set_gauge(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    GAUGES.lock().unwrap().insert(key(name, labels), value);
+}
+
+/// A counter summed over all its labels.
+pub fn total(name: &str) -> u64 {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((n, _), _)| *n == name)
+        .map(|(_, v)| v)
+        .sum()
+}
+
+/// Record one observation of a summary, e.g. a latency in seconds.
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut summaries = SUMMARIES.lock().unwrap();
+    let summary = summaries.entry(key(name, labels)).or_default();
+    if summary.recent.len() == SUMMARY_WINDOW {
+        summary.recent.pop_front();
+    }
+    summary.recent.push_back(value);
+    summary.sum += value;
+    summary.count += 1;
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let mut write = |kind: &str, entries: Vec<(Key, String)>| {
+        let mut last = "";
+        for ((name, labels), value) in entries {
+            if name != last {
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                last = name;
+            }
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    };
+    write(
+        "counter",
+        COUNTERS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_string()))
+            .collect(),
+    );
+    write(
+        "gauge",
+        GAUGES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_string()))
+            .collect(),
+    );
+    let mut last = "";
+    for ((name, labels), summary) in SUMMARIES.lock().unwrap().iter() {
+        if *name != last {
+            let _ = writeln!(out, "# TYPE {} summary", name);
+            last = name;
+        }
+        let mut sorted = summary.recent.iter().copied().collect::<Vec<_>>();
+        sorted.sort_by(f64::total_cmp);
+        let separator = if labels.is_empty() { "" } else { "," };
+        if !sorted.is_empty() {
+            for q in QUANTILES {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}{}quantile=\"{}\"}} {}",
+                    name,
+                    labels,
+                    separator,
+                    q,
+                    Summary::quantile(&sorted, *q)
+                );
+            }
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, summary.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, summary.count);
+    }
+    out
+}
+
+pub async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(),
+    )
+}
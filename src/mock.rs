@@ -0,0 +1,182 @@
+//! `UPSTREAM_PROVIDER=mock`: made-up addresses computed from the coordinates
+//! instead of a provider call, for integration tests and local development
+//! without a Radar key. The same coordinates always give the same address,
+//! the same forward query the same place (and the same completions), the
+//! same place search the same places and the same point the same context.
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{places::Place, RadarAddress, RadarReverseGeocodeResponse};
+
+const STREETS: &[&str] = &[
+    "Main St",
+    "Oak Ave",
+    "Maple Dr",
+    "Cedar Ln",
+    "Elm St",
+    "Park Pl",
+    "Lake Rd",
+    "Hill St",
+    "River Rd",
+    "Pine Ct",
+    "Washington Ave",
+    "Church St",
+];
+const CITIES: &[(&str, &str, &str, &str)] = &[
+    ("Springfield", "Sangamon County", "Illinois", "IL"),
+    ("Riverton", "Fremont County", "Wyoming", "WY"),
+    ("Fairview", "Williamson County", "Tennessee", "TN"),
+    ("Georgetown", "Scott County", "Kentucky", "KY"),
+    ("Greenville", "Pitt County", "North Carolina", "NC"),
+    ("Madison", "Dane County", "Wisconsin", "WI"),
+];
+
+/// The mock provider's answer for `lat`/`lon`.
+pub fn reverse_geocode(lat: &str, lon: &str) -> RadarReverseGeocodeResponse {
+    let (latitude, longitude) = (lat.parse::<f64>().ok(), lon.parse::<f64>().ok());
+    // keyed on the ~10m cell so nearby lookups agree, as a real provider would
+    let cell = match (latitude, longitude) {
+        (Some(lat), Some(lon)) => format!("{:.4},{:.4}", lat, lon),
+        _ => format!("{},{}", lat, lon),
+    };
+    let digest = Sha256::digest(cell.as_bytes());
+    let pick = |i: usize, n: usize| usize::from(digest[i]) % n;
+
+    let number = (u16::from_be_bytes([digest[0], digest[1]]) % 9899 + 1).to_string();
+    let street = STREETS[pick(2, STREETS.len())];
+    let (city, county, state, state_code) = CITIES[pick(3, CITIES.len())];
+    let postal_code = format!(
+        "{:05}",
+        u32::from_be_bytes([0, digest[4], digest[5], digest[6]]) % 100000
+    );
+    let address = RadarAddress {
+        address_label: Some(format!("{} {}", number, street)),
+        city: Some(city.to_string()),
+        confidence: Some(String::from("exact")),
+        country: Some(String::from("United States")),
+        country_code: Some(String::from("US")),
+        county: Some(county.to_string()),
+        formatted_address: Some(format!(
+            "{} {}, {}, {} {} US",
+            number, street, city, state_code, postal_code
+        )),
+        latitude,
+        layer: Some(String::from("address")),
+        longitude,
+        number: Some(number),
+        postal_code: Some(postal_code),
+        state: Some(state.to_string()),
+        state_code: Some(state_code.to_string()),
+        street: Some(street.to_string()),
+        sources: vec![],
+    };
+
+    let raw = json!({"meta": {"code": 200, "mock": true}, "addresses": [address]});
+    RadarReverseGeocodeResponse {
+        meta: raw["meta"].clone(),
+        addresses: vec![address],
+        raw,
+    }
+}
+
+/// The mock provider's answer for a forward `query`: somewhere in the
+/// contiguous US picked from the query, with whatever address that spot has
+/// in `reverse_geocode`, so forward and reverse lookups agree.
+pub fn forward_geocode(query: &str) -> Vec<RadarAddress> {
+    let normalized = query.split_whitespace().collect::<Vec<_>>().join(" ");
+    if normalized.is_empty() {
+        return vec![];
+    }
+    let digest = Sha256::digest(normalized.to_lowercase().as_bytes());
+    let fraction = |i: usize| f64::from(u16::from_be_bytes([digest[i], digest[i + 1]])) / 65535.0;
+    let lat = format!("{:.4}", 25.0 + fraction(0) * 24.0);
+    let lon = format!("{:.4}", -124.0 + fraction(2) * 57.0);
+    reverse_geocode(&lat, &lon).addresses
+}
+
+/// The mock provider's completions of `query`: the places a few longer
+/// queries starting with it would find.
+pub fn autocomplete(query: &str) -> Vec<RadarAddress> {
+    let query = query.split_whitespace().collect::<Vec<_>>().join(" ");
+    if query.is_empty() {
+        return vec![];
+    }
+    STREETS
+        .iter()
+        .take(5)
+        .flat_map(|street| forward_geocode(&format!("{} {}", query, street)))
+        .collect()
+}
+
+/// The mock provider's places in each of `categories` (comma-separated)
+/// within `radius` metres of `lat`/`lon`: a few of each, strewn around it.
+pub fn places(lat: &str, lon: &str, categories: &str, radius: u32) -> Vec<Place> {
+    let (Ok(latitude), Ok(longitude)) = (lat.parse::<f64>(), lon.parse::<f64>()) else {
+        return vec![];
+    };
+    let mut places = vec![];
+    for category in categories.split(',').filter(|c| !c.is_empty()) {
+        let digest = Sha256::digest(format!("{},{},{}", lat, lon, category).as_bytes());
+        for i in 0..usize::from(digest[0] % 4) {
+            let fraction = |j: usize| f64::from(digest[1 + i * 3 + j]) / 255.0;
+            // within the radius: up to it north or south, and east or west
+            let reach = f64::from(radius) * fraction(0) / 111_320.0;
+            let angle = std::f64::consts::TAU * fraction(1);
+            let (place_lat, place_lon) = (
+                latitude + reach * angle.sin(),
+                longitude + reach * angle.cos() / latitude.to_radians().cos().max(0.01),
+            );
+            let address =
+                reverse_geocode(&format!("{:.4}", place_lat), &format!("{:.4}", place_lon))
+                    .addresses
+                    .remove(0);
+            let title = category
+                .split(['-', '_'])
+                .map(|word| {
+                    let mut chars = word.chars();
+                    chars.next().map_or(String::new(), |first| {
+                        first.to_uppercase().chain(chars).collect()
+                    })
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            places.push(Place {
+                id: Some(hex::encode(&digest[i * 4..i * 4 + 12])),
+                name: format!(
+                    "{} {}",
+                    address.street.as_deref().unwrap_or_default(),
+                    title
+                ),
+                categories: vec![category.to_string()],
+                chain: None,
+                latitude: place_lat,
+                longitude: place_lon,
+                formatted_address: address.formatted_address,
+                distance: 0.0,
+            });
+        }
+    }
+    places
+}
+
+/// The mock provider's context for `lat`/`lon`, from the state its address
+/// is in and a time zone by longitude.
+pub fn context(lat: &str, lon: &str) -> Value {
+    let address = reverse_geocode(lat, lon).addresses.remove(0);
+    let time_zone = match lon.parse::<f64>().unwrap_or(0.0) {
+        lon if lon < -115.0 => ("America/Los_Angeles", "PST", -28800),
+        lon if lon < -101.0 => ("America/Denver", "MST", -25200),
+        lon if lon < -87.0 => ("America/Chicago", "CST", -21600),
+        _ => ("America/New_York", "EST", -18000),
+    };
+    json!({
+        "country": {"code": "US", "name": address.country, "type": "country", "flag": "🇺🇸"},
+        "state": {"code": address.state_code, "name": address.state, "type": "state"},
+        "postalCode": {"code": address.postal_code, "name": address.postal_code, "type": "postalCode"},
+        "dma": {"code": "501", "name": format!("{} Area", address.city.as_deref().unwrap_or_default()), "type": "dma"},
+        "timeZone": {"id": time_zone.0, "code": time_zone.1, "utcOffset": time_zone.2},
+        "geofences": [],
+        "place": null,
+    })
+}
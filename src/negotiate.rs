@@ -0,0 +1,406 @@
+//! Content negotiation: the `Accept` header picks how a response is sent.
+//!
+//! Every JSON endpoint can also answer in MessagePack
+//! (`application/msgpack`). Endpoints returning geocodes (reverse, bulk,
+//! nearest, cache search and bbox) can in addition answer as a GeoJSON
+//! FeatureCollection (`application/geo+json`) with a Point per address, or
+//! as CSV (`text/csv`) with a row per address in the columns job results
+//! use. JSON stays the default, for requests without `Accept` and for
+//! `*/*`; quality values are honoured. A request accepting none of what the
+//! endpoint offers is a 406.
+//!
+//! Only successful responses are converted; errors are always JSON.
+//! Endpoints with a format of their own (job results, exports, backups)
+//! send it unchanged.
+
+use axum::{
+    body::{self, Body},
+    extract::{MatchedPath, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+
+/// Route paths (below the version prefix) whose results are geocodes.
+const GEOCODE_ROUTES: &[&str] = &[
+    "/geocode/reverse",
+    "/geocode/reverse/bulk",
+    "/geocode/nearest",
+    "/cache/search",
+    "/cache/bbox",
+];
+
+/// CSV columns after the input and status: the `distance` and `confidence`
+/// of each result, then fields of its address.
+pub const CSV_ADDRESS_COLUMNS: &[(&str, &str)] = &[
+    ("formatted_address", "formattedAddress"),
+    ("number", "number"),
+    ("street", "street"),
+    ("city", "city"),
+    ("county", "county"),
+    ("state", "state"),
+    ("state_code", "stateCode"),
+    ("postal_code", "postalCode"),
+    ("country_code", "countryCode"),
+    ("latitude", "latitude"),
+    ("longitude", "longitude"),
+    ("layer", "layer"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    GeoJson,
+    Csv,
+    MessagePack,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::GeoJson => "application/geo+json",
+            Format::Csv => "text/csv",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// Whether `media_range` (`text/csv`, `text/*`, `*/*`) accepts this.
+    fn matches(self, media_range: &str) -> bool {
+        let content_type = self.content_type();
+        match media_range {
+            "*/*" => true,
+            "application/x-msgpack" | "application/vnd.msgpack" => self == Format::MessagePack,
+            range => match range.strip_suffix("/*") {
+                Some(kind) => content_type.split('/').next() == Some(kind),
+                None => range == content_type,
+            },
+        }
+    }
+}
+
+/// The format `accept` prefers among `offered`, which lists the endpoint's
+/// formats best first; `None` if it accepts none of them.
+pub fn choose(accept: Option<&str>, offered: &[Format]) -> Option<Format> {
+    let Some(accept) = accept.filter(|a| !a.trim().is_empty()) else {
+        return offered.first().copied();
+    };
+    let mut best: Option<(f32, Format)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media_range = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|p| p.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality <= 0.0 {
+            continue;
+        }
+        let Some(format) = offered.iter().copied().find(|f| f.matches(&media_range)) else {
+            continue;
+        };
+        if best.is_none_or(|(q, _)| quality > q) {
+            best = Some((quality, format));
+        }
+    }
+    best.map(|(_, format)| format)
+}
+
+fn csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => csv_escape(s),
+        other => csv_escape(&other.to_string()),
+    }
+}
+
+/// `text` as a CSV field, quoted if it needs to be.
+pub fn csv_escape(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// A geocode and, for bulk results, the item it answers.
+struct Row<'a> {
+    item: Option<&'a Value>,
+    result: Option<&'a Value>,
+}
+
+/// The geocodes in a response body: the elements of an array, of a page's
+/// `results`, or the body itself; bulk items give one row per result and a
+/// row without a result when they found none.
+fn rows(body: &Value) -> Vec<Row<'_>> {
+    let elements = match body {
+        Value::Array(elements) => elements.iter().collect::<Vec<_>>(),
+        Value::Object(fields) if fields.contains_key("input") => vec![body],
+        Value::Object(fields) => match fields.get("results") {
+            Some(Value::Array(results)) => results.iter().collect(),
+            _ => vec![body],
+        },
+        _ => vec![],
+    };
+    let mut rows = vec![];
+    for element in elements {
+        if element.get("input").is_none() {
+            rows.push(Row {
+                item: None,
+                result: Some(element),
+            });
+            continue;
+        }
+        match element["results"].as_array() {
+            Some(results) if !results.is_empty() => rows.extend(results.iter().map(|result| Row {
+                item: Some(element),
+                result: Some(result),
+            })),
+            _ => rows.push(Row {
+                item: Some(element),
+                result: None,
+            }),
+        }
+    }
+    rows
+}
+
+const NULL: Value = Value::Null;
+
+/// Bulk items (or bare geocodes) as CSV, one row per address.
+pub fn csv<'a>(items: impl IntoIterator<Item = &'a Value>) -> String {
+    let mut out = String::from("input_lat,input_lon,status,error,distance,confidence");
+    for (column, _) in CSV_ADDRESS_COLUMNS {
+        out.push(',');
+        out.push_str(column);
+    }
+    out.push('\n');
+    for item in items {
+        for row in rows(item) {
+            // a bare geocode's input is the cell it was looked up for
+            let prefix = match row.item {
+                Some(item) => [
+                    &item["input"]["lat"],
+                    &item["input"]["lon"],
+                    &item["status"],
+                    &item["error"],
+                ],
+                None => {
+                    let result = row.result.unwrap_or(&NULL);
+                    [&result["lat"], &result["lon"], &NULL, &NULL]
+                }
+            }
+            .map(csv_field)
+            .join(",");
+            out.push_str(&prefix);
+            match row.result {
+                Some(result) => {
+                    out.push(',');
+                    out.push_str(&csv_field(&result["distance"]));
+                    out.push(',');
+                    out.push_str(&csv_field(&result["confidence"]));
+                    for (_, key) in CSV_ADDRESS_COLUMNS {
+                        out.push(',');
+                        out.push_str(&csv_field(&result["address"][key]));
+                    }
+                }
+                None => out.push_str(&",".repeat(CSV_ADDRESS_COLUMNS.len() + 2)),
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The geocodes in `body` as a FeatureCollection, skipping any without a
+/// position.
+fn geojson(body: &Value) -> Value {
+    let features = rows(body)
+        .into_iter()
+        .filter_map(|row| {
+            let result = row.result?;
+            let address = &result["address"];
+            let (latitude, longitude) = (
+                address["latitude"].as_f64()?,
+                address["longitude"].as_f64()?,
+            );
+            let mut properties = address.clone();
+            for key in ["lat", "lon", "distance", "confidence"] {
+                if let Some(value) = result.get(key) {
+                    properties[key] = value.clone();
+                }
+            }
+            if let Some(item) = row.item {
+                properties["input"] = item["input"].clone();
+            }
+            Some(json!({
+                "type": "Feature",
+                "geometry": {"type": "Point", "coordinates": [longitude, latitude]},
+                "properties": properties,
+            }))
+        })
+        .collect::<Vec<_>>();
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+/// A MessagePack length prefix: the `fix` form up to `fix_max`, then the 8
+/// bit form if the type has one, then the 16 bit form at `marker16` and the
+/// 32 bit form after it.
+fn msgpack_length(
+    out: &mut Vec<u8>,
+    length: usize,
+    (fix, fix_max): (u8, usize),
+    marker8: Option<u8>,
+    marker16: u8,
+) {
+    if length <= fix_max {
+        out.push(fix | length as u8);
+    } else if let Some(marker8) = marker8.filter(|_| length <= 0xff) {
+        out.extend([marker8, length as u8]);
+    } else if length <= 0xffff {
+        out.push(marker16);
+        out.extend((length as u16).to_be_bytes());
+    } else {
+        out.push(marker16 + 1);
+        out.extend((length as u32).to_be_bytes());
+    }
+}
+
+/// `value` encoded as MessagePack.
+pub fn msgpack(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                match u {
+                    0..=0x7f => out.push(u as u8),
+                    _ => {
+                        out.push(0xcf);
+                        out.extend(u.to_be_bytes());
+                    }
+                }
+            } else if let Some(i) = n.as_i64() {
+                match i {
+                    -32..=-1 => out.push(i as u8),
+                    _ => {
+                        out.push(0xd3);
+                        out.extend(i.to_be_bytes());
+                    }
+                }
+            } else {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            msgpack_length(out, s.len(), (0xa0, 31), Some(0xd9), 0xda);
+            out.extend(s.as_bytes());
+        }
+        Value::Array(elements) => {
+            msgpack_length(out, elements.len(), (0x90, 15), None, 0xdc);
+            for element in elements {
+                msgpack(element, out);
+            }
+        }
+        Value::Object(fields) => {
+            msgpack_length(out, fields.len(), (0x80, 15), None, 0xde);
+            for (key, field) in fields {
+                msgpack(&Value::String(key.clone()), out);
+                msgpack(field, out);
+            }
+        }
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"))
+}
+
+/// Middleware sending JSON responses in the format the request accepts.
+pub async fn respond(request: Request, next: Next) -> Response {
+    let geocodes = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| GEOCODE_ROUTES.iter().any(|r| path.as_str().ends_with(r)));
+    let offered: &[Format] = if geocodes {
+        &[
+            Format::Json,
+            Format::GeoJson,
+            Format::Csv,
+            Format::MessagePack,
+        ]
+    } else {
+        &[Format::Json, Format::MessagePack]
+    };
+    let accept = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    let response = next.run(request).await;
+    if !response.status().is_success() || !is_json(response.headers()) {
+        return response;
+    }
+    let Some(format) = choose(accept.as_deref(), offered) else {
+        let offered = offered
+            .iter()
+            .map(|f| f.content_type())
+            .collect::<Vec<_>>()
+            .join(", ");
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            Json(json!(format!("this endpoint can answer with {}", offered))),
+        )
+            .into_response();
+    };
+    if format == Format::Json {
+        return with_vary(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let value = match body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).map_err(|e| e.to_string()))
+    {
+        Ok(value) => value,
+        Err(e) => {
+            tracing::error!("failed to read response body: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!("failed to encode response")),
+            )
+                .into_response();
+        }
+    };
+    let body = match format {
+        Format::GeoJson => geojson(&value).to_string().into_bytes(),
+        Format::Csv => csv([&value]).into_bytes(),
+        _ => {
+            let mut out = vec![];
+            msgpack(&value, &mut out);
+            out
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    with_vary(Response::from_parts(parts, Body::from(body)))
+}
+
+fn with_vary(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
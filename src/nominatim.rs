@@ -0,0 +1,132 @@
+//! `UPSTREAM_PROVIDER=nominatim`, or a route to it (see `routing`):
+//! OpenStreetMap's Nominatim, at `NOMINATIM_URL` (default the public
+//! instance, `https://nominatim.openstreetmap.org`). It takes no key. The
+//! public instance allows at most one request a second and asks for an
+//! identifying User-Agent, which gaia sends; anything busier wants its own
+//! instance.
+//!
+//! Its places are mapped onto the address shape Radar's use, with the layer
+//! taken from the place's rank.
+
+use serde_json::{json, Value};
+
+use crate::{upstream::UpstreamError, RadarAddress, RadarReverseGeocodeResponse};
+
+#[derive(Debug, Clone)]
+pub struct Nominatim {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl Nominatim {
+    /// Nominatim at `NOMINATIM_URL`, called through `agent`.
+    pub fn from_env(agent: ureq::Agent) -> Self {
+        Nominatim {
+            base_url: std::env::var("NOMINATIM_URL")
+                .unwrap_or_else(|_| String::from("https://nominatim.openstreetmap.org"))
+                .trim_end_matches('/')
+                .to_string(),
+            agent,
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<Value, UpstreamError> {
+        let user_agent = format!(
+            "gaia/{}",
+            option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
+        );
+        self.agent
+            .get(&format!("{}{}", self.base_url, path))
+            .set("User-Agent", &user_agent)
+            .call()?
+            .into_json()
+            .map_err(|e| UpstreamError::Decode(e.to_string()))
+    }
+
+    /// The place at `lat`/`lon`. Blocks; run it off the async threads.
+    pub fn reverse(
+        &self,
+        lat: &str,
+        lon: &str,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        let raw = self.get(&format!(
+            "/reverse?format=jsonv2&addressdetails=1&lat={}&lon={}",
+            lat, lon
+        ))?;
+        // "Unable to geocode" comes back as a 200 with an error field
+        let addresses = match raw.get("error") {
+            Some(_) => vec![],
+            None => address(&raw).into_iter().collect(),
+        };
+        Ok(RadarReverseGeocodeResponse {
+            meta: json!({"code": 200}),
+            addresses,
+            raw,
+        })
+    }
+
+    /// Places matching `query`, best first. Blocks like `reverse`.
+    pub fn search(&self, query: &str) -> Result<Vec<RadarAddress>, UpstreamError> {
+        let query = url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>();
+        let raw = self.get(&format!(
+            "/search?format=jsonv2&addressdetails=1&q={}",
+            query
+        ))?;
+        Ok(raw
+            .as_array()
+            .map(|places| places.iter().filter_map(address).collect())
+            .unwrap_or_default())
+    }
+}
+
+/// A Nominatim place as an address, if it has coordinates.
+fn address(place: &Value) -> Option<RadarAddress> {
+    let latitude = place["lat"].as_str()?.parse::<f64>().ok()?;
+    let longitude = place["lon"].as_str()?.parse::<f64>().ok()?;
+    let parts = &place["address"];
+    let part = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|k| parts[*k].as_str())
+            .map(String::from)
+    };
+    let number = part(&["house_number"]);
+    let street = part(&["road", "pedestrian", "footway"]);
+    let rank = place["place_rank"].as_u64().unwrap_or(0);
+    let layer = match rank {
+        28.. if number.is_some() => "address",
+        26.. => "street",
+        17..=25 => "neighborhood",
+        13..=16 => "locality",
+        9..=12 => "county",
+        5..=8 => "state",
+        _ => "country",
+    };
+    let address_label = match (&number, &street) {
+        (Some(number), Some(street)) => Some(format!("{} {}", number, street)),
+        (None, Some(street)) => Some(street.clone()),
+        _ => place["name"]
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .map(String::from),
+    };
+    Some(RadarAddress {
+        address_label,
+        city: part(&["city", "town", "village", "hamlet", "municipality"]),
+        confidence: None,
+        country: part(&["country"]),
+        country_code: part(&["country_code"]).map(|code| code.to_uppercase()),
+        county: part(&["county"]),
+        formatted_address: place["display_name"].as_str().map(String::from),
+        latitude: Some(latitude),
+        layer: Some(layer.to_string()),
+        longitude: Some(longitude),
+        number,
+        postal_code: part(&["postcode"]),
+        state: part(&["state"]),
+        // ISO 3166-2, e.g. US-NY
+        state_code: part(&["ISO3166-2-lvl4"])
+            .and_then(|code| code.split_once('-').map(|(_, state)| state.to_string())),
+        street,
+        sources: vec![],
+    })
+}
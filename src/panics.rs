@@ -0,0 +1,67 @@
+//! Turning a handler panic into a 500 instead of a dropped connection.
+//!
+//! The response names a request ID, the client's `X-Request-Id` if it sent
+//! one or a random one otherwise, which is also logged with the panic and
+//! returned in `X-Request-Id` so a report can be matched to the log line.
+//! `gaia_panics_total` counts them by route.
+
+use std::{any::Any, panic::AssertUnwindSafe};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::FutureExt;
+use serde_json::json;
+
+use crate::metrics;
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// What a panic said, as far as it can be told.
+pub fn message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Middleware catching panics from everything it wraps.
+pub async fn catch(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let panic = match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+        Ok(response) => return response,
+        Err(panic) => panic,
+    };
+    tracing::error!(
+        "request {} to {} panicked: {}",
+        id,
+        route,
+        message(panic.as_ref())
+    );
+    metrics::increment("gaia_panics_total", &[("route", &route)]);
+    let mut response = (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!(format!("internal error (request {})", id))),
+    )
+        .into_response();
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, id);
+    }
+    response
+}
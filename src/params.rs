@@ -0,0 +1,143 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::{
+    http::{HeaderValue, StatusCode},
+    response::Response,
+    Json,
+};
+use gaia_core::grid;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+
+pub type ParamError = (StatusCode, Json<Value>);
+
+/// The response header carrying the cursor for the next page, on endpoints
+/// whose body is a bare array. Absent on the last page.
+pub const NEXT_CURSOR: &str = "x-gaia-next-cursor";
+
+/// Parse a required query parameter, producing the usual
+/// `missing <name>` / `invalid <name>` 400 responses.
+pub fn required<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<T, ParamError> {
+    match params.get(name).map(|v| v.parse::<T>()) {
+        Some(Ok(v)) => Ok(v),
+        Some(Err(_)) => Err(bad_request(&format!("invalid {}", name))),
+        None => Err(bad_request(&format!("missing {}", name))),
+    }
+}
+
+/// Parse an optional query parameter, falling back to `default` when absent.
+pub fn optional<T: FromStr>(
+    params: &HashMap<String, String>,
+    name: &str,
+    default: T,
+) -> Result<T, ParamError> {
+    match params.get(name) {
+        Some(_) => required(params, name),
+        None => Ok(default),
+    }
+}
+
+/// The point a request is about: `lat` and `lon`, or a UTM or MGRS grid
+/// reference in `utm` or `mgrs` (see `gaia_core::grid`) read into them.
+pub fn point(params: &HashMap<String, String>) -> Result<(f64, f64), ParamError> {
+    let (name, point) = match (params.get("utm"), params.get("mgrs")) {
+        (None, None) => {
+            return Ok((
+                coordinate(params, "lat", 90.0)?,
+                coordinate(params, "lon", 180.0)?,
+            ))
+        }
+        (Some(utm), None) => ("utm", grid::parse_utm(utm)),
+        (None, Some(mgrs)) => ("mgrs", grid::parse_mgrs(mgrs)),
+        (Some(_), Some(_)) => return Err(bad_request("give one of lat and lon, utm or mgrs")),
+    };
+    if params.contains_key("lat") || params.contains_key("lon") {
+        return Err(bad_request("give one of lat and lon, utm or mgrs"));
+    }
+    point.ok_or_else(|| bad_request(&format!("invalid {}", name)))
+}
+
+/// The required degrees `name`, finite and within `range` of zero.
+fn coordinate(params: &HashMap<String, String>, name: &str, range: f64) -> Result<f64, ParamError> {
+    match required::<f64>(params, name)? {
+        value if value.is_finite() && value.abs() <= range => Ok(value),
+        _ => Err(bad_request(&format!(
+            "{} must be between -{} and {}",
+            name, range, range
+        ))),
+    }
+}
+
+pub fn bad_request(message: &str) -> ParamError {
+    (StatusCode::BAD_REQUEST, Json(json!(message)))
+}
+
+/// Parse an optional boolean flag (`true`/`false`/`1`/`0`), defaulting to false.
+pub fn flag(params: &HashMap<String, String>, name: &str) -> Result<bool, ParamError> {
+    match params.get(name).map(String::as_str) {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") | Some("") => Ok(true),
+        Some(_) => Err(bad_request(&format!("invalid {}", name))),
+    }
+}
+
+/// An opaque pagination cursor: the sort key of the last result on a page,
+/// which the next page starts after.
+pub fn encode_cursor<T: Serialize>(key: &T) -> String {
+    hex::encode(serde_json::to_vec(key).expect("cursor keys serialize"))
+}
+
+/// Parse the optional `cursor` parameter written by `encode_cursor`.
+pub fn cursor<T: DeserializeOwned>(
+    params: &HashMap<String, String>,
+) -> Result<Option<T>, ParamError> {
+    let Some(cursor) = params.get("cursor") else {
+        return Ok(None);
+    };
+    hex::decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .map(Some)
+        .ok_or_else(|| bad_request("invalid cursor"))
+}
+
+/// Point `response` at the page after the one ending with `last`, if the page
+/// was full. `key` gives a result's sort key.
+pub fn next_cursor<T, K: Serialize>(
+    response: &mut Response,
+    results: &[T],
+    limit: i64,
+    key: impl Fn(&T) -> K,
+) {
+    let Some(last) = results.last().filter(|_| results.len() as i64 == limit) else {
+        return;
+    };
+    if let Ok(cursor) = HeaderValue::from_str(&encode_cursor(&key(last))) {
+        response.headers_mut().insert(NEXT_CURSOR, cursor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_cursor(cursor: &str) -> HashMap<String, String> {
+        HashMap::from([(String::from("cursor"), String::from(cursor))])
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let key = (String::from("2024-05-01"), 42i64);
+        let parsed: Option<(String, i64)> = cursor(&with_cursor(&encode_cursor(&key))).unwrap();
+        assert_eq!(parsed, Some(key));
+        assert_eq!(cursor::<(i64,)>(&HashMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn only_encoded_cursors_parse() {
+        for raw in ["42", "[42]", "zz", ""] {
+            let (status, _) = cursor::<(i64,)>(&with_cursor(raw)).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", raw);
+        }
+    }
+}
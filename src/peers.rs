@@ -0,0 +1,90 @@
+//! Sibling gaia instances asked on a cache miss before the paid provider, so
+//! regions don't each re-buy the same geocodes.
+//!
+//! `PEERS` is a comma-separated list of base URLs. Peers are queried with
+//! `cacheOnly=true`, so a peer never calls its own provider (or its own peers)
+//! on our behalf.
+
+use std::time::Duration;
+
+use crate::{config, metrics, privacy, GeocodeResponse};
+
+#[derive(Debug, Clone)]
+pub struct Peers {
+    urls: Vec<String>,
+    agent: ureq::Agent,
+    /// `PEER_WRITE_THROUGH`: keep what peers return in the local cache.
+    pub write_through: bool,
+}
+
+impl Peers {
+    pub fn from_env() -> Self {
+        Peers {
+            urls: std::env::var("PEERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|url| url.trim().trim_end_matches('/').to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_millis(config::var("PEER_TIMEOUT_MS", 500)))
+                .build(),
+            write_through: config::var("PEER_WRITE_THROUGH", true),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.urls.is_empty()
+    }
+
+    /// The first non-empty answer from a peer's cache, trying peers in order.
+    pub async fn lookup(&self, lat: &str, lon: &str) -> Option<Vec<GeocodeResponse>> {
+        for peer in &self.urls {
+            let (agent, url) = (
+                self.agent.clone(),
+                format!("{}/api/v0/geocode/reverse", peer),
+            );
+            let (lat, lon) = (lat.to_string(), lon.to_string());
+            let result = tokio::task::spawn_blocking(move || {
+                match agent
+                    .get(&url)
+                    .query("lat", &lat)
+                    .query("lon", &lon)
+                    .query("cacheOnly", "true")
+                    .call()
+                {
+                    Ok(response) => response
+                        .into_json::<Vec<GeocodeResponse>>()
+                        .map_err(|e| e.to_string()),
+                    // the peer's "not in cache"
+                    Err(ureq::Error::Status(404, _)) => Ok(vec![]),
+                    Err(e) => Err(privacy::ureq_error(&e)),
+                }
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+
+            match result {
+                Ok(geocodes) if !geocodes.is_empty() => {
+                    metrics::increment(
+                        "gaia_peer_requests_total",
+                        &[("peer", peer), ("result", "hit")],
+                    );
+                    return Some(geocodes);
+                }
+                Ok(_) => metrics::increment(
+                    "gaia_peer_requests_total",
+                    &[("peer", peer), ("result", "miss")],
+                ),
+                Err(e) => {
+                    tracing::warn!("peer {} lookup failed: {}", peer, e);
+                    metrics::increment(
+                        "gaia_peer_requests_total",
+                        &[("peer", peer), ("result", "error")],
+                    );
+                }
+            }
+        }
+        None
+    }
+}
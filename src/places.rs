@@ -0,0 +1,310 @@
+//! `GET /api/v0/search/places?near=lat,lon&categories=`: places of some kind
+//! near a point ("the nearest gas station"), from the provider's place
+//! search and cached like lookups are.
+//!
+//! `categories` is a comma-separated list of the provider's category names
+//! (`gas-station,ev-charging-station`), `radius` how far to look (default
+//! 1000 metres, at most 10000, in `units`) and `limit` how many places to
+//! answer with (default 10, at most 100). Places come nearest first, each
+//! with its `distance` from `near`.
+//!
+//! Searches are cached per tenant namespace by `near` rounded to
+//! `PLACES_PRECISION` decimal places (default 3, about 100 metres) with the
+//! categories and radius, searching from the rounded point: everyone asking
+//! from the same block shares one provider call. Answers stay fresh for the
+//! `CACHE_TTL_LAYER_DAYS` of the `place` layer, or `CACHE_TTL_DAYS`
+//! (`CACHE_TTL_EMPTY_DAYS` when nothing was found), and are removed after
+//! `RETENTION_PLACE_SEARCH_DAYS`. `cacheOnly` and `refresh` work as they do
+//! for lookups. Only Radar and the mock provider search places.
+
+use std::{collections::HashMap, sync::Arc, sync::OnceLock};
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config, db, dry_run, geo, janitor, metrics, params, precision,
+    tenants::{self, Tenant},
+    units::Unit,
+    upstream::{Caller, Upstream, UpstreamError},
+    LookupOptions,
+};
+
+const MAX_RADIUS_METERS: f64 = 10_000.0;
+const MAX_LIMIT: usize = 100;
+
+/// Read `PLACES_PRECISION`.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> usize {
+    static PRECISION: OnceLock<usize> = OnceLock::new();
+    *PRECISION.get_or_init(|| config::var("PLACES_PRECISION", 3usize))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Place {
+    pub id: Option<String>,
+    pub name: String,
+    pub categories: Vec<String>,
+    /// The brand, for places that belong to one.
+    pub chain: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub formatted_address: Option<String>,
+    /// From the search point, in the request's units; computed per response,
+    /// never stored.
+    #[serde(default)]
+    pub distance: f64,
+}
+
+/// The places of a Radar `/v1/search/places` answer. Places without a name
+/// or a location are skipped.
+pub fn from_radar(raw: &Value) -> Vec<Place> {
+    let places = raw.get("places").and_then(Value::as_array);
+    places
+        .into_iter()
+        .flatten()
+        .filter_map(|place| {
+            let coordinates = place.pointer("/location/coordinates")?.as_array()?;
+            let (longitude, latitude) = (
+                coordinates.first()?.as_f64()?,
+                coordinates.get(1)?.as_f64()?,
+            );
+            let string = |value: &Value| value.as_str().map(String::from);
+            Some(Place {
+                id: place.get("_id").and_then(string),
+                name: place.get("name").and_then(string)?,
+                categories: place
+                    .get("categories")
+                    .and_then(Value::as_array)
+                    .map(|categories| categories.iter().filter_map(string).collect())
+                    .unwrap_or_default(),
+                chain: place.pointer("/chain/name").and_then(string),
+                latitude,
+                longitude,
+                formatted_address: place.get("formattedAddress").and_then(string),
+                distance: 0.0,
+            })
+        })
+        .collect()
+}
+
+fn database_error(e: sqlx::Error) -> UpstreamError {
+    tracing::error!("place search cache query failed: {}", e);
+    UpstreamError::Transport(String::from("database error"))
+}
+
+/// `categories` as cached: trimmed, lowercased, sorted and deduplicated,
+/// or `None` if any isn't a plain category name.
+fn normalized(categories: &str) -> Option<String> {
+    let mut categories = categories
+        .split(',')
+        .map(|c| c.trim().to_lowercase())
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    // they go into the provider's query string as they are
+    if categories.is_empty()
+        || categories.iter().any(|c| {
+            !c.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+    {
+        return None;
+    }
+    categories.sort();
+    categories.dedup();
+    Some(categories.join(","))
+}
+
+/// How long an answer of `places` stays fresh. Zero is forever.
+fn ttl(places: &[Place]) -> i64 {
+    match places.is_empty() {
+        true => janitor::empty_ttl(),
+        false => janitor::layer_ttl(Some("place")),
+    }
+}
+
+/// The fresh cached answer for the search, if there is one.
+async fn cached(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    (lat, lon): (&str, &str),
+    categories: &str,
+    radius: u32,
+) -> Result<Option<Vec<Place>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (sqlx::types::Json<Vec<Place>>, i64)>(
+        "SELECT places, created_at FROM place_search \
+         WHERE namespace = ? AND lat = ? AND lon = ? AND categories = ? AND radius = ?",
+    )
+    .bind(namespace)
+    .bind(lat)
+    .bind(lon)
+    .bind(categories)
+    .bind(radius)
+    .fetch_optional(db::reads(pool))
+    .await?;
+    Ok(row
+        .filter(|(places, created_at)| {
+            gaia_core::matching::fresh(Some(*created_at), ttl(places), db::now())
+        })
+        .map(|(places, _)| places.0))
+}
+
+/// Places in `categories` within `radius` metres of the cell `lat`/`lon`,
+/// from the cache or else the provider.
+async fn search(
+    (lat, lon): (&str, &str),
+    categories: &str,
+    radius: u32,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Upstream,
+    options: &LookupOptions,
+) -> Result<Option<Vec<Place>>, UpstreamError> {
+    let cached = match options.refresh {
+        true => None,
+        false => cached(pool, &options.namespace, (lat, lon), categories, radius)
+            .await
+            .map_err(database_error)?,
+    };
+    metrics::increment(
+        "gaia_place_searches_total",
+        &[("result", if cached.is_some() { "hit" } else { "miss" })],
+    );
+    if cached.is_some() || options.cache_only || dry_run::enabled() {
+        return Ok(cached);
+    }
+    let caller = Caller {
+        tenant: options.tenant.as_deref(),
+        client: options.client,
+    };
+    let places = upstream
+        .search_places(pool, (lat, lon), categories, radius, caller)
+        .await?;
+    tenants::record_upstream_call(pool.clone(), options.tenant.clone());
+    sqlx::query(
+        "INSERT OR REPLACE INTO place_search(namespace, lat, lon, categories, radius, places, \
+         created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&options.namespace)
+    .bind(lat)
+    .bind(lon)
+    .bind(categories)
+    .bind(radius)
+    .bind(json!(places))
+    .bind(db::now())
+    .execute(&**pool)
+    .await
+    .map_err(database_error)?;
+    Ok(Some(places))
+}
+
+/// `GET /api/v0/search/places`.
+pub async fn get_search_places(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+) -> Response {
+    let near = match params::required::<String>(&params, "near") {
+        Ok(near) => near,
+        Err(e) => return e.into_response(),
+    };
+    let (lat, lon) = match near.split_once(',').and_then(|(lat, lon)| {
+        Some((
+            lat.trim().parse::<f64>().ok()?,
+            lon.trim().parse::<f64>().ok()?,
+        ))
+    }) {
+        Some((lat, lon)) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => (lat, lon),
+        _ => return params::bad_request("near must be lat,lon").into_response(),
+    };
+    let categories = match params::required::<String>(&params, "categories") {
+        Ok(categories) => match normalized(&categories) {
+            Some(categories) => categories,
+            None => {
+                return params::bad_request(
+                    "categories must be a comma-separated list of category names",
+                )
+                .into_response()
+            }
+        },
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    let radius = match params::optional::<f64>(&params, "radius", units.of(1000.0)) {
+        Ok(radius) if radius > 0.0 && units.in_meters(radius) <= MAX_RADIUS_METERS => {
+            units.in_meters(radius).ceil() as u32
+        }
+        Ok(_) => {
+            return params::bad_request("radius must be positive and at most 10000 metres")
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<usize>(&params, "limit", 10) {
+        Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 100").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+
+    let cell = (
+        precision::round(lat, settings()),
+        precision::round_lon(lon, settings()),
+    );
+    let places = match search(
+        (&cell.0, &cell.1),
+        &categories,
+        radius,
+        &pool,
+        &upstream,
+        &options,
+    )
+    .await
+    {
+        Ok(Some(places)) => places,
+        Ok(None) if options.cache_only => {
+            return (StatusCode::NOT_FOUND, Json(json!("not in cache"))).into_response()
+        }
+        Ok(None) => vec![],
+        Err(e) => return e.into_response(),
+    };
+
+    // searched from the cell, but measured from where the client is
+    let mut places = places
+        .into_iter()
+        .map(|mut place| {
+            place.distance = geo::distance_meters(lat, lon, place.latitude, place.longitude);
+            place
+        })
+        .filter(|place| place.distance <= f64::from(radius))
+        .collect::<Vec<_>>();
+    places.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    places.truncate(limit);
+    for place in &mut places {
+        place.distance = units.of(place.distance);
+    }
+    (
+        StatusCode::OK,
+        [("x-gaia-distance-algorithm", geo::algorithm().name())],
+        Json(places),
+    )
+        .into_response()
+}
@@ -0,0 +1,63 @@
+//! How precisely lookups take their input coordinates.
+//!
+//! A lookup rounds `lat`/`lon` to `COORDINATE_PRECISION` decimal places
+//! (default 5, about a metre). That rounded pair is the cell: it is what gets
+//! cached, and the distances in a response are measured from it rather than
+//! from the exact input. Any cached address within `MATCH_RADIUS_METERS` of
+//! the cell answers the lookup.
+//!
+//! A request can ask for coarser or finer cells with `precision=`, between
+//! `MIN_COORDINATE_PRECISION` (default 3) and `MAX_COORDINATE_PRECISION`
+//! (default 6). Single lookups report the cell and precision used in the
+//! `X-Gaia-Cell` and `X-Gaia-Precision` headers; bulk items carry a `cell`.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+pub use gaia_core::matching::{round, round_lon, MATCH_RADIUS_METERS};
+
+use crate::{config, params};
+
+#[derive(Debug)]
+struct Settings {
+    default: usize,
+    min: usize,
+    max: usize,
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let settings = Settings {
+            default: config::var("COORDINATE_PRECISION", 5usize),
+            min: config::var("MIN_COORDINATE_PRECISION", 3usize),
+            max: config::var("MAX_COORDINATE_PRECISION", 6usize),
+        };
+        if settings.min > settings.max || settings.max > 8 {
+            panic!("Invalid MIN/MAX_COORDINATE_PRECISION: need MIN <= MAX <= 8");
+        }
+        if !(settings.min..=settings.max).contains(&settings.default) {
+            panic!(
+                "Invalid COORDINATE_PRECISION: must be between MIN and MAX_COORDINATE_PRECISION"
+            );
+        }
+        settings
+    })
+}
+
+/// The configured precision, for lookups no request asked about.
+pub fn default() -> usize {
+    settings().default
+}
+
+/// The request's `precision`, within the configured bounds.
+pub fn from_params(params: &HashMap<String, String>) -> Result<usize, params::ParamError> {
+    let settings = settings();
+    let precision = params::optional(params, "precision", settings.default)?;
+    if !(settings.min..=settings.max).contains(&precision) {
+        return Err(params::bad_request(&format!(
+            "precision must be between {} and {}",
+            settings.min, settings.max
+        )));
+    }
+    Ok(precision)
+}
@@ -0,0 +1,126 @@
+//! Keeping users' coordinates out of logs and analytics. The cache is not
+//! affected: it needs exact coordinates to work.
+//!
+//! `PRIVACY_MODE=truncate` cuts coordinates to `PRIVACY_PRECISION` decimal
+//! places (default 2, roughly 1km); `hash` replaces them with a keyed hash of
+//! the truncated value, so repeat lookups of an area can still be correlated
+//! without revealing where it is. The key is `PRIVACY_HASH_KEY` (or
+//! `PRIVACY_HASH_KEY_FILE`), random per process when unset. The default,
+//! `off`, logs coordinates as they are.
+
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Off,
+    Truncate,
+    Hash,
+}
+
+#[derive(Debug)]
+struct Settings {
+    mode: Mode,
+    precision: usize,
+    hash_key: Vec<u8>,
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        mode: match config::var("PRIVACY_MODE", String::from("off")).as_str() {
+            "off" => Mode::Off,
+            "truncate" => Mode::Truncate,
+            "hash" => Mode::Hash,
+            other => panic!("Invalid PRIVACY_MODE: {}", other),
+        },
+        precision: config::var("PRIVACY_PRECISION", 2usize).min(5),
+        hash_key: config::secret("PRIVACY_HASH_KEY")
+            .map(String::into_bytes)
+            .unwrap_or_else(|| rand::random::<[u8; 32]>().to_vec()),
+    })
+}
+
+/// Decimal places coordinates are cut to, if they are.
+pub fn precision() -> Option<usize> {
+    match settings().mode {
+        Mode::Off => None,
+        Mode::Truncate | Mode::Hash => Some(settings().precision),
+    }
+}
+
+/// A short keyed hash of `value`.
+fn hash(value: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&settings().hash_key).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..8])
+}
+
+/// `lat,lon` as it may appear in a log line.
+pub fn coordinates(lat: &str, lon: &str) -> String {
+    let settings = settings();
+    let truncated = || match (lat.parse::<f64>(), lon.parse::<f64>()) {
+        (Ok(lat), Ok(lon)) => format!(
+            "{:.*},{:.*}",
+            settings.precision, lat, settings.precision, lon
+        ),
+        _ => String::from("?,?"),
+    };
+    match settings.mode {
+        Mode::Off => format!("{},{}", lat, lon),
+        Mode::Truncate => truncated(),
+        Mode::Hash => hash(&truncated()),
+    }
+}
+
+/// A coordinate as it may be kept outside the cache: cut to `precision`
+/// decimal places, or to `PRIVACY_PRECISION` if that is coarser, and under
+/// `hash` mode reported as the hash of the cut pair with an empty `lon`.
+/// Values that aren't numbers are passed through as they are.
+pub fn stored(lat: &str, lon: &str, precision: Option<usize>) -> (String, String) {
+    let precision = match (precision, self::precision()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    let (lat, lon) = match (precision, lat.parse::<f64>(), lon.parse::<f64>()) {
+        (Some(p), Ok(lat), Ok(lon)) => (format!("{:.*}", p, lat), format!("{:.*}", p, lon)),
+        _ => (lat.to_string(), lon.to_string()),
+    };
+    match settings().mode {
+        Mode::Hash => (hash(&format!("{},{}", lat, lon)), String::new()),
+        Mode::Off | Mode::Truncate => (lat, lon),
+    }
+}
+
+/// A ureq error as text. Its own formatting leads with the request URL,
+/// which carries the coordinates, so that is left out unless privacy is off.
+pub fn ureq_error(e: &ureq::Error) -> String {
+    match (settings().mode, e) {
+        (Mode::Off, e) => e.to_string(),
+        (_, ureq::Error::Status(status, _)) => format!("status code {}", status),
+        (_, ureq::Error::Transport(t)) => {
+            let mut text = t.kind().to_string();
+            if let Some(message) = t.message() {
+                text = format!("{}: {}", text, message);
+            }
+            if let Some(source) = std::error::Error::source(t) {
+                text = format!("{}: {}", text, source);
+            }
+            text
+        }
+    }
+}
+
+/// Extra `RUST_LOG` directives: the HTTP client logs full request URLs at
+/// debug level.
+pub fn log_directives() -> Option<&'static str> {
+    match settings().mode {
+        Mode::Off => None,
+        Mode::Truncate | Mode::Hash => Some("ureq=warn"),
+    }
+}
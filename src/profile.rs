@@ -0,0 +1,188 @@
+//! `GET /api/v0/admin/profile?seconds=5&format=`: where the process spends
+//! CPU and memory, taken from a running instance without rebuilding it.
+//!
+//! Every thread's CPU time is read from `/proc` at the start and end of the
+//! window and reported per thread name, busiest first, so a latency spike
+//! can be put down to the async workers, the blocking pool (provider calls,
+//! backups) or SQLite's workers. Memory is the process's resident set, its
+//! peak and the anonymous part of it, which is mostly heap. `format=folded`
+//! gives the CPU as collapsed stacks (`gaia;<thread> <ms>`), which
+//! flamegraph.pl, inferno and speedscope draw directly. Function-level
+//! profiles need a sampling profiler, which isn't built in; this works on
+//! Linux only and answers 501 elsewhere.
+
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{auth::Admin, params};
+
+/// The unit of `/proc/<pid>/stat` times, fixed at 100 on Linux.
+const TICKS_PER_SECOND: f64 = 100.0;
+
+/// CPU ticks used so far by each live thread, by thread id.
+fn thread_ticks() -> std::io::Result<HashMap<u64, (String, u64)>> {
+    let mut threads = HashMap::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let Ok(tid) = entry.file_name().to_string_lossy().parse::<u64>() else {
+            continue;
+        };
+        // the thread may have exited since it was listed
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // `tid (name) state ...`: the name may hold spaces and parentheses
+        let (Some(open), Some(close)) = (stat.find('('), stat.rfind(')')) else {
+            continue;
+        };
+        let name = stat[open + 1..close].to_string();
+        // utime and stime are the 12th and 13th fields after the name
+        let fields = stat[close + 1..].split_whitespace().collect::<Vec<_>>();
+        let ticks = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        if let (Some(user), Some(system)) = (ticks(11), ticks(12)) {
+            threads.insert(tid, (name, user + system));
+        }
+    }
+    Ok(threads)
+}
+
+/// `/proc/self/status` sizes, in bytes.
+fn memory() -> std::io::Result<Memory> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kb| kb * 1024)
+    };
+    Ok(Memory {
+        rss_bytes: field("VmRSS"),
+        peak_rss_bytes: field("VmHWM"),
+        anonymous_bytes: field("RssAnon"),
+    })
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Memory {
+    pub rss_bytes: Option<u64>,
+    pub peak_rss_bytes: Option<u64>,
+    /// Resident memory not backed by a file: the heap, mostly.
+    pub anonymous_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadCpu {
+    pub name: String,
+    pub threads: usize,
+    pub cpu_seconds: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub seconds: u64,
+    pub cpu_seconds: f64,
+    /// Of one core, so it can pass 100.
+    pub cpu_percent: f64,
+    pub threads: Vec<ThreadCpu>,
+    pub memory: Memory,
+}
+
+fn unavailable(e: std::io::Error) -> Response {
+    tracing::warn!("profiling unavailable: {}", e);
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(json!("profiling needs /proc, which only Linux has")),
+    )
+        .into_response()
+}
+
+pub async fn get_profile(_: Admin, Query(params): Query<HashMap<String, String>>) -> Response {
+    let seconds = match params::optional::<u64>(&params, "seconds", 5) {
+        Ok(seconds) if (1..=60).contains(&seconds) => seconds,
+        Ok(_) => return params::bad_request("seconds must be between 1 and 60").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let folded = match params.get("format").map(String::as_str) {
+        None | Some("json") => false,
+        Some("folded") => true,
+        Some(_) => return params::bad_request("format must be json or folded").into_response(),
+    };
+
+    let before = match thread_ticks() {
+        Ok(before) => before,
+        Err(e) => return unavailable(e),
+    };
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let after = match thread_ticks() {
+        Ok(after) => after,
+        Err(e) => return unavailable(e),
+    };
+
+    // threads started during the window count from zero
+    let mut by_name = HashMap::<String, (usize, u64)>::new();
+    for (tid, (name, ticks)) in after {
+        let start = before.get(&tid).map_or(0, |(_, ticks)| *ticks);
+        let entry = by_name.entry(name).or_default();
+        entry.0 += 1;
+        entry.1 += ticks.saturating_sub(start);
+    }
+    let mut threads = by_name
+        .into_iter()
+        .map(|(name, (threads, ticks))| ThreadCpu {
+            name,
+            threads,
+            cpu_seconds: ticks as f64 / TICKS_PER_SECOND,
+        })
+        .collect::<Vec<_>>();
+    threads.sort_by(|a, b| {
+        b.cpu_seconds
+            .total_cmp(&a.cpu_seconds)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    if folded {
+        let mut out = String::new();
+        for thread in threads.iter().filter(|t| t.cpu_seconds > 0.0) {
+            let name = thread.name.replace([';', ' '], "_");
+            let _ = writeln!(
+                out,
+                "gaia;{} {}",
+                name,
+                (thread.cpu_seconds * 1000.0) as u64
+            );
+        }
+        return ([(header::CONTENT_TYPE, "text/plain")], out).into_response();
+    }
+    let memory = match memory() {
+        Ok(memory) => memory,
+        Err(e) => return unavailable(e),
+    };
+    let cpu_seconds = threads.iter().map(|t| t.cpu_seconds).sum::<f64>();
+    Json(Profile {
+        seconds,
+        cpu_seconds,
+        cpu_percent: cpu_seconds / seconds as f64 * 100.0,
+        threads,
+        memory,
+    })
+    .into_response()
+}
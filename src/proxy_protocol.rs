@@ -0,0 +1,274 @@
+//! HAProxy's PROXY protocol, versions 1 and 2, so the client address a TCP
+//! load balancer saw survives the extra hop.
+//!
+//! With `PROXY_PROTOCOL=true` every connection to the `BIND_ADDRESS`
+//! listeners must open with a PROXY header; one that doesn't within five
+//! seconds is dropped, since serving it would attribute its requests to the
+//! balancer. The admin listeners never expect one. The address from the
+//! header is the one request handlers see as the peer.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+use crate::config;
+
+/// How long a connection has to send its header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header allowed, line ending included.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Whether `PROXY_PROTOCOL` is on.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| config::var("PROXY_PROTOCOL", false))
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`, from after the first
+/// `start.len()` bytes.
+async fn v1(
+    stream: &mut (impl AsyncRead + Unpin),
+    start: &[u8],
+) -> std::io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not text"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, port, _] => {
+            let address = |address: &str| match (*family, address.parse::<IpAddr>()) {
+                ("TCP4", Ok(ip @ IpAddr::V4(_))) | ("TCP6", Ok(ip @ IpAddr::V6(_))) => Some(ip),
+                _ => None,
+            };
+            let ip = address(source).ok_or_else(|| invalid("bad PROXY source address"))?;
+            address(destination).ok_or_else(|| invalid("bad PROXY destination address"))?;
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| invalid("bad PROXY source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+/// The binary header, from after its signature.
+async fn v2(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, ..] = header;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY version"));
+    }
+    // LOCAL: a health check from the balancer itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        1 if length >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        2 if length >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port(32),
+            )))
+        }
+        // unix sockets and unspecified families carry no usable client
+        _ => Ok(None),
+    }
+}
+
+/// Read the PROXY header at the start of `stream`, leaving it at the first
+/// byte after. `None` when the header says the connection is the balancer's
+/// own.
+async fn read(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<SocketAddr>> {
+    // as long as the signature and shorter than any version 1 header
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        v1(stream, &start).await
+    } else {
+        Err(invalid("connection did not start with a PROXY header"))
+    }
+}
+
+/// The client `stream` is relaying for, or `peer` if it is the balancer's
+/// own connection.
+pub async fn client(
+    stream: &mut (impl AsyncRead + Unpin),
+    peer: SocketAddr,
+) -> std::io::Result<SocketAddr> {
+    match timeout(HEADER_TIMEOUT, read(stream)).await {
+        Ok(client) => Ok(client?.unwrap_or(peer)),
+        Err(_) => Err(invalid("timed out waiting for a PROXY header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 40000))
+    }
+
+    /// The client `header` names, and what's left of the stream after it.
+    async fn parse(header: &[u8]) -> std::io::Result<(SocketAddr, Vec<u8>)> {
+        let mut stream = header;
+        let client = client(&mut stream, peer()).await?;
+        Ok((client, stream.to_vec()))
+    }
+
+    /// A version 2 header: `command` 0 (LOCAL) or 1 (PROXY) over `family`.
+    fn v2_header(version: u8, command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version << 4 | command);
+        header.push(family);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (client, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(client, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (client, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(client, "[2001:db8::1]:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_is_the_balancer() {
+        let (client, rest) = parse(b"PROXY UNKNOWN\r\nGET /").await.unwrap();
+        assert_eq!(client, peer());
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_addresses_must_match_the_family() {
+        for header in [
+            &b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            assert!(parse(header).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: gaia\r\n\r\n",
+        ] {
+            assert!(parse(header).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_fail() {
+        assert!(parse(b"PROXY TCP4 192.0.2.1").await.is_err());
+        assert!(parse(b"PROXY").await.is_err());
+        let mut header = v2_header(
+            2,
+            1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187],
+        );
+        header.truncate(header.len() - 3);
+        assert!(parse(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_without_a_line_ending_is_cut_off() {
+        let mut header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443".to_vec();
+        header.resize(V1_MAX_LENGTH + 20, b' ');
+        let error = parse(&header).await.unwrap_err();
+        assert_eq!(error.to_string(), "PROXY header too long");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_and_tcp6() {
+        let header = v2_header(
+            2,
+            1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187],
+        );
+        let (client, _) = parse(&[&header[..], b"GET /"].concat()).await.unwrap();
+        assert_eq!(client, "192.0.2.1:56324".parse().unwrap());
+
+        let source = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        let destination = "2001:db8::2".parse::<Ipv6Addr>().unwrap().octets();
+        let addresses = [&source[..], &destination, &[0xdc, 0x04, 1, 187]].concat();
+        let (client, _) = parse(&v2_header(2, 1, 0x21, &addresses)).await.unwrap();
+        assert_eq!(client, "[2001:db8::1]:56324".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2_local_is_the_balancer() {
+        let header = v2_header(2, 0, 0x00, &[]);
+        let (client, rest) = parse(&[&header[..], b"GET /"].concat()).await.unwrap();
+        assert_eq!(client, peer());
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v2_bad_signature_or_version() {
+        let good = v2_header(
+            2,
+            1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187],
+        );
+        let mut signature = good.clone();
+        signature[6] = b'X';
+        assert!(parse(&signature).await.is_err());
+        let version = v2_header(1, 1, 0x11, &good[16..]);
+        assert_eq!(
+            parse(&version).await.unwrap_err().to_string(),
+            "unsupported PROXY version"
+        );
+    }
+}
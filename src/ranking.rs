@@ -0,0 +1,145 @@
+//! The settings for `gaia_core::ranking`.
+//!
+//! `RESULT_LAYER_PRIORITY` lists layers best first (default
+//! `address,intersection,street,neighborhood,postalCode,locality,county,state,country`).
+//! `RESULT_SORT=distance` makes distance the first key instead of layer.
+//! `RESULT_DEDUP_METERS` (default 10) is how close copies of an address are
+//! collapsed; zero turns that off.
+//!
+//! Forward results score their confidence plus these weights, all zero by
+//! default:
+//!
+//! - `FORWARD_RANK_PROXIMITY_WEIGHT`, added in full for a result at the bias
+//!   point and half of it `FORWARD_RANK_PROXIMITY_KM` (default 50) away. The
+//!   bias point is the request's `near=lat,lon`, or `FORWARD_RANK_BIAS` in
+//!   the same form; without either proximity doesn't count.
+//! - `FORWARD_RANK_LAYERS`, `layer=weight` pairs such as
+//!   `address=0.2,postalCode=-0.1`, added to results of those layers.
+//! - `FORWARD_RANK_COUNTRIES`, `code=weight` pairs such as `US=0.3`, added to
+//!   results in those countries.
+//! - `FORWARD_RANK_PO_BOX_PENALTY`, taken off post office boxes.
+//!
+//! Weights may be negative. The ranking only orders results; `minConfidence`
+//! still filters on confidence alone.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use gaia_core::ranking::{ForwardRanking, Ranking, DEFAULT_LAYERS};
+
+use crate::{config, geo, params, GeocodeResponse, RadarAddress};
+
+/// Read the ranking settings ahead of the first lookup.
+pub fn check() {
+    ranking();
+    forward_ranking();
+}
+
+/// The configured reverse geocode ranking.
+pub fn ranking() -> &'static Ranking {
+    static RANKING: OnceLock<Ranking> = OnceLock::new();
+    RANKING.get_or_init(|| Ranking {
+        layers: config::var("RESULT_LAYER_PRIORITY", String::from(DEFAULT_LAYERS))
+            .split(',')
+            .map(|layer| layer.trim().to_string())
+            .filter(|layer| !layer.is_empty())
+            .collect(),
+        distance_first: match config::var("RESULT_SORT", String::from("layer")).as_str() {
+            "layer" => false,
+            "distance" => true,
+            other => panic!("Invalid RESULT_SORT: {}", other),
+        },
+        dedup_meters: config::var("RESULT_DEDUP_METERS", 10.0),
+        algorithm: geo::algorithm(),
+    })
+}
+
+/// Put `geocodes` in ranking order. Equal results keep their order.
+pub fn sort(geocodes: &mut [GeocodeResponse]) {
+    ranking().sort(geocodes)
+}
+
+/// Whether `a` and `b` are the same address: the same formatted address
+/// within `RESULT_DEDUP_METERS` of each other.
+pub fn same_address(a: &RadarAddress, b: &RadarAddress) -> bool {
+    ranking().same_address(a, b)
+}
+
+/// Drop results that repeat an earlier one's address, keeping the earlier
+/// one.
+pub fn dedup(geocodes: &mut Vec<GeocodeResponse>) {
+    ranking().dedup(geocodes)
+}
+
+/// `name=weight` pairs from `name`'s comma-separated list.
+fn weights(name: &str) -> Vec<(String, f64)> {
+    let configured = std::env::var(name).unwrap_or_default();
+    configured
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            entry
+                .split_once('=')
+                .and_then(|(key, weight)| {
+                    let weight = weight
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|w| w.is_finite())?;
+                    Some((key.trim().to_string(), weight)).filter(|(key, _)| !key.is_empty())
+                })
+                .unwrap_or_else(|| panic!("Invalid {}: {}", name, entry))
+        })
+        .collect()
+}
+
+/// A `lat,lon` point.
+fn point(value: &str) -> Option<(f64, f64)> {
+    let (lat, lon) = value.split_once(',')?;
+    let (lat, lon) = (
+        lat.trim().parse::<f64>().ok()?,
+        lon.trim().parse::<f64>().ok()?,
+    );
+    (lat.abs() <= 90.0 && lon.abs() <= 180.0).then_some((lat, lon))
+}
+
+struct ForwardSettings {
+    ranking: ForwardRanking,
+    bias: Option<(f64, f64)>,
+}
+
+fn forward_ranking() -> &'static ForwardSettings {
+    static SETTINGS: OnceLock<ForwardSettings> = OnceLock::new();
+    SETTINGS.get_or_init(|| ForwardSettings {
+        ranking: ForwardRanking {
+            proximity_weight: config::var("FORWARD_RANK_PROXIMITY_WEIGHT", 0.0),
+            proximity_scale_meters: config::var("FORWARD_RANK_PROXIMITY_KM", 50.0) * 1000.0,
+            layers: weights("FORWARD_RANK_LAYERS"),
+            countries: weights("FORWARD_RANK_COUNTRIES"),
+            po_box_penalty: config::var("FORWARD_RANK_PO_BOX_PENALTY", 0.0),
+            algorithm: geo::algorithm(),
+        },
+        bias: std::env::var("FORWARD_RANK_BIAS").ok().map(|value| {
+            point(&value).unwrap_or_else(|| panic!("Invalid FORWARD_RANK_BIAS: {}", value))
+        }),
+    })
+}
+
+/// The point forward results near are favoured: the request's `near`, or
+/// else `FORWARD_RANK_BIAS`.
+pub fn forward_bias(
+    params: &HashMap<String, String>,
+) -> Result<Option<(f64, f64)>, params::ParamError> {
+    match params.get("near") {
+        Some(near) => point(near)
+            .map(Some)
+            .ok_or_else(|| params::bad_request("near must be lat,lon")),
+        None => Ok(forward_ranking().bias),
+    }
+}
+
+/// Where a forward result with `confidence` and `address` ranks, higher
+/// first, favouring those near `bias`.
+pub fn forward_score(confidence: f64, address: &RadarAddress, bias: Option<(f64, f64)>) -> f64 {
+    forward_ranking().ranking.score(confidence, address, bias)
+}
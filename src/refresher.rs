@@ -0,0 +1,121 @@
+//! Background re-fetching of popular cache entries before they go stale, so
+//! the request path rarely has to pay for a refresh itself.
+
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    config, db,
+    flags::{self, Flag},
+    geo_reverse, maintenance, metrics,
+    upstream::Upstream,
+    LookupOptions,
+};
+
+#[derive(Debug, Clone)]
+struct Settings {
+    interval: Duration,
+    stale_after: i64,
+    batch_size: i64,
+    min_hits: i64,
+    delay: Duration,
+    /// UTC hours `[start, end)` during which refreshes may run; wraps midnight.
+    window: Option<(u64, u64)>,
+}
+
+/// Parse the `CACHE_REFRESH_*` settings even while the refresher is off.
+pub fn check() {
+    Settings::from_env();
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        Settings {
+            interval: Duration::from_secs(config::var("CACHE_REFRESH_INTERVAL_SECS", 600)),
+            stale_after: config::var("CACHE_REFRESH_STALE_DAYS", 90i64) * 86400,
+            batch_size: config::var("CACHE_REFRESH_BATCH_SIZE", 100),
+            min_hits: config::var("CACHE_REFRESH_MIN_HITS", 2),
+            delay: Duration::from_millis(config::var("CACHE_REFRESH_DELAY_MS", 1000)),
+            window: std::env::var("CACHE_REFRESH_HOURS")
+                .ok()
+                .map(|hours| parse_window(&hours)),
+        }
+    }
+
+    fn in_window(&self) -> bool {
+        let hour = (db::now() as u64 / 3600) % 24;
+        match self.window {
+            None => true,
+            Some((start, end)) if start <= end => (start..end).contains(&hour),
+            Some((start, end)) => hour >= start || hour < end,
+        }
+    }
+}
+
+fn parse_window(hours: &str) -> (u64, u64) {
+    hours
+        .split_once('-')
+        .and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)))
+        .filter(|(start, end)| *start < 24 && *end <= 24)
+        .unwrap_or_else(|| panic!("Invalid CACHE_REFRESH_HOURS: {}", hours))
+}
+
+/// Start the refresher if `CACHE_REFRESH_ENABLED=true`.
+pub fn spawn(pool: Arc<Pool<Sqlite>>, upstream: Arc<Upstream>) {
+    if !config::var("CACHE_REFRESH_ENABLED", false) {
+        return;
+    }
+    let settings = Settings::from_env();
+    tracing::info!("background cache refresh enabled: {:?}", settings);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(settings.interval).await;
+            if settings.in_window() && !maintenance::active() && flags::enabled(Flag::Refresher) {
+                if let Err(e) = refresh_batch(&settings, &pool, &upstream).await {
+                    tracing::warn!("background cache refresh failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+async fn refresh_batch(
+    settings: &Settings,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Arc<Upstream>,
+) -> Result<(), String> {
+    let candidates = sqlx::query_as::<_, (String, String, String)>(
+        "SELECT lat, lon, namespace FROM geocode GROUP BY lat, lon, namespace \
+         HAVING MAX(COALESCE(created_at, 0)) < ? AND SUM(hits) >= ? \
+         ORDER BY SUM(hits) DESC LIMIT ?",
+    )
+    .bind(db::now() - settings.stale_after)
+    .bind(settings.min_hits)
+    .bind(settings.batch_size)
+    .fetch_all(&**pool)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if candidates.is_empty() {
+        return Ok(());
+    }
+    tracing::info!("refreshing {} stale cache entries", candidates.len());
+
+    for (lat, lon, namespace) in candidates {
+        if !settings.in_window() {
+            break;
+        }
+        let options = LookupOptions {
+            refresh: true,
+            namespace,
+            ..Default::default()
+        };
+        geo_reverse(lat, lon, pool.clone(), upstream.clone(), &options)
+            .await
+            .map_err(|e| e.to_string())?;
+        metrics::increment("gaia_cache_background_refreshed_total", &[]);
+        tokio::time::sleep(settings.delay).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,312 @@
+//! `GET /api/v0/geocode/snap?lat=&lon=&radius=`: the nearest road to a point
+//! and the point on it, for GPS fixes that land in a car park or a
+//! building's footprint and so reverse geocode to whichever street is behind
+//! them rather than the one the device was on.
+//!
+//! Roads are read at startup from `ROADS_FILE`, a GeoJSON FeatureCollection
+//! of LineStrings and MultiLineStrings such as `ogr2ogr` writes from an OSM
+//! extract (`highway=*` ways) or TIGER's edges or roads shapefiles. A road's
+//! name is taken from its `name`, `NAME` or `FULLNAME` property, its route
+//! number from `ref`, and its kind from `highway` or `MTFCC`; roads with
+//! neither a name nor a number, like most service roads, are left out, as
+//! they are the ones to snap away from. Without `ROADS_FILE` the endpoint
+//! answers 503.
+//!
+//! `radius` (default 100 metres, at most 1000, in `units`) is how far to
+//! look; nothing within it is a 404. The response has the snapped `lat` and
+//! `lon`, the `distance` to it and the `road`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{geo, params, tenants::Tenant, units::Unit};
+
+/// Degrees per side of an index cell: about a kilometre north to south.
+const CELL_DEGREES: f64 = 0.01;
+
+/// Property names checked (in order) for a road's name.
+const NAME_PROPERTIES: [&str; 4] = ["name", "NAME", "FULLNAME", "name_en"];
+
+/// Property names checked (in order) for a road's route number.
+const REF_PROPERTIES: [&str; 2] = ["ref", "REF"];
+
+/// Property names checked (in order) for a road's classification.
+const KIND_PROPERTIES: [&str; 3] = ["highway", "MTFCC", "fclass"];
+
+const MAX_RADIUS_METERS: f64 = 1000.0;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Road {
+    pub name: Option<String>,
+    #[serde(rename = "ref")]
+    pub route_number: Option<String>,
+    pub kind: Option<String>,
+}
+
+/// A straight piece of a road, between two (lon, lat) positions.
+#[derive(Debug)]
+struct Segment {
+    road: usize,
+    from: (f64, f64),
+    to: (f64, f64),
+}
+
+#[derive(Debug, Default)]
+pub struct Roads {
+    roads: Vec<Road>,
+    segments: Vec<Segment>,
+    /// Segments by the index cells their bounding boxes touch.
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Snap {
+    pub lat: f64,
+    pub lon: f64,
+    pub distance: f64,
+    pub road: Road,
+}
+
+fn cell(lon: f64, lat: f64) -> (i64, i64) {
+    (
+        (lon / CELL_DEGREES).floor() as i64,
+        (lat / CELL_DEGREES).floor() as i64,
+    )
+}
+
+/// Metres per degree of longitude and of latitude near `lat`, close enough
+/// over the few hundred metres a snap looks.
+fn meters_per_degree(lat: f64) -> (f64, f64) {
+    (111_320.0 * lat.to_radians().cos(), 110_574.0)
+}
+
+impl Roads {
+    /// The roads in `ROADS_FILE`, or none when it isn't set.
+    pub fn from_env() -> Self {
+        match std::env::var("ROADS_FILE") {
+            Ok(path) => Roads::load(Path::new(&path)).expect("Failed to load roads"),
+            Err(_) => Roads::default(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let collection: Value = serde_json::from_str(&raw)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        let roads = parse_feature_collection(&collection)
+            .map_err(|e| format!("invalid geojson in {}: {}", path.display(), e))?;
+        tracing::info!(
+            "loaded {} roads ({} segments) from {}",
+            roads.roads.len(),
+            roads.segments.len(),
+            path.display()
+        );
+        Ok(roads)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    fn add(&mut self, road: Road, lines: Vec<Vec<(f64, f64)>>) {
+        let id = self.roads.len();
+        self.roads.push(road);
+        for line in lines {
+            for pair in line.windows(2) {
+                let (from, to) = (pair[0], pair[1]);
+                let (min, max) = (
+                    cell(from.0.min(to.0), from.1.min(to.1)),
+                    cell(from.0.max(to.0), from.1.max(to.1)),
+                );
+                let segment = self.segments.len();
+                for x in min.0..=max.0 {
+                    for y in min.1..=max.1 {
+                        self.cells.entry((x, y)).or_default().push(segment);
+                    }
+                }
+                self.segments.push(Segment { road: id, from, to });
+            }
+        }
+    }
+
+    /// The closest point on any road within `radius` metres of the point.
+    pub fn snap(&self, lat: f64, lon: f64, radius: f64) -> Option<Snap> {
+        let (x_scale, y_scale) = meters_per_degree(lat);
+        let (dlon, dlat) = (radius / x_scale.max(1.0), radius / y_scale);
+        let (min, max) = (cell(lon - dlon, lat - dlat), cell(lon + dlon, lat + dlat));
+
+        // project onto a flat plane in metres around the point
+        let plane = |(x, y): (f64, f64)| ((x - lon) * x_scale, (y - lat) * y_scale);
+        let mut best: Option<(f64, usize, (f64, f64))> = None;
+        let mut seen = HashSet::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for &id in self.cells.get(&(x, y)).into_iter().flatten() {
+                    if !seen.insert(id) {
+                        continue;
+                    }
+                    let segment = &self.segments[id];
+                    let (a, b) = (plane(segment.from), plane(segment.to));
+                    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+                    let length = dx * dx + dy * dy;
+                    let t = if length == 0.0 {
+                        0.0
+                    } else {
+                        ((-a.0 * dx - a.1 * dy) / length).clamp(0.0, 1.0)
+                    };
+                    let (px, py) = (a.0 + dx * t, a.1 + dy * t);
+                    let distance = px.hypot(py);
+                    if best.is_none_or(|(closest, _, _)| distance < closest) {
+                        best = Some((distance, segment.road, (px, py)));
+                    }
+                }
+            }
+        }
+        let (_, road, (px, py)) = best?;
+        let (snapped_lat, snapped_lon) = (lat + py / y_scale, lon + px / x_scale);
+        let distance = geo::distance_meters(lat, lon, snapped_lat, snapped_lon);
+        (distance <= radius).then(|| Snap {
+            lat: snapped_lat,
+            lon: snapped_lon,
+            distance,
+            road: self.roads[road].clone(),
+        })
+    }
+}
+
+fn parse_feature_collection(collection: &Value) -> Result<Roads, String> {
+    let features = collection
+        .get("features")
+        .and_then(Value::as_array)
+        .ok_or("expected a FeatureCollection")?;
+
+    let mut roads = Roads::default();
+    for feature in features {
+        let properties = feature.get("properties").unwrap_or(&Value::Null);
+        let road = Road {
+            name: first_string(properties, &NAME_PROPERTIES),
+            route_number: first_string(properties, &REF_PROPERTIES),
+            kind: first_string(properties, &KIND_PROPERTIES),
+        };
+        if road.name.is_none() && road.route_number.is_none() {
+            continue;
+        }
+        let geometry = match feature.get("geometry") {
+            Some(geometry) if !geometry.is_null() => geometry,
+            _ => continue,
+        };
+        let lines = parse_geometry(geometry)?;
+        if !lines.is_empty() {
+            roads.add(road, lines);
+        }
+    }
+    Ok(roads)
+}
+
+fn first_string(properties: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .filter_map(|k| properties.get(*k).and_then(Value::as_str))
+        .find(|s| !s.is_empty())
+        .map(String::from)
+}
+
+fn parse_geometry(geometry: &Value) -> Result<Vec<Vec<(f64, f64)>>, String> {
+    let coordinates = geometry
+        .get("coordinates")
+        .ok_or("geometry is missing coordinates")?;
+    match geometry.get("type").and_then(Value::as_str) {
+        Some("LineString") => Ok(vec![parse_line(coordinates)?]),
+        Some("MultiLineString") => coordinates
+            .as_array()
+            .ok_or("MultiLineString coordinates must be an array")?
+            .iter()
+            .map(parse_line)
+            .collect(),
+        // a road drawn as a point or an area has no line to snap to
+        _ => Ok(vec![]),
+    }
+}
+
+fn parse_line(coordinates: &Value) -> Result<Vec<(f64, f64)>, String> {
+    coordinates
+        .as_array()
+        .ok_or("LineString coordinates must be an array")?
+        .iter()
+        .map(|point| match point.as_array().map(Vec::as_slice) {
+            Some([lon, lat, ..]) => match (lon.as_f64(), lat.as_f64()) {
+                (Some(lon), Some(lat)) => Ok((lon, lat)),
+                _ => Err(String::from("position must be numeric")),
+            },
+            _ => Err(String::from("position must have at least two elements")),
+        })
+        .collect()
+}
+
+pub async fn get_snap(
+    Query(params): Query<HashMap<String, String>>,
+    // for the key check and usage count
+    _tenant: Tenant,
+    Extension(roads): Extension<Arc<Roads>>,
+) -> Response {
+    if roads.is_empty() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!("no roads loaded")),
+        )
+            .into_response();
+    }
+    let (lat, lon) = match params::point(&params) {
+        Ok(point) => point,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    let radius = match params::optional::<f64>(&params, "radius", units.of(100.0)) {
+        Ok(radius) if radius > 0.0 && units.in_meters(radius) <= MAX_RADIUS_METERS => {
+            units.in_meters(radius)
+        }
+        Ok(_) => {
+            return params::bad_request("radius must be positive and at most 1000 metres")
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    if !(lat.abs() <= 90.0 && lon.abs() <= 180.0) {
+        return params::bad_request("lat and lon must be valid coordinates").into_response();
+    }
+
+    match roads.snap(lat, lon, radius) {
+        Some(mut snap) => {
+            snap.distance = units.of(snap.distance);
+            (
+                StatusCode::OK,
+                [("x-gaia-distance-algorithm", geo::algorithm().name())],
+                Json(snap),
+            )
+                .into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!("no road within the radius")),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,428 @@
+//! `POST /api/v0/geocode/reverse/route?interval=`: the addresses along a
+//! route, in order, in one request instead of one per GPS fix.
+//!
+//! The body is `{"polyline": "..."}`, an encoded polyline (Google's format,
+//! at 5 decimal places or `polylinePrecision` of them, e.g. 6 for OSRM and
+//! Valhalla), or `{"coordinates": [[lat, lon], ...]}`, in the polyline's
+//! order rather than GeoJSON's. The route is sampled every `interval` metres
+//! (default `ROUTE_SAMPLE_METERS`, 100) from its first point to its last,
+//! and each sample looked up like a bulk item, rounded to `precision` with
+//! samples in the same cell looked up once. More than `ROUTE_MAX_SAMPLES`
+//! samples (default 1000) is a 413; a longer interval brings them down.
+//!
+//! The response lists the route's addresses in order: consecutive samples
+//! with the same top address become one entry spanning `fromMeters` to
+//! `toMeters` along the route. Samples with no address (at sea, say) are
+//! left out, and failed lookups appear as entries with a `status` and
+//! `error` like bulk items. The body limit and the `bulk` flag are the bulk
+//! route's.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    sync::OnceLock,
+};
+
+use axum::{
+    extract::{rejection::JsonRejection, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    bulk, config,
+    flags::{self, Flag},
+    geo, params, precision,
+    tenants::Tenant,
+    units::Unit,
+    upstream::Upstream,
+    LookupOptions, RadarAddress,
+};
+
+#[derive(Debug)]
+struct Settings {
+    sample_meters: f64,
+    max_samples: usize,
+}
+
+/// Read `ROUTE_SAMPLE_METERS` and `ROUTE_MAX_SAMPLES`.
+pub fn check() {
+    settings();
+}
+
+/// The default sampling interval, in metres, shared with `track`.
+pub fn sample_meters() -> f64 {
+    settings().sample_meters
+}
+
+/// The most lookups one request may make, shared with `track`.
+pub fn max_samples() -> usize {
+    settings().max_samples
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        sample_meters: config::var("ROUTE_SAMPLE_METERS", 100.0),
+        max_samples: config::var("ROUTE_MAX_SAMPLES", 1000usize),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteBody {
+    pub polyline: Option<String>,
+    pub polyline_precision: Option<u32>,
+    pub coordinates: Option<Vec<[f64; 2]>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteAddress {
+    pub from_meters: f64,
+    pub to_meters: f64,
+    /// The cell of the first sample with this address.
+    pub cell: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<RadarAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteResponse {
+    pub length_meters: f64,
+    pub samples: usize,
+    /// Distinct cells looked up.
+    pub cells: usize,
+    pub addresses: Vec<RouteAddress>,
+}
+
+/// The points of an encoded polyline at `precision` decimal places.
+fn decode(polyline: &str, precision: u32) -> Option<Vec<(f64, f64)>> {
+    let factor = 10f64.powi(precision as i32);
+    let mut bytes = polyline.bytes();
+    let mut next = || -> Option<Option<i64>> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let Some(byte) = bytes.next() else {
+                // the end, unless it cut a value short
+                return (shift == 0).then_some(None);
+            };
+            let chunk = i64::from(byte.checked_sub(63).filter(|b| *b < 64)?);
+            value |= (chunk & 0x1f) << shift;
+            shift += 5;
+            if chunk < 0x20 {
+                break;
+            }
+            if shift > 60 {
+                return None;
+            }
+        }
+        Some(Some(if value & 1 == 1 {
+            !(value >> 1)
+        } else {
+            value >> 1
+        }))
+    };
+    let (mut points, mut lat, mut lon) = (vec![], 0i64, 0i64);
+    while let Some(dlat) = next()? {
+        lat += dlat;
+        lon += next()??;
+        points.push((lat as f64 / factor, lon as f64 / factor));
+    }
+    Some(points)
+}
+
+/// A point on the route, `along` metres from its start.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    lat: f64,
+    lon: f64,
+    along: f64,
+}
+
+/// Points every `interval` metres along `path`, its ends included, and the
+/// route's length; `None` if that is more than `max` points.
+fn sample(path: &[(f64, f64)], interval: f64, max: usize) -> Option<(Vec<Sample>, f64)> {
+    let (lat, lon) = path[0];
+    let mut samples = vec![Sample {
+        lat,
+        lon,
+        along: 0.0,
+    }];
+    let (mut along, mut next) = (0.0, interval);
+    for pair in path.windows(2) {
+        let ((lat1, lon1), (lat2, lon2)) = (pair[0], pair[1]);
+        let length = geo::distance_meters(lat1, lon1, lat2, lon2);
+        // segments are short enough to interpolate in degrees, the short way
+        // round for those crossing the antimeridian
+        let lon2 = match lon2 - lon1 {
+            d if d > 180.0 => lon2 - 360.0,
+            d if d < -180.0 => lon2 + 360.0,
+            _ => lon2,
+        };
+        while next < along + length {
+            let t = (next - along) / length;
+            samples.push(Sample {
+                lat: lat1 + (lat2 - lat1) * t,
+                lon: gaia_core::geo::wrap_lon(lon1 + (lon2 - lon1) * t),
+                along: next,
+            });
+            if samples.len() > max {
+                return None;
+            }
+            next += interval;
+        }
+        along += length;
+    }
+    let &(lat, lon) = path.last().unwrap();
+    if samples.last().is_some_and(|s| s.along < along) {
+        samples.push(Sample { lat, lon, along });
+    }
+    Some((samples, along)).filter(|(samples, _)| samples.len() <= max)
+}
+
+fn path(body: RouteBody) -> Result<Vec<(f64, f64)>, String> {
+    let path = match (body.polyline, body.coordinates) {
+        (Some(_), Some(_)) => return Err(String::from("send polyline or coordinates, not both")),
+        (Some(polyline), None) => {
+            let precision = body.polyline_precision.unwrap_or(5);
+            if !(1..=7).contains(&precision) {
+                return Err(String::from("polylinePrecision must be between 1 and 7"));
+            }
+            decode(&polyline, precision).ok_or("invalid polyline")?
+        }
+        (None, Some(coordinates)) => coordinates
+            .into_iter()
+            .map(|[lat, lon]| (lat, lon))
+            .collect(),
+        (None, None) => return Err(String::from("polyline or coordinates is required")),
+    };
+    if path.is_empty() {
+        return Err(String::from("the route has no points"));
+    }
+    if path
+        .iter()
+        .any(|(lat, lon)| !(lat.abs() <= 90.0 && lon.abs() <= 180.0))
+    {
+        return Err(String::from("the route leaves the valid coordinate range"));
+    }
+    Ok(path)
+}
+
+pub async fn post_geo_reverse_route(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+    body: Result<Json<RouteBody>, JsonRejection>,
+) -> Response {
+    if !flags::enabled(Flag::Bulk) {
+        return flags::disabled(Flag::Bulk).into_response();
+    }
+    let settings = settings();
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!("request body is too large")),
+            )
+                .into_response()
+        }
+        Err(e) => return (e.status(), Json(json!(e.body_text()))).into_response(),
+    };
+    let interval = match params::optional::<f64>(&params, "interval", settings.sample_meters) {
+        Ok(interval) if (1.0..=100_000.0).contains(&interval) => interval,
+        Ok(_) => {
+            return params::bad_request("interval must be between 1 and 100000 metres")
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let precision = match precision::from_params(&params) {
+        Ok(precision) => precision,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    let path = match path(body) {
+        Ok(path) => path,
+        Err(e) => return params::bad_request(&e).into_response(),
+    };
+    let Some((samples, length)) = sample(&path, interval, settings.max_samples) else {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!(format!(
+                "the route needs more than {} samples at {} metres; use a longer interval",
+                settings.max_samples, interval
+            ))),
+        )
+            .into_response();
+    };
+
+    // a slow stretch samples one cell many times over: look each run up once
+    let mut runs: Vec<((String, String), f64, f64)> = vec![];
+    for sample in &samples {
+        let cell = (
+            precision::round(sample.lat, precision),
+            precision::round_lon(sample.lon, precision),
+        );
+        match runs.last_mut() {
+            Some((last, _, to)) if *last == cell => *to = sample.along,
+            _ => runs.push((cell, sample.along, sample.along)),
+        }
+    }
+    let data = runs
+        .iter()
+        .map(|((lat, lon), _, _)| json!({"lat": lat, "lon": lon}))
+        .collect::<Vec<_>>();
+    let resolved = bulk::resolve(data, precision, units, &pool, &upstream, &options).await;
+    let cells = runs
+        .iter()
+        .map(|(cell, _, _)| cell)
+        .collect::<HashSet<_>>()
+        .len();
+
+    let mut addresses: Vec<RouteAddress> = vec![];
+    for (item, (_, from, to)) in resolved.into_iter().zip(runs) {
+        let cell = item.cell.unwrap_or_default();
+        let entry = match (item.results, item.error) {
+            (Some(results), _) => match results.into_iter().next() {
+                Some(top) => RouteAddress {
+                    from_meters: from,
+                    to_meters: to,
+                    cell,
+                    address: Some(top.address),
+                    status: None,
+                    error: None,
+                },
+                None => continue,
+            },
+            (None, error) => RouteAddress {
+                from_meters: from,
+                to_meters: to,
+                cell,
+                address: None,
+                status: Some(item.status),
+                error,
+            },
+        };
+        match addresses.last_mut() {
+            Some(last)
+                if last.error == entry.error
+                    && last.address.as_ref().map(|a| &a.formatted_address)
+                        == entry.address.as_ref().map(|a| &a.formatted_address) =>
+            {
+                last.to_meters = to;
+            }
+            _ => addresses.push(entry),
+        }
+    }
+
+    (
+        StatusCode::OK,
+        [("x-gaia-distance-algorithm", geo::algorithm().name())],
+        Json(RouteResponse {
+            length_meters: length,
+            samples: samples.len(),
+            cells,
+            addresses,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Google's example: (38.5, -120.2), (40.7, -120.95), (43.252, -126.453).
+    const EXAMPLE: &str = "_p~iF~ps|U_ulLnnqC_mqNvxq`@";
+
+    /// The polyline of `points` at `precision`, for checking the decoder.
+    fn encode(points: &[(f64, f64)], precision: u32) -> String {
+        let factor = 10f64.powi(precision as i32);
+        let mut out = String::new();
+        let mut push = |delta: i64| {
+            let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+            while value >= 0x20 {
+                out.push(char::from(((0x20 | (value & 0x1f)) + 63) as u8));
+                value >>= 5;
+            }
+            out.push(char::from((value + 63) as u8));
+        };
+        let (mut lat, mut lon) = (0i64, 0i64);
+        for &(point_lat, point_lon) in points {
+            let (next_lat, next_lon) = (
+                (point_lat * factor).round() as i64,
+                (point_lon * factor).round() as i64,
+            );
+            push(next_lat - lat);
+            push(next_lon - lon);
+            (lat, lon) = (next_lat, next_lon);
+        }
+        out
+    }
+
+    fn assert_points(got: &[(f64, f64)], want: &[(f64, f64)]) {
+        assert_eq!(got.len(), want.len(), "{:?}", got);
+        for (g, w) in got.iter().zip(want) {
+            assert!(
+                (g.0 - w.0).abs() < 1e-9 && (g.1 - w.1).abs() < 1e-9,
+                "{:?} != {:?}",
+                got,
+                want
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_the_example_at_precision_5() {
+        let points = decode(EXAMPLE, 5).unwrap();
+        assert_points(
+            &points,
+            &[(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)],
+        );
+        assert_eq!(encode(&points, 5), EXAMPLE);
+    }
+
+    #[test]
+    fn decodes_the_example_at_precision_6() {
+        // the same integers, a tenth the size
+        let points = decode(EXAMPLE, 6).unwrap();
+        assert_points(
+            &points,
+            &[(3.85, -12.02), (4.07, -12.095), (4.3252, -12.6453)],
+        );
+        assert_eq!(encode(&points, 6), EXAMPLE);
+
+        let precise = [(38.500001, -120.200002), (40.7, -120.950003)];
+        assert_points(&decode(&encode(&precise, 6), 6).unwrap(), &precise);
+    }
+
+    #[test]
+    fn refuses_broken_polylines() {
+        // cut inside a value, and after a latitude with no longitude
+        assert_eq!(decode(&EXAMPLE[..EXAMPLE.len() - 1], 5), None);
+        assert_eq!(decode("_p~iF", 5), None);
+        // a byte outside the alphabet
+        assert_eq!(decode("_p~iF ~ps|U", 5), None);
+        assert_eq!(decode("", 5), Some(vec![]));
+    }
+}
@@ -0,0 +1,79 @@
+//! Per-country provider routing, since provider quality and licensing vary
+//! by region.
+//!
+//! `UPSTREAM_ROUTES` sends misses in some countries to a provider other than
+//! `UPSTREAM_PROVIDER`, as a comma-separated list of `country=provider` with
+//! ISO 3166-1 alpha-2 codes, or `EU` for its member states, e.g.
+//! `UPSTREAM_ROUTES=US=radar,CA=radar,EU=nominatim`; a country listed itself
+//! wins over `EU`. The country is found offline before the call, in the
+//! `country.geojson` of `BOUNDARIES_DIR` by each boundary's code, so routes
+//! need those boundaries. Points in no listed country, at sea or in a gap
+//! between polygons go to `UPSTREAM_PROVIDER`, as do forward lookups, which
+//! have no point to route by.
+
+use std::collections::HashMap;
+
+use crate::{boundaries::Boundaries, upstream::Provider};
+
+const EU: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+#[derive(Debug, Default)]
+pub struct Routes {
+    countries: HashMap<String, Provider>,
+}
+
+impl Routes {
+    /// The configured `UPSTREAM_ROUTES`.
+    pub fn from_env() -> Self {
+        let configured = std::env::var("UPSTREAM_ROUTES").unwrap_or_default();
+        let mut countries = HashMap::new();
+        let mut regions = vec![];
+        for entry in configured
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let Some((code, provider)) = entry.split_once('=').and_then(|(code, provider)| {
+                let code = code.trim().to_uppercase();
+                let valid = code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic());
+                Some((code, Provider::parse(provider.trim())?)).filter(|_| valid)
+            }) else {
+                panic!("Invalid UPSTREAM_ROUTES: {}", entry);
+            };
+            match code.as_str() {
+                "EU" => regions.push(provider),
+                _ => {
+                    countries.insert(code, provider);
+                }
+            }
+        }
+        for provider in regions {
+            for code in EU {
+                countries.entry(code.to_string()).or_insert(provider);
+            }
+        }
+        Routes { countries }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+    }
+
+    /// Every provider some country is routed to.
+    pub fn providers(&self) -> impl Iterator<Item = Provider> + '_ {
+        self.countries.values().copied()
+    }
+
+    /// The provider routed to for `lat`/`lon`, if its country has one.
+    pub fn provider(&self, boundaries: &Boundaries, lat: &str, lon: &str) -> Option<Provider> {
+        if self.is_empty() {
+            return None;
+        }
+        let (lat, lon) = (lat.parse::<f64>().ok()?, lon.parse::<f64>().ok()?);
+        let country = boundaries.lookup_level("country", lat, lon)?;
+        self.countries.get(&country.code?.to_uppercase()).copied()
+    }
+}
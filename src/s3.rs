@@ -0,0 +1,219 @@
+//! Just enough of the S3 API (path-style, SigV4-signed) to store backups on
+//! AWS S3, MinIO or any other compatible store.
+
+use std::{fs::File, io::Read, path::Path};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{config, cron::civil_from_days, db};
+
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://minio:9000`.
+    endpoint: url::Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    agent: ureq::Agent,
+}
+
+impl Bucket {
+    /// From `BACKUP_S3_*`; `None` when no bucket is configured.
+    pub fn from_env() -> Option<Self> {
+        let bucket = std::env::var("BACKUP_S3_BUCKET").ok()?;
+        let endpoint = std::env::var("BACKUP_S3_ENDPOINT")
+            .unwrap_or_else(|_| String::from("https://s3.amazonaws.com"));
+        Some(Bucket {
+            endpoint: url::Url::parse(&endpoint)
+                .unwrap_or_else(|_| panic!("Invalid BACKUP_S3_ENDPOINT: {}", endpoint)),
+            bucket,
+            region: config::var("BACKUP_S3_REGION", String::from("us-east-1")),
+            access_key_id: config::secret("BACKUP_S3_ACCESS_KEY_ID")
+                .expect("Missing BACKUP_S3_ACCESS_KEY_ID"),
+            secret_access_key: config::secret("BACKUP_S3_SECRET_ACCESS_KEY")
+                .expect("Missing BACKUP_S3_SECRET_ACCESS_KEY"),
+            agent: ureq::AgentBuilder::new().build(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.bucket
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        payload_hash: &str,
+    ) -> ureq::Request {
+        let mut url = self.endpoint.clone();
+        if key.is_empty() {
+            url.set_path(&format!("/{}", self.bucket));
+        } else {
+            url.set_path(&format!("/{}/{}", self.bucket, encode(key, false)));
+        }
+        let mut query = query.to_vec();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", encode(k, true), encode(v, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+        if !canonical_query.is_empty() {
+            url.set_query(Some(&canonical_query));
+        }
+
+        let now = db::now();
+        let (year, month, day) = civil_from_days(now.div_euclid(86400));
+        let secs = now.rem_euclid(86400);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let amz_date = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        );
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            url.path(),
+            canonical_query,
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+
+        self.agent
+            .request_url(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", payload_hash)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, signed_headers, signature
+                ),
+            )
+    }
+
+    pub fn put_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        let mut hasher = Sha256::new();
+        let mut file = File::open(path).map_err(|e| e.to_string())?;
+        std::io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+        let length = file.metadata().map_err(|e| e.to_string())?.len();
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        self.request("PUT", key, &[], &hex::encode(hasher.finalize()))
+            .set("Content-Length", &length.to_string())
+            .send(file)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<impl Read + Send, String> {
+        Ok(self
+            .request("GET", key, &[], EMPTY_SHA256)
+            .call()
+            .map_err(|e| e.to_string())?
+            .into_reader())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), String> {
+        self.request("DELETE", key, &[], EMPTY_SHA256)
+            .call()
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Every key under `prefix`, in lexicographic order.
+    pub fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .request("GET", "", &query, EMPTY_SHA256)
+                .call()
+                .map_err(|e| e.to_string())?
+                .into_string()
+                .map_err(|e| e.to_string())?;
+            keys.extend(xml_values(&body, "Key"));
+            token = xml_values(&body, "NextContinuationToken")
+                .into_iter()
+                .next();
+            if token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// SigV4 URI encoding; `/` is left alone in paths.
+fn encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Text of every `<tag>...</tag>` in a (flat, unescaped-enough) S3 response.
+fn xml_values(body: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    body.split(&open)
+        .skip(1)
+        .filter_map(|rest| rest.split_once(&close))
+        .map(|(value, _)| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
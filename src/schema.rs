@@ -0,0 +1,178 @@
+//! Versions of the address schema cached in `geocode`, so that a change to
+//! what a cached address holds doesn't mean dropping years of them.
+//!
+//! Every row has the `schema_version` it was written in, and new rows are
+//! written in `CURRENT`. A change to the schema bumps `CURRENT` and adds the
+//! upgrade from the version before to `UPGRADES`; addresses from older rows
+//! go through each upgrade after their version when they are read, so
+//! nothing reading the cache sees an old one. Lookups then write what they
+//! upgraded back, off the request path, so the rows people ask for migrate
+//! as they are asked for, and
+//!
+//! ```text
+//! gaia migrate-cache [--batch 500]
+//! ```
+//!
+//! migrates the rest in batches. Upgrades work on the address as the
+//! current `RadarAddress` reads it, so a renamed field also wants a
+//! `#[serde(alias)]` there for old JSON rows; binary rows change with the
+//! binary format's own version.
+//!
+//! Version 2 drops the blank text fields some providers send for what they
+//! don't know and trims the rest, which fresh addresses get before they are
+//! cached.
+
+use std::{sync::Arc, time::Instant};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{compression, metrics, RadarAddress};
+
+pub const CURRENT: i64 = 2;
+
+/// The upgrade from each version to the next, from version 1.
+const UPGRADES: &[fn(&mut RadarAddress)] = &[drop_blank_fields];
+
+fn drop_blank_fields(address: &mut RadarAddress) {
+    for field in [
+        &mut address.address_label,
+        &mut address.city,
+        &mut address.confidence,
+        &mut address.country,
+        &mut address.country_code,
+        &mut address.county,
+        &mut address.formatted_address,
+        &mut address.layer,
+        &mut address.number,
+        &mut address.postal_code,
+        &mut address.state,
+        &mut address.state_code,
+        &mut address.street,
+    ] {
+        *field = field
+            .take()
+            .map(|text| text.trim().to_string())
+            .filter(|text| !text.is_empty());
+    }
+}
+
+/// Make a provider's fresh `address` what `CURRENT` expects.
+pub fn fresh(address: &mut RadarAddress) {
+    drop_blank_fields(address);
+}
+
+/// Bring `address`, read from a row in `version`, up to `CURRENT`. Whether
+/// it needed it.
+pub fn upgrade(address: &mut RadarAddress, version: i64) -> bool {
+    let done = usize::try_from(version - 1).unwrap_or(0);
+    for upgrade in UPGRADES.iter().skip(done) {
+        upgrade(address);
+    }
+    version < CURRENT
+}
+
+/// Store the upgraded addresses of the rows, with the columns kept from them,
+/// in `CURRENT`.
+async fn write_back(pool: &Pool<Sqlite>, rows: &[(i64, RadarAddress)]) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut written = 0;
+    for (rowid, address) in rows {
+        let payload = compression::encode(address);
+        written += sqlx::query(
+            "UPDATE geocode SET address = ?, address_format = ?, schema_version = ?, \
+             country_code = ?, state_code = ?, postal_code = ?, city = ?, layer = ?, \
+             formatted_address = ? WHERE rowid = ? AND schema_version < ?",
+        )
+        .bind(&payload)
+        .bind(payload.format.id())
+        .bind(CURRENT)
+        .bind(&address.country_code)
+        .bind(&address.state_code)
+        .bind(&address.postal_code)
+        .bind(&address.city)
+        .bind(&address.layer)
+        .bind(&address.formatted_address)
+        .bind(rowid)
+        .bind(CURRENT)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    }
+    tx.commit().await?;
+    Ok(written)
+}
+
+/// Write the rows' upgraded addresses back off the request path, so a read
+/// never waits on a write.
+pub fn write_back_later(pool: Arc<Pool<Sqlite>>, rows: Vec<(i64, RadarAddress)>) {
+    if rows.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        match write_back(&pool, &rows).await {
+            Ok(written) => metrics::increment_by("gaia_schema_upgrades_total", &[], written),
+            Err(e) => tracing::warn!("failed to write back upgraded addresses: {}", e),
+        }
+    });
+}
+
+/// `gaia migrate-cache`.
+pub async fn run(args: &[String], pool: &Pool<Sqlite>) -> Result<(), String> {
+    let mut batch = 500i64;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--batch" => {
+                batch = args
+                    .next()
+                    .and_then(|batch| batch.parse().ok())
+                    .filter(|batch| (1..=10_000).contains(batch))
+                    .ok_or("--batch must be between 1 and 10000")?;
+            }
+            other => return Err(format!("unexpected argument {:?}", other)),
+        }
+    }
+
+    let started = Instant::now();
+    let (mut after, mut migrated, mut unreadable) = (0, 0, 0);
+    loop {
+        let rows = sqlx::query_as::<_, (i64, Vec<u8>, i64)>(
+            "SELECT rowid, address, schema_version FROM geocode \
+             WHERE rowid > ? AND schema_version < ? AND address IS NOT NULL \
+             ORDER BY rowid LIMIT ?",
+        )
+        .bind(after)
+        .bind(CURRENT)
+        .bind(batch)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let Some(&(last, _, _)) = rows.last() else {
+            break;
+        };
+        after = last;
+
+        let mut upgraded = vec![];
+        for (rowid, stored, version) in rows {
+            // left for the janitor
+            let Ok(mut address) = compression::read(&stored) else {
+                unreadable += 1;
+                continue;
+            };
+            upgrade(&mut address, version);
+            upgraded.push((rowid, address));
+        }
+        migrated += write_back(pool, &upgraded)
+            .await
+            .map_err(|e| e.to_string())?;
+        tracing::debug!("migrated {} cached addresses", migrated);
+    }
+    tracing::info!(
+        "migrated {} cached addresses to schema version {} in {:.1}s; {} unreadable",
+        migrated,
+        CURRENT,
+        started.elapsed().as_secs_f64(),
+        unreadable
+    );
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! Listening sockets, graceful shutdown and zero-downtime binary upgrades.
+//!
+//! `BIND_ADDRESS` (default `0.0.0.0:8081`) and `ADMIN_BIND_ADDRESS` each take
+//! a comma-separated list of addresses, e.g. `0.0.0.0:8081,[::]:8081` to
+//! serve on IPv4 and IPv6 at once. IPv6 sockets are IPv6-only so they can
+//! share a port with an IPv4 one.
+//!
+//! Handlers can take `ConnectInfo<SocketAddr>` for the client's address;
+//! with `PROXY_PROTOCOL=true` that is the one from the load balancer's PROXY
+//! header (see `proxy_protocol`).
+//!
+//! `SIGTERM` (or Ctrl-C) stops accepting connections, lets the requests and
+//! jobs in flight finish for up to `SHUTDOWN_TIMEOUT_SECS` (default 30) and
+//! exits.
+//!
+//! To upgrade, replace the binary and send the running process `SIGUSR1`. It
+//! starts the file at the path it was started from, with the same arguments
+//! and its listening sockets passed down as inherited descriptors
+//! (`GAIA_LISTEN_FDS`). The new process serves on the same sockets as soon as
+//! it is ready and then sends the old one `SIGTERM`, so the sockets never
+//! close and no connection is refused along the way. Addresses added to the
+//! configuration in between are bound fresh and removed ones are closed. If
+//! the new binary fails to start, the old one keeps serving.
+//!
+//! The new process is a child of the old one, so this is for running gaia
+//! directly; a supervisor that watches the first process (systemd's
+//! `Type=simple`, a container's PID 1) sees the upgrade as an exit.
+
+use std::{
+    env,
+    net::{SocketAddr, TcpListener},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    path::PathBuf,
+    process::Command,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::TcpStream,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tower::ServiceExt;
+
+use crate::{config, jobs, proxy_protocol, write_behind};
+
+const LISTEN_FDS: &str = "GAIA_LISTEN_FDS";
+const PARENT: &str = "GAIA_UPGRADE_PARENT";
+
+/// The addresses listed in `name`, if set.
+pub fn addresses(name: &str) -> Option<Vec<SocketAddr>> {
+    let value = env::var(name).ok()?;
+    let addresses = value
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(|a| {
+            a.parse()
+                .unwrap_or_else(|e| panic!("Invalid {} {:?}: {}", name, a, e))
+        })
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        panic!("Invalid {}: no addresses", name);
+    }
+    Some(addresses)
+}
+
+/// The binary as it was started, before any replacement of the file.
+fn executable() -> &'static Option<PathBuf> {
+    static EXECUTABLE: OnceLock<Option<PathBuf>> = OnceLock::new();
+    EXECUTABLE.get_or_init(|| env::current_exe().ok())
+}
+
+/// Whether this process took over from an older one.
+pub fn inherited() -> bool {
+    env::var_os(PARENT).is_some()
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) {
+    // SAFETY: only changes flags of a descriptor this process owns
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        libc::fcntl(fd, libc::F_SETFD, flags);
+    }
+}
+
+fn bind(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// The sockets handed down by the process being upgraded.
+fn inherited_listeners() -> Vec<TcpListener> {
+    let Ok(fds) = env::var(LISTEN_FDS) else {
+        return vec![];
+    };
+    tracing::info!("taking over the listening sockets from the previous process");
+    fds.split(',')
+        .map(|fd| {
+            let fd = fd
+                .parse::<RawFd>()
+                .unwrap_or_else(|e| panic!("Invalid {}: {}", LISTEN_FDS, e));
+            set_cloexec(fd, true);
+            // SAFETY: the previous process passed this descriptor down for us alone
+            unsafe { TcpListener::from_raw_fd(fd) }
+        })
+        .collect()
+}
+
+/// A listener for each of `addresses`, taken over from the process being
+/// upgraded where it was already listening there.
+fn listeners(addresses: &[SocketAddr], inherited: &mut Vec<TcpListener>) -> Vec<TcpListener> {
+    addresses
+        .iter()
+        .map(|&address| {
+            let reused = inherited
+                .iter()
+                .position(|l| l.local_addr().is_ok_and(|local| local == address));
+            match reused {
+                Some(index) => inherited.swap_remove(index),
+                None => {
+                    bind(address).unwrap_or_else(|e| panic!("Failed to bind {}: {}", address, e))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Start the current binary again with `fds` to listen on.
+fn upgrade(fds: &[RawFd]) -> std::io::Result<u32> {
+    let Some(executable) = executable() else {
+        return Err(std::io::Error::other("cannot tell which binary is running"));
+    };
+    for &fd in fds {
+        set_cloexec(fd, false);
+    }
+    let child = Command::new(executable)
+        .args(env::args_os().skip(1))
+        .env(
+            LISTEN_FDS,
+            fds.iter()
+                .map(|fd| fd.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .env(PARENT, std::process::id().to_string())
+        .spawn();
+    for &fd in fds {
+        set_cloexec(fd, true);
+    }
+    child.map(|child| child.id())
+}
+
+/// Returns once the process should stop accepting, handling upgrades until
+/// then.
+async fn signals(fds: Vec<RawFd>) {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut upgrades = signal(SignalKind::user_defined1()).expect("failed to listen for SIGUSR1");
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = upgrades.recv() => match upgrade(&fds) {
+                Ok(pid) => tracing::info!("upgrading: started process {}", pid),
+                Err(e) => tracing::error!("upgrade failed, still serving: {}", e),
+            },
+        }
+    }
+
+    let timeout = Duration::from_secs(config::var("SHUTDOWN_TIMEOUT_SECS", 30));
+    tracing::info!(
+        "shutting down, waiting up to {:?} for work in flight",
+        timeout
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        tracing::warn!("shutdown timed out with work still in flight");
+        // what bulk lookups buffered is cheap to keep and lost on exit
+        write_behind::drain().await;
+        std::process::exit(0);
+    });
+}
+
+/// Serve HTTP/1 on one accepted connection until it closes, or until
+/// `stopped` and the request in flight has been answered.
+async fn connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    app: Router,
+    proxied: bool,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let client = if proxied {
+        match proxy_protocol::client(&mut stream, peer).await {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("dropping connection from {}: {}", peer, e);
+                return;
+            }
+        }
+    } else {
+        peer
+    };
+    let service = service_fn(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(client));
+        app.clone().oneshot(request)
+    });
+    let conn = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades();
+    tokio::pin!(conn);
+    let mut shutting_down = false;
+    loop {
+        tokio::select! {
+            result = conn.as_mut() => {
+                if let Err(e) = result {
+                    tracing::debug!("connection from {} failed: {}", client, e);
+                }
+                break;
+            }
+            _ = stopped.wait_for(|&stop| stop), if !shutting_down => {
+                shutting_down = true;
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// Accept connections on `listener` until `stopped`, then wait for the ones
+/// open to finish.
+async fn run(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    proxied: bool,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let (close, closed) = watch::channel(());
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stopped.wait_for(|&stop| stop) => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                if !matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionRefused
+                        | std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::ConnectionReset
+                ) {
+                    // most likely out of file descriptors; spinning won't help
+                    tracing::error!("failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                continue;
+            }
+        };
+        let app = app.clone();
+        let stopped = stopped.clone();
+        let closed = closed.clone();
+        tokio::spawn(async move {
+            connection(stream, peer, app, proxied, stopped).await;
+            drop(closed);
+        });
+    }
+    drop(listener);
+    drop(closed);
+    close.closed().await;
+}
+
+/// Serve each app on its addresses until shut down. The first set of
+/// addresses is the public one, the only one expecting PROXY headers.
+pub async fn serve(servers: Vec<(Vec<SocketAddr>, Router)>) {
+    executable();
+    let mut inherited = inherited_listeners();
+    let servers = servers
+        .into_iter()
+        .map(|(addresses, app)| (listeners(&addresses, &mut inherited), app))
+        .collect::<Vec<_>>();
+    // whatever is left was only listening on addresses no longer configured
+    drop(inherited);
+
+    let (stop, stopped) = watch::channel(false);
+    let mut fds = vec![];
+    let mut tasks = vec![];
+    for (index, (listeners, app)) in servers.into_iter().enumerate() {
+        let proxied = index == 0 && proxy_protocol::enabled();
+        for listener in listeners {
+            listener
+                .set_nonblocking(true)
+                .expect("failed to make the listener non-blocking");
+            fds.push(listener.as_raw_fd());
+            tracing::info!(
+                "listening on {}{}",
+                listener.local_addr().expect("listener has an address"),
+                if proxied { " (PROXY protocol)" } else { "" }
+            );
+            let listener =
+                tokio::net::TcpListener::from_std(listener).expect("failed to use the listener");
+            tasks.push(tokio::spawn(run(
+                listener,
+                app.clone(),
+                proxied,
+                stopped.clone(),
+            )));
+        }
+    }
+
+    if let Some(parent) = env::var(PARENT).ok().and_then(|p| p.parse::<i32>().ok()) {
+        tracing::info!("serving, telling process {} to stop", parent);
+        // SAFETY: kill has no memory safety requirements
+        unsafe {
+            libc::kill(parent, libc::SIGTERM);
+        }
+    }
+
+    signals(fds).await;
+    let _ = stop.send(true);
+    for task in tasks {
+        if let Err(e) = task.await {
+            tracing::error!("server task failed: {}", e);
+        }
+    }
+    jobs::drain().await;
+    write_behind::drain().await;
+}
@@ -0,0 +1,289 @@
+//! Reading ESRI shapefiles: the shapes in a `.shp` and their attributes in
+//! the `.dbf` beside it, paired up by record number.
+//!
+//! Only what boundaries need is read. Polygons (with or without Z or M
+//! values, which are skipped) keep their rings as (x, y) points; every other
+//! shape type comes back with no rings. Attributes are the dBase field
+//! values as trimmed text, read as UTF-8, and deleted records are dropped.
+
+use std::collections::HashMap;
+
+/// The file code every `.shp` starts with.
+const FILE_CODE: i32 = 9994;
+
+/// The `.shp` header, before the first record.
+const HEADER_LENGTH: usize = 100;
+
+/// Polygon, PolygonZ and PolygonM.
+const POLYGON_TYPES: [i32; 3] = [5, 15, 25];
+
+/// A ring of (x, y) points.
+pub type Ring = Vec<(f64, f64)>;
+
+/// One record: a shape's rings, in the order the file lists them, and its
+/// attributes by field name.
+#[derive(Debug)]
+pub struct Record {
+    pub rings: Vec<Ring>,
+    pub attributes: HashMap<String, String>,
+}
+
+fn i32_be(bytes: &[u8], at: usize) -> Option<i32> {
+    Some(i32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn i32_le(bytes: &[u8], at: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn f64_le(bytes: &[u8], at: usize) -> Option<f64> {
+    Some(f64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// The records of the shapefile `shp` with attributes `dbf`.
+pub fn read(shp: &[u8], dbf: &[u8]) -> Result<Vec<Record>, String> {
+    let shapes = shapes(shp)?;
+    let attributes = attributes(dbf)?;
+    if attributes.len() != shapes.len() {
+        return Err(format!(
+            "{} shapes but {} attribute records",
+            shapes.len(),
+            attributes.len()
+        ));
+    }
+    Ok(shapes
+        .into_iter()
+        .zip(attributes)
+        .filter_map(|(rings, attributes)| {
+            Some(Record {
+                rings,
+                attributes: attributes?,
+            })
+        })
+        .collect())
+}
+
+/// Each record's rings.
+fn shapes(shp: &[u8]) -> Result<Vec<Vec<Ring>>, String> {
+    if shp.len() < HEADER_LENGTH || i32_be(shp, 0) != Some(FILE_CODE) {
+        return Err(String::from("not a shapefile"));
+    }
+    let mut shapes = vec![];
+    let mut at = HEADER_LENGTH;
+    while at < shp.len() {
+        // lengths count 16-bit words
+        let length = i32_be(shp, at + 4)
+            .and_then(|words| usize::try_from(words).ok())
+            .ok_or("truncated record header")?
+            * 2;
+        let content = shp.get(at + 8..at + 8 + length).ok_or("truncated record")?;
+        shapes.push(rings(content).ok_or("malformed polygon")?);
+        at += 8 + length;
+    }
+    Ok(shapes)
+}
+
+/// The rings of a record's `content`; none for shapes that aren't polygons.
+fn rings(content: &[u8]) -> Option<Vec<Ring>> {
+    if !POLYGON_TYPES.contains(&i32_le(content, 0)?) {
+        return Some(vec![]);
+    }
+    // after the type and the bounding box
+    let parts = usize::try_from(i32_le(content, 36)?).ok()?;
+    let points = usize::try_from(i32_le(content, 40)?).ok()?;
+    let starts = (0..parts)
+        .map(|part| usize::try_from(i32_le(content, 44 + part * 4)?).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let first_point = 44 + parts * 4;
+    let point = |index: usize| {
+        let at = first_point + index * 16;
+        Some((f64_le(content, at)?, f64_le(content, at + 8)?))
+    };
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&points]))
+        .map(|(&start, &end)| match start <= end && end <= points {
+            true => (start..end).map(point).collect(),
+            false => None,
+        })
+        .collect()
+}
+
+/// Each record's attributes, `None` for a deleted one.
+fn attributes(dbf: &[u8]) -> Result<Vec<Option<HashMap<String, String>>>, String> {
+    let u16_le = |at: usize| Some(u16::from_le_bytes(dbf.get(at..at + 2)?.try_into().ok()?));
+    let count = dbf
+        .get(4..8)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        .ok_or("not a dBase file")?;
+    let header_length = usize::from(u16_le(8).ok_or("not a dBase file")?);
+    let record_length = usize::from(u16_le(10).ok_or("not a dBase file")?);
+
+    // 32-byte descriptors after the 32-byte header, up to a 0x0d
+    let mut fields = vec![];
+    let mut at = 32;
+    while dbf.get(at).is_some_and(|&b| b != 0x0d) {
+        let descriptor = dbf.get(at..at + 32).ok_or("truncated field descriptor")?;
+        let name = descriptor[..11].split(|&b| b == 0).next().unwrap_or(&[]);
+        fields.push((
+            String::from_utf8_lossy(name).trim().to_string(),
+            usize::from(descriptor[16]),
+        ));
+        at += 32;
+    }
+
+    (0..count)
+        .map(|index| {
+            let start = header_length + index * record_length;
+            let record = dbf
+                .get(start..start + record_length)
+                .ok_or("truncated attribute record")?;
+            if record[0] == b'*' {
+                return Ok(None);
+            }
+            let mut values = HashMap::new();
+            let mut at = 1;
+            for (name, width) in &fields {
+                let value = record
+                    .get(at..at + width)
+                    .ok_or("attribute record shorter than its fields")?;
+                values.insert(
+                    name.clone(),
+                    String::from_utf8_lossy(value).trim().to_string(),
+                );
+                at += width;
+            }
+            Ok(Some(values))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `.shp` of `records`, each a shape type and its parts.
+    fn shp(records: &[(i32, Vec<Ring>)]) -> Vec<u8> {
+        let mut out = vec![0u8; HEADER_LENGTH];
+        out[..4].copy_from_slice(&FILE_CODE.to_be_bytes());
+        out[28..32].copy_from_slice(&1000i32.to_le_bytes());
+        out[32..36].copy_from_slice(&5i32.to_le_bytes());
+        for (number, (shape_type, parts)) in records.iter().enumerate() {
+            let mut content = shape_type.to_le_bytes().to_vec();
+            content.extend([0u8; 32]);
+            let points = parts.iter().map(Vec::len).sum::<usize>();
+            content.extend((parts.len() as i32).to_le_bytes());
+            content.extend((points as i32).to_le_bytes());
+            let mut start = 0;
+            for part in parts {
+                content.extend((start as i32).to_le_bytes());
+                start += part.len();
+            }
+            for (x, y) in parts.iter().flatten() {
+                content.extend(x.to_le_bytes());
+                content.extend(y.to_le_bytes());
+            }
+            out.extend((number as i32 + 1).to_be_bytes());
+            out.extend((content.len() as i32 / 2).to_be_bytes());
+            out.extend(content);
+        }
+        let words = (out.len() / 2) as i32;
+        out[24..28].copy_from_slice(&words.to_be_bytes());
+        out
+    }
+
+    /// A `.dbf` with character `fields` of the given widths, and `records`
+    /// of their values, deleted when the flag is set.
+    fn dbf(fields: &[(&str, u8)], records: &[(bool, Vec<&str>)]) -> Vec<u8> {
+        let record_length = 1 + fields.iter().map(|(_, w)| usize::from(*w)).sum::<usize>();
+        let header_length = 32 + 32 * fields.len() + 1;
+        let mut out = vec![0u8; 32];
+        out[0] = 3;
+        out[4..8].copy_from_slice(&(records.len() as u32).to_le_bytes());
+        out[8..10].copy_from_slice(&(header_length as u16).to_le_bytes());
+        out[10..12].copy_from_slice(&(record_length as u16).to_le_bytes());
+        for (name, width) in fields {
+            let mut descriptor = [0u8; 32];
+            descriptor[..name.len()].copy_from_slice(name.as_bytes());
+            descriptor[11] = b'C';
+            descriptor[16] = *width;
+            out.extend(descriptor);
+        }
+        out.push(0x0d);
+        for (deleted, values) in records {
+            out.push(if *deleted { b'*' } else { b' ' });
+            for ((_, width), value) in fields.iter().zip(values) {
+                let mut cell = value.as_bytes().to_vec();
+                cell.resize(usize::from(*width), b' ');
+                out.extend(cell);
+            }
+        }
+        out.push(0x1a);
+        out
+    }
+
+    fn square(x: f64, y: f64, size: f64) -> Vec<(f64, f64)> {
+        vec![
+            (x, y),
+            (x, y + size),
+            (x + size, y + size),
+            (x + size, y),
+            (x, y),
+        ]
+    }
+
+    #[test]
+    fn polygons_with_their_attributes() {
+        let records = read(
+            &shp(&[
+                (5, vec![square(0.0, 0.0, 2.0), square(0.5, 0.5, 1.0)]),
+                (15, vec![square(5.0, 5.0, 1.0)]),
+            ]),
+            &dbf(
+                &[("NAME", 10), ("ISO_A2", 2)],
+                &[
+                    (false, vec!["Ringland", "RL"]),
+                    (false, vec!["Zland", "ZL"]),
+                ],
+            ),
+        )
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].rings,
+            vec![square(0.0, 0.0, 2.0), square(0.5, 0.5, 1.0)]
+        );
+        assert_eq!(records[0].attributes["NAME"], "Ringland");
+        assert_eq!(records[0].attributes["ISO_A2"], "RL");
+        assert_eq!(records[1].rings, vec![square(5.0, 5.0, 1.0)]);
+    }
+
+    #[test]
+    fn other_shapes_have_no_rings_and_deleted_records_go() {
+        let records = read(
+            &shp(&[
+                (3, vec![vec![(0.0, 0.0), (1.0, 1.0)]]),
+                (5, vec![square(0.0, 0.0, 1.0)]),
+            ]),
+            &dbf(
+                &[("NAME", 8)],
+                &[(false, vec!["Line"]), (true, vec!["Gone"])],
+            ),
+        )
+        .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].rings.is_empty());
+        assert_eq!(records[0].attributes["NAME"], "Line");
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let good = shp(&[(5, vec![square(0.0, 0.0, 1.0)])]);
+        let names = dbf(&[("NAME", 8)], &[(false, vec!["Square"])]);
+        assert!(read(&good[..good.len() - 8], &names).is_err());
+        assert!(read(&good[4..], &names).is_err());
+        assert!(read(&good, &names[..40]).is_err());
+        // a shape without attributes, or the other way round
+        assert!(read(&good, &dbf(&[("NAME", 8)], &[])).is_err());
+    }
+}
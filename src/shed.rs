@@ -0,0 +1,201 @@
+//! Global concurrency limit with early load shedding, so a burst of bulk
+//! requests degrades into quick 503s instead of slowing everyone down.
+//!
+//! Off unless `MAX_CONCURRENT_REQUESTS` is set. Requests beyond the limit
+//! wait in a queue of at most `MAX_QUEUED_REQUESTS` (default 100) for up to
+//! `REQUEST_QUEUE_TIMEOUT_MS` (default 5000); past either they are turned
+//! away immediately. Health checks and metrics bypass the limit so an
+//! overloaded instance can still be observed.
+//!
+//! `MAX_CONCURRENT_REQUESTS_PER_CLIENT`, also off by default, caps how many
+//! requests one client address (see `forwarded`) can have in flight at once;
+//! past it that client gets a 429 while everyone else carries on.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use tokio::sync::Semaphore;
+
+use crate::{config, forwarded::ClientIp, metrics};
+
+#[derive(Debug)]
+struct Limits {
+    max_concurrent: usize,
+    permits: Semaphore,
+    max_queued: usize,
+    queued: AtomicUsize,
+    queue_timeout: Duration,
+}
+
+/// Read the limits now instead of on the first request.
+pub fn check() {
+    limits();
+    per_client();
+}
+
+fn limits() -> Option<&'static Limits> {
+    static LIMITS: OnceLock<Option<Limits>> = OnceLock::new();
+    LIMITS
+        .get_or_init(|| {
+            let max_concurrent = config::var("MAX_CONCURRENT_REQUESTS", 0usize);
+            (max_concurrent > 0).then(|| Limits {
+                max_concurrent,
+                permits: Semaphore::new(max_concurrent),
+                max_queued: config::var("MAX_QUEUED_REQUESTS", 100usize),
+                queued: AtomicUsize::new(0),
+                queue_timeout: Duration::from_millis(config::var("REQUEST_QUEUE_TIMEOUT_MS", 5000)),
+            })
+        })
+        .as_ref()
+}
+
+const EXEMPT: &[&str] = &["/metrics", "/api/v0/health", "/api/v1/health"];
+
+fn overloaded(reason: &str) -> Response {
+    metrics::increment("gaia_requests_shed_total", &[("reason", reason)]);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "1")],
+        Json(json!("server overloaded, try again later")),
+    )
+        .into_response()
+}
+
+fn report(limits: &Limits) {
+    metrics::set_gauge(
+        "gaia_requests_in_flight",
+        &[],
+        (limits.max_concurrent - limits.permits.available_permits()) as f64,
+    );
+    metrics::set_gauge(
+        "gaia_requests_queued",
+        &[],
+        limits.queued.load(Ordering::Relaxed) as f64,
+    );
+}
+
+/// A place in the queue, given up when dropped (including when the client
+/// goes away while waiting).
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    /// How many were already waiting.
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn join(queued: &'a AtomicUsize) -> Self {
+        Queued {
+            queued,
+            position: queued.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn per_client() -> usize {
+    static PER_CLIENT: OnceLock<usize> = OnceLock::new();
+    *PER_CLIENT.get_or_init(|| config::var("MAX_CONCURRENT_REQUESTS_PER_CLIENT", 0usize))
+}
+
+/// Requests in flight per client, for those with any.
+fn clients() -> &'static Mutex<HashMap<IpAddr, usize>> {
+    static CLIENTS: OnceLock<Mutex<HashMap<IpAddr, usize>>> = OnceLock::new();
+    CLIENTS.get_or_init(Default::default)
+}
+
+/// One of a client's requests in flight, finished when dropped.
+struct InFlight(IpAddr);
+
+impl InFlight {
+    /// `None` when `client` already has `limit` in flight.
+    fn start(client: IpAddr, limit: usize) -> Option<Self> {
+        let mut clients = clients().lock().unwrap();
+        let count = clients.entry(client).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(InFlight(client))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut clients = clients().lock().unwrap();
+        if let Some(count) = clients.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Middleware applying the limits to every request but `EXEMPT` ones.
+pub async fn limit(request: Request, next: Next) -> Response {
+    if EXEMPT.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let client = request.extensions().get::<ClientIp>().copied();
+    let _in_flight = match client {
+        Some(ClientIp(client)) if per_client() > 0 => match InFlight::start(client, per_client()) {
+            Some(in_flight) => Some(in_flight),
+            None => {
+                metrics::increment("gaia_requests_shed_total", &[("reason", "client_limit")]);
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, "1")],
+                    Json(json!("too many concurrent requests, try again later")),
+                )
+                    .into_response();
+            }
+        },
+        _ => None,
+    };
+    let Some(limits) = limits() else {
+        return next.run(request).await;
+    };
+
+    let permit = match limits.permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let slot = Queued::join(&limits.queued);
+            if slot.position >= limits.max_queued {
+                return overloaded("queue_full");
+            }
+            report(limits);
+            let waited = tokio::time::timeout(limits.queue_timeout, limits.permits.acquire()).await;
+            drop(slot);
+            match waited {
+                Ok(Ok(permit)) => permit,
+                _ => {
+                    report(limits);
+                    return overloaded("queue_timeout");
+                }
+            }
+        }
+    };
+    report(limits);
+    let response = next.run(request).await;
+    drop(permit);
+    report(limits);
+    response
+}
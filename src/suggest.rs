@@ -0,0 +1,270 @@
+//! "Did you mean" suggestions for forward lookups nothing answered, so a
+//! search box can offer the next thing to try instead of an empty list.
+//!
+//! With `suggest=true`, forward geocodes answer `{results, suggestions}`;
+//! the suggestions are only made when there are no results. Each is a
+//! `query` to try and the `reason` for it:
+//!
+//! - `spelling`: the query with its misspelt words corrected, either common
+//!   misspellings of street words (`stret`, `aveune`) or words one or two
+//!   letters away from those of cached addresses.
+//! - `postalCode`: the query with its postal code replaced by nearby ones
+//!   the cache has (sharing the first three characters, closest first).
+//! - `city`: the city alone, one the cache knows of that the query names,
+//!   or else whatever follows the query's first comma.
+//!
+//! Suggestions come from the cache and the query alone, so making them
+//! costs no provider calls, and whether one finds anything isn't checked.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::{forward, fuzzy};
+
+const MAX_SUGGESTIONS: usize = 5;
+
+/// How many nearby postal codes to suggest.
+const POSTAL_CODES: i64 = 2;
+
+/// Common misspellings of the words addresses are made of.
+const MISSPELLINGS: &[(&str, &str)] = &[
+    ("stret", "street"),
+    ("steet", "street"),
+    ("streat", "street"),
+    ("sreet", "street"),
+    ("avenu", "avenue"),
+    ("aveune", "avenue"),
+    ("avnue", "avenue"),
+    ("avenuw", "avenue"),
+    ("raod", "road"),
+    ("rode", "road"),
+    ("boulavard", "boulevard"),
+    ("bulevard", "boulevard"),
+    ("boulevad", "boulevard"),
+    ("drvie", "drive"),
+    ("dirve", "drive"),
+    ("lnae", "lane"),
+    ("cirlce", "circle"),
+    ("plaec", "place"),
+    ("hieghts", "heights"),
+    ("heigths", "heights"),
+    ("parkwya", "parkway"),
+    ("terrance", "terrace"),
+    ("highwya", "highway"),
+    ("centre", "center"),
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Suggestion {
+    pub query: String,
+    pub reason: &'static str,
+}
+
+/// `word` cased like `like`: capitalized if it is.
+fn cased(word: &str, like: &str) -> String {
+    match like.chars().next() {
+        Some(c) if c.is_uppercase() => {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+                .unwrap_or_default()
+        }
+        _ => word.to_string(),
+    }
+}
+
+/// `query` with every word `correct` has a correction for replaced, keeping
+/// the punctuation around it.
+fn rewrite(query: &str, mut correct: impl FnMut(&str) -> Option<String>) -> String {
+    query
+        .split_whitespace()
+        .map(|token| {
+            let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+            match (!word.is_empty()).then(|| correct(word)).flatten() {
+                Some(correction) => token.replacen(word, &cased(&correction, word), 1),
+                None => token.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The words of cached formatted addresses sharing trigrams with `word`.
+async fn cached_words(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    word: &str,
+) -> Result<HashSet<String>, sqlx::Error> {
+    let Some(fts_query) = fuzzy::fts_query(word) else {
+        return Ok(HashSet::new());
+    };
+    let addresses = sqlx::query_as::<_, (String,)>(
+        "SELECT DISTINCT g.formatted_address FROM geocode_trigrams \
+         JOIN geocode g ON g.rowid = geocode_trigrams.rowid \
+         WHERE geocode_trigrams MATCH ? AND g.namespace = ? \
+         ORDER BY geocode_trigrams.rank LIMIT 50",
+    )
+    .bind(fts_query)
+    .bind(namespace)
+    .fetch_all(pool)
+    .await?;
+    Ok(addresses
+        .iter()
+        .flat_map(|(address,)| gaia_core::matching::words(address))
+        .collect())
+}
+
+/// The query with misspelt words corrected, if any were.
+async fn spelling(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut corrections = vec![];
+    for word in gaia_core::matching::words(query) {
+        if let Some((_, correct)) = MISSPELLINGS.iter().find(|(wrong, _)| *wrong == word) {
+            corrections.push((word, correct.to_string()));
+            continue;
+        }
+        // numbers and short words are too easily one letter from another
+        if word.chars().count() < 4 || word.chars().any(|c| c.is_numeric()) {
+            continue;
+        }
+        let known = cached_words(pool, namespace, &word).await?;
+        if known.contains(&word) {
+            continue;
+        }
+        let allowed = if word.chars().count() <= 5 { 1 } else { 2 };
+        let closest = known
+            .into_iter()
+            .map(|candidate| {
+                (
+                    gaia_core::matching::edit_distance(&word, &candidate),
+                    candidate,
+                )
+            })
+            .filter(|(distance, _)| *distance <= allowed)
+            .min();
+        if let Some((_, candidate)) = closest {
+            corrections.push((word, candidate));
+        }
+    }
+    if corrections.is_empty() {
+        return Ok(None);
+    }
+    let corrected = rewrite(query, |word| {
+        let word = word.to_lowercase();
+        corrections
+            .iter()
+            .find(|(wrong, _)| *wrong == word)
+            .map(|(_, correct)| correct.clone())
+    });
+    Ok(Some(corrected))
+}
+
+/// The query with its postal code swapped for nearby cached ones.
+async fn postal_codes(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    // the last word with digits and three characters or more, as in
+    // "12 Main St, Rochester, NY 14604" or "SW1A 1AA"
+    let Some(code) = query
+        .split(|c: char| !c.is_alphanumeric())
+        .rev()
+        .find(|w| w.chars().count() >= 3 && w.chars().any(|c| c.is_ascii_digit()))
+    else {
+        return Ok(vec![]);
+    };
+    let prefix = code.chars().take(3).collect::<String>().to_uppercase();
+    let nearby = sqlx::query_as::<_, (String,)>(
+        "SELECT DISTINCT postal_code FROM geocode \
+         WHERE namespace = ? AND substr(upper(postal_code), 1, 3) = ? AND upper(postal_code) != ? \
+         ORDER BY abs(CAST(postal_code AS INTEGER) - CAST(? AS INTEGER)), postal_code LIMIT ?",
+    )
+    .bind(namespace)
+    .bind(&prefix)
+    .bind(code.to_uppercase())
+    .bind(code)
+    .bind(POSTAL_CODES)
+    .fetch_all(pool)
+    .await?;
+    Ok(nearby
+        .into_iter()
+        .map(|(nearby,)| query.replacen(code, &nearby, 1))
+        .collect())
+}
+
+/// The city of the query alone.
+async fn city(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let words = gaia_core::matching::words(query).collect::<Vec<_>>();
+    let padded = format!(" {} ", words.join(" "));
+    let cities = sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT city, max(state_code) FROM geocode \
+         WHERE namespace = ? AND city IS NOT NULL AND city != '' AND instr(?, lower(city)) > 0 \
+         GROUP BY city ORDER BY count(*) DESC LIMIT 20",
+    )
+    .bind(namespace)
+    .bind(&padded)
+    .fetch_all(pool)
+    .await?;
+    // whole words only, the longest name first: "West Palm Beach", not "Palm"
+    let named = cities
+        .into_iter()
+        .filter(|(city, _)| {
+            let city = gaia_core::matching::words(city).collect::<Vec<_>>();
+            !city.is_empty() && padded.contains(&format!(" {} ", city.join(" ")))
+        })
+        .max_by_key(|(city, _)| city.len());
+    Ok(match named {
+        Some((city, Some(state))) => Some(format!("{}, {}", city, state)),
+        Some((city, None)) => Some(city),
+        None => query
+            .split_once(',')
+            .map(|(_, rest)| rest.trim().to_string())
+            .filter(|rest| !rest.is_empty()),
+    })
+}
+
+/// Suggestions for `query`, which found nothing, best first.
+pub async fn suggestions(
+    pool: &Pool<Sqlite>,
+    namespace: &str,
+    query: &str,
+) -> Result<Vec<Suggestion>, sqlx::Error> {
+    let mut seen = HashSet::from([forward::normalized(query)]);
+    let mut suggestions = vec![];
+    let corrected = spelling(pool, namespace, query).await?;
+    if let Some(corrected) = &corrected {
+        suggestions.push(Suggestion {
+            query: corrected.clone(),
+            reason: "spelling",
+        });
+    }
+    // the other suggestions fix the spelling too
+    let query = corrected.as_deref().unwrap_or(query);
+    for nearby in postal_codes(pool, namespace, query).await? {
+        suggestions.push(Suggestion {
+            query: nearby,
+            reason: "postalCode",
+        });
+    }
+    if let Some(city) = city(pool, namespace, query).await? {
+        suggestions.push(Suggestion {
+            query: city,
+            reason: "city",
+        });
+    }
+    suggestions.retain(|s| seen.insert(forward::normalized(&s.query)));
+    suggestions.truncate(MAX_SUGGESTIONS);
+    Ok(suggestions)
+}
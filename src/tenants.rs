@@ -0,0 +1,271 @@
+//! Serving several customers from one deployment.
+//!
+//! `TENANTS` (or `TENANTS_FILE`) lists `name:key[:daily quota]` entries,
+//! comma or newline separated. Once set, the geocoding, cache and geofence
+//! endpoints need one of those keys in `X-API-Key` (or the Google-style `key`
+//! parameter), usage is counted per tenant per day, and a tenant over its
+//! quota gets a 429. Without `TENANTS` everything runs as a single anonymous tenant.
+//!
+//! `TENANT_CACHE=shared` (the default) lets every tenant benefit from the one
+//! address cache while usage and quotas stay separate; `isolated` gives each
+//! tenant its own cache namespace as well.
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc, sync::OnceLock};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{auth::Admin, config, db, forwarded::ClientIp, metrics, params};
+
+#[derive(Debug, Clone)]
+struct TenantConfig {
+    name: String,
+    quota: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+struct Tenants {
+    by_key: HashMap<String, TenantConfig>,
+    isolated: bool,
+}
+
+/// Parse `TENANTS` and `TENANT_CACHE` before the first request needs them.
+pub fn check() {
+    tenants();
+}
+
+fn tenants() -> &'static Tenants {
+    static TENANTS: OnceLock<Tenants> = OnceLock::new();
+    TENANTS.get_or_init(|| {
+        let mut by_key = HashMap::new();
+        for entry in config::secret("TENANTS")
+            .unwrap_or_default()
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(key)) = (parts.next(), parts.next()) else {
+                panic!("Invalid TENANTS entry: expected name:key[:quota]");
+            };
+            let quota = parts.next().map(|q| {
+                q.parse()
+                    .unwrap_or_else(|_| panic!("Invalid TENANTS quota for {}", name))
+            });
+            by_key.insert(
+                key.to_string(),
+                TenantConfig {
+                    name: name.to_string(),
+                    quota,
+                },
+            );
+        }
+        let isolated = match config::var("TENANT_CACHE", String::from("shared")).as_str() {
+            "shared" => false,
+            "isolated" => true,
+            other => panic!("Invalid TENANT_CACHE: {}", other),
+        };
+        Tenants { by_key, isolated }
+    })
+}
+
+/// Whether `TENANTS` is set.
+pub fn configured() -> bool {
+    !tenants().by_key.is_empty()
+}
+
+/// Who a request is for. Extracting it authenticates the key and counts the
+/// request against the tenant's quota.
+#[derive(Debug, Clone, Default)]
+pub struct Tenant {
+    /// `None` when tenants aren't configured.
+    pub name: Option<String>,
+    /// The address the request came from, for the audit log.
+    pub client: Option<IpAddr>,
+}
+
+impl Tenant {
+    /// The cache namespace this tenant reads and writes; empty when shared.
+    pub fn namespace(&self) -> String {
+        match &self.name {
+            Some(name) if tenants().isolated => name.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn rejection(status: StatusCode, message: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!(message)))
+}
+
+fn today() -> i64 {
+    db::now() / 86400 * 86400
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let client = parts.extensions.get::<ClientIp>().map(|&ClientIp(ip)| ip);
+        let tenants = tenants();
+        if tenants.by_key.is_empty() {
+            return Ok(Tenant { name: None, client });
+        }
+
+        let key = match parts.headers.get("x-api-key") {
+            Some(key) => key.to_str().ok().map(String::from),
+            None => Query::<HashMap<String, String>>::from_request_parts(parts, state)
+                .await
+                .ok()
+                .and_then(|Query(params)| params.get("key").cloned()),
+        };
+        let Some(tenant) = key.and_then(|key| tenants.by_key.get(&key)) else {
+            return Err(rejection(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid API key",
+            ));
+        };
+        let Some(pool) = parts.extensions.get::<Arc<Pool<Sqlite>>>().cloned() else {
+            return Err(rejection(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database unavailable",
+            ));
+        };
+
+        metrics::increment("gaia_tenant_requests_total", &[("tenant", &tenant.name)]);
+        let used = sqlx::query_as::<_, (i64,)>(
+            "INSERT INTO tenant_usage(tenant, day, requests, upstream_calls) VALUES (?, ?, 1, 0) \
+             ON CONFLICT DO UPDATE SET requests = requests + 1 RETURNING requests",
+        )
+        .bind(&tenant.name)
+        .bind(today())
+        .fetch_one(&*pool)
+        .await;
+        match (used, tenant.quota) {
+            (Ok((used,)), Some(quota)) if used > quota => {
+                metrics::increment(
+                    "gaia_tenant_quota_exceeded_total",
+                    &[("tenant", &tenant.name)],
+                );
+                Err(rejection(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "daily quota exceeded",
+                ))
+            }
+            (Err(e), _) => {
+                tracing::error!("failed to record tenant usage: {}", e);
+                Err(rejection(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "database error",
+                ))
+            }
+            _ => Ok(Tenant {
+                name: Some(tenant.name.clone()),
+                client,
+            }),
+        }
+    }
+}
+
+/// `(tenant, requests today, daily quota)` for every tenant with a quota.
+pub async fn quota_usage(pool: &Pool<Sqlite>) -> Result<Vec<(String, i64, i64)>, sqlx::Error> {
+    let mut usage = Vec::new();
+    for tenant in tenants().by_key.values() {
+        let Some(quota) = tenant.quota else {
+            continue;
+        };
+        let used = sqlx::query_as::<_, (i64,)>(
+            "SELECT requests FROM tenant_usage WHERE tenant = ? AND day = ?",
+        )
+        .bind(&tenant.name)
+        .bind(today())
+        .fetch_optional(pool)
+        .await?
+        .map_or(0, |(used,)| used);
+        usage.push((tenant.name.clone(), used, quota));
+    }
+    Ok(usage)
+}
+
+/// Count a provider call made on a tenant's behalf. Off the request path.
+pub fn record_upstream_call(pool: Arc<Pool<Sqlite>>, tenant: Option<String>) {
+    let Some(tenant) = tenant else {
+        return;
+    };
+    metrics::increment("gaia_tenant_upstream_calls_total", &[("tenant", &tenant)]);
+    tokio::spawn(async move {
+        if let Err(e) = sqlx::query(
+            "INSERT INTO tenant_usage(tenant, day, requests, upstream_calls) VALUES (?, ?, 0, 1) \
+             ON CONFLICT DO UPDATE SET upstream_calls = upstream_calls + 1",
+        )
+        .bind(&tenant)
+        .bind(today())
+        .execute(&*pool)
+        .await
+        {
+            tracing::warn!("failed to record tenant upstream call: {}", e);
+        }
+    });
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub day: i64,
+    pub requests: i64,
+    pub upstream_calls: i64,
+}
+
+/// `GET /api/v0/admin/tenants/usage?since=&limit=&cursor=`: per-tenant daily
+/// usage. The next page's cursor is in `X-Gaia-Next-Cursor`.
+pub async fn get_tenant_usage(
+    _: Admin,
+    Query(params): Query<HashMap<String, String>>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> Response {
+    let since = match params::optional::<i64>(&params, "since", today() - 30 * 86400) {
+        Ok(since) => since,
+        Err(e) => return e.into_response(),
+    };
+    let limit = match params::optional::<i64>(&params, "limit", 1000) {
+        Ok(limit) if (1..=10000).contains(&limit) => limit,
+        Ok(_) => return params::bad_request("limit must be between 1 and 10000").into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let (after_day, after_tenant) = match params::cursor::<(i64, String)>(&params) {
+        Ok(cursor) => cursor.unzip(),
+        Err(e) => return e.into_response(),
+    };
+    match sqlx::query_as::<_, TenantUsage>(
+        "SELECT tenant, day, requests, upstream_calls FROM tenant_usage \
+         WHERE day >= ?1 AND (?2 IS NULL OR (day, tenant) > (?2, ?3)) \
+         ORDER BY day, tenant LIMIT ?4",
+    )
+    .bind(since)
+    .bind(after_day)
+    .bind(after_tenant)
+    .bind(limit)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(usage) => {
+            let mut response = (StatusCode::OK, Json(&usage)).into_response();
+            params::next_cursor(&mut response, &usage, limit, |u| (u.day, u.tenant.clone()));
+            response
+        }
+        Err(e) => {
+            tracing::error!("tenant usage query failed: {}", e);
+            rejection(StatusCode::INTERNAL_SERVER_ERROR, "database error").into_response()
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Running gaia inside another service's integration tests, with no database
+//! file, provider key or network setup.
+//!
+//! `Instance::start` serves the whole API, admin endpoints included, on a
+//! free local port over an in-memory database, so a test can point its HTTP
+//! client at `Instance::url`. `router` is the same app without a listener,
+//! to drive with `tower::ServiceExt::oneshot` and the request builders here.
+//! Either way misses are answered by the mock provider (see `mock`), so the
+//! same coordinates always give the same made-up address. Other settings
+//! come from the environment as they do for the server; the background
+//! tasks (janitor, refresher, jobs, backups) are not started.
+//!
+//! ```ignore
+//! let gaia = gaia::testing::Instance::start().await;
+//! let response = reqwest::get(gaia.url("/api/v0/geocode/reverse?lat=40.7&lon=-74")).await?;
+//! ```
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request},
+    response::Response,
+    Router,
+};
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{db, routing::Routes, service, upstream::Provider, Surface, Upstream};
+
+/// An empty in-memory cache with the schema applied.
+pub async fn database() -> Arc<Pool<Sqlite>> {
+    Arc::new(
+        db::memory()
+            .await
+            .expect("Failed to create the in-memory database"),
+    )
+}
+
+fn mock_upstream() -> Arc<Upstream> {
+    let mut upstream = Upstream::from_env();
+    upstream.provider = Provider::Mock;
+    upstream.merge = None;
+    upstream.routes = Routes::default();
+    upstream.fixtures = None;
+    Arc::new(upstream)
+}
+
+/// The API over `pool`, with the mock provider.
+pub fn router(pool: Arc<Pool<Sqlite>>) -> Router {
+    service(Surface::All, &pool, &mock_upstream())
+}
+
+/// gaia serving on a local port until dropped.
+#[derive(Debug)]
+pub struct Instance {
+    pub addr: SocketAddr,
+    /// The instance's database, to seed or inspect.
+    pub pool: Arc<Pool<Sqlite>>,
+    server: JoinHandle<()>,
+}
+
+impl Instance {
+    /// Serve over a fresh in-memory database.
+    pub async fn start() -> Self {
+        Self::with_database(database().await).await
+    }
+
+    /// Serve over `pool`, e.g. one already seeded.
+    pub async fn with_database(pool: Arc<Pool<Sqlite>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind a local port");
+        let addr = listener.local_addr().unwrap();
+        let app = router(pool.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Instance { addr, pool, server }
+    }
+
+    /// The URL of `path` (starting with `/`) on this instance.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A request from a local client, as the server would see one.
+fn request(method: &str, path: &str) -> axum::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(path)
+        .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+}
+
+/// `GET path`, for `router`.
+pub fn get(path: &str) -> Request<Body> {
+    request("GET", path).body(Body::empty()).unwrap()
+}
+
+/// `POST path` with `body` as JSON, for `router`.
+pub fn post_json(path: &str, body: &Value) -> Request<Body> {
+    request("POST", path)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// A single reverse geocode of `lat`/`lon`, for `router`.
+pub fn reverse(lat: f64, lon: f64) -> Request<Body> {
+    get(&format!("/api/v0/geocode/reverse?lat={}&lon={}", lat, lon))
+}
+
+/// `response`'s body as JSON.
+pub async fn json(response: Response) -> Value {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("Failed to read the response body");
+    serde_json::from_slice(&body).expect("Response body is not JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// How many addresses `pool` has cached.
+    async fn cached(pool: &Pool<Sqlite>) -> i64 {
+        let (count,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM geocode")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        count
+    }
+
+    #[tokio::test]
+    async fn reverse_answers_from_the_mock_and_then_the_cache() {
+        let pool = database().await;
+        let response = router(pool.clone())
+            .oneshot(reverse(40.7128, -74.006))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let first = json(response).await;
+        let answers = first.as_array().expect("an array of answers");
+        assert!(!answers.is_empty());
+        assert_eq!(answers[0]["lat"], "40.71280");
+        assert_eq!(answers[0]["lon"], "-74.00600");
+        assert!(answers[0]["address"]["formattedAddress"].is_string());
+        assert_eq!(cached(&pool).await, answers.len() as i64);
+
+        // the same cell again is the same made-up address, now from the cache
+        let again = router(pool.clone())
+            .oneshot(reverse(40.7128, -74.006))
+            .await
+            .unwrap();
+        assert_eq!(json(again).await, first);
+        assert_eq!(cached(&pool).await, answers.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn reverse_refuses_points_off_the_globe() {
+        let pool = database().await;
+        for path in [
+            "/api/v0/geocode/reverse?lat=NaN&lon=-73.9",
+            "/api/v0/geocode/reverse?lat=1000&lon=5000",
+            "/api/v0/geocode/reverse?lat=40.7&lon=inf",
+        ] {
+            let response = router(pool.clone()).oneshot(get(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert_eq!(cached(&pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_on_a_test_runtime() {
+        let pool = database().await;
+        router(pool.clone())
+            .oneshot(reverse(40.7128, -74.006))
+            .await
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("gaia-snapshot-test-{}.db", rand::random::<u32>()));
+        db::snapshot(&pool, &path).await.unwrap();
+
+        let copy = sqlx::SqlitePool::connect(&format!("sqlite:{}", path.display()))
+            .await
+            .unwrap();
+        let copied = cached(&copy).await;
+        copy.close().await;
+        let _ = std::fs::remove_file(&path);
+        assert!(copied > 0);
+        assert_eq!(copied, cached(&pool).await);
+    }
+}
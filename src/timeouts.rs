@@ -0,0 +1,109 @@
+//! Per-route time limits, so a slow provider can't hold connections open
+//! indefinitely.
+//!
+//! A request that hasn't been answered in time gets a 504. Single reverse
+//! lookups get 10 seconds, bulk, route and track ones 2 minutes and profiles, which can run
+//! for a minute, 90 seconds; everything else gets `REQUEST_TIMEOUT_MS`
+//! (default 30000). `ROUTE_TIMEOUTS` overrides any of them as a
+//! comma-separated list of `route=milliseconds`, with routes as they appear
+//! below the version prefix, e.g.
+//! `ROUTE_TIMEOUTS=/geocode/reverse=5000,/jobs/:id/result=0`; 0 means no
+//! limit.
+
+use std::{collections::HashMap, sync::OnceLock, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{config, metrics};
+
+const DEFAULTS: &[(&str, u64)] = &[
+    ("/geocode/reverse", 10_000),
+    ("/geocode/reverse/bulk", 120_000),
+    ("/geocode/reverse/route", 120_000),
+    ("/geocode/reverse/track", 120_000),
+    ("/admin/profile", 90_000),
+];
+
+#[derive(Debug)]
+struct Settings {
+    default: u64,
+    routes: HashMap<String, u64>,
+}
+
+/// Parse `ROUTE_TIMEOUTS` and `REQUEST_TIMEOUT_MS` early.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let mut routes = DEFAULTS
+            .iter()
+            .map(|&(route, ms)| (route.to_string(), ms))
+            .collect::<HashMap<_, _>>();
+        let configured = std::env::var("ROUTE_TIMEOUTS").unwrap_or_default();
+        for entry in configured
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let Some((route, ms)) = entry
+                .rsplit_once('=')
+                .and_then(|(route, ms)| Some((route.trim(), ms.trim().parse::<u64>().ok()?)))
+            else {
+                panic!("Invalid ROUTE_TIMEOUTS: {}", entry);
+            };
+            routes.insert(route.to_string(), ms);
+        }
+        Settings {
+            default: config::var("REQUEST_TIMEOUT_MS", 30_000),
+            routes,
+        }
+    })
+}
+
+/// `path` without its version prefix.
+fn route(path: &str) -> &str {
+    ["/api/v0", "/api/v1"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path)
+}
+
+/// Middleware answering 504 once the route's time is up.
+pub async fn limit(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| route(path.as_str()).to_string())
+        .unwrap_or_default();
+    let settings = settings();
+    let ms = settings
+        .routes
+        .get(&route)
+        .copied()
+        .unwrap_or(settings.default);
+    if ms == 0 {
+        return next.run(request).await;
+    }
+    match tokio::time::timeout(Duration::from_millis(ms), next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("{} timed out after {}ms", route, ms);
+            metrics::increment("gaia_requests_timed_out_total", &[("route", &route)]);
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                Json(json!("request timed out")),
+            )
+                .into_response()
+        }
+    }
+}
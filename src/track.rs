@@ -0,0 +1,587 @@
+//! `POST /api/v0/geocode/reverse/track?interval=&seconds=`: a recorded track
+//! handed back with addresses on its points, as a file to download.
+//!
+//! The body is a GPX file, or JSON of the form `{"points": [{"lat", "lon",
+//! "time"}, ...]}` with any other fields a point carries left alone; which
+//! one is told by `Content-Type` (anything with `xml` or `gpx` in it is
+//! GPX) or, failing that, by the body's first character. The answer is in
+//! the same format.
+//!
+//! A second-by-second recording would be thousands of lookups for a few
+//! streets, so each track or route is downsampled: its first and last
+//! points are looked up, and so is every point `interval` metres (default
+//! `ROUTE_SAMPLE_METERS`) along it from the last one looked up, or `seconds`
+//! after it when both points have times. `interval=0` looks up every point.
+//! GPX waypoints are always looked up. More than `ROUTE_MAX_SAMPLES` lookups
+//! is a 413.
+//!
+//! In GPX the address is written as each looked-up point's `<desc>`, and
+//! the file is rewritten as GPX 1.1 keeping the waypoints, routes and tracks
+//! with their names and each point's elevation, time and name; anything
+//! else in the original is dropped. In JSON a looked-up point gains the
+//! `address`, or an `error` when its lookup failed. The body limit, `bulk`
+//! flag and lookup parameters are the bulk route's.
+
+use std::{collections::HashMap, fmt::Write, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    bulk, cron,
+    flags::{self, Flag},
+    geo, params, precision, route,
+    tenants::Tenant,
+    units::Unit,
+    upstream::Upstream,
+    LookupOptions, RadarAddress,
+};
+
+#[derive(Debug, Default)]
+struct Point {
+    lat: f64,
+    lon: f64,
+    ele: Option<String>,
+    time: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Track {
+    name: Option<String>,
+    segments: Vec<Vec<Point>>,
+}
+
+/// The parts of a GPX file that are kept.
+#[derive(Debug, Default)]
+struct Gpx {
+    waypoints: Vec<Point>,
+    routes: Vec<Track>,
+    tracks: Vec<Track>,
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The value of attribute `name` in a tag's attribute text.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let mut rest = attributes.trim_start();
+    while !rest.is_empty() {
+        let equals = rest.find('=')?;
+        let key = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = value[1..].find(quote)? + 1;
+        if key == name {
+            return Some(unescape(&value[1..end]));
+        }
+        rest = value[end + 1..].trim_start();
+    }
+    None
+}
+
+/// Read the waypoints, routes and tracks of a GPX file. Elements are matched
+/// by local name, so namespace prefixes don't matter.
+fn parse_gpx(text: &str) -> Result<Gpx, String> {
+    let mut gpx = Gpx::default();
+    let mut stack: Vec<String> = vec![];
+    let (mut point, mut text_of): (Option<Point>, Option<String>) = (None, None);
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        if let Some(captured) = text_of.as_mut() {
+            captured.push_str(&rest[..start]);
+        }
+        rest = &rest[start..];
+        // declarations, comments and doctypes carry nothing we keep
+        let (skip, end) = if rest.starts_with("<?") {
+            (true, "?>")
+        } else if rest.starts_with("<!--") {
+            (true, "-->")
+        } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let close = cdata.find("]]>").ok_or("unterminated CDATA section")?;
+            if let Some(captured) = text_of.as_mut() {
+                // kept escaped, as the text around it is unescaped later
+                captured.push_str(&escape(&cdata[..close]));
+            }
+            rest = &cdata[close + 3..];
+            continue;
+        } else if rest.starts_with("<!") {
+            (true, ">")
+        } else {
+            (false, ">")
+        };
+        let close = rest.find(end).ok_or("unterminated tag")?;
+        let tag = &rest[1..close];
+        rest = &rest[close + end.len()..];
+        if skip {
+            continue;
+        }
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = local(name.trim());
+            if stack.pop().as_deref() != Some(name) {
+                return Err(format!("unexpected </{}>", name));
+            }
+            let parent = stack.last().map(String::as_str);
+            let value = text_of.take().map(|text| unescape(text.trim()));
+            match (name, parent) {
+                ("ele" | "time" | "name", Some("wpt" | "rtept" | "trkpt")) => {
+                    if let Some(point) = point.as_mut() {
+                        match name {
+                            "ele" => point.ele = value,
+                            "time" => point.time = value,
+                            _ => point.name = value,
+                        }
+                    }
+                }
+                ("name", Some("rte")) => gpx.routes.last_mut().unwrap().name = value,
+                ("name", Some("trk")) => gpx.tracks.last_mut().unwrap().name = value,
+                ("wpt" | "rtept" | "trkpt", _) => close_point(&mut gpx, name, parent, point.take()),
+                _ => {}
+            }
+            continue;
+        }
+
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let name = local(name);
+        let parent = stack.last().map(String::as_str);
+        match (name, parent) {
+            ("wpt", Some("gpx")) | ("rtept", Some("rte")) | ("trkpt", Some("trkseg")) => {
+                let coordinate = |axis: &str| {
+                    attribute(attributes, axis)
+                        .and_then(|value| value.trim().parse::<f64>().ok())
+                        .ok_or_else(|| format!("<{}> without a valid {}", name, axis))
+                };
+                let (lat, lon) = (coordinate("lat")?, coordinate("lon")?);
+                if !(lat.abs() <= 90.0 && lon.abs() <= 180.0) {
+                    return Err(format!("<{}> outside the valid coordinate range", name));
+                }
+                point = Some(Point {
+                    lat,
+                    lon,
+                    ..Point::default()
+                });
+            }
+            ("rte", Some("gpx")) => gpx.routes.push(Track {
+                name: None,
+                segments: vec![vec![]],
+            }),
+            ("trk", Some("gpx")) => gpx.tracks.push(Track::default()),
+            ("trkseg", Some("trk")) => gpx.tracks.last_mut().unwrap().segments.push(vec![]),
+            ("ele" | "time" | "name", Some("wpt" | "rtept" | "trkpt" | "rte" | "trk")) => {
+                text_of = Some(String::new());
+            }
+            _ => {}
+        }
+        if empty {
+            if matches!(name, "wpt" | "rtept" | "trkpt") {
+                close_point(&mut gpx, name, parent, point.take());
+            }
+            text_of = None;
+        } else {
+            stack.push(name.to_string());
+        }
+    }
+    if !stack.is_empty() {
+        return Err(format!("<{}> is never closed", stack.last().unwrap()));
+    }
+    Ok(gpx)
+}
+
+fn local(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn close_point(gpx: &mut Gpx, name: &str, parent: Option<&str>, point: Option<Point>) {
+    let Some(point) = point else {
+        return;
+    };
+    match (name, parent) {
+        ("wpt", _) => gpx.waypoints.push(point),
+        ("rtept", _) => gpx.routes.last_mut().unwrap().segments[0].push(point),
+        _ => gpx
+            .tracks
+            .last_mut()
+            .unwrap()
+            .segments
+            .last_mut()
+            .unwrap()
+            .push(point),
+    }
+}
+
+/// Seconds since the epoch of an RFC 3339 time such as GPX uses.
+fn timestamp(time: &str) -> Option<f64> {
+    let (date, clock) = time.trim().split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (clock, offset) = match clock.find(['Z', 'z', '+', '-']) {
+        Some(i) => clock.split_at(i),
+        None => (clock, "Z"),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        offset => {
+            let (hours, minutes) = offset[1..].split_once(':').unwrap_or((&offset[1..], "0"));
+            let seconds = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            if offset.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            }
+        }
+    };
+    let mut clock = clock.splitn(3, ':');
+    let (hour, minute) = (
+        clock.next()?.parse::<i64>().ok()?,
+        clock.next()?.parse::<i64>().ok()?,
+    );
+    let second = clock.next().map_or(Some(0.0), |s| s.parse::<f64>().ok())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = cron::days_from_civil(year, month as u32, day as u32);
+    Some((days * 86400 + hour * 3600 + minute * 60 - offset) as f64 + second)
+}
+
+/// Which of a sequence of points to look up: the first and last, and those
+/// `interval` metres along it or `seconds` on from the last one picked.
+fn downsample(
+    points: &[(f64, f64, Option<f64>)],
+    interval: f64,
+    seconds: Option<f64>,
+) -> Vec<bool> {
+    let mut picked = vec![false; points.len()];
+    let (mut along, mut last) = (0.0, 0);
+    for (i, &(lat, lon, time)) in points.iter().enumerate() {
+        if i > 0 {
+            let (lat0, lon0, _) = points[i - 1];
+            along += geo::distance_meters(lat0, lon0, lat, lon);
+        }
+        let elapsed = match (points[last].2, time) {
+            (Some(then), Some(now)) => seconds.is_some_and(|seconds| now - then >= seconds),
+            _ => false,
+        };
+        if i == 0 || along >= interval || elapsed || i + 1 == points.len() {
+            picked[i] = true;
+            (along, last) = (0.0, i);
+        }
+    }
+    picked
+}
+
+/// A point's lookup: its top address, or why there isn't one.
+#[derive(Debug, Clone)]
+enum Lookup {
+    Address(Box<RadarAddress>),
+    Nothing,
+    Failed(String),
+}
+
+fn render_gpx(gpx: &Gpx, lookups: &mut impl Iterator<Item = Option<Lookup>>) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"gaia\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    let mut point = |out: &mut String, tag: &str, point: &Point, indent: &str| {
+        let _ = write!(
+            out,
+            "{}<{} lat=\"{}\" lon=\"{}\">",
+            indent, tag, point.lat, point.lon
+        );
+        for (field, value) in [
+            ("ele", &point.ele),
+            ("time", &point.time),
+            ("name", &point.name),
+        ] {
+            if let Some(value) = value {
+                let _ = write!(out, "<{0}>{1}</{0}>", field, escape(value));
+            }
+        }
+        if let Some(Lookup::Address(address)) = lookups.next().flatten() {
+            if let Some(formatted) = &address.formatted_address {
+                let _ = write!(out, "<desc>{}</desc>", escape(formatted));
+            }
+        }
+        let _ = writeln!(out, "</{}>", tag);
+    };
+    for waypoint in &gpx.waypoints {
+        point(&mut out, "wpt", waypoint, "  ");
+    }
+    for rte in &gpx.routes {
+        out.push_str("  <rte>\n");
+        if let Some(name) = &rte.name {
+            let _ = writeln!(out, "    <name>{}</name>", escape(name));
+        }
+        for rtept in rte.segments.iter().flatten() {
+            point(&mut out, "rtept", rtept, "    ");
+        }
+        out.push_str("  </rte>\n");
+    }
+    for trk in &gpx.tracks {
+        out.push_str("  <trk>\n");
+        if let Some(name) = &trk.name {
+            let _ = writeln!(out, "    <name>{}</name>", escape(name));
+        }
+        for segment in &trk.segments {
+            out.push_str("    <trkseg>\n");
+            for trkpt in segment {
+                point(&mut out, "trkpt", trkpt, "      ");
+            }
+            out.push_str("    </trkseg>\n");
+        }
+        out.push_str("  </trk>\n");
+    }
+    out.push_str("</gpx>\n");
+    out
+}
+
+/// The points of a JSON track, as `(lat, lon, seconds)`.
+fn json_points(track: &Value) -> Result<Vec<(f64, f64, Option<f64>)>, String> {
+    let points = track
+        .get("points")
+        .and_then(Value::as_array)
+        .ok_or("expected {\"points\": [...]}")?;
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let number = |field: &str| match &point[field] {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse::<f64>().ok(),
+                _ => None,
+            };
+            match (number("lat"), number("lon")) {
+                (Some(lat), Some(lon)) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => {
+                    let time = match &point["time"] {
+                        Value::Number(n) => n.as_f64(),
+                        Value::String(s) => timestamp(s),
+                        _ => None,
+                    };
+                    Ok((lat, lon, time))
+                }
+                _ => Err(format!("point {} has no valid lat and lon", i)),
+            }
+        })
+        .collect()
+}
+
+fn too_large(lookups: usize) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!(format!(
+            "the track needs {} lookups, more than {}; use a longer interval",
+            lookups,
+            route::max_samples()
+        ))),
+    )
+        .into_response()
+}
+
+pub async fn post_geo_reverse_track(
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    tenant: Tenant,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(upstream): Extension<Arc<Upstream>>,
+    body: Result<Bytes, BytesRejection>,
+) -> Response {
+    if !flags::enabled(Flag::Bulk) {
+        return flags::disabled(Flag::Bulk).into_response();
+    }
+    let body = match body {
+        Ok(body) => body,
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!("request body is too large")),
+            )
+                .into_response()
+        }
+        Err(e) => return (e.status(), Json(json!(e.body_text()))).into_response(),
+    };
+    let interval = match params::optional::<f64>(&params, "interval", route::sample_meters()) {
+        Ok(interval) if (0.0..=100_000.0).contains(&interval) => interval,
+        Ok(_) => {
+            return params::bad_request("interval must be between 0 and 100000 metres")
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+    let seconds = match params.get("seconds").map(|s| s.parse::<f64>()) {
+        None => None,
+        Some(Ok(seconds)) if seconds > 0.0 => Some(seconds),
+        Some(_) => return params::bad_request("seconds must be a positive number").into_response(),
+    };
+    let options = match LookupOptions::from_params(&params, &headers, &upstream, &tenant) {
+        Ok(options) => options,
+        Err(e) => return e.into_response(),
+    };
+    let precision = match precision::from_params(&params) {
+        Ok(precision) => precision,
+        Err(e) => return e.into_response(),
+    };
+    let units = match Unit::from_params(&params) {
+        Ok(units) => units,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(text) = std::str::from_utf8(&body) else {
+        return params::bad_request("the track is not UTF-8").into_response();
+    };
+    let is_gpx = match headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(kind) if kind.contains("xml") || kind.contains("gpx") => true,
+        Some(kind) if kind.contains("json") => false,
+        _ => text.trim_start().starts_with('<'),
+    };
+
+    // every point as (lat, lon, seconds), in the order they are written out,
+    // with whether each is looked up
+    let mut picked = vec![];
+    let mut gpx = None;
+    let mut track = None;
+    if is_gpx {
+        let parsed = match parse_gpx(text) {
+            Ok(parsed) => parsed,
+            Err(e) => return params::bad_request(&format!("invalid GPX: {}", e)).into_response(),
+        };
+        let coordinates = |p: &Point| (p.lat, p.lon, p.time.as_deref().and_then(timestamp));
+        picked.extend(parsed.waypoints.iter().map(|p| (coordinates(p), true)));
+        for sequence in parsed.routes.iter().chain(&parsed.tracks) {
+            let points = sequence
+                .segments
+                .iter()
+                .flatten()
+                .map(coordinates)
+                .collect::<Vec<_>>();
+            let keep = downsample(&points, interval, seconds);
+            picked.extend(points.into_iter().zip(keep));
+        }
+        gpx = Some(parsed);
+    } else {
+        let parsed = match serde_json::from_str::<Value>(text) {
+            Ok(parsed) => parsed,
+            Err(e) => return params::bad_request(&format!("invalid JSON: {}", e)).into_response(),
+        };
+        let points = match json_points(&parsed) {
+            Ok(points) => points,
+            Err(e) => return params::bad_request(&e).into_response(),
+        };
+        let keep = downsample(&points, interval, seconds);
+        picked.extend(points.into_iter().zip(keep));
+        track = Some(parsed);
+    }
+    if picked.is_empty() {
+        return params::bad_request("the track has no points").into_response();
+    }
+    let wanted = picked.iter().filter(|(_, keep)| *keep).count();
+    if wanted > route::max_samples() {
+        return too_large(wanted);
+    }
+
+    let data = picked
+        .iter()
+        .filter(|(_, keep)| *keep)
+        .map(|((lat, lon, _), _)| json!({"lat": lat, "lon": lon}))
+        .collect::<Vec<_>>();
+    let mut resolved = bulk::resolve(data, precision, units, &pool, &upstream, &options)
+        .await
+        .into_iter()
+        .map(|item| match (item.results, item.error) {
+            (Some(results), _) => match results.into_iter().next() {
+                Some(top) => Lookup::Address(Box::new(top.address)),
+                None => Lookup::Nothing,
+            },
+            (None, error) => Lookup::Failed(error.unwrap_or_default()),
+        });
+    let mut lookups = picked
+        .iter()
+        .map(|(_, keep)| keep.then(|| resolved.next()).flatten())
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let (content_type, filename, body) = match (gpx, track) {
+        (Some(gpx), _) => (
+            "application/gpx+xml",
+            "track.gpx",
+            render_gpx(&gpx, &mut lookups),
+        ),
+        (None, Some(mut track)) => {
+            let points = track["points"].as_array_mut().unwrap();
+            for (point, lookup) in points.iter_mut().zip(lookups) {
+                match lookup {
+                    Some(Lookup::Address(address)) => point["address"] = json!(address),
+                    Some(Lookup::Nothing) => point["address"] = Value::Null,
+                    Some(Lookup::Failed(e)) => point["error"] = json!(e),
+                    None => {}
+                }
+            }
+            ("application/json", "track.json", track.to_string())
+        }
+        (None, None) => unreachable!(),
+    };
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
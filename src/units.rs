@@ -0,0 +1,57 @@
+//! `units=meters|feet|miles`: the unit distances are reported in and radii
+//! are given in. Meters unless asked otherwise; everything is stored and
+//! computed in meters and converted at the edges.
+
+use std::collections::HashMap;
+
+use crate::{params, GeocodeResponse};
+
+const METERS_PER_FOOT: f64 = 0.3048;
+const METERS_PER_MILE: f64 = 1609.344;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unit {
+    #[default]
+    Meters,
+    Feet,
+    Miles,
+}
+
+impl Unit {
+    /// The request's `units`.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, params::ParamError> {
+        match params.get("units").map(String::as_str) {
+            None | Some("meters") => Ok(Unit::Meters),
+            Some("feet") => Ok(Unit::Feet),
+            Some("miles") => Ok(Unit::Miles),
+            Some(_) => Err(params::bad_request(
+                "units must be one of meters, feet or miles",
+            )),
+        }
+    }
+
+    fn meters(self) -> f64 {
+        match self {
+            Unit::Meters => 1.0,
+            Unit::Feet => METERS_PER_FOOT,
+            Unit::Miles => METERS_PER_MILE,
+        }
+    }
+
+    /// `meters` in this unit.
+    pub fn of(self, meters: f64) -> f64 {
+        meters / self.meters()
+    }
+
+    /// `value` of this unit in meters.
+    pub fn in_meters(self, value: f64) -> f64 {
+        value * self.meters()
+    }
+
+    /// Report the `distance` of each of `geocodes` in this unit.
+    pub fn convert(self, geocodes: &mut [GeocodeResponse]) {
+        for geocode in geocodes {
+            geocode.distance = self.of(geocode.distance);
+        }
+    }
+}
@@ -0,0 +1,769 @@
+use std::{
+    env, fmt,
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::Rng;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    audit,
+    boundaries::Boundaries,
+    breaker::CircuitBreaker,
+    cluster::Cluster,
+    config,
+    fixtures::{self, Fixtures},
+    flags::{self, Flag},
+    keys::{ApiKey, ApiKeys},
+    merge, metrics, mock,
+    nominatim::Nominatim,
+    peers::Peers,
+    places::{self, Place},
+    privacy,
+    roads::Roads,
+    routing::Routes,
+    RadarAddress, RadarReverseGeocodeResponse,
+};
+
+/// How provider calls are retried: up to `max_attempts` tries in total, with
+/// full-jitter exponential backoff between them. Only server errors and
+/// connection failures are retried; 4xx responses are returned immediately.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        RetryPolicy {
+            max_attempts: config::var("UPSTREAM_RETRY_MAX_ATTEMPTS", 3u32).max(1),
+            base_delay: Duration::from_millis(config::var("UPSTREAM_RETRY_BASE_DELAY_MS", 100)),
+            max_delay: Duration::from_millis(config::var("UPSTREAM_RETRY_MAX_DELAY_MS", 2000)),
+        }
+    }
+
+    /// Delay before retry number `attempt` (starting at 1).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The provider answered with a non-2xx status.
+    Status(u16),
+    /// The request never got a response (DNS, connect, TLS, reset...).
+    Transport(String),
+    /// The provider answered 2xx but the body wasn't what we expected.
+    Decode(String),
+    /// The provider answered 429, optionally saying how long to back off.
+    RateLimited(Option<Duration>),
+    /// The circuit breaker is open; no call was made.
+    CircuitOpen,
+    /// No provider API key is configured.
+    MissingApiKey,
+    /// Replaying fixtures and there is none for these coordinates.
+    NoFixture,
+    /// The cache a lookup goes through couldn't be read or written.
+    Database,
+}
+
+impl UpstreamError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            UpstreamError::Status(status) => *status >= 500,
+            UpstreamError::Transport(_) => true,
+            UpstreamError::Decode(_)
+            | UpstreamError::RateLimited(_)
+            | UpstreamError::CircuitOpen
+            | UpstreamError::MissingApiKey
+            | UpstreamError::NoFixture
+            | UpstreamError::Database => false,
+        }
+    }
+}
+
+impl UpstreamError {
+    /// The HTTP status this error is reported to clients with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::RateLimited(_) | UpstreamError::CircuitOpen => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            UpstreamError::Database => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl IntoResponse for UpstreamError {
+    fn into_response(self) -> Response {
+        match self {
+            UpstreamError::RateLimited(retry_after) => (
+                self.status(),
+                [(
+                    header::RETRY_AFTER,
+                    // round up so clients never come back too early
+                    retry_after
+                        .map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0))
+                        .unwrap_or(1)
+                        .to_string(),
+                )],
+                Json(json!(self.to_string())),
+            )
+                .into_response(),
+            _ => (self.status(), Json(json!(self.to_string()))).into_response(),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Status(status) => write!(f, "upstream returned status {}", status),
+            UpstreamError::Transport(e) => write!(f, "upstream request failed: {}", e),
+            UpstreamError::Decode(e) => write!(f, "invalid upstream response: {}", e),
+            UpstreamError::RateLimited(_) => write!(f, "upstream rate limit exceeded"),
+            UpstreamError::CircuitOpen => write!(f, "upstream circuit breaker is open"),
+            UpstreamError::MissingApiKey => write!(f, "Missing RADAR_API_KEY"),
+            UpstreamError::NoFixture => write!(f, "no recorded upstream response"),
+            UpstreamError::Database => write!(f, "database error"),
+        }
+    }
+}
+
+impl From<ureq::Error> for UpstreamError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(429, response) => UpstreamError::RateLimited(
+                response.header("Retry-After").and_then(parse_retry_after),
+            ),
+            ureq::Error::Status(status, _) => UpstreamError::Status(status),
+            e @ ureq::Error::Transport(_) => UpstreamError::Transport(privacy::ureq_error(&e)),
+        }
+    }
+}
+
+/// Parse a `Retry-After` value, which is either delay-seconds or an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    httpdate::parse_http_date(value)
+        .ok()
+        .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Where reverse geocodes come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Radar,
+    /// OpenStreetMap's Nominatim; see `nominatim`.
+    Nominatim,
+    /// Deterministic made-up addresses; see `mock`.
+    Mock,
+    /// The offline `BOUNDARIES_DIR` polygons, down to locality level.
+    Boundaries,
+}
+
+impl Provider {
+    pub fn from_env() -> Self {
+        let name = config::var("UPSTREAM_PROVIDER", String::from("radar"));
+        Provider::parse(&name).unwrap_or_else(|| panic!("Invalid UPSTREAM_PROVIDER: {}", name))
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "radar" => Some(Provider::Radar),
+            "nominatim" => Some(Provider::Nominatim),
+            "mock" => Some(Provider::Mock),
+            "boundaries" => Some(Provider::Boundaries),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Radar => "radar",
+            Provider::Nominatim => "nominatim",
+            Provider::Mock => "mock",
+            Provider::Boundaries => "boundaries",
+        }
+    }
+}
+
+/// Who a provider call is made for, as counted and audited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Caller<'a> {
+    pub tenant: Option<&'a str>,
+    pub client: Option<IpAddr>,
+}
+
+/// Everything needed to talk to the geocoding provider.
+#[derive(Debug)]
+pub struct Upstream {
+    /// `UPSTREAM_PROVIDER`: `radar` (the default), `nominatim`, `mock` or
+    /// `boundaries`.
+    pub provider: Provider,
+    /// `UPSTREAM_MERGE`: a second source whose results are merged in.
+    pub merge: Option<Provider>,
+    /// `UPSTREAM_ROUTES`: providers to use in place of `provider` by country.
+    pub routes: Routes,
+    pub boundaries: Arc<Boundaries>,
+    /// `ROADS_FILE`, for snapping points to roads.
+    pub roads: Arc<Roads>,
+    nominatim: Nominatim,
+    pub base_url: String,
+    agent: ureq::Agent,
+    pub retry: RetryPolicy,
+    pub breaker: CircuitBreaker,
+    pub keys: Arc<ApiKeys>,
+    /// Longest we'll hold a request waiting out a provider rate limit before
+    /// giving up and telling the client to come back later.
+    pub rate_limit_max_wait: Duration,
+    /// `OFFLINE_MODE`: never call the provider, answer from cache only.
+    pub offline: bool,
+    /// `STORE_RAW_RESPONSES`: keep the provider's original JSON next to the
+    /// cache rows parsed from it, for backfilling after mapping changes.
+    pub store_raw: bool,
+    /// Sibling instances whose caches are checked before the provider.
+    pub peers: Peers,
+    /// Keeps concurrent misses for one cell down to a single provider call.
+    pub cluster: Cluster,
+    /// Recording provider responses to, or replaying them from, disk.
+    pub fixtures: Option<Fixtures>,
+}
+
+impl Upstream {
+    pub fn from_env() -> Self {
+        let provider = Provider::from_env();
+        let boundaries = Boundaries::from_env();
+        let routes = Routes::from_env();
+        if !routes.is_empty() && !boundaries.has_level("country") {
+            panic!("Invalid UPSTREAM_ROUTES: routing needs country.geojson in BOUNDARIES_DIR");
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_millis(config::var(
+                "UPSTREAM_CONNECT_TIMEOUT_MS",
+                5000,
+            )))
+            .timeout_read(Duration::from_millis(config::var(
+                "UPSTREAM_READ_TIMEOUT_MS",
+                10000,
+            )))
+            .build();
+        Upstream {
+            provider,
+            merge: merge::from_env(provider),
+            routes,
+            boundaries: Arc::new(boundaries),
+            roads: Arc::new(Roads::from_env()),
+            nominatim: Nominatim::from_env(agent.clone()),
+            base_url: env::var("RADAR_API_URL")
+                .unwrap_or_else(|_| String::from("https://api.radar.io")),
+            agent,
+            retry: RetryPolicy::from_env(),
+            breaker: CircuitBreaker::from_env(),
+            keys: Arc::new(ApiKeys::from_env()),
+            offline: config::var("OFFLINE_MODE", false),
+            store_raw: config::var("STORE_RAW_RESPONSES", false),
+            peers: Peers::from_env(),
+            cluster: Cluster::from_env(),
+            fixtures: Fixtures::from_env(),
+            rate_limit_max_wait: Duration::from_millis(config::var(
+                "UPSTREAM_RATE_LIMIT_MAX_WAIT_MS",
+                2000,
+            )),
+        }
+    }
+
+    /// Look up `lat`/`lon` with the provider routed to for its country (or
+    /// else the configured one), and the merge source if there is one,
+    /// auditing every attempt made on `caller`'s behalf.
+    pub async fn reverse_geocode(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        lat: &str,
+        lon: &str,
+        caller: Caller<'_>,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        let provider = match self.routes.provider(&self.boundaries, lat, lon) {
+            Some(routed) => {
+                metrics::increment("gaia_upstream_routed_total", &[("provider", routed.name())]);
+                routed
+            }
+            None => self.provider,
+        };
+        let Some(merge) = self
+            .merge
+            .filter(|merge| *merge != provider && flags::enabled(Flag::Merge))
+        else {
+            return self.lookup(provider, pool, lat, lon, caller).await;
+        };
+        let (primary, secondary) = tokio::join!(
+            self.lookup(provider, pool, lat, lon, caller),
+            self.lookup(merge, pool, lat, lon, caller),
+        );
+        let mut response = primary?;
+        match secondary {
+            Ok(secondary) => merge::combine(&mut response, provider, secondary, merge),
+            Err(e) => tracing::warn!("merge source {} failed: {}", merge.name(), e),
+        }
+        Ok(response)
+    }
+
+    async fn lookup(
+        &self,
+        provider: Provider,
+        pool: &Arc<Pool<Sqlite>>,
+        lat: &str,
+        lon: &str,
+        caller: Caller<'_>,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        if provider == Provider::Boundaries {
+            let (Ok(latitude), Ok(longitude)) = (lat.parse::<f64>(), lon.parse::<f64>()) else {
+                return Err(UpstreamError::Decode(String::from("invalid coordinates")));
+            };
+            let addresses = merge::boundary_address(
+                latitude,
+                longitude,
+                self.boundaries.lookup(latitude, longitude),
+            )
+            .into_iter()
+            .collect::<Vec<_>>();
+            let raw = json!({"meta": {"code": 200}, "addresses": addresses});
+            return Ok(RadarReverseGeocodeResponse {
+                meta: raw["meta"].clone(),
+                addresses,
+                raw,
+            });
+        }
+        if provider == Provider::Mock {
+            let started = Instant::now();
+            let result = Ok(mock::reverse_geocode(lat, lon));
+            audit::record(
+                pool,
+                audit::Call {
+                    lat,
+                    lon,
+                    provider: "mock",
+                    provider_key: String::from("mock"),
+                    tenant: caller.tenant,
+                    client: caller.client,
+                    latency: started.elapsed(),
+                },
+                &result,
+            );
+            metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+            return result;
+        }
+        if provider == Provider::Nominatim {
+            let (lat_owned, lon_owned) = (lat.to_string(), lon.to_string());
+            return self
+                .call_nominatim(pool, (lat, lon), caller, move |nominatim| {
+                    nominatim.reverse(&lat_owned, &lon_owned)
+                })
+                .await;
+        }
+        if let Some(fixtures) = &self.fixtures {
+            if fixtures.mode == fixtures::Mode::Replay {
+                return match fixtures.load(lat, lon).await {
+                    Ok(Some(raw)) => parse_response(raw),
+                    Ok(None) => Err(UpstreamError::NoFixture),
+                    Err(e) => Err(UpstreamError::Decode(e)),
+                };
+            }
+        }
+        let path = format!("/v1/geocode/reverse?coordinates={},{}", lat, lon);
+        let result = self.call_provider(pool, (lat, lon), &path, caller).await;
+        if let (Ok(response), Some(fixtures)) = (&result, &self.fixtures) {
+            fixtures.save(lat, lon, &response.raw).await;
+        }
+        result
+    }
+
+    /// Look `query` up with the provider, auditing every attempt made on
+    /// `caller`'s behalf. The mock makes an address up; the offline
+    /// boundaries have nothing to search by name, so find nothing.
+    pub async fn forward_geocode(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        query: &str,
+        caller: Caller<'_>,
+    ) -> Result<Vec<RadarAddress>, UpstreamError> {
+        match self.provider {
+            Provider::Boundaries => Ok(vec![]),
+            Provider::Mock => {
+                metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+                Ok(mock::forward_geocode(query))
+            }
+            Provider::Nominatim => {
+                let owned = query.to_string();
+                // as for Radar, no coordinates are kept for a forward query
+                self.call_nominatim(pool, ("", ""), caller, move |nominatim| {
+                    nominatim.search(&owned)
+                })
+                .await
+            }
+            Provider::Radar => {
+                let query =
+                    url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>();
+                let path = format!("/v1/geocode/forward?query={}", query);
+                // the query is a user's address; the audit keeps no coordinates for it
+                self.call_provider(pool, ("", ""), &path, caller)
+                    .await
+                    .map(|response| response.addresses)
+            }
+        }
+    }
+
+    /// Completions of the partial address `query`, best first and favouring
+    /// those near `near`, audited like a forward lookup. The mock makes them
+    /// up; Nominatim's usage policy rules out autocomplete and the boundaries
+    /// have no addresses, so they find nothing.
+    pub async fn autocomplete(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        query: &str,
+        near: Option<(&str, &str)>,
+        caller: Caller<'_>,
+    ) -> Result<Vec<RadarAddress>, UpstreamError> {
+        match self.provider {
+            Provider::Boundaries | Provider::Nominatim => Ok(vec![]),
+            Provider::Mock => {
+                metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+                Ok(mock::autocomplete(query))
+            }
+            Provider::Radar => {
+                let query =
+                    url::form_urlencoded::byte_serialize(query.as_bytes()).collect::<String>();
+                let mut path = format!("/v1/search/autocomplete?query={}&limit=10", query);
+                if let Some((lat, lon)) = near {
+                    path.push_str(&format!("&near={},{}", lat, lon));
+                }
+                self.call_provider(pool, ("", ""), &path, caller)
+                    .await
+                    .map(|response| response.addresses)
+            }
+        }
+    }
+
+    /// Places in `categories` within `radius` metres of `lat`/`lon`, with
+    /// every attempt audited like a lookup there. The mock makes places up;
+    /// Nominatim and the boundaries have no place search, so find nothing.
+    pub async fn search_places(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        (lat, lon): (&str, &str),
+        categories: &str,
+        radius: u32,
+        caller: Caller<'_>,
+    ) -> Result<Vec<Place>, UpstreamError> {
+        match self.provider {
+            Provider::Boundaries | Provider::Nominatim => Ok(vec![]),
+            Provider::Mock => {
+                metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+                Ok(mock::places(lat, lon, categories, radius))
+            }
+            Provider::Radar => {
+                let path = format!(
+                    "/v1/search/places?near={},{}&categories={}&radius={}&limit=100",
+                    lat, lon, categories, radius
+                );
+                let response = self.call_provider(pool, (lat, lon), &path, caller).await?;
+                Ok(places::from_radar(&response.raw))
+            }
+        }
+    }
+
+    /// Radar's context for `lat`/`lon` (the country, state, DMA, postal code
+    /// and time zone there), audited like a lookup; `None` from providers
+    /// without a context API. The mock makes one up.
+    pub async fn context(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        (lat, lon): (&str, &str),
+        caller: Caller<'_>,
+    ) -> Result<Option<Value>, UpstreamError> {
+        match self.provider {
+            Provider::Boundaries | Provider::Nominatim => Ok(None),
+            Provider::Mock => {
+                metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+                Ok(Some(mock::context(lat, lon)))
+            }
+            Provider::Radar => {
+                let path = format!("/v1/context?coordinates={},{}", lat, lon);
+                let response = self.call_provider(pool, (lat, lon), &path, caller).await?;
+                match response.raw.get("context") {
+                    Some(context) if context.is_object() => Ok(Some(context.clone())),
+                    _ => Err(UpstreamError::Decode(String::from("missing context"))),
+                }
+            }
+        }
+    }
+
+    /// Call Nominatim through the circuit breaker, retried like Radar, with
+    /// every attempt audited at `audited` on `caller`'s behalf. `call` blocks,
+    /// so each attempt runs on the blocking pool, like `call_once`.
+    async fn call_nominatim<T: audit::Answer + Send + 'static>(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        (lat, lon): (&str, &str),
+        caller: Caller<'_>,
+        call: impl Fn(&Nominatim) -> Result<T, UpstreamError> + Clone + Send + 'static,
+    ) -> Result<T, UpstreamError> {
+        self.guarded(async {
+            let mut attempt = 1;
+            loop {
+                let (nominatim, call) = (self.nominatim.clone(), call.clone());
+                let started = Instant::now();
+                let result = tokio::task::spawn_blocking(move || call(&nominatim))
+                    .await
+                    .unwrap_or_else(|e| Err(UpstreamError::Transport(e.to_string())));
+                audit::record(
+                    pool,
+                    audit::Call {
+                        lat,
+                        lon,
+                        provider: "nominatim",
+                        provider_key: String::from("nominatim"),
+                        tenant: caller.tenant,
+                        client: caller.client,
+                        latency: started.elapsed(),
+                    },
+                    &result,
+                );
+                let delay = match &result {
+                    // there is no other key to move on to, so wait it out here
+                    Err(UpstreamError::RateLimited(retry_after)) => {
+                        retry_after.unwrap_or_else(|| self.retry.backoff(attempt))
+                    }
+                    Err(e) if e.is_retryable() => self.retry.backoff(attempt),
+                    _ => return result,
+                };
+                if attempt >= self.retry.max_attempts || delay > self.rate_limit_max_wait {
+                    return result;
+                }
+                tracing::warn!(
+                    "nominatim: {} (attempt {}/{}), retrying in {:?}",
+                    result.err().unwrap(),
+                    attempt,
+                    self.retry.max_attempts,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        })
+        .await
+    }
+
+    /// Call the provider's `path`, through the key pool, circuit breaker and
+    /// retries. `audited` is the coordinates the audit records.
+    async fn call_provider(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        audited: (&str, &str),
+        path: &str,
+        caller: Caller<'_>,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        if self.keys.is_empty() {
+            return Err(UpstreamError::MissingApiKey);
+        }
+        self.guarded(self.call_with_retries(pool, audited, path, caller))
+            .await
+    }
+
+    /// Make `call` unless the circuit breaker is open, then tell the breaker
+    /// and `gaia_upstream_requests_total` how it went.
+    async fn guarded<T>(
+        &self,
+        call: impl Future<Output = Result<T, UpstreamError>>,
+    ) -> Result<T, UpstreamError> {
+        if !self.breaker.allow() {
+            metrics::increment("gaia_upstream_requests_total", &[("result", "rejected")]);
+            return Err(UpstreamError::CircuitOpen);
+        }
+
+        let result = call.await;
+        match &result {
+            Ok(_) => {
+                self.breaker.record_success();
+                metrics::increment("gaia_upstream_requests_total", &[("result", "ok")]);
+            }
+            Err(UpstreamError::RateLimited(_)) => {
+                metrics::increment(
+                    "gaia_upstream_requests_total",
+                    &[("result", "rate_limited")],
+                );
+            }
+            Err(e) => {
+                // client errors mean the provider is up and answering
+                if e.is_retryable() {
+                    self.breaker.record_failure();
+                } else {
+                    self.breaker.record_success();
+                }
+                metrics::increment("gaia_upstream_requests_total", &[("result", "error")]);
+            }
+        }
+        result
+    }
+
+    async fn call_with_retries(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        (lat, lon): (&str, &str),
+        path: &str,
+        caller: Caller<'_>,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        let mut attempt = 1;
+        loop {
+            let key = self.acquire_key().await?;
+            let started = Instant::now();
+            let result = self.call_once(&key, path).await;
+            audit::record(
+                pool,
+                audit::Call {
+                    lat,
+                    lon,
+                    provider: "radar",
+                    provider_key: key.label(),
+                    tenant: caller.tenant,
+                    client: caller.client,
+                    latency: started.elapsed(),
+                },
+                &result,
+            );
+            match result {
+                Err(UpstreamError::RateLimited(retry_after)) => {
+                    let delay = retry_after.unwrap_or_else(|| self.retry.backoff(attempt));
+                    tracing::warn!(
+                        "upstream rate limited key {}, backing off for {:?}",
+                        key.label(),
+                        delay
+                    );
+                    key.mark_rate_limited(delay);
+                    if attempt >= self.retry.max_attempts {
+                        return Err(UpstreamError::RateLimited(Some(delay)));
+                    }
+                    attempt += 1;
+                }
+                Err(e) if e.is_retryable() && attempt < self.retry.max_attempts => {
+                    let delay = self.retry.backoff(attempt);
+                    tracing::warn!(
+                        "{} (attempt {}/{}), retrying in {:?}",
+                        e,
+                        attempt,
+                        self.retry.max_attempts,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Pick an API key, holding the request while every key is rate limited,
+    /// or failing fast if none frees up within `rate_limit_max_wait`.
+    async fn acquire_key(&self) -> Result<Arc<ApiKey>, UpstreamError> {
+        match self.keys.pick() {
+            Ok(key) => Ok(key),
+            Err(remaining) if remaining > self.rate_limit_max_wait => {
+                Err(UpstreamError::RateLimited(Some(remaining)))
+            }
+            Err(remaining) => {
+                tokio::time::sleep(remaining).await;
+                self.keys
+                    .pick()
+                    .map_err(|remaining| UpstreamError::RateLimited(Some(remaining)))
+            }
+        }
+    }
+
+    /// One audited call with `key`, to see whether the provider accepts it.
+    pub async fn verify_key(
+        &self,
+        pool: &Arc<Pool<Sqlite>>,
+        key: &ApiKey,
+    ) -> Result<(), UpstreamError> {
+        // anywhere will do; the answer is thrown away
+        let (lat, lon) = ("40.74", "-73.99");
+        let started = Instant::now();
+        let result = self
+            .call_once(
+                key,
+                &format!("/v1/geocode/reverse?coordinates={},{}", lat, lon),
+            )
+            .await;
+        audit::record(
+            pool,
+            audit::Call {
+                lat,
+                lon,
+                provider: "radar",
+                provider_key: key.label(),
+                tenant: None,
+                client: None,
+                latency: started.elapsed(),
+            },
+            &result,
+        );
+        result.map(|_| ())
+    }
+
+    /// Run one blocking provider call on the blocking pool. If the client goes
+    /// away and this future is dropped, the handler stops waiting immediately;
+    /// the call itself is bounded by the agent's connect/read timeouts.
+    async fn call_once(
+        &self,
+        key: &ApiKey,
+        path: &str,
+    ) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+        key.record_request();
+        let agent = self.agent.clone();
+        let key = key.value().to_string();
+        let url = format!("{}{}", self.base_url, path);
+        tokio::task::spawn_blocking(move || call_radar(&agent, &key, &url))
+            .await
+            .map_err(|e| UpstreamError::Transport(e.to_string()))?
+    }
+}
+
+fn call_radar(
+    agent: &ureq::Agent,
+    key: &str,
+    url: &str,
+) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+    let raw: Value = agent
+        .get(url)
+        .set("Authorization", key)
+        .call()?
+        .into_json()
+        .map_err(|e| UpstreamError::Decode(e.to_string()))?;
+    parse_response(raw)
+}
+
+fn parse_response(raw: Value) -> Result<RadarReverseGeocodeResponse, UpstreamError> {
+    let mut response: RadarReverseGeocodeResponse =
+        serde_json::from_value(raw.clone()).map_err(|e| UpstreamError::Decode(e.to_string()))?;
+    response.raw = raw;
+    Ok(response)
+}
@@ -0,0 +1,138 @@
+//! `/api/v1`: the same endpoints as `/api/v0`, every JSON response wrapped
+//! in one stable envelope so the shapes inside can grow without breaking
+//! consumers:
+//!
+//! ```json
+//! {
+//!   "results": [...],
+//!   "meta": { "version": "v1", ... },
+//!   "errors": [{ "status": 404, "message": "not in cache" }]
+//! }
+//! ```
+//!
+//! - `results` is always an array: a v0 array body as is, the `results` of a
+//!   paged body, or a single object as its only element. It is empty on
+//!   errors.
+//! - `meta` always has `version`, plus what v0 sends beside the data: the
+//!   `X-Gaia-*` headers under camelCase names (`cell`, `precision`,
+//!   `distanceAlgorithm`) and the other fields of a paged body
+//!   (`nextCursor`).
+//! - `errors` is empty on success. Otherwise it holds one entry with the
+//!   HTTP `status`, a `message`, and the v0 body as `detail` when that was
+//!   more than a message.
+//!
+//! Status codes and headers are those of v0. Responses that aren't JSON
+//! (CSV, GeoJSON downloads, backups) pass through untouched.
+
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Serialize, Debug, Default)]
+pub struct Envelope {
+    pub results: Vec<Value>,
+    pub meta: Map<String, Value>,
+    pub errors: Vec<Value>,
+}
+
+/// `x-gaia-distance-algorithm` as `distanceAlgorithm`.
+fn meta_name(header: &str) -> Option<String> {
+    let mut words = header.strip_prefix("x-gaia-")?.split('-');
+    let mut name = words.next()?.to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            name.extend(first.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    Some(name)
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"))
+}
+
+/// The envelope for a v0 response.
+fn wrap(status: StatusCode, headers: &HeaderMap, body: Value) -> Envelope {
+    let mut envelope = Envelope::default();
+    envelope.meta.insert(String::from("version"), json!("v1"));
+    for (name, value) in headers {
+        if let (Some(name), Ok(value)) = (meta_name(name.as_str()), value.to_str()) {
+            let value = value
+                .parse::<serde_json::Number>()
+                .map(Value::Number)
+                .unwrap_or_else(|_| json!(value));
+            envelope.meta.insert(name, value);
+        }
+    }
+
+    if !status.is_success() {
+        let mut error = json!({ "status": status.as_u16() });
+        match body {
+            Value::String(message) => error["message"] = json!(message),
+            detail => {
+                error["message"] = json!(status.canonical_reason().unwrap_or("error"));
+                if !detail.is_null() {
+                    error["detail"] = detail;
+                }
+            }
+        }
+        envelope.errors.push(error);
+        return envelope;
+    }
+
+    match body {
+        Value::Array(results) => envelope.results = results,
+        Value::Object(mut fields) if fields.get("results").is_some_and(Value::is_array) => {
+            if let Some(Value::Array(results)) = fields.remove("results") {
+                envelope.results = results;
+            }
+            envelope.meta.extend(fields);
+        }
+        Value::Null => {}
+        result => envelope.results.push(result),
+    }
+    envelope
+}
+
+/// Middleware putting v0 responses in the envelope.
+pub async fn envelope(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let (mut parts, body) = response.into_parts();
+    if let Some(location) = parts.headers.get(header::LOCATION) {
+        if let Some(v1) = location
+            .to_str()
+            .ok()
+            .and_then(|l| l.strip_prefix("/api/v0/"))
+            .and_then(|rest| HeaderValue::from_str(&format!("/api/v1/{}", rest)).ok())
+        {
+            parts.headers.insert(header::LOCATION, v1);
+        }
+    }
+    if !is_json(&parts.headers) {
+        return Response::from_parts(parts, body);
+    }
+
+    let bytes = match body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("failed to read response body: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    let envelope = wrap(parts.status, &parts.headers, value);
+    let body = serde_json::to_vec(&envelope).expect("envelope serializes");
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,267 @@
+//! Checking the whole configuration at startup, so a deployment with bad
+//! settings fails right away with every problem listed at once, instead of
+//! panicking on the first one to be read, possibly mid-request.
+//!
+//! Most settings are parsed where they are used, and report a bad value by
+//! panicking with an `Invalid ...` message; here each is read once with the
+//! panic caught and kept. On top of that come the checks no single module
+//! can make: that the configured provider has a key, that the listeners
+//! don't overlap and that numbers are in range.
+
+use std::{
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    alerts, analytics, audit, auth, autocomplete, backup,
+    breaker::CircuitBreaker,
+    bulk,
+    cluster::Cluster,
+    compression, config, context, cost, credentials, db, deprecation, dry_run,
+    fixtures::{self, Fixtures},
+    flags, formatting, forward, forwarded, fuzzy, geo, http_cache, janitor, jobs,
+    keys::ApiKeys,
+    maintenance, merge, panics,
+    peers::Peers,
+    places, precision, privacy, proxy_protocol, ranking, refresher, route,
+    routing::Routes,
+    server, shed, tenants, timeouts,
+    upstream::{Provider, RetryPolicy},
+    write_behind,
+};
+
+/// `(name, min, max)` for numeric settings whose type alone doesn't rule out
+/// nonsense.
+const RANGES: &[(&str, f64, f64)] = &[
+    ("CACHE_TTL_DAYS", 0.0, 36500.0),
+    ("CACHE_TTL_EMPTY_DAYS", 0.0, 36500.0),
+    ("RETENTION_DAYS", 0.0, 36500.0),
+    ("JOB_RESULT_TTL_HOURS", 1.0, 8760.0),
+    ("CACHE_REFRESH_STALE_DAYS", 1.0, 36500.0),
+    ("RESULT_DEDUP_METERS", 0.0, 100_000.0),
+    ("COORDINATE_PRECISION", 0.0, 8.0),
+    ("MIN_COORDINATE_PRECISION", 0.0, 8.0),
+    ("MAX_COORDINATE_PRECISION", 0.0, 8.0),
+    ("PRIVACY_PRECISION", 0.0, 8.0),
+    ("ANALYTICS_CELL_PRECISION", 0.0, 5.0),
+    ("UPSTREAM_RETRY_MAX_ATTEMPTS", 1.0, 100.0),
+    ("CLUSTER_LEASE_SECS", 1.0, 3600.0),
+    ("SHUTDOWN_TIMEOUT_SECS", 0.0, 3600.0),
+    ("UPSTREAM_CONNECT_TIMEOUT_MS", 1.0, 600_000.0),
+    ("UPSTREAM_READ_TIMEOUT_MS", 1.0, 600_000.0),
+    ("ALERT_UPSTREAM_ERROR_RATE", 0.0, 1.0),
+    ("ALERT_QUOTA_PERCENT", 0.0, 100.0),
+    ("ALERT_DATABASE_PERCENT", 0.0, 100.0),
+    ("UPSTREAM_PRICE_PER_1000", 0.0, 1_000_000.0),
+    ("UPSTREAM_FREE_CALLS_PER_MONTH", 0.0, 1e12),
+    ("ROUTE_SAMPLE_METERS", 1.0, 100_000.0),
+    ("ROUTE_MAX_SAMPLES", 1.0, 1_000_000.0),
+    ("PLACES_PRECISION", 0.0, 6.0),
+    ("CONTEXT_PRECISION", 0.0, 6.0),
+    ("AUTOCOMPLETE_BUCKET_PRECISION", 0.0, 4.0),
+    ("AUTOCOMPLETE_MIN_CHARS", 1.0, 100.0),
+    ("FORWARD_FUZZY_MIN_SIMILARITY", 0.0, 1.0),
+    ("FORWARD_FUZZY_CANDIDATES", 1.0, 10_000.0),
+    ("CACHE_COMPRESSION_LEVEL", 0.0, 9.0),
+    ("DATABASE_SLOW_QUERY_MS", 0.0, 3_600_000.0),
+    ("DATABASE_MAX_CONNECTIONS", 1.0, 1000.0),
+    ("DATABASE_ACQUIRE_TIMEOUT_SECS", 1.0, 3600.0),
+    ("DATABASE_STATEMENT_CACHE_CAPACITY", 1.0, 100_000.0),
+    ("CACHE_WRITE_BEHIND_BATCH", 1.0, 100_000.0),
+    ("CACHE_WRITE_BEHIND_INTERVAL_MS", 10.0, 3_600_000.0),
+];
+
+#[derive(Debug, Default)]
+struct Report {
+    problems: Vec<String>,
+}
+
+impl Report {
+    /// Run `check`, keeping its panic message as a problem if it panics.
+    fn catch<T>(&mut self, check: impl FnOnce() -> T) -> Option<T> {
+        match panic::catch_unwind(AssertUnwindSafe(check)) {
+            Ok(value) => Some(value),
+            Err(panic) => {
+                self.problems
+                    .push(panics::message(panic.as_ref()).to_string());
+                None
+            }
+        }
+    }
+
+    fn ranges(&mut self) {
+        for &(name, min, max) in RANGES {
+            let Ok(value) = std::env::var(name) else {
+                continue;
+            };
+            match value.trim().parse::<f64>() {
+                Ok(number) if (min..=max).contains(&number) => {}
+                Ok(_) => self.problems.push(format!(
+                    "Invalid {}: {} is outside {}..={}",
+                    name, value, min, max
+                )),
+                Err(_) => self
+                    .problems
+                    .push(format!("Invalid {}: {:?} is not a number", name, value)),
+            }
+        }
+    }
+
+    fn listeners(&mut self) {
+        let public = self.catch(|| server::addresses("BIND_ADDRESS")).flatten();
+        let admin = self
+            .catch(|| server::addresses("ADMIN_BIND_ADDRESS"))
+            .flatten();
+        let (Some(public), Some(admin)) = (public, admin) else {
+            return;
+        };
+        let public = public.into_iter().collect::<HashSet<_>>();
+        for address in admin.iter().filter(|a| public.contains(a)) {
+            self.problems.push(format!(
+                "Invalid ADMIN_BIND_ADDRESS: {} is also in BIND_ADDRESS",
+                address
+            ));
+        }
+    }
+
+    /// Everything `Upstream::from_env` reads, short of loading boundaries and
+    /// roads.
+    fn upstream(&mut self) {
+        let provider = self.catch(Provider::from_env);
+        let merge = provider.and_then(|provider| self.catch(|| merge::from_env(provider)));
+        let routes = self.catch(Routes::from_env);
+        let keys = self.catch(ApiKeys::from_env);
+        let fixtures = self.catch(Fixtures::from_env);
+        let offline = self.catch(|| config::var("OFFLINE_MODE", false));
+        self.catch(|| config::var("STORE_RAW_RESPONSES", false));
+        self.catch(|| config::var("UPSTREAM_RATE_LIMIT_MAX_WAIT_MS", 0u64));
+        self.catch(RetryPolicy::from_env);
+        self.catch(CircuitBreaker::from_env);
+        self.catch(Peers::from_env);
+        self.catch(Cluster::from_env);
+        let boundaries = std::env::var("BOUNDARIES_DIR").ok();
+        if let Some(dir) = &boundaries {
+            if !std::path::Path::new(dir).is_dir() {
+                self.problems.push(format!(
+                    "Invalid BOUNDARIES_DIR: {} is not a directory",
+                    dir
+                ));
+            }
+        }
+        if let Ok(file) = std::env::var("ROADS_FILE") {
+            if !std::path::Path::new(&file).is_file() {
+                self.problems
+                    .push(format!("Invalid ROADS_FILE: {} is not a file", file));
+            }
+        }
+        let countries = boundaries
+            .is_some_and(|dir| std::path::Path::new(&dir).join("country.geojson").exists());
+        if routes.as_ref().is_some_and(|r| !r.is_empty()) && !countries {
+            self.problems.push(String::from(
+                "Invalid UPSTREAM_ROUTES: routing needs country.geojson in BOUNDARIES_DIR",
+            ));
+        }
+
+        let (Some(provider), Some(keys), Some(fixtures), Some(offline)) =
+            (provider, keys, fixtures, offline)
+        else {
+            return;
+        };
+        let uses_radar = provider == Provider::Radar
+            || merge.flatten() == Some(Provider::Radar)
+            || routes.is_some_and(|r| r.providers().any(|p| p == Provider::Radar));
+        let replaying = fixtures.is_some_and(|f| f.mode == fixtures::Mode::Replay);
+        if uses_radar && keys.is_empty() && !offline && !replaying && !dry_run::enabled() {
+            self.problems.push(String::from(
+                "Missing RADAR_API_KEY (or RADAR_API_KEYS, or either as a _FILE): the radar \
+                 provider needs a key unless OFFLINE_MODE=true, DRY_RUN=true or \
+                 UPSTREAM_FIXTURES_MODE=replay",
+            ));
+        }
+    }
+}
+
+/// Check every setting, and exit listing each problem if there are any.
+pub fn run() {
+    let mut report = Report::default();
+    // the problems are reported together below, not one panic at a time
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    report.catch(db::connect_options);
+    report.catch(db::read_options);
+    report.listeners();
+    report.ranges();
+    let checks: &[fn()] = &[
+        alerts::check,
+        analytics::check,
+        audit::check,
+        autocomplete::check,
+        backup::check,
+        bulk::check,
+        compression::check,
+        context::check,
+        cost::check,
+        credentials::check,
+        db::check,
+        deprecation::check,
+        formatting::check,
+        forward::check,
+        forwarded::check,
+        fuzzy::check,
+        http_cache::check,
+        janitor::check,
+        jobs::check,
+        places::check,
+        ranking::check,
+        refresher::check,
+        route::check,
+        shed::check,
+        tenants::check,
+        timeouts::check,
+        write_behind::check,
+        || {
+            dry_run::enabled();
+        },
+        || {
+            geo::algorithm();
+        },
+        || {
+            precision::default();
+        },
+        || {
+            privacy::precision();
+        },
+        || {
+            flags::enabled(flags::Flag::Bulk);
+        },
+        || {
+            proxy_protocol::enabled();
+        },
+        || {
+            maintenance::active();
+        },
+        || {
+            auth::is_admin(&Default::default());
+        },
+    ];
+    for check in checks {
+        report.catch(check);
+    }
+    report.upstream();
+
+    panic::set_hook(hook);
+    if report.problems.is_empty() {
+        return;
+    }
+    eprintln!(
+        "gaia: {} configuration problem{}:",
+        report.problems.len(),
+        if report.problems.len() == 1 { "" } else { "s" }
+    );
+    for problem in &report.problems {
+        eprintln!("  - {}", problem);
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,342 @@
+//! Watched coordinates: re-geocoded on a cron schedule with any change in the
+//! returned addresses recorded (and optionally posted to a webhook), for
+//! monitoring address data drift at key sites.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::Path, http::StatusCode, response::IntoResponse, response::Response, Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{
+    auth::Admin,
+    config,
+    cron::Schedule,
+    db,
+    flags::{self, Flag},
+    geo_reverse, maintenance, precision,
+    upstream::Upstream,
+    LookupOptions, RadarAddress,
+};
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Watch {
+    pub id: i64,
+    pub name: String,
+    pub lat: String,
+    pub lon: String,
+    pub schedule: String,
+    pub webhook_url: Option<String>,
+    pub last_result: Option<sqlx::types::Json<Vec<RadarAddress>>>,
+    pub last_checked_at: Option<i64>,
+    pub next_run_at: i64,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchRequest {
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// Five-field cron expression (UTC), or `@hourly`, `@daily`, ...
+    pub schedule: String,
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Serialize, FromRow, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchChange {
+    pub id: i64,
+    pub watch_id: i64,
+    pub detected_at: i64,
+    pub previous: Option<sqlx::types::Json<Vec<RadarAddress>>>,
+    pub current: sqlx::types::Json<Vec<RadarAddress>>,
+}
+
+const WATCH_COLUMNS: &str = "id, name, lat, lon, schedule, webhook_url, last_result, \
+                             last_checked_at, next_run_at, created_at";
+
+fn database_error(e: sqlx::Error) -> Response {
+    tracing::error!("watch query failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!("database error")),
+    )
+        .into_response()
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, Json(json!("watch not found"))).into_response()
+}
+
+pub async fn get_watches(
+    _: Admin,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query_as::<_, Watch>(&format!(
+        "SELECT {} FROM watches ORDER BY id",
+        WATCH_COLUMNS
+    ))
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(watches) => (StatusCode::OK, Json(watches)).into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn get_watch(
+    _: Admin,
+    Path(id): Path<i64>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query_as::<_, Watch>(&format!(
+        "SELECT {} FROM watches WHERE id = ?",
+        WATCH_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(&*pool)
+    .await
+    {
+        Ok(Some(watch)) => (StatusCode::OK, Json(watch)).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn post_watch(
+    _: Admin,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Json(req): Json<WatchRequest>,
+) -> impl IntoResponse {
+    if !(-90.0..=90.0).contains(&req.lat) || !(-180.0..=180.0).contains(&req.lon) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!("coordinate out of range")),
+        )
+            .into_response();
+    }
+    let schedule = match Schedule::parse(&req.schedule) {
+        Ok(schedule) => schedule,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!(e))).into_response(),
+    };
+    let now = db::now();
+    let next_run_at = match schedule.next_after(now) {
+        Some(next) => next,
+        None => {
+            return (StatusCode::BAD_REQUEST, Json(json!("schedule never fires"))).into_response()
+        }
+    };
+
+    let id = match sqlx::query(
+        "INSERT INTO watches(name, lat, lon, schedule, webhook_url, next_run_at, created_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&req.name)
+    .bind(precision::round(req.lat, precision::default()))
+    .bind(precision::round(req.lon, precision::default()))
+    .bind(schedule.to_string())
+    .bind(&req.webhook_url)
+    .bind(next_run_at)
+    .bind(now)
+    .execute(&*pool)
+    .await
+    {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => return database_error(e),
+    };
+
+    match sqlx::query_as::<_, Watch>(&format!(
+        "SELECT {} FROM watches WHERE id = ?",
+        WATCH_COLUMNS
+    ))
+    .bind(id)
+    .fetch_one(&*pool)
+    .await
+    {
+        Ok(watch) => (StatusCode::CREATED, Json(watch)).into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn delete_watch(
+    _: Admin,
+    Path(id): Path<i64>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query("DELETE FROM watches WHERE id = ?")
+        .bind(id)
+        .execute(&*pool)
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => not_found(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn get_watch_changes(
+    _: Admin,
+    Path(id): Path<i64>,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+) -> impl IntoResponse {
+    match sqlx::query_as::<_, WatchChange>(
+        "SELECT id, watch_id, detected_at, previous, current FROM watch_changes \
+         WHERE watch_id = ? ORDER BY id DESC",
+    )
+    .bind(id)
+    .fetch_all(&*pool)
+    .await
+    {
+        Ok(changes) => (StatusCode::OK, Json(changes)).into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+/// What we compare between runs: the set of formatted addresses returned.
+fn fingerprint(addresses: &[RadarAddress]) -> Vec<String> {
+    let mut formatted = addresses
+        .iter()
+        .map(|a| a.formatted_address.clone().unwrap_or_default())
+        .collect::<Vec<_>>();
+    formatted.sort();
+    formatted
+}
+
+/// Poll for due watches every `WATCH_POLL_SECS` (default 60).
+pub fn spawn(pool: Arc<Pool<Sqlite>>, upstream: Arc<Upstream>) {
+    let interval = Duration::from_secs(config::var("WATCH_POLL_SECS", 60).max(1));
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if maintenance::active() || !flags::enabled(Flag::Watches) {
+                continue;
+            }
+            let due = match sqlx::query_as::<_, Watch>(&format!(
+                "SELECT {} FROM watches WHERE next_run_at <= ? ORDER BY next_run_at",
+                WATCH_COLUMNS
+            ))
+            .bind(db::now())
+            .fetch_all(&*pool)
+            .await
+            {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::warn!("failed to load due watches: {}", e);
+                    continue;
+                }
+            };
+            for watch in due {
+                if let Err(e) = run_watch(&watch, &pool, &upstream, &agent).await {
+                    tracing::warn!("watch {} ({}) failed: {}", watch.id, watch.name, e);
+                }
+            }
+        }
+    });
+}
+
+async fn run_watch(
+    watch: &Watch,
+    pool: &Arc<Pool<Sqlite>>,
+    upstream: &Arc<Upstream>,
+    agent: &ureq::Agent,
+) -> Result<(), String> {
+    let now = db::now();
+    // an unparseable schedule can only come from hand-edited rows; park it
+    let next_run_at = Schedule::parse(&watch.schedule)
+        .ok()
+        .and_then(|s| s.next_after(now))
+        .unwrap_or(i64::MAX);
+
+    let result = geo_reverse(
+        watch.lat.clone(),
+        watch.lon.clone(),
+        pool.clone(),
+        upstream.clone(),
+        &LookupOptions {
+            refresh: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    let current = match result {
+        Ok(geocodes) => geocodes.into_iter().map(|g| g.address).collect::<Vec<_>>(),
+        Err(e) => {
+            sqlx::query("UPDATE watches SET next_run_at = ? WHERE id = ?")
+                .bind(next_run_at)
+                .bind(watch.id)
+                .execute(&**pool)
+                .await
+                .map_err(|e| e.to_string())?;
+            return Err(e.to_string());
+        }
+    };
+
+    let previous = watch.last_result.as_ref().map(|r| &r.0);
+    let changed = previous.is_some_and(|p| fingerprint(p) != fingerprint(&current));
+
+    if changed {
+        tracing::info!(
+            "watch {} ({}) detected an address change",
+            watch.id,
+            watch.name
+        );
+        sqlx::query(
+            "INSERT INTO watch_changes(watch_id, detected_at, previous, current) VALUES (?, ?, ?, ?)",
+        )
+        .bind(watch.id)
+        .bind(now)
+        .bind(previous.map(|p| json!(p)))
+        .bind(json!(current))
+        .execute(&**pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        if let Some(url) = watch.webhook_url.clone() {
+            let payload = json!({
+                "watch": {
+                    "id": watch.id,
+                    "name": watch.name,
+                    "lat": watch.lat,
+                    "lon": watch.lon,
+                },
+                "detectedAt": now,
+                "previous": previous,
+                "current": current,
+            });
+            let agent = agent.clone();
+            let sent = tokio::task::spawn_blocking(move || {
+                agent
+                    .post(&url)
+                    .send_json(payload)
+                    .map(drop)
+                    .map_err(|e| e.to_string())
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+            if let Err(e) = sent {
+                tracing::warn!("watch {} webhook failed: {}", watch.id, e);
+            }
+        }
+    }
+
+    sqlx::query(
+        "UPDATE watches SET last_result = ?, last_checked_at = ?, next_run_at = ? WHERE id = ?",
+    )
+    .bind(json!(current))
+    .bind(now)
+    .bind(next_run_at)
+    .bind(watch.id)
+    .execute(&**pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Write-behind for what bulk lookups (`bulk`, and the `jobs` built on it)
+//! cache: addresses fetched on a miss are buffered and inserted together in
+//! one transaction, rather than as an INSERT each, which on a slow disk is
+//! most of a big batch's time.
+//!
+//! It is off unless `CACHE_WRITE_BEHIND=true`. The buffer is flushed once it
+//! holds `CACHE_WRITE_BEHIND_BATCH` addresses (default 500), at least every
+//! `CACHE_WRITE_BEHIND_INTERVAL_MS` (default 1000) and at shutdown, a timed
+//! out one included. Until then the addresses are only in the answer they
+//! were fetched for: another lookup of the cell in between, here or on
+//! another instance, misses and fetches it again even if it waited on the
+//! fetch (see `cluster`), and a crash loses them, so single lookups and
+//! refreshes always write straight through.
+
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{config, metrics, NewAddress, RadarAddress};
+
+/// Rows per INSERT, well inside SQLite's limit on bound parameters.
+const INSERT_ROWS: usize = 500;
+
+#[derive(Debug)]
+struct Settings {
+    enabled: bool,
+    batch: usize,
+    interval: Duration,
+}
+
+/// Read the `CACHE_WRITE_BEHIND` settings.
+pub fn check() {
+    settings();
+}
+
+fn settings() -> &'static Settings {
+    static SETTINGS: OnceLock<Settings> = OnceLock::new();
+    SETTINGS.get_or_init(|| Settings {
+        enabled: config::var("CACHE_WRITE_BEHIND", false),
+        batch: config::var("CACHE_WRITE_BEHIND_BATCH", 500usize),
+        interval: Duration::from_millis(config::var("CACHE_WRITE_BEHIND_INTERVAL_MS", 1000u64)),
+    })
+}
+
+/// An address waiting to be cached at the cell it was looked up at.
+#[derive(Debug)]
+struct Pending {
+    lat: String,
+    lon: String,
+    namespace: String,
+    address: RadarAddress,
+    raw_id: Option<i64>,
+}
+
+static PENDING: Mutex<Vec<Pending>> = Mutex::new(vec![]);
+
+/// Held while flushing, so `drain` waits for a flush already under way.
+static FLUSHING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The pool `spawn` flushes to; unset, nothing is buffered.
+static POOL: OnceLock<Arc<Pool<Sqlite>>> = OnceLock::new();
+
+/// Whether bulk lookups should buffer what they cache.
+pub fn enabled() -> bool {
+    settings().enabled && POOL.get().is_some()
+}
+
+/// Buffer `new`, flushing if that fills the buffer.
+pub fn push(new: &[NewAddress<'_>]) {
+    let full = {
+        let mut pending = PENDING.lock().unwrap();
+        pending.extend(new.iter().map(|new| Pending {
+            lat: new.lat.to_string(),
+            lon: new.lon.to_string(),
+            namespace: new.namespace.to_string(),
+            address: new.address.clone(),
+            raw_id: new.raw_id,
+        }));
+        pending.len() >= settings().batch
+    };
+    if full {
+        tokio::spawn(flush());
+    }
+}
+
+/// Insert everything buffered in one transaction.
+async fn flush() {
+    let Some(pool) = POOL.get() else {
+        return;
+    };
+    let _flushing = FLUSHING.lock().await;
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+    let result = async {
+        let mut tx = pool.begin().await?;
+        for chunk in pending.chunks(INSERT_ROWS) {
+            let new = chunk
+                .iter()
+                .map(|p| NewAddress {
+                    lat: &p.lat,
+                    lon: &p.lon,
+                    namespace: &p.namespace,
+                    address: &p.address,
+                    raw_id: p.raw_id,
+                })
+                .collect::<Vec<_>>();
+            crate::insert_addresses(&mut *tx, &new).await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            metrics::increment_by("gaia_write_behind_flushed_total", &[], pending.len() as u64)
+        }
+        Err(e) => {
+            tracing::warn!(
+                "failed to cache {} buffered addresses: {}",
+                pending.len(),
+                e
+            );
+            metrics::increment_by("gaia_write_behind_dropped_total", &[], pending.len() as u64);
+        }
+    }
+}
+
+/// Start buffering bulk lookups' inserts into `pool`, flushing every
+/// `CACHE_WRITE_BEHIND_INTERVAL_MS`.
+pub fn spawn(pool: Arc<Pool<Sqlite>>) {
+    let settings = settings();
+    if !settings.enabled || POOL.set(pool).is_err() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(settings.interval);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// Flush what is still buffered, at shutdown.
+pub async fn drain() {
+    flush().await;
+}